@@ -1,109 +1,490 @@
-use dataflow::operations::data::{Comparison, DataType, RowUpdate, Source, Updates};
-use dataflow::operations::state::MemStore;
-use dataflow::operations::{Count, Filter, Map};
-use dataflow::processing::worker::DebugWorker;
-use dataflow::processing::{Message, MessageRouter, OpWorker};
+use dataflow::connectors::load::{Format, Loader};
+use dataflow::frontend::sql::{self, Statement};
+use dataflow::frontend::{Frontend, QueryResult};
+use dataflow::operations::data::{DataType, Row, RowUpdate};
+use dataflow::operations::json::Json;
+use dataflow::operations::schema::ColumnSchema;
+use dataflow::processing::definition::{Definition, Graph, Node};
+use dataflow::processing::{MessageRouter, ReaderWorker};
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufRead, Write};
 use std::sync::Arc;
-use std::thread;
-use std::thread::sleep;
 use std::time::Duration;
-use dataflow::operations::filter::{ColumnConstraint, Constraint};
 
-fn main() {
+/// EXAMPLE counts rows with a value over 30 by their flag
+const EXAMPLE: &str = include_str!("../../graphs/example.json");
+
+/// Runs a graph built from a definition file, or the example graph if none is given. Rows are loaded into a table
+/// from the file when using the load subcommand and the shell subcommand starts an interactive shell. Otherwise a
+/// few example rows are written to the example graph.
+///
+/// Usage: dataflow [--graph <file>] [shell | load <file> [--table <name>] [--format csv|jsonl] [--no-header]]
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let definition = match args.first().map(|a| a.as_str()) {
+        Some("--graph") => {
+            let path = args.get(1).ok_or("--graph needs a definition file")?;
+            let definition = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+            args.drain(..2);
+            Some(definition)
+        }
+        _ => None,
+    };
+    let (load, interactive) = match args.first().map(|a| a.as_str()) {
+        None => (None, false),
+        Some("load") => (Some(load_args(&args[1..])?), false),
+        Some("shell") => (None, true),
+        Some(command) => return Err(format!("unknown command {}", command).into()),
+    };
+
     let router = Arc::new(MessageRouter::new());
+    let mut graph =
+        Definition::parse(definition.as_deref().unwrap_or(EXAMPLE))?.build(router.clone())?;
+    graph.start();
+    if interactive {
+        shell(&mut graph)?;
+        graph.stop();
+        return Ok(());
+    }
 
-    let mut worker1 = OpWorker::new(
-        router.clone(),
-        Filter {
-            constraints: vec![ColumnConstraint {
-                column: 0,
-                constraint: Constraint::Comparison(Comparison::GreaterThan, DataType::Integer(30)),
-            }],
-        },
-        vec![],
-    );
+    let last = match load {
+        Some(load) => {
+            let table = match (load.table, graph.tables().as_slice()) {
+                (Some(table), _) => table,
+                (None, [table]) => table.to_string(),
+                (None, _) => return Err("the graph has more than one table, use --table".into()),
+            };
+            let base = graph
+                .id(&table)
+                .ok_or_else(|| format!("no table named {}", table))?;
+            let loaded = Loader::new(router.clone(), base)?.load_file(&load.path, load.format)?;
+            println!(
+                "loaded {} rows from {} into {}",
+                loaded.rows, load.path, table
+            );
+            loaded.timestamp.unwrap_or(0)
+        }
+        None if definition.is_none() => {
+            let base_id = graph
+                .id("rows")
+                .ok_or("the example graph has no rows table")?;
+            router.write(
+                base_id,
+                vec![RowUpdate::Add(vec![300.into(), true.into()].into())],
+            )?;
+            router.write(
+                base_id,
+                vec![RowUpdate::Add(vec![200.into(), true.into()].into())],
+            )?;
+            router.write(
+                base_id,
+                vec![RowUpdate::Add(vec![20.into(), true.into()].into())],
+            )?;
+            router.write(
+                base_id,
+                vec![RowUpdate::Add(vec![50.into(), false.into()].into())],
+            )?
+        }
+        None => {
+            println!("built graph with nodes {}", graph.names().join(", "));
+            0
+        }
+    };
+
+    for leaf in graph.leaves() {
+        router.wait_for(leaf, last, Duration::from_secs(10));
+    }
+    graph.stop();
+    println!("Finished waiting for threads to run");
+    Ok(())
+}
+
+/// Load is the file to load and where to load it
+struct Load {
+    path: String,
+    table: Option<String>,
+    format: Format,
+}
 
-    let mut worker2 = OpWorker::new(router.clone(), Map { sources: vec![Source::Column(1)] }, vec![worker1.id]);
+const LOAD_USAGE: &str =
+    "usage: dataflow load <file> [--table <name>] [--format csv|jsonl] [--no-header]";
 
-    let mut worker3 = OpWorker::new(
-        router.clone(),
-        Count {
-            source: Source::Literal(1.into()),
-            group: vec![0],
-            state: MemStore::new(),
+/// load_args reads the file, table and format to load from the arguments of the load command
+fn load_args(args: &[String]) -> Result<Load, Box<dyn Error>> {
+    let mut path = None;
+    let mut table = None;
+    let mut format = None;
+    let mut header = true;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => format = Some(args.next().ok_or(LOAD_USAGE)?.clone()),
+            "--table" => table = Some(args.next().ok_or(LOAD_USAGE)?.clone()),
+            "--no-header" => header = false,
+            flag if flag.starts_with("--") => {
+                return Err(format!("unknown flag {}, {}", flag, LOAD_USAGE).into())
+            }
+            _ if path.is_some() => return Err(LOAD_USAGE.into()),
+            _ => path = Some(arg.clone()),
+        }
+    }
+
+    let path = path.ok_or(LOAD_USAGE)?;
+    let format = match format.as_deref() {
+        Some("csv") => Format::Csv { header },
+        Some("jsonl") => Format::JsonLines,
+        Some(f) => return Err(format!("unknown format {}", f).into()),
+        None => match Format::from_path(&path) {
+            Some(Format::Csv { .. }) => Format::Csv { header },
+            Some(f) => f,
+            None => return Err(format!("can't tell the format of {}, use --format", path).into()),
         },
-        vec![worker2.id],
-    );
+    };
+    Ok(Load {
+        path,
+        table,
+        format,
+    })
+}
 
-    let mut result_worker = DebugWorker::new(router.clone(), vec![worker3.id]);
-
-    let worker1_id = worker1.id;
-    let worker2_id = worker2.id;
-    let worker3_id = worker3.id;
-    let worker4_id = result_worker.id;
-
-    let thread = thread::spawn(move || {
-        worker1.start();
-        println!("finished processing thread 1");
-    });
-
-    let thread2 = thread::spawn(move || {
-        worker2.start();
-        println!("finished processing thread 2");
-    });
-
-    let thread3 = thread::spawn(move || {
-        worker3.start();
-        println!("finished processing thread 3");
-    });
-
-    let thread4 = thread::spawn(move || {
-        result_worker.start();
-        println!("finished processing thread 4");
-    });
-
-    router.send_message(
-        worker1_id,
-        Message::Update(Updates {
-            updates: vec![RowUpdate::Add(vec![300.into(), true.into()].into())],
-            source: 0,
-            destination: worker1_id,
-        }),
-    );
-    router.send_message(
-        worker1_id,
-        Message::Update(Updates {
-            updates: vec![RowUpdate::Add(vec![200.into(), true.into()].into())],
-            source: 0,
-            destination: worker1_id,
-        }),
-    );
-    router.send_message(
-        worker1_id,
-        Message::Update(Updates {
-            updates: vec![RowUpdate::Add(vec![20.into(), true.into()].into())],
-            source: 0,
-            destination: worker1_id,
-        }),
-    );
-    router.send_message(
-        worker1_id,
-        Message::Update(Updates {
-            updates: vec![RowUpdate::Add(vec![50.into(), false.into()].into())],
-            source: 0,
-            destination: worker1_id,
-        }),
+const HELP: &str = r#"SQL statements run against the tables and views, for example:
+  CREATE TABLE items (id integer not null, name text)
+  INSERT INTO items VALUES (1, 'a'), (2, 'b')
+  DELETE FROM items WHERE id = 1
+  SELECT * FROM items
+Commands:
+  \node <json>   add a node to the graph, like {"name": "big", "type": "filter", "parents": ["items"],
+                 "constraints": [{"column": "id", "op": ">", "value": 1}]}
+  \nodes         list the nodes in the graph
+  \show <node>   show a node's type, parents, columns and frontier, the rows of tables and views and the
+                 counts held by count nodes
+  \dot           print the graph in the Graphviz DOT format
+  \json          print the graph as JSON
+  \metrics       print each node's metrics in the Prometheus text format
+  \trace on|off  start or stop tracing writes through the graph
+  \trace [time]  show how the latest write, or the write at the timestamp, moved through the graph
+  \dead [node]   list the rows nodes failed to process
+  \replay <from> <to>
+                 replay the rows the first node failed to process into the second
+  \help          show this help
+  \quit          leave the shell"#;
+
+/// shell reads commands from stdin until it's closed or the user quits. Tables and views in the graph can be used
+/// from SQL, and tables created with SQL can be used as parents of new nodes.
+fn shell(graph: &mut Graph) -> Result<(), Box<dyn Error>> {
+    let frontend = Frontend::new(graph.router.clone());
+    for name in graph
+        .names()
+        .into_iter()
+        .map(String::from)
+        .collect::<Vec<_>>()
+    {
+        let id = graph.id(&name).ok_or("node disappeared")?;
+        add_relation(&frontend, graph, &name, id)?;
+    }
+
+    println!("dataflow shell, type \\help for help");
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("dataflow> ");
+        io::stdout().flush()?;
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => return Ok(()),
+        };
+        let line = line.trim();
+        let (command, rest) = line.split_at(line.find(' ').unwrap_or(line.len()));
+        let result = match command {
+            "" => Ok(()),
+            "\\quit" | "\\q" => return Ok(()),
+            "\\help" | "\\?" => {
+                println!("{}", HELP);
+                Ok(())
+            }
+            "\\nodes" => {
+                for name in graph.names() {
+                    let parents = graph.parents(name).unwrap_or_default().join(", ");
+                    println!(
+                        "{} ({}) <- {}",
+                        name,
+                        graph.kind(name).unwrap_or("?"),
+                        parents
+                    );
+                }
+                Ok(())
+            }
+            "\\node" => Node::parse(rest)
+                .map_err(Box::from)
+                .and_then(|node| add_node(&frontend, graph, node)),
+            "\\show" => show(&frontend, graph, rest.trim()),
+            "\\dot" => {
+                println!("{}", graph.router.to_dot());
+                Ok(())
+            }
+            "\\json" => {
+                println!("{}", graph.router.to_json());
+                Ok(())
+            }
+            "\\trace" => trace(graph, rest.trim()),
+            "\\dead" => dead(graph, rest.trim()),
+            "\\replay" => replay(graph, rest.trim()),
+            "\\metrics" => {
+                print!("{}", graph.router.prometheus());
+                Ok(())
+            }
+            c if c.starts_with('\\') => Err(format!("unknown command {}, try \\help", c).into()),
+            _ => run_sql(&frontend, graph, line),
+        };
+        if let Err(e) = result {
+            println!("error: {}", e);
+        }
+    }
+}
+
+/// add_node adds the node to the running graph. Nodes reading from tables are filled in with the rows already in them.
+/// Nodes whose other parents have already processed rows are refused, since nothing keeps what those parents sent.
+fn add_node(frontend: &Frontend, graph: &mut Graph, node: Node) -> Result<(), Box<dyn Error>> {
+    let metrics = graph.router.metrics();
+    let mut existing = vec![];
+    for parent in &node.parents {
+        let id = graph
+            .id(parent)
+            .ok_or_else(|| format!("no node named {}", parent))?;
+        match graph.kind(parent) {
+            Some("base") => {
+                if let Some(QueryResult::Rows { rows, .. }) =
+                    frontend.run(&format!("SELECT * FROM {}", parent))?.pop()
+                {
+                    existing.extend(rows.into_iter().map(|row| (row, 1)));
+                }
+            }
+            _ if metrics.get(&id).is_some_and(|m| m.rows_in > 0) => {
+                return Err(format!(
+                    "{} has already processed rows that {} would miss, add it to the graph definition instead",
+                    parent, node.name
+                )
+                .into())
+            }
+            _ => {}
+        }
+    }
+
+    let name = node.name.clone();
+    let id = graph.add(node)?;
+    add_relation(frontend, graph, &name, id)?;
+    if existing.is_empty() {
+        return Ok(());
+    }
+    // The shell is the only writer, so nothing was written between reading the tables and adding the node
+    let timestamp = graph
+        .router
+        .replay(id, existing)
+        .ok_or("node disappeared")?;
+    match graph
+        .router
+        .wait_for(id, timestamp, Duration::from_secs(10))
+    {
+        true => Ok(()),
+        false => Err(format!("timed out filling in {}", name).into()),
+    }
+}
+
+/// add_relation lets SQL statements use tables and views built from definitions
+fn add_relation(
+    frontend: &Frontend,
+    graph: &mut Graph,
+    name: &str,
+    id: usize,
+) -> Result<(), Box<dyn Error>> {
+    match graph.kind(name) {
+        Some("base") => {
+            // Statements need every row of a table to find the rows they change
+            let reader = ReaderWorker::new(graph.router.clone(), vec![id], vec![])?;
+            frontend.add_table(name, id, &reader);
+            graph.run(Box::new(reader));
+        }
+        Some("view") => {
+            let view = graph.view(name).ok_or("view disappeared")?;
+            frontend.add_view_by_id(name, id, view);
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn run_sql(frontend: &Frontend, graph: &mut Graph, line: &str) -> Result<(), Box<dyn Error>> {
+    for statement in sql::parse(line)? {
+        match frontend.execute(&statement)? {
+            QueryResult::Created => {
+                if let Statement::CreateTable { name, .. } = &statement {
+                    let id = frontend.base(name).ok_or("table disappeared")?;
+                    graph.add_table(name, id)?;
+                }
+                println!("CREATE TABLE");
+            }
+            QueryResult::Inserted(n) => println!("INSERT {}", n),
+            QueryResult::Updated(n) => println!("UPDATE {}", n),
+            QueryResult::Deleted(n) => println!("DELETE {}", n),
+            QueryResult::Set => println!("SET"),
+            QueryResult::Rows { columns, rows } => print_rows(&columns, &rows),
+        }
+    }
+    Ok(())
+}
+
+/// show prints what's known about a node. Only tables and views keep their rows, and only counts keep state.
+fn show(frontend: &Frontend, graph: &Graph, name: &str) -> Result<(), Box<dyn Error>> {
+    let id = graph
+        .id(name)
+        .ok_or_else(|| format!("no node named {}", name))?;
+    let kind = graph.kind(name).unwrap_or("?");
+    println!("{} is a {} node with id {}", name, kind, id);
+    println!(
+        "parents: {}",
+        graph.parents(name).unwrap_or_default().join(", ")
     );
+    println!("frontier: {}", graph.router.frontier(id));
+    if let Some(lag) = graph.router.lag(id) {
+        println!("lag: {:?}", lag);
+    }
+
+    let columns = graph.router.schema(id).unwrap_or_default().columns;
+    match kind {
+        "base" => {
+            if let Some(QueryResult::Rows { columns, rows }) =
+                frontend.run(&format!("SELECT * FROM {}", name))?.pop()
+            {
+                print_rows(&columns, &rows);
+            }
+            Ok(())
+        }
+        "view" => {
+            print_rows(
+                &columns,
+                &graph.view(name).ok_or("view disappeared")?.rows(),
+            );
+            Ok(())
+        }
+        _ => {
+            let names: Vec<&str> = columns.iter().map(|c| c.name.as_str()).collect();
+            println!("columns: {}", names.join(", "));
+            if let Some(state) = graph.state(name) {
+                println!("state:");
+                for (key, values) in &state {
+                    println!("  {} -> {}", format_values(key), format_values(values));
+                }
+                println!("({} entries)", state.len());
+            }
+            Ok(())
+        }
+    }
+}
+
+/// trace turns tracing on or off, or prints the hops of a traced write
+fn trace(graph: &Graph, arg: &str) -> Result<(), Box<dyn Error>> {
+    let trace = match arg {
+        "on" | "off" => {
+            graph.router.set_tracing(arg == "on");
+            println!("tracing {}", arg);
+            return Ok(());
+        }
+        "" => graph.router.latest_trace(),
+        timestamp => graph.router.trace(timestamp.parse()?),
+    };
+
+    let trace = trace.ok_or("no trace found, turn tracing on with \\trace on")?;
+    println!("write at {}", trace.timestamp);
+    for hop in &trace.hops {
+        let name = graph.name(hop.node).unwrap_or("?");
+        println!("  {} ({})", name, hop);
+    }
+    Ok(())
+}
+
+fn dead(graph: &Graph, arg: &str) -> Result<(), Box<dyn Error>> {
+    let letters = match arg {
+        "" => graph.router.dead_letters().list(),
+        name => {
+            let id = graph
+                .id(name)
+                .ok_or_else(|| format!("no node named {}", name))?;
+            graph.router.dead_letters().for_node(id)
+        }
+    };
+
+    for letter in &letters {
+        let name = graph.name(letter.node).unwrap_or("?");
+        println!(
+            "#{} {} at {}: {:?} ({})",
+            letter.id, name, letter.timestamp, letter.update, letter.error
+        );
+    }
+    println!("{} dead letters", letters.len());
+    Ok(())
+}
+
+fn replay(graph: &Graph, arg: &str) -> Result<(), Box<dyn Error>> {
+    let names: Vec<&str> = arg.split_whitespace().collect();
+    let (from, to) = match names.as_slice() {
+        [from, to] => (*from, *to),
+        _ => return Err("usage: \\replay <from> <to>".into()),
+    };
+    let id = |name: &str| {
+        graph
+            .id(name)
+            .ok_or_else(|| format!("no node named {}", name))
+    };
+    match graph.router.replay_dead_letters(id(from)?, id(to)?) {
+        Some(timestamp) => println!("replayed into {} at {}", to, timestamp),
+        None => println!("no dead letters for {}", from),
+    }
+    Ok(())
+}
+
+fn print_rows(columns: &[ColumnSchema], rows: &[Row]) {
+    let mut table = vec![columns
+        .iter()
+        .map(|c| c.name.clone())
+        .collect::<Vec<String>>()];
+    for row in rows {
+        table.push(row.data.iter().map(format_value).collect());
+    }
+
+    let widths: Vec<usize> = (0..columns.len())
+        .map(|i| {
+            table
+                .iter()
+                .map(|r| r.get(i).map_or(0, |v| v.chars().count()))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    for (i, row) in table.iter().enumerate() {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(v, w)| format!("{:w$}", v, w = w))
+            .collect();
+        println!("{}", cells.join(" | ").trim_end());
+        if i == 0 {
+            let lines: Vec<String> = widths.iter().map(|w| "-".repeat(*w)).collect();
+            println!("{}", lines.join("-+-"));
+        }
+    }
+    println!("({} rows)", rows.len());
+}
+
+fn format_value(value: &DataType) -> String {
+    match Json::from(value) {
+        Json::String(s) => s,
+        json => json.to_string(),
+    }
+}
 
-    sleep(Duration::from_secs(10));
-    router.send_message(worker1_id, Message::Stop);
-    router.send_message(worker2_id, Message::Stop);
-    router.send_message(worker3_id, Message::Stop);
-    router.send_message(worker4_id, Message::Stop);
-
-    thread.join().unwrap();
-    thread2.join().unwrap();
-    thread3.join().unwrap();
-    thread4.join().unwrap();
-    println!("Finished waiting for threads to run")
+fn format_values(values: &[DataType]) -> String {
+    let values: Vec<String> = values.iter().map(format_value).collect();
+    values.join(", ")
 }
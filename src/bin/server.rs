@@ -0,0 +1,84 @@
+use dataflow::frontend::{postgres, Frontend};
+use dataflow::operations::data::{Comparison, DataType};
+use dataflow::operations::filter::{ColumnConstraint, Constraint};
+use dataflow::operations::schema::{ColumnSchema, ColumnType, Schema};
+use dataflow::operations::{Base, Filter};
+use dataflow::processing::{MessageRouter, OpWorker, ReaderWorker};
+use dataflow::server::{metrics, Server};
+use std::env;
+use std::error::Error;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread;
+
+/// Serves an items table along with views of items by category and of cheap items by id. The same tables and views
+/// are served to Postgres clients, which can also create new tables. Metrics are served to Prometheus at /metrics.
+///
+/// Usage: server [address] [postgres address] [metrics address]
+fn main() -> Result<(), Box<dyn Error>> {
+    let addr = env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:7878".into());
+    let postgres_addr = env::args()
+        .nth(2)
+        .unwrap_or_else(|| "127.0.0.1:5433".into());
+    let metrics_addr = env::args()
+        .nth(3)
+        .unwrap_or_else(|| "127.0.0.1:9187".into());
+    let router = Arc::new(MessageRouter::new());
+
+    let mut items = OpWorker::new(
+        router.clone(),
+        Base {
+            schema: Schema::new(vec![
+                ColumnSchema::new("id", ColumnType::Integer, false),
+                ColumnSchema::new("category", ColumnType::Text, true),
+                ColumnSchema::new("price", ColumnType::Integer, false),
+            ]),
+        },
+        vec![],
+    )?;
+
+    let mut cheap = OpWorker::new(
+        router.clone(),
+        Filter {
+            constraints: vec![ColumnConstraint {
+                column: "price".into(),
+                constraint: Constraint::Comparison(Comparison::LessThan, DataType::Integer(10)),
+            }],
+        },
+        vec![items.id],
+    )?;
+
+    let mut by_category =
+        ReaderWorker::new(router.clone(), vec![items.id], vec!["category".into()])?;
+    let mut cheap_by_id = ReaderWorker::new(router.clone(), vec![cheap.id], vec!["id".into()])?;
+
+    let mut server = Server::new(router.clone());
+    server.add_table("items", items.id);
+    server.add_view("items_by_category", &by_category);
+    server.add_view("cheap_items", &cheap_by_id);
+
+    let frontend = Arc::new(Frontend::new(router.clone()));
+    frontend.add_table("items", items.id, &by_category);
+    frontend.add_view("cheap_items", &cheap_by_id);
+
+    thread::spawn(move || items.start());
+    thread::spawn(move || cheap.start());
+    thread::spawn(move || by_category.start());
+    thread::spawn(move || cheap_by_id.start());
+
+    let postgres = TcpListener::bind(&postgres_addr)?;
+    println!("postgres listening on {}", postgres.local_addr()?);
+    thread::spawn(move || postgres::serve(frontend, postgres));
+
+    let scrapes = TcpListener::bind(&metrics_addr)?;
+    println!("metrics listening on {}", scrapes.local_addr()?);
+    let metrics_router = router.clone();
+    thread::spawn(move || metrics::serve(metrics_router, scrapes));
+
+    let listener = TcpListener::bind(&addr)?;
+    println!("listening on {}", listener.local_addr()?);
+    Arc::new(server).serve(listener)?;
+    Ok(())
+}
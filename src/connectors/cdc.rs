@@ -0,0 +1,430 @@
+//! Ingests change events captured from another database so views can be kept over its tables.
+//!
+//! The change log holds one JSON event per line in the format Debezium writes, either with or without the schema
+//! envelope:
+//!
+//! ```json
+//! {"payload": {"op": "u", "source": {"table": "items"}, "before": {"id": 1, "price": 5}, "after": {"id": 1, "price": 6}}}
+//! ```
+//!
+//! Creates (c) and snapshot reads (r) add the after row, deletes (d) remove the before row and updates (u) do both.
+//! Blank lines and null tombstone events are skipped. Fields are matched to columns by name and converted using
+//! `Json::to_value`, so timestamps may be written as microseconds and dates as days since 1970-01-01.
+//!
+//! Databases often only include the key columns in before images. Tables registered with key columns remember the
+//! latest row for each key so those updates and deletes still remove the full row.
+
+use crate::operations::data::{Column, DataType, Row, RowUpdate, Timestamp};
+use crate::operations::json::Json;
+use crate::operations::schema::{Schema, SchemaError};
+use crate::operations::types::ParseError;
+use crate::processing::{MessageRouter, WriteError};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// CdcError is returned when a change event can't be ingested
+#[derive(Debug)]
+pub enum CdcError {
+    Io(io::Error),
+    Json(ParseError),
+    /// The JSON doesn't describe a change, like when the op is missing
+    InvalidEvent(String),
+    UnknownTable(String),
+    InvalidValue {
+        column: String,
+        error: ParseError,
+    },
+    Schema(SchemaError),
+    Write(WriteError),
+    /// The error happened on the line of the change log
+    Line(usize, Box<CdcError>),
+}
+
+impl fmt::Display for CdcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CdcError::Io(e) => e.fmt(f),
+            CdcError::Json(e) => e.fmt(f),
+            CdcError::InvalidEvent(message) => write!(f, "invalid change event: {}", message),
+            CdcError::UnknownTable(name) => write!(f, "no table named {}", name),
+            CdcError::InvalidValue { column, error } => write!(f, "column {}: {}", column, error),
+            CdcError::Schema(e) => e.fmt(f),
+            CdcError::Write(e) => e.fmt(f),
+            CdcError::Line(line, e) => write!(f, "line {}: {}", line, e),
+        }
+    }
+}
+
+impl std::error::Error for CdcError {}
+
+impl From<io::Error> for CdcError {
+    fn from(e: io::Error) -> Self {
+        CdcError::Io(e)
+    }
+}
+
+impl From<SchemaError> for CdcError {
+    fn from(e: SchemaError) -> Self {
+        CdcError::Schema(e)
+    }
+}
+
+impl From<WriteError> for CdcError {
+    fn from(e: WriteError) -> Self {
+        CdcError::Write(e)
+    }
+}
+
+struct Table {
+    base: usize,
+    schema: Schema,
+    key: Vec<usize>,
+    // The latest row for each key, only kept when the table has key columns
+    rows: HashMap<Vec<DataType>, Row>,
+}
+
+impl Table {
+    fn row(&self, fields: &Json) -> Result<Row, CdcError> {
+        if !matches!(fields, Json::Object(_)) {
+            return Err(CdcError::InvalidEvent("row isn't an object".into()));
+        }
+
+        let row: Row = self
+            .schema
+            .columns
+            .iter()
+            .map(|column| {
+                fields
+                    .get(&column.name)
+                    .map_or(Ok(DataType::None), |v| v.to_value(column.column_type))
+                    .map_err(|error| CdcError::InvalidValue {
+                        column: column.name.clone(),
+                        error,
+                    })
+            })
+            .collect::<Result<Vec<DataType>, CdcError>>()?
+            .into();
+        Ok(row)
+    }
+
+    fn key(&self, row: &Row) -> Vec<DataType> {
+        self.key.iter().map(|i| row[*i].clone()).collect()
+    }
+
+    /// remove returns the update removing the row. Tables with keys remove the latest row with the same key.
+    fn remove(&self, row: Row) -> Result<Option<RowUpdate>, CdcError> {
+        if self.key.is_empty() {
+            self.schema.check_row(&row)?;
+            return Ok(Some(RowUpdate::Remove(row)));
+        }
+        Ok(self
+            .rows
+            .get(&self.key(&row))
+            .cloned()
+            .map(RowUpdate::Remove))
+    }
+
+    fn add(&self, row: Row) -> Result<RowUpdate, CdcError> {
+        self.schema.check_row(&row)?;
+        Ok(RowUpdate::Add(row))
+    }
+
+    /// record remembers the latest row for each key once the updates have been written
+    fn record(&mut self, updates: &[RowUpdate]) {
+        if self.key.is_empty() {
+            return;
+        }
+        for update in updates {
+            match update {
+                RowUpdate::Add(row) => drop(self.rows.insert(self.key(row), row.clone())),
+                RowUpdate::Remove(row) => drop(self.rows.remove(&self.key(row))),
+            }
+        }
+    }
+}
+
+/// ChangeLog turns change events into writes to base tables
+pub struct ChangeLog {
+    router: Arc<MessageRouter>,
+    tables: Mutex<HashMap<String, Table>>,
+}
+
+impl ChangeLog {
+    pub fn new(router: Arc<MessageRouter>) -> Self {
+        Self {
+            router,
+            tables: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// add_table sends changes to the named table to the base worker. When key columns are given the latest row
+    /// for each key is remembered so changes without full before images still remove the right row.
+    pub fn add_table(&self, name: &str, base: usize, key: Vec<Column>) -> Result<(), SchemaError> {
+        let schema = self
+            .router
+            .schema(base)
+            .ok_or(SchemaError::MissingParent(base))?;
+        let key = key
+            .into_iter()
+            .map(|mut c| c.resolve(&schema))
+            .collect::<Result<Vec<usize>, SchemaError>>()?;
+
+        let table = Table {
+            base,
+            schema,
+            key,
+            rows: HashMap::new(),
+        };
+        // Fine with panicking on thread poisoning
+        self.tables.lock().unwrap().insert(name.into(), table);
+        Ok(())
+    }
+
+    /// apply writes a single change event into the graph, returning the timestamp it was written at. Returns None
+    /// for events that don't change anything, like tombstones.
+    pub fn apply(&self, event: &str) -> Result<Option<Timestamp>, CdcError> {
+        if event.trim().is_empty() {
+            return Ok(None);
+        }
+        let event = Json::parse(event).map_err(CdcError::Json)?;
+        let event = match event.get("payload") {
+            Some(payload) if event.get("schema").is_some() => payload,
+            _ => &event,
+        };
+        if event.is_null() {
+            return Ok(None);
+        }
+
+        let invalid = |message: &str| CdcError::InvalidEvent(message.into());
+        let op = event
+            .get("op")
+            .and_then(Json::as_str)
+            .ok_or_else(|| invalid("missing op"))?;
+        let name = event
+            .get("source")
+            .and_then(|s| s.get("table"))
+            .and_then(Json::as_str)
+            .ok_or_else(|| invalid("missing source table"))?;
+
+        let mut tables = self.tables.lock().unwrap(); // Fine with panicking on thread poisoning
+        let table = tables
+            .get_mut(name)
+            .ok_or_else(|| CdcError::UnknownTable(name.into()))?;
+        let image = |field: &str| {
+            event
+                .get(field)
+                .filter(|r| !r.is_null())
+                .ok_or_else(|| invalid(&format!("{} event is missing its {} row", op, field)))
+        };
+
+        let mut updates = vec![];
+        match op {
+            "c" | "r" => updates.push(table.add(table.row(image("after")?)?)?),
+            "u" => {
+                let after = table.row(image("after")?)?;
+                // The before row may be missing when only keys are logged and they didn't change
+                let before = match image("before") {
+                    Ok(before) => table.row(before)?,
+                    Err(_) if !table.key.is_empty() => after.clone(),
+                    Err(e) => return Err(e),
+                };
+                updates.extend(table.remove(before)?);
+                updates.push(table.add(after)?);
+            }
+            "d" => updates.extend(table.remove(table.row(image("before")?)?)?),
+            op => return Err(invalid(&format!("unsupported op {}", op))),
+        }
+
+        // Deletes for keys that were never added don't change anything
+        if updates.is_empty() {
+            return Ok(None);
+        }
+        // Writing while the tables are locked keeps events for the same key in order. The rows for each key are only
+        // changed once the write succeeds, so a failed event leaves them matching what the graph holds.
+        let written = self.router.write(table.base, updates.clone())?;
+        table.record(&updates);
+        Ok(Some(written))
+    }
+
+    /// ingest applies every event from the reader, returning the timestamp of the last write
+    pub fn ingest<R: BufRead>(&self, reader: R) -> Result<Option<Timestamp>, CdcError> {
+        let mut last = None;
+        for (i, line) in reader.lines().enumerate() {
+            let written = self
+                .apply(&line?)
+                .map_err(|e| CdcError::Line(i + 1, Box::new(e)))?;
+            last = written.or(last);
+        }
+        Ok(last)
+    }
+
+    /// tail follows the change log file, applying events as they're appended, until stop is set. Reading starts at the
+    /// byte offset in checkpoint, and the offset just past each applied event is stored back into it, so a restarted
+    /// tail given the same checkpoint resumes after the last event applied. New events are checked for every poll
+    /// interval once the end is reached.
+    pub fn tail<P: AsRef<Path>>(
+        &self,
+        path: P,
+        checkpoint: &AtomicU64,
+        poll: Duration,
+        stop: &AtomicBool,
+    ) -> Result<(), CdcError> {
+        let mut offset = checkpoint.load(Ordering::SeqCst);
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut reader = BufReader::new(file);
+        let mut line = String::new();
+        let mut number = 0;
+        while !stop.load(Ordering::Relaxed) {
+            // Partly written lines are kept until the rest of the line arrives
+            if reader.read_line(&mut line)? == 0 || !line.ends_with('\n') {
+                thread::sleep(poll);
+                continue;
+            }
+
+            number += 1;
+            self.apply(&line)
+                .map_err(|e| CdcError::Line(number, Box::new(e)))?;
+            offset += line.len() as u64;
+            checkpoint.store(offset, Ordering::SeqCst);
+            line.clear();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::schema::{ColumnSchema, ColumnType};
+    use crate::operations::Base;
+    use crate::processing::{OpWorker, ReaderWorker};
+    use std::io::Write;
+
+    #[test]
+    fn ingests_changes() {
+        let router = Arc::new(MessageRouter::new());
+        let mut base = OpWorker::new(
+            router.clone(),
+            Base {
+                schema: Schema::new(vec![
+                    ColumnSchema::new("id", ColumnType::Integer, false),
+                    ColumnSchema::new("name", ColumnType::Text, true),
+                    ColumnSchema::new("added", ColumnType::Date, true),
+                ]),
+            },
+            vec![],
+        )
+        .unwrap();
+        let mut reader = ReaderWorker::new(router.clone(), vec![base.id], vec![]).unwrap();
+        let view = reader.view();
+        let log = Arc::new(ChangeLog::new(router.clone()));
+        log.add_table("items", base.id, vec!["id".into()]).unwrap();
+        let reader_id = reader.id;
+        thread::spawn(move || base.start());
+        thread::spawn(move || reader.start());
+
+        let events = r#"{"op": "r", "source": {"table": "items"}, "before": null, "after": {"id": 1, "name": "a", "added": 2}}
+{"schema": {}, "payload": {"op": "c", "source": {"table": "items"}, "after": {"id": 2, "name": "b", "added": "1970-01-02"}}}
+
+null
+{"op": "u", "source": {"table": "items"}, "before": {"id": 1}, "after": {"id": 1, "name": "c", "added": 2}}
+{"op": "d", "source": {"table": "items"}, "before": {"id": 2}}
+"#;
+        let t = log.ingest(events.as_bytes()).unwrap().unwrap();
+        assert!(router.wait_for(reader_id, t, Duration::from_secs(5)));
+        assert_eq!(
+            view.rows(),
+            vec![vec![1.into(), "c".into(), DataType::Date(2)].into()]
+        );
+
+        let err = log
+            .ingest(r#"{"op": "c", "source": {"table": "orders"}, "after": {}}"#.as_bytes())
+            .unwrap_err();
+        assert_eq!(err.to_string(), "line 1: no table named orders");
+        let err = log
+            .apply(r#"{"op": "c", "source": {"table": "items"}, "after": {"name": "x"}}"#)
+            .unwrap_err();
+        assert!(matches!(err, CdcError::Schema(SchemaError::NullValue(0))));
+
+        // An update that fails doesn't forget the row it would have replaced, so it can still be deleted by key
+        log.apply(r#"{"op": "c", "source": {"table": "items"}, "after": {"id": 5}}"#)
+            .unwrap();
+        let err = log
+            .apply(r#"{"op": "u", "source": {"table": "items"}, "before": {"id": 5}, "after": {"id": 5, "added": "x"}}"#)
+            .unwrap_err();
+        assert!(matches!(err, CdcError::InvalidValue { .. }));
+        let err = log
+            .apply(r#"{"op": "u", "source": {"table": "items"}, "before": {"id": 5}, "after": {"name": "z"}}"#)
+            .unwrap_err();
+        assert!(matches!(err, CdcError::Schema(SchemaError::NullValue(0))));
+        let t = log
+            .apply(r#"{"op": "d", "source": {"table": "items"}, "before": {"id": 5}}"#)
+            .unwrap()
+            .unwrap();
+        assert!(router.wait_for(reader_id, t, Duration::from_secs(5)));
+        assert_eq!(view.rows().len(), 1);
+
+        // Tailing picks up events appended after it started
+        let path = std::env::temp_dir().join(format!("dataflow-cdc-{}.jsonl", std::process::id()));
+        let mut file = File::create(&path).unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let checkpoint = Arc::new(AtomicU64::new(0));
+        let tail = |stop: &Arc<AtomicBool>| {
+            let (log, stop, path) = (log.clone(), stop.clone(), path.clone());
+            let checkpoint = checkpoint.clone();
+            thread::spawn(move || log.tail(path, &checkpoint, Duration::from_millis(5), &stop))
+        };
+        let tailing = tail(&stop);
+        write!(file, r#"{{"op": "c", "source": {{"table": "items"}}, "#).unwrap();
+        file.flush().unwrap();
+        thread::sleep(Duration::from_millis(20));
+        writeln!(file, r#""after": {{"id": 3}}}}"#).unwrap();
+        file.flush().unwrap();
+
+        let wait_for_rows = |rows: usize| {
+            let deadline = std::time::Instant::now() + Duration::from_secs(5);
+            while view.rows().len() < rows && std::time::Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(5));
+            }
+            assert_eq!(view.rows().len(), rows);
+        };
+        wait_for_rows(2);
+        stop.store(true, Ordering::Relaxed);
+        tailing.join().unwrap().unwrap();
+        let written = std::fs::metadata(&path).unwrap().len();
+        assert_eq!(checkpoint.load(Ordering::SeqCst), written);
+
+        // A restarted tail resumes from the checkpoint rather than applying the first event again
+        let stop = Arc::new(AtomicBool::new(false));
+        let tailing = tail(&stop);
+        writeln!(
+            file,
+            r#"{{"op": "c", "source": {{"table": "items"}}, "after": {{"id": 4}}}}"#
+        )
+        .unwrap();
+        file.flush().unwrap();
+        wait_for_rows(3);
+        let mut ids: Vec<DataType> = view.rows().into_iter().map(|r| r[0].clone()).collect();
+        ids.sort();
+        assert_eq!(ids, vec![1.into(), 3.into(), 4.into()]);
+        stop.store(true, Ordering::Relaxed);
+        tailing.join().unwrap().unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        // Deleting a key that was never added doesn't write anything
+        let latest = router.latest();
+        let deleted = log
+            .apply(r#"{"op": "d", "source": {"table": "items"}, "before": {"id": 9}}"#)
+            .unwrap();
+        assert_eq!(deleted, None);
+        assert_eq!(router.latest(), latest);
+    }
+}
@@ -0,0 +1,337 @@
+//! An embedded key-value store keeping the rows of a view on disk, indexed by key columns.
+//!
+//! The store is a single log file of records, each written as a 4 byte big endian length followed by the record in
+//! the binary encoding from `operations::encoding`. A record starts with a tag byte:
+//!
+//! | Tag | Record     | Body                          |
+//! |-----|------------|-------------------------------|
+//! | 0   | Put        | key row, list of rows for key |
+//! | 1   | Delete     | key row                       |
+//! | 2   | Checkpoint | varint timestamp              |
+//!
+//! Opening the store replays the log up to the last checkpoint and cuts off everything after it. Once the log holds
+//! many more records than there are keys it's rewritten with a single Put for each key.
+
+use super::sink::Sink;
+use super::ConnectorError;
+use crate::operations::data::{Column, DataType, Diff, Row, Timestamp};
+use crate::operations::encoding::{from_bytes, to_bytes, Decode, DecodeError, Encode};
+use crate::operations::schema::{Schema, SchemaError};
+use crate::processing::Change;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+type Key = Vec<DataType>;
+
+#[derive(Debug, PartialEq)]
+enum Record {
+    Put(Key, Vec<Row>),
+    Delete(Key),
+    Checkpoint(Timestamp),
+}
+
+impl Encode for Record {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Record::Put(key, rows) => {
+                buf.push(0);
+                key.as_slice().encode(buf);
+                rows.as_slice().encode(buf);
+            }
+            Record::Delete(key) => {
+                buf.push(1);
+                key.as_slice().encode(buf);
+            }
+            Record::Checkpoint(timestamp) => {
+                buf.push(2);
+                timestamp.encode(buf);
+            }
+        }
+    }
+}
+
+impl Decode for Record {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let (tag, rest) = input.split_first().ok_or(DecodeError::UnexpectedEnd)?;
+        *input = rest;
+        match tag {
+            0 => Ok(Record::Put(Vec::decode(input)?, Vec::decode(input)?)),
+            1 => Ok(Record::Delete(Vec::decode(input)?)),
+            2 => Ok(Record::Checkpoint(u64::decode(input)?)),
+            tag => Err(DecodeError::UnknownTag(*tag)),
+        }
+    }
+}
+
+struct Log {
+    file: BufWriter<File>,
+    records: usize,
+    checkpoint: Option<Timestamp>,
+}
+
+impl Log {
+    fn append(&mut self, record: &Record) -> io::Result<()> {
+        let payload = to_bytes(record);
+        let len = u32::try_from(payload.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "record too large"))?;
+        self.file.write_all(&len.to_be_bytes())?;
+        self.file.write_all(&payload)?;
+        self.records += 1;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()
+    }
+}
+
+/// KvStore holds rows on disk by key. It can be read while a KvSink is writing to it.
+pub struct KvStore {
+    path: PathBuf,
+    rows: RwLock<HashMap<Key, Vec<Row>>>,
+    log: Mutex<Log>,
+}
+
+impl KvStore {
+    /// open opens the store at the path, creating it if it doesn't exist
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, ConnectorError> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .open(&path)?;
+        let mut bytes = vec![];
+        file.read_to_end(&mut bytes)?;
+
+        let mut rows = HashMap::new();
+        let mut pending = vec![];
+        let mut records = 0;
+        let mut checkpoint = None;
+        let mut checkpointed_len = 0;
+        let mut input = bytes.as_slice();
+        // A record cut short by a crash ends the log
+        while input.len() >= 4 {
+            let len = u32::from_be_bytes([input[0], input[1], input[2], input[3]]) as usize;
+            if input.len() < 4 + len {
+                break;
+            }
+            let record = from_bytes(&input[4..4 + len])?;
+            input = &input[4 + len..];
+            records += 1;
+
+            match record {
+                Record::Checkpoint(timestamp) => {
+                    for record in pending.drain(..) {
+                        match record {
+                            Record::Put(key, values) => rows.insert(key, values),
+                            Record::Delete(key) => rows.remove(&key),
+                            Record::Checkpoint(_) => None,
+                        };
+                    }
+                    checkpoint = Some(timestamp);
+                    checkpointed_len = bytes.len() - input.len();
+                }
+                record => pending.push(record),
+            }
+        }
+
+        // Records after the last checkpoint belong to changes that will be delivered again
+        file.set_len(checkpointed_len as u64)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            path,
+            rows: RwLock::new(rows),
+            log: Mutex::new(Log {
+                file: BufWriter::new(file),
+                records: records - pending.len(),
+                checkpoint,
+            }),
+        })
+    }
+
+    /// get returns the rows with the key
+    pub fn get(&self, key: &[DataType]) -> Vec<Row> {
+        let rows = self.rows.read().unwrap(); // Fine with panicking on thread poisoning
+        rows.get(key).cloned().unwrap_or_default()
+    }
+
+    /// len returns the number of keys in the store
+    pub fn len(&self) -> usize {
+        self.rows.read().unwrap().len() // Fine with panicking on thread poisoning
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// checkpointed returns the timestamp of the last checkpoint
+    pub fn checkpointed(&self) -> Option<Timestamp> {
+        self.log.lock().unwrap().checkpoint // Fine with panicking on thread poisoning
+    }
+
+    /// apply adds and removes rows, grouping them by the values of the key columns
+    fn apply(&self, key: &[usize], updates: &[Diff]) -> Result<(), ConnectorError> {
+        let mut log = self.log.lock().unwrap(); // Fine with panicking on thread poisoning
+        let mut rows = self.rows.write().unwrap();
+        let mut changed = HashSet::new();
+        for (row, multiplicity) in updates {
+            let k: Key = key.iter().map(|c| row[*c].clone()).collect();
+            let values = rows.entry(k.clone()).or_default();
+            for _ in 0..multiplicity.unsigned_abs() {
+                match *multiplicity > 0 {
+                    true => values.push(row.clone()),
+                    false => {
+                        if let Some(i) = values.iter().position(|r| r == row) {
+                            values.swap_remove(i);
+                        }
+                    }
+                }
+            }
+            changed.insert(k);
+        }
+
+        for k in changed {
+            let record = match rows.get(&k) {
+                Some(values) if !values.is_empty() => Record::Put(k, values.clone()),
+                _ => {
+                    rows.remove(&k);
+                    Record::Delete(k)
+                }
+            };
+            log.append(&record)?;
+        }
+        Ok(())
+    }
+
+    /// checkpoint makes the log durable up to the timestamp, compacting it if it has grown too large
+    fn checkpoint(&self, timestamp: Timestamp) -> Result<(), ConnectorError> {
+        let mut log = self.log.lock().unwrap(); // Fine with panicking on thread poisoning
+        let rows = self.rows.read().unwrap();
+        if log.records > 2 * rows.len() + 1000 {
+            // Renaming over the old log means a crash leaves either the old or new one whole
+            let temp = self.path.with_extension("compact");
+            let mut compacted = Log {
+                file: BufWriter::new(File::create(&temp)?),
+                records: 0,
+                checkpoint: None,
+            };
+            for (key, values) in rows.iter() {
+                compacted.append(&Record::Put(key.clone(), values.clone()))?;
+            }
+            compacted.append(&Record::Checkpoint(timestamp))?;
+            compacted.sync()?;
+            fs::rename(temp, &self.path)?;
+            *log = compacted;
+        } else {
+            log.append(&Record::Checkpoint(timestamp))?;
+            log.sync()?;
+        }
+        log.checkpoint = Some(timestamp);
+        Ok(())
+    }
+}
+
+/// KvSink keeps a store up to date with the rows of a view, grouped by the key columns
+pub struct KvSink {
+    store: Arc<KvStore>,
+    key: Vec<Column>,
+    indices: Vec<usize>,
+}
+
+impl KvSink {
+    pub fn new(store: Arc<KvStore>, key: Vec<Column>) -> Self {
+        Self {
+            store,
+            key,
+            indices: vec![],
+        }
+    }
+}
+
+impl Sink for KvSink {
+    fn open(&mut self, schema: &Schema) -> Result<Option<Timestamp>, ConnectorError> {
+        self.indices = self
+            .key
+            .iter_mut()
+            .map(|c| c.resolve(schema))
+            .collect::<Result<Vec<usize>, SchemaError>>()?;
+        Ok(self.store.checkpointed())
+    }
+
+    fn write(&mut self, change: &Change) -> Result<(), ConnectorError> {
+        self.store.apply(&self.indices, &change.updates)
+    }
+
+    fn checkpoint(&mut self, timestamp: Timestamp) -> Result<(), ConnectorError> {
+        self.store.checkpoint(timestamp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connectors::sink::SinkWorker;
+    use crate::operations::data::RowUpdate;
+    use crate::operations::schema::{ColumnSchema, ColumnType};
+    use crate::operations::Base;
+    use crate::processing::{MessageRouter, OpWorker};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn stores_rows_by_key() {
+        let path = std::env::temp_dir().join(format!("dataflow-kv-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let store = Arc::new(KvStore::open(&path).unwrap());
+
+        let router = Arc::new(MessageRouter::new());
+        let schema = Schema::new(vec![
+            ColumnSchema::new("id", ColumnType::Integer, false),
+            ColumnSchema::new("category", ColumnType::Text, true),
+        ]);
+        let mut base = OpWorker::new(router.clone(), Base { schema }, vec![]).unwrap();
+        let sink = KvSink::new(store.clone(), vec!["category".into()]);
+        let mut worker = SinkWorker::new(router.clone(), sink, vec![base.id]).unwrap();
+        let worker_id = worker.id;
+        let row = |id: i32, category: &str| -> Row { vec![id.into(), category.into()].into() };
+        router
+            .write(
+                base.id,
+                vec![
+                    RowUpdate::Add(row(1, "a")),
+                    RowUpdate::Add(row(2, "a")),
+                    RowUpdate::Add(row(3, "b")),
+                ],
+            )
+            .unwrap();
+        let b = router
+            .write(base.id, vec![RowUpdate::Remove(row(3, "b"))])
+            .unwrap();
+        thread::spawn(move || base.start());
+        thread::spawn(move || worker.start());
+
+        assert!(router.wait_for(worker_id, b, Duration::from_secs(5)));
+        assert_eq!(store.get(&["a".into()]).len(), 2);
+        assert_eq!(store.get(&["b".into()]), vec![]);
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.checkpointed(), Some(b));
+
+        // Records after the checkpoint are dropped when reopened
+        store.apply(&[1], &[(row(4, "c"), 1)]).unwrap();
+        store.log.lock().unwrap().sync().unwrap();
+        let reopened = KvStore::open(&path).unwrap();
+        assert_eq!(reopened.checkpointed(), Some(b));
+        assert_eq!(reopened.get(&["c".into()]), vec![]);
+        let mut rows = reopened.get(&["a".into()]);
+        rows.sort();
+        assert_eq!(rows, vec![row(1, "a"), row(2, "a")]);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,403 @@
+//! Bulk loads rows from CSV and JSON Lines files into base tables.
+//!
+//! CSV follows RFC 4180: fields containing commas, quotes or newlines are quoted and quotes inside them are doubled.
+//! Empty unquoted fields are null while "" is empty text. When the file has a header its names are matched to
+//! columns, otherwise fields are taken in column order. Fields are parsed with `ColumnType::parse`.
+//!
+//! JSON Lines files hold one row per line, either an object with a field per column or an array of values in column
+//! order.
+
+use super::{json_row, ConnectorError};
+use crate::operations::data::{DataType, Row, RowUpdate, Timestamp};
+use crate::operations::json::Json;
+use crate::operations::schema::{Schema, SchemaError};
+use crate::processing::MessageRouter;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Format is the layout of the file being loaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Csv { header: bool },
+    JsonLines,
+}
+
+impl Format {
+    /// from_path guesses the format from the file extension, assuming CSV files have a header
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Format> {
+        let extension = path.as_ref().extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "csv" => Some(Format::Csv { header: true }),
+            "jsonl" | "ndjson" | "json" => Some(Format::JsonLines),
+            _ => None,
+        }
+    }
+}
+
+/// Loaded describes the rows written by a load
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Loaded {
+    pub rows: usize,
+    /// The timestamp of the last batch, or None if the file was empty
+    pub timestamp: Option<Timestamp>,
+}
+
+/// Loader writes rows read from files into a base table in large batches
+pub struct Loader {
+    router: Arc<MessageRouter>,
+    base: usize,
+    schema: Schema,
+    batch_size: usize,
+}
+
+impl Loader {
+    pub fn new(router: Arc<MessageRouter>, base: usize) -> Result<Self, SchemaError> {
+        let schema = router
+            .schema(base)
+            .ok_or(SchemaError::MissingParent(base))?;
+        Ok(Self {
+            router,
+            base,
+            schema,
+            batch_size: 10_000,
+        })
+    }
+
+    /// batch_size sets the most rows written at a single timestamp
+    pub fn batch_size(&mut self, batch_size: usize) {
+        self.batch_size = batch_size.max(1);
+    }
+
+    /// load_file loads every row in the file
+    pub fn load_file<P: AsRef<Path>>(
+        &self,
+        path: P,
+        format: Format,
+    ) -> Result<Loaded, ConnectorError> {
+        self.load(BufReader::new(File::open(path)?), format)
+    }
+
+    /// load loads every row from the reader. Rows are checked against the table's schema and the first bad row
+    /// stops the load, though batches before it will already have been written.
+    pub fn load<R: BufRead>(&self, reader: R, format: Format) -> Result<Loaded, ConnectorError> {
+        let mut batch = Batcher {
+            loader: self,
+            updates: vec![],
+            loaded: Loaded {
+                rows: 0,
+                timestamp: None,
+            },
+        };
+
+        match format {
+            Format::Csv { header } => {
+                let mut records = CsvRecords::new(reader);
+                let columns = match header {
+                    true => match records.next() {
+                        None => return batch.finish(),
+                        Some(record) => Some(self.header(record?.1)?),
+                    },
+                    false => None,
+                };
+
+                for record in records {
+                    let (line, fields) = record?;
+                    let row = self
+                        .csv_row(columns.as_deref(), fields)
+                        .map_err(|e| ConnectorError::Line(line, Box::new(e)))?;
+                    batch.push(row, line)?;
+                }
+            }
+            Format::JsonLines => {
+                for (i, line) in reader.lines().enumerate() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let row = self
+                        .json_row(&line)
+                        .map_err(|e| ConnectorError::Line(i + 1, Box::new(e)))?;
+                    batch.push(row, i + 1)?;
+                }
+            }
+        }
+
+        batch.finish()
+    }
+
+    /// header returns the column each field of the CSV is loaded into
+    fn header(&self, names: Vec<Option<String>>) -> Result<Vec<usize>, ConnectorError> {
+        names
+            .into_iter()
+            .map(|name| {
+                let name = name.unwrap_or_default();
+                self.schema
+                    .index_of(name.trim())
+                    .ok_or(ConnectorError::UnknownColumn(name))
+            })
+            .collect()
+    }
+
+    fn csv_row(
+        &self,
+        columns: Option<&[usize]>,
+        fields: Vec<Option<String>>,
+    ) -> Result<Row, ConnectorError> {
+        let positions: Vec<usize> = match columns {
+            Some(columns) => columns.to_vec(),
+            None => (0..self.schema.len()).collect(),
+        };
+        if fields.len() != positions.len() {
+            return Err(SchemaError::RowWidth {
+                expected: positions.len(),
+                found: fields.len(),
+            }
+            .into());
+        }
+
+        let mut row = vec![DataType::None; self.schema.len()];
+        for (position, field) in positions.into_iter().zip(fields) {
+            let column = &self.schema.columns[position];
+            if let Some(field) = field {
+                row[position] = column.column_type.parse(&field).map_err(|error| {
+                    ConnectorError::InvalidValue {
+                        column: column.name.clone(),
+                        error,
+                    }
+                })?;
+            }
+        }
+        Ok(row.into())
+    }
+
+    fn json_row(&self, line: &str) -> Result<Row, ConnectorError> {
+        match Json::parse(line).map_err(ConnectorError::Json)? {
+            Json::Array(values) => values
+                .iter()
+                .enumerate()
+                .map(|(i, value)| {
+                    let column = self.schema.column(i)?;
+                    value.to_value(column.column_type).map_err(|error| {
+                        ConnectorError::InvalidValue {
+                            column: column.name.clone(),
+                            error,
+                        }
+                    })
+                })
+                .collect::<Result<Vec<DataType>, ConnectorError>>()
+                .map(Row::from),
+            fields => json_row(&self.schema, &fields),
+        }
+    }
+}
+
+/// Batcher collects rows and writes them once there's a full batch
+struct Batcher<'a> {
+    loader: &'a Loader,
+    updates: Vec<RowUpdate>,
+    loaded: Loaded,
+}
+
+impl Batcher<'_> {
+    fn push(&mut self, row: Row, line: usize) -> Result<(), ConnectorError> {
+        self.loader
+            .schema
+            .check_row(&row)
+            .map_err(|e| ConnectorError::Line(line, Box::new(e.into())))?;
+        self.updates.push(RowUpdate::Add(row));
+        if self.updates.len() >= self.loader.batch_size {
+            self.write()?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self) -> Result<(), ConnectorError> {
+        let updates = std::mem::take(&mut self.updates);
+        self.loaded.rows += updates.len();
+        self.loaded.timestamp = Some(self.loader.router.write(self.loader.base, updates)?);
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Loaded, ConnectorError> {
+        if !self.updates.is_empty() {
+            self.write()?;
+        }
+        Ok(self.loaded)
+    }
+}
+
+/// CsvRecords reads CSV records along with the line they start on. Quoted fields can span lines.
+struct CsvRecords<R> {
+    reader: R,
+    line: usize,
+}
+
+impl<R: BufRead> CsvRecords<R> {
+    fn new(reader: R) -> Self {
+        Self { reader, line: 0 }
+    }
+
+    fn read_line(&mut self, buf: &mut String) -> Result<bool, ConnectorError> {
+        buf.clear();
+        let read = self.reader.read_line(buf)?;
+        self.line += 1;
+        Ok(read > 0)
+    }
+}
+
+impl<R: BufRead> Iterator for CsvRecords<R> {
+    type Item = Result<(usize, Vec<Option<String>>), ConnectorError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = String::new();
+        loop {
+            match self.read_line(&mut line) {
+                Err(e) => return Some(Err(e)),
+                Ok(false) => return None,
+                Ok(true) if line.trim_end_matches(['\r', '\n']).is_empty() => continue,
+                Ok(true) => break,
+            }
+        }
+
+        let start = self.line;
+        let mut fields = vec![];
+        let mut field = String::new();
+        let mut quoted = false;
+        let mut in_quotes = false;
+        loop {
+            let mut chars = line.chars().peekable();
+            while let Some(c) = chars.next() {
+                match (c, in_quotes) {
+                    ('"', true) if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    ('"', true) => in_quotes = false,
+                    ('"', false) if field.is_empty() && !quoted => {
+                        in_quotes = true;
+                        quoted = true;
+                    }
+                    (',', false) => {
+                        let value = std::mem::take(&mut field);
+                        fields.push(if quoted || !value.is_empty() {
+                            Some(value)
+                        } else {
+                            None
+                        });
+                        quoted = false;
+                    }
+                    ('\r', false) | ('\n', false) => {}
+                    (c, _) => field.push(c),
+                }
+            }
+
+            if !in_quotes {
+                break;
+            }
+            // The quoted field carries on over the next line
+            match self.read_line(&mut line) {
+                Err(e) => return Some(Err(e)),
+                Ok(false) => {
+                    let error = ConnectorError::Invalid("unterminated quoted field".into());
+                    return Some(Err(ConnectorError::Line(start, Box::new(error))));
+                }
+                Ok(true) => {}
+            }
+        }
+
+        fields.push(if quoted || !field.is_empty() {
+            Some(field)
+        } else {
+            None
+        });
+        Some(Ok((start, fields)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::schema::{ColumnSchema, ColumnType};
+    use crate::operations::Base;
+    use crate::processing::{OpWorker, ReaderWorker};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn reads_csv_records() {
+        let csv = "a,\"b,c\",\"say \"\"hi\"\"\"\r\n\n,\"\",\"two\nlines\"\nlast";
+        let records: Vec<(usize, Vec<Option<String>>)> = CsvRecords::new(csv.as_bytes())
+            .map(Result::unwrap)
+            .collect();
+        let some = |s: &str| Some(s.to_string());
+        assert_eq!(
+            records,
+            vec![
+                (1, vec![some("a"), some("b,c"), some("say \"hi\"")]),
+                (3, vec![None, some(""), some("two\nlines")]),
+                (5, vec![some("last")]),
+            ]
+        );
+        assert!(CsvRecords::new("\"open".as_bytes())
+            .next()
+            .unwrap()
+            .is_err());
+    }
+
+    #[test]
+    fn loads_files() {
+        let router = Arc::new(MessageRouter::new());
+        let mut base = OpWorker::new(
+            router.clone(),
+            Base {
+                schema: Schema::new(vec![
+                    ColumnSchema::new("id", ColumnType::Integer, false),
+                    ColumnSchema::new("name", ColumnType::Text, true),
+                    ColumnSchema::new("added", ColumnType::Date, true),
+                ]),
+            },
+            vec![],
+        )
+        .unwrap();
+        let mut reader =
+            ReaderWorker::new(router.clone(), vec![base.id], vec!["id".into()]).unwrap();
+        let (reader_id, view) = (reader.id, reader.view());
+        let mut loader = Loader::new(router.clone(), base.id).unwrap();
+        loader.batch_size(2);
+        thread::spawn(move || base.start());
+        thread::spawn(move || reader.start());
+
+        let csv = "added,id,name\n2021-01-01,1,a\n,2,\n2021-01-03,3,\"c, d\"\n";
+        let loaded = loader
+            .load(csv.as_bytes(), Format::Csv { header: true })
+            .unwrap();
+        assert_eq!(loaded.rows, 3);
+        assert_eq!(loaded.timestamp, Some(2)); // Two batches
+
+        let json = "{\"id\": 4, \"name\": \"e\"}\n\n[5, null, \"2021-01-05\"]\n";
+        let loaded = loader.load(json.as_bytes(), Format::JsonLines).unwrap();
+        assert_eq!(loaded.rows, 2);
+        assert!(router.wait_for(reader_id, loaded.timestamp.unwrap(), Duration::from_secs(5)));
+        assert_eq!(view.rows().len(), 5);
+        assert_eq!(
+            view.lookup(&[3.into()]),
+            vec![vec![3.into(), "c, d".into(), DataType::Date(18630)].into()]
+        );
+        assert_eq!(
+            view.lookup(&[2.into()]),
+            vec![vec![2.into(), DataType::None, DataType::None].into()]
+        );
+
+        let err = loader
+            .load("1,x\n".as_bytes(), Format::Csv { header: false })
+            .unwrap_err();
+        assert_eq!(err.to_string(), "line 1: expected 3 columns but found 2");
+        let err = loader
+            .load("{\"id\": \"x\"}".as_bytes(), Format::JsonLines)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "line 1: column id: invalid integer: x");
+        assert_eq!(Format::from_path("rows.ndjson"), Some(Format::JsonLines));
+    }
+}
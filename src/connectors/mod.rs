@@ -0,0 +1,106 @@
+//! Connectors move rows between the graph and other systems
+
+use crate::operations::data::{DataType, Row};
+use crate::operations::encoding::DecodeError;
+use crate::operations::json::Json;
+use crate::operations::schema::{Schema, SchemaError};
+use crate::operations::types::ParseError;
+use crate::processing::WriteError;
+use std::fmt;
+use std::io;
+
+pub mod cdc;
+pub mod kv;
+pub mod load;
+pub mod sink;
+
+/// ConnectorError is returned when rows can't be moved between the graph and another system
+#[derive(Debug)]
+pub enum ConnectorError {
+    Io(io::Error),
+    Json(ParseError),
+    /// The input is well formed but doesn't describe rows or changes the connector understands
+    Invalid(String),
+    UnknownTable(String),
+    UnknownColumn(String),
+    InvalidValue {
+        column: String,
+        error: ParseError,
+    },
+    Schema(SchemaError),
+    Decode(DecodeError),
+    Write(WriteError),
+    /// The error happened on the line of the input
+    Line(usize, Box<ConnectorError>),
+}
+
+impl fmt::Display for ConnectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectorError::Io(e) => e.fmt(f),
+            ConnectorError::Json(e) => e.fmt(f),
+            ConnectorError::Invalid(message) => f.write_str(message),
+            ConnectorError::UnknownTable(name) => write!(f, "no table named {}", name),
+            ConnectorError::UnknownColumn(name) => write!(f, "no column named {}", name),
+            ConnectorError::InvalidValue { column, error } => {
+                write!(f, "column {}: {}", column, error)
+            }
+            ConnectorError::Schema(e) => e.fmt(f),
+            ConnectorError::Decode(e) => e.fmt(f),
+            ConnectorError::Write(e) => e.fmt(f),
+            ConnectorError::Line(line, e) => write!(f, "line {}: {}", line, e),
+        }
+    }
+}
+
+impl std::error::Error for ConnectorError {}
+
+impl From<io::Error> for ConnectorError {
+    fn from(e: io::Error) -> Self {
+        ConnectorError::Io(e)
+    }
+}
+
+impl From<DecodeError> for ConnectorError {
+    fn from(e: DecodeError) -> Self {
+        ConnectorError::Decode(e)
+    }
+}
+
+impl From<SchemaError> for ConnectorError {
+    fn from(e: SchemaError) -> Self {
+        ConnectorError::Schema(e)
+    }
+}
+
+impl From<WriteError> for ConnectorError {
+    fn from(e: WriteError) -> Self {
+        ConnectorError::Write(e)
+    }
+}
+
+/// json_row reads a row from a JSON object by matching its fields to columns by name. Missing fields are null and
+/// fields without a column are ignored.
+fn json_row(schema: &Schema, fields: &Json) -> Result<Row, ConnectorError> {
+    if !matches!(fields, Json::Object(_)) {
+        return Err(ConnectorError::Invalid(format!(
+            "expected an object but found {}",
+            fields
+        )));
+    }
+
+    let row = schema
+        .columns
+        .iter()
+        .map(|column| {
+            fields
+                .get(&column.name)
+                .map_or(Ok(DataType::None), |v| v.to_value(column.column_type))
+                .map_err(|error| ConnectorError::InvalidValue {
+                    column: column.name.clone(),
+                    error,
+                })
+        })
+        .collect::<Result<Vec<DataType>, ConnectorError>>()?;
+    Ok(row.into())
+}
@@ -0,0 +1,608 @@
+//! Sinks write the changes reaching a leaf of the graph to other systems.
+//!
+//! Delivery is at least once. Every so often a sink checkpoints, making everything it has written durable and
+//! recording the timestamp of the last change. When a sink is reopened anything written after its checkpoint is
+//! thrown away and changes at or before the checkpoint are skipped. Replaying the same writes into a new graph, like
+//! reloading a file or change log, carries on from where the sink stopped, delivering the changes after the
+//! checkpoint again.
+
+use super::load::Format;
+use super::ConnectorError;
+use crate::operations::data::{DataType, Timestamp};
+use crate::operations::json::Json;
+use crate::operations::schema::Schema;
+use crate::operations::{Description, ProcessError};
+use crate::processing::router::Epoch;
+use crate::processing::{Change, MessageRouter, Worker};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// MAX_RETRY_DELAY is the longest a sink waits between retries
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Sink is where a SinkWorker writes the changes it receives
+pub trait Sink {
+    /// open prepares the sink for rows with the schema, returning the timestamp of its last checkpoint
+    fn open(&mut self, schema: &Schema) -> Result<Option<Timestamp>, ConnectorError>;
+
+    /// write delivers the change. Failed writes are retried so may be delivered more than once.
+    fn write(&mut self, change: &Change) -> Result<(), ConnectorError>;
+
+    /// checkpoint makes every change written so far durable and records the timestamp of the last one
+    fn checkpoint(&mut self, timestamp: Timestamp) -> Result<(), ConnectorError>;
+}
+
+/// SinkWorkers are leaves of the graph writing every change they receive to a sink
+pub struct SinkWorker<S: Sink> {
+    pub id: usize,
+    router: Arc<MessageRouter>,
+    sink: S,
+    checkpointed: Option<Timestamp>,
+    // Changes written since the last checkpoint, and the timestamp of the latest
+    pending: usize,
+    last: Option<Timestamp>,
+    checkpoint_every: usize,
+    retry_delay: Duration,
+    max_retries: usize,
+}
+
+impl<S: Sink> SinkWorker<S> {
+    pub fn new(
+        router: Arc<MessageRouter>,
+        mut sink: S,
+        parents: Vec<usize>,
+    ) -> Result<Self, ConnectorError> {
+        let id = router.add_worker(parents, Schema::passthrough)?;
+        router.set_description(id, Description::new("sink"));
+        let checkpointed = sink.open(&router.schema(id).unwrap_or_default())?;
+        Ok(Self {
+            id,
+            router,
+            sink,
+            checkpointed,
+            pending: 0,
+            last: None,
+            checkpoint_every: 1,
+            retry_delay: Duration::from_millis(100),
+            max_retries: 5,
+        })
+    }
+
+    /// checkpoint_every sets how many changes are written between checkpoints. The sink is always checkpointed
+    /// when a worker running on its own thread stops, but Executors never stop their workers.
+    pub fn checkpoint_every(&mut self, changes: usize) {
+        self.checkpoint_every = changes.max(1);
+    }
+
+    /// retry_delay sets how long to wait before retrying a failed write. The delay doubles after each failure.
+    pub fn retry_delay(&mut self, delay: Duration) {
+        self.retry_delay = delay;
+    }
+
+    /// max_retries sets how many times a failed write or checkpoint is retried before giving up. Changes that still
+    /// can't be written are sent to the router's dead letters, so they can be replayed into the sink later.
+    pub fn max_retries(&mut self, retries: usize) {
+        self.max_retries = retries;
+    }
+
+    /// starts running the worker. This will loop until the message router stops providing messages
+    pub fn start(&mut self) {
+        let router = Arc::clone(&self.router);
+        for epoch in router.epochs(self.id) {
+            self.handle(epoch);
+        }
+        self.stop();
+    }
+
+    fn checkpoint(&mut self, timestamp: Timestamp) -> Result<(), ConnectorError> {
+        self.retry(|sink| sink.checkpoint(timestamp))?;
+        self.checkpointed = Some(timestamp);
+        Ok(())
+    }
+
+    /// retry calls f until it succeeds, waiting twice as long after each failure. Returns the last error once it has
+    /// been retried max_retries times.
+    fn retry<F: FnMut(&mut S) -> Result<(), ConnectorError>>(
+        &mut self,
+        mut f: F,
+    ) -> Result<(), ConnectorError> {
+        let mut delay = self.retry_delay;
+        let mut retries = 0;
+        loop {
+            match f(&mut self.sink) {
+                Err(_) if retries < self.max_retries => {
+                    thread::sleep(delay);
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// dead_letter sends every update of a change the sink couldn't write to the router's dead letters
+    fn dead_letter(&self, change: &Change, error: ConnectorError) {
+        let error = ProcessError::Delivery(error.to_string());
+        for update in change.updates.iter() {
+            self.router
+                .dead_letter(self.id, change.timestamp, update.clone(), error.clone());
+        }
+    }
+}
+
+impl<S: Sink> Worker for SinkWorker<S> {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Progress is completed once a change has been written to the sink, which may be before it is checkpointed.
+    fn handle(&mut self, epoch: Epoch) {
+        let started = Instant::now();
+        // A single batch can be written without copying it
+        let updates = match epoch.updates.as_slice() {
+            [u] => Arc::clone(&u.updates),
+            all => Arc::new(all.iter().flat_map(|u| u.updates.iter().cloned()).collect()),
+        };
+        let change = Change {
+            timestamp: epoch.timestamp,
+            updates,
+        };
+
+        let replayed = self.checkpointed.is_some_and(|t| change.timestamp <= t);
+        if !change.updates.is_empty() && !replayed {
+            match self.retry(|sink| sink.write(&change)) {
+                Ok(()) => {
+                    self.pending += 1;
+                    self.last = Some(change.timestamp);
+                }
+                Err(e) => self.dead_letter(&change, e),
+            }
+        }
+        // Failed checkpoints are tried again after the next change
+        if self.pending >= self.checkpoint_every && self.checkpoint(change.timestamp).is_ok() {
+            self.pending = 0;
+        }
+
+        self.router.record_hop(
+            self.id,
+            epoch.timestamp,
+            &epoch.sources,
+            change.updates.len(),
+            started,
+        );
+        for source in &epoch.sources {
+            self.router.complete(self.id, *source, epoch.timestamp);
+        }
+    }
+
+    /// The changes written since the last checkpoint are checkpointed. If that fails they're delivered again when the
+    /// sink is reopened.
+    fn stop(&mut self) {
+        if let (true, Some(timestamp)) = (self.pending > 0, self.last) {
+            if self.checkpoint(timestamp).is_ok() {
+                self.pending = 0;
+            }
+        }
+    }
+}
+
+/// FileSink writes changes to CSV or JSON Lines files, starting a new file once the current one grows too large.
+///
+/// Files are named `<prefix>-<number>.csv` or `.jsonl` and every line holds a single row update. CSV files start with
+/// a header of `timestamp,diff` followed by the column names, where diff is 1 for added rows and -1 for removed rows.
+/// JSON Lines files hold objects like `{"timestamp":3,"diff":1,"row":{"id":1}}`. Values are written so the loader
+/// can read them back. The checkpoint is kept in `<prefix>.checkpoint`.
+pub struct FileSink {
+    dir: PathBuf,
+    prefix: String,
+    format: Format,
+    rotate_bytes: u64,
+    columns: Vec<String>,
+    file: Option<BufWriter<File>>,
+    number: u64,
+    written: u64,
+}
+
+impl FileSink {
+    pub fn new<P: AsRef<Path>>(dir: P, prefix: &str, format: Format) -> Self {
+        Self {
+            dir: dir.as_ref().into(),
+            prefix: prefix.into(),
+            format,
+            rotate_bytes: 64 * 1024 * 1024,
+            columns: vec![],
+            file: None,
+            number: 0,
+            written: 0,
+        }
+    }
+
+    /// rotate_after sets the size a file can grow to before a new one is started
+    pub fn rotate_after(&mut self, bytes: u64) {
+        self.rotate_bytes = bytes;
+    }
+
+    /// path returns the path of the numbered file
+    pub fn path(&self, number: u64) -> PathBuf {
+        let extension = match self.format {
+            Format::Csv { .. } => "csv",
+            Format::JsonLines => "jsonl",
+        };
+        self.dir
+            .join(format!("{}-{:06}.{}", self.prefix, number, extension))
+    }
+
+    fn checkpoint_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.checkpoint", self.prefix))
+    }
+
+    /// read_checkpoint returns the timestamp, file number and length of the file at the last checkpoint
+    fn read_checkpoint(&self) -> Result<Option<(Timestamp, u64, u64)>, ConnectorError> {
+        let text = match fs::read_to_string(self.checkpoint_path()) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let invalid = || ConnectorError::Invalid(format!("invalid checkpoint {}", text.trim()));
+        let fields = text
+            .split_whitespace()
+            .map(|f| f.parse().map_err(|_| invalid()))
+            .collect::<Result<Vec<u64>, ConnectorError>>()?;
+        match fields.as_slice() {
+            [timestamp, number, len] => Ok(Some((*timestamp, *number, *len))),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// open_file opens the numbered file, cutting it down to the length and writing the header to empty CSV files
+    fn open_file(&mut self, number: u64, len: u64) -> Result<(), ConnectorError> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(self.path(number))?;
+        file.set_len(len)?;
+        file.seek(SeekFrom::End(0))?;
+        self.file = Some(BufWriter::new(file));
+        self.number = number;
+        self.written = len;
+
+        if let (Format::Csv { header: true }, 0) = (self.format, len) {
+            let header: Vec<String> = ["timestamp", "diff"]
+                .iter()
+                .map(|s| s.to_string())
+                .chain(self.columns.iter().map(|c| csv_field(c)))
+                .collect();
+            self.write_line(&header.join(","))?;
+        }
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<(), ConnectorError> {
+        // open always sets the file
+        let file = self
+            .file
+            .as_mut()
+            .expect("file sink written before being opened");
+        writeln!(file, "{}", line)?;
+        self.written += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), ConnectorError> {
+        if let Some(file) = &mut self.file {
+            file.flush()?;
+            file.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+impl Sink for FileSink {
+    fn open(&mut self, schema: &Schema) -> Result<Option<Timestamp>, ConnectorError> {
+        self.columns = schema.columns.iter().map(|c| c.name.clone()).collect();
+        fs::create_dir_all(&self.dir)?;
+
+        let checkpoint = self.read_checkpoint()?;
+        let (number, len) = checkpoint.map_or((0, 0), |(_, number, len)| (number, len));
+        // Files started after the checkpoint only hold changes that will be delivered again
+        let mut later = number + 1;
+        while self.path(later).exists() {
+            fs::remove_file(self.path(later))?;
+            later += 1;
+        }
+
+        self.open_file(number, len)?;
+        Ok(checkpoint.map(|(timestamp, _, _)| timestamp))
+    }
+
+    fn write(&mut self, change: &Change) -> Result<(), ConnectorError> {
+        if self.written >= self.rotate_bytes {
+            self.sync()?;
+            self.open_file(self.number + 1, 0)?;
+        }
+
+        for (row, diff) in change.updates.iter() {
+            let line = match self.format {
+                Format::Csv { .. } => {
+                    let mut fields = vec![change.timestamp.to_string(), diff.to_string()];
+                    fields.extend(row.data.iter().map(csv_value));
+                    fields.join(",")
+                }
+                Format::JsonLines => {
+                    let row = self
+                        .columns
+                        .iter()
+                        .zip(&row.data)
+                        .map(|(name, value)| (name.clone(), Json::from(value)))
+                        .collect();
+                    Json::Object(vec![
+                        (
+                            "timestamp".into(),
+                            Json::Number(change.timestamp.to_string()),
+                        ),
+                        ("diff".into(), Json::Number(diff.to_string())),
+                        ("row".into(), Json::Object(row)),
+                    ])
+                    .to_string()
+                }
+            };
+            self.write_line(&line)?;
+        }
+        Ok(())
+    }
+
+    fn checkpoint(&mut self, timestamp: Timestamp) -> Result<(), ConnectorError> {
+        self.sync()?;
+        // Renaming over the old checkpoint means a crash leaves either the old or new one whole
+        let path = self.checkpoint_path();
+        let temp = path.with_extension("checkpoint.tmp");
+        let mut file = File::create(&temp)?;
+        writeln!(file, "{} {} {}", timestamp, self.number, self.written)?;
+        file.sync_all()?;
+        fs::rename(temp, path)?;
+        Ok(())
+    }
+}
+
+/// csv_value writes the value as a CSV field that the loader parses back into the same value. Nulls are empty fields
+/// and empty text is quoted.
+fn csv_value(value: &DataType) -> String {
+    match Json::from(value) {
+        Json::Null => String::new(),
+        Json::String(s) => csv_field(&s),
+        json => json.to_string(),
+    }
+}
+
+fn csv_field(s: &str) -> String {
+    match s.is_empty() || s.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", s.replace('"', "\"\"")),
+        false => s.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connectors::load::Loader;
+    use crate::operations::data::{Batch, Diff, RowUpdate};
+    use crate::operations::schema::{ColumnSchema, ColumnType};
+    use crate::operations::Base;
+    use crate::processing::{Executor, Message, OpWorker};
+
+    fn items() -> Schema {
+        Schema::new(vec![
+            ColumnSchema::new("id", ColumnType::Integer, false),
+            ColumnSchema::new("name", ColumnType::Text, true),
+        ])
+    }
+
+    fn change(timestamp: Timestamp, updates: Vec<RowUpdate>) -> Change {
+        Change {
+            timestamp,
+            updates: Arc::new(updates.into_iter().map(Diff::from).collect()),
+        }
+    }
+
+    #[test]
+    fn writes_rotating_files() {
+        let dir = std::env::temp_dir().join(format!("dataflow-sink-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let add = |id: i32, name: &str| RowUpdate::Add(vec![id.into(), name.into()].into());
+
+        let mut sink = FileSink::new(&dir, "items", Format::Csv { header: true });
+        sink.rotate_after(30);
+        assert_eq!(sink.open(&items()).unwrap(), None);
+        sink.write(&change(1, vec![add(1, "a, b"), add(2, "")]))
+            .unwrap();
+        sink.write(&change(
+            2,
+            vec![RowUpdate::Remove(vec![1.into(), "a, b".into()].into())],
+        ))
+        .unwrap();
+        sink.checkpoint(2).unwrap();
+        sink.write(&change(3, vec![add(3, "c")])).unwrap();
+        sink.sync().unwrap();
+        drop(sink);
+
+        assert_eq!(
+            fs::read_to_string(dir.join("items-000000.csv")).unwrap(),
+            "timestamp,diff,id,name\n1,1,1,\"a, b\"\n1,1,2,\"\"\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("items-000001.csv")).unwrap(),
+            "timestamp,diff,id,name\n2,-1,1,\"a, b\"\n"
+        );
+        assert!(dir.join("items-000002.csv").exists());
+
+        // Reopening drops the change written after the checkpoint
+        let mut sink = FileSink::new(&dir, "items", Format::Csv { header: true });
+        sink.rotate_after(30);
+        assert_eq!(sink.open(&items()).unwrap(), Some(2));
+        assert!(!dir.join("items-000002.csv").exists());
+
+        // The files can be loaded back into a table with timestamp and diff columns
+        let router = Arc::new(MessageRouter::new());
+        let mut schema = Schema::new(vec![
+            ColumnSchema::new("timestamp", ColumnType::BigInt, false),
+            ColumnSchema::new("diff", ColumnType::Integer, false),
+        ]);
+        schema.columns.extend(items().columns);
+        let base = OpWorker::new(router.clone(), Base { schema }, vec![]).unwrap();
+        let loader = Loader::new(router, base.id).unwrap();
+        let path = dir.join("items-000000.csv");
+        assert_eq!(
+            loader
+                .load_file(path, Format::Csv { header: true })
+                .unwrap()
+                .rows,
+            2
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sinks_changes_from_the_graph() {
+        let dir = std::env::temp_dir().join(format!("dataflow-sink-worker-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let run = |rows: Vec<i32>| {
+            let router = Arc::new(MessageRouter::new());
+            let mut base = OpWorker::new(router.clone(), Base { schema: items() }, vec![]).unwrap();
+            let sink = FileSink::new(&dir, "items", Format::JsonLines);
+            let mut worker = SinkWorker::new(router.clone(), sink, vec![base.id]).unwrap();
+            let (base_id, worker_id) = (base.id, worker.id);
+            let threads = vec![
+                thread::spawn(move || base.start()),
+                thread::spawn(move || worker.start()),
+            ];
+
+            let mut last = 0;
+            for id in rows {
+                last = router
+                    .write(
+                        base_id,
+                        vec![RowUpdate::Add(vec![id.into(), DataType::None].into())],
+                    )
+                    .unwrap();
+            }
+            assert!(router.wait_for(worker_id, last, Duration::from_secs(5)));
+            router.send_message(base_id, Message::Stop);
+            router.send_message(worker_id, Message::Stop);
+            threads.into_iter().for_each(|t| t.join().unwrap());
+        };
+
+        run(vec![1, 2]);
+        // Replaying the same writes skips the ones already checkpointed
+        run(vec![1, 2, 3]);
+        assert_eq!(
+            fs::read_to_string(dir.join("items-000000.jsonl")).unwrap(),
+            concat!(
+                "{\"timestamp\":1,\"diff\":1,\"row\":{\"id\":1,\"name\":null}}\n",
+                "{\"timestamp\":2,\"diff\":1,\"row\":{\"id\":2,\"name\":null}}\n",
+                "{\"timestamp\":3,\"diff\":1,\"row\":{\"id\":3,\"name\":null}}\n",
+            )
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// FlakySink fails a number of writes before accepting any
+    struct FlakySink {
+        failures: usize,
+        written: Arc<std::sync::Mutex<Vec<Change>>>,
+    }
+
+    impl Sink for FlakySink {
+        fn open(&mut self, _: &Schema) -> Result<Option<Timestamp>, ConnectorError> {
+            Ok(None)
+        }
+
+        fn write(&mut self, change: &Change) -> Result<(), ConnectorError> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(ConnectorError::Invalid("unavailable".into()));
+            }
+            self.written.lock().unwrap().push(change.clone());
+            Ok(())
+        }
+
+        fn checkpoint(&mut self, _: Timestamp) -> Result<(), ConnectorError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn dead_letters_changes_it_cant_write() {
+        let router = Arc::new(MessageRouter::new());
+        let mut base = OpWorker::new(router.clone(), Base { schema: items() }, vec![]).unwrap();
+        let written = Arc::new(std::sync::Mutex::new(vec![]));
+        let sink = FlakySink {
+            failures: 3,
+            written: written.clone(),
+        };
+        let mut worker = SinkWorker::new(router.clone(), sink, vec![base.id]).unwrap();
+        worker.retry_delay(Duration::from_millis(1));
+        worker.max_retries(1);
+        let (base_id, worker_id) = (base.id, worker.id);
+        thread::spawn(move || base.start());
+        thread::spawn(move || worker.start());
+
+        // The first change fails twice and is given up on, the second succeeds on its retry
+        let add = |id: i32| vec![RowUpdate::Add(vec![id.into(), DataType::None].into())];
+        router.write(base_id, add(1)).unwrap();
+        let t = router.write(base_id, add(2)).unwrap();
+        assert!(router.wait_for(worker_id, t, Duration::from_secs(5)));
+        let letters = router.dead_letters().for_node(worker_id);
+        assert_eq!(letters.len(), 1);
+        assert_eq!(
+            letters[0].error,
+            ProcessError::Delivery("unavailable".into())
+        );
+        assert_eq!(router.metrics()[&worker_id].rows_failed, 1);
+
+        let t = router.replay_dead_letters(worker_id, worker_id).unwrap();
+        assert!(router.wait_for(worker_id, t, Duration::from_secs(5)));
+        let written: Vec<Batch> = written
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|c| c.updates.clone())
+            .collect();
+        assert_eq!(
+            written,
+            vec![change(0, add(2)).updates, change(0, add(1)).updates]
+        );
+        assert!(router.dead_letters().is_empty());
+    }
+
+    #[test]
+    fn runs_on_an_executor() {
+        let mut executor = Executor::new();
+        let router = executor.router();
+        let base = OpWorker::new(router.clone(), Base { schema: items() }, vec![]).unwrap();
+        let written = Arc::new(std::sync::Mutex::new(vec![]));
+        let sink = FlakySink {
+            failures: 0,
+            written: written.clone(),
+        };
+        let worker = SinkWorker::new(router.clone(), sink, vec![base.id]).unwrap();
+        let base_id = executor.add(base);
+        executor.add(worker);
+
+        let add = |id: i32| vec![RowUpdate::Add(vec![id.into(), DataType::None].into())];
+        router.write(base_id, add(1)).unwrap();
+        router.write(base_id, add(2)).unwrap();
+        executor.run();
+        let timestamps: Vec<Timestamp> = written
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|c| c.timestamp)
+            .collect();
+        assert_eq!(timestamps, vec![1, 2]);
+    }
+}
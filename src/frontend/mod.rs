@@ -0,0 +1,590 @@
+use self::sql::{Condition, Literal, Statement};
+use crate::operations::data::{Collation, Comparison, DataType, Row, RowUpdate, Timestamp};
+use crate::operations::schema::{ColumnSchema, ColumnType, Schema, SchemaError};
+use crate::operations::types::ParseError;
+use crate::operations::Base;
+use crate::processing::{MessageRouter, OpWorker, ReaderWorker, View, WriteError};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+pub mod postgres;
+pub mod sql;
+
+/// SqlError is returned when a statement can't be run
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlError {
+    /// The statement couldn't be parsed
+    Syntax(String),
+    /// The statement uses something the frontend doesn't support
+    Unsupported(String),
+    UnknownTable(String),
+    UnknownColumn(String),
+    TableExists(String),
+    /// Only tables can be written to, not views
+    ReadOnly(String),
+    InvalidValue {
+        column: String,
+        error: ParseError,
+    },
+    Schema(SchemaError),
+    Write(WriteError),
+    /// The view didn't catch up with earlier writes in time
+    Timeout(String),
+}
+
+impl fmt::Display for SqlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SqlError::Syntax(message) => write!(f, "syntax error: {}", message),
+            SqlError::Unsupported(what) => write!(f, "{} aren't supported", what),
+            SqlError::UnknownTable(name) => write!(f, "relation {} doesn't exist", name),
+            SqlError::UnknownColumn(name) => write!(f, "column {} doesn't exist", name),
+            SqlError::TableExists(name) => write!(f, "relation {} already exists", name),
+            SqlError::ReadOnly(name) => write!(f, "{} is a view and can't be written to", name),
+            SqlError::InvalidValue { column, error } => write!(f, "column {}: {}", column, error),
+            SqlError::Schema(e) => e.fmt(f),
+            SqlError::Write(e) => e.fmt(f),
+            SqlError::Timeout(name) => write!(f, "timed out waiting for {} to catch up", name),
+        }
+    }
+}
+
+impl std::error::Error for SqlError {}
+
+impl From<SchemaError> for SqlError {
+    fn from(e: SchemaError) -> Self {
+        SqlError::Schema(e)
+    }
+}
+
+impl From<WriteError> for SqlError {
+    fn from(e: WriteError) -> Self {
+        SqlError::Write(e)
+    }
+}
+
+/// QueryResult is the outcome of a statement
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryResult {
+    Created,
+    Inserted(usize),
+    Updated(usize),
+    Deleted(usize),
+    Set,
+    Rows {
+        columns: Vec<ColumnSchema>,
+        rows: Vec<Row>,
+    },
+}
+
+/// Relation is a table or view that statements can use. Tables are backed by a Base worker and keep every row in a
+/// reader so updates and deletes can find the rows they change.
+struct Relation {
+    base: Option<usize>,
+    reader: usize,
+    view: Arc<View>,
+}
+
+/// Frontend runs SQL statements against the graph. Protocol servers like the Postgres one parse requests into
+/// statements and run them here.
+///
+/// Reads wait for every earlier write made through the frontend, so clients always see their own writes.
+pub struct Frontend {
+    router: Arc<MessageRouter>,
+    relations: RwLock<HashMap<String, Relation>>,
+    // Holds the timestamp of the latest write. Writes keep it locked so updates and deletes don't race.
+    written: Mutex<Timestamp>,
+    timeout: Duration,
+}
+
+impl Frontend {
+    pub fn new(router: Arc<MessageRouter>) -> Self {
+        Self {
+            router,
+            relations: RwLock::new(HashMap::new()),
+            written: Mutex::new(0),
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// add_table lets statements use the base worker as a table. The reader must hold every row of the table.
+    pub fn add_table(&self, name: &str, base: usize, reader: &ReaderWorker) {
+        self.add_relation(name, Some(base), reader)
+    }
+
+    /// add_view lets statements select from the reader's view
+    pub fn add_view(&self, name: &str, reader: &ReaderWorker) {
+        self.add_relation(name, None, reader)
+    }
+
+    /// add_view_by_id is add_view for readers that have already been moved to their own thread
+    pub fn add_view_by_id(&self, name: &str, reader: usize, view: Arc<View>) {
+        let relation = Relation {
+            base: None,
+            reader,
+            view,
+        };
+        // Fine with panicking on thread poisoning
+        self.relations
+            .write()
+            .unwrap()
+            .insert(name.into(), relation);
+    }
+
+    /// base returns the id of the Base worker behind the table
+    pub fn base(&self, table: &str) -> Option<usize> {
+        let relations = self.relations.read().unwrap(); // Fine with panicking on thread poisoning
+        relations.get(table).and_then(|r| r.base)
+    }
+
+    fn add_relation(&self, name: &str, base: Option<usize>, reader: &ReaderWorker) {
+        let relation = Relation {
+            base,
+            reader: reader.id,
+            view: reader.view(),
+        };
+        // Fine with panicking on thread poisoning
+        self.relations
+            .write()
+            .unwrap()
+            .insert(name.into(), relation);
+    }
+
+    /// timeout sets how long reads wait for views to catch up with earlier writes
+    pub fn timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// run parses and runs every statement in the SQL, stopping at the first error
+    pub fn run(&self, sql: &str) -> Result<Vec<QueryResult>, SqlError> {
+        sql::parse(sql)?
+            .iter()
+            .map(|statement| self.execute(statement))
+            .collect()
+    }
+
+    /// execute runs a single statement
+    pub fn execute(&self, statement: &Statement) -> Result<QueryResult, SqlError> {
+        match statement {
+            Statement::CreateTable { name, columns } => {
+                self.create_table(name, Schema::new(columns.clone()))?;
+                Ok(QueryResult::Created)
+            }
+            Statement::Insert {
+                table,
+                columns,
+                rows,
+            } => self.insert(table, columns, rows),
+            Statement::Update {
+                table,
+                assignments,
+                conditions,
+            } => self.update(table, assignments, conditions),
+            Statement::Delete { table, conditions } => self.delete(table, conditions),
+            Statement::Select {
+                source,
+                columns,
+                conditions,
+            } => self.select(source, columns, conditions),
+            Statement::Set => Ok(QueryResult::Set),
+        }
+    }
+
+    /// describe returns the columns of the rows the statement returns, or None if it doesn't return rows
+    pub fn describe(&self, statement: &Statement) -> Result<Option<Vec<ColumnSchema>>, SqlError> {
+        match statement {
+            Statement::Select {
+                source, columns, ..
+            } => {
+                let schema = self.schema(source)?;
+                let indices = projection(&schema, columns)?;
+                Ok(Some(
+                    indices.iter().map(|i| schema.columns[*i].clone()).collect(),
+                ))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// parameter_types returns the type of each $n parameter in the statement, taken from the column it's used with.
+    /// Parameters that can't be matched to a column are Any.
+    pub fn parameter_types(&self, statement: &Statement) -> Vec<ColumnType> {
+        let mut types = vec![ColumnType::Any; statement.parameters()];
+        let relation = match statement {
+            Statement::Insert { table, .. }
+            | Statement::Update { table, .. }
+            | Statement::Delete { table, .. } => table,
+            Statement::Select { source, .. } => source,
+            Statement::CreateTable { .. } | Statement::Set => return types,
+        };
+        let schema = match self.schema(relation) {
+            Ok(schema) => schema,
+            Err(_) => return types,
+        };
+
+        for (i, (column, literal)) in statement.literals().into_iter().enumerate() {
+            let n = match literal {
+                Literal::Parameter(n) => *n,
+                _ => continue,
+            };
+            let index = match (column, statement) {
+                (Some(column), _) => schema.index_of(column),
+                (None, Statement::Insert { rows, .. }) => Some(i % rows[0].len()),
+                (None, _) => None,
+            };
+            if let Some(column) = index.and_then(|i| schema.columns.get(i)) {
+                types[n - 1] = column.column_type;
+            }
+        }
+        types
+    }
+
+    /// create_table adds a new base table to the graph and starts its workers
+    pub fn create_table(&self, name: &str, schema: Schema) -> Result<(), SqlError> {
+        let mut relations = self.relations.write().unwrap(); // Fine with panicking on thread poisoning
+        if relations.contains_key(name) {
+            return Err(SqlError::TableExists(name.into()));
+        }
+
+        let mut base = OpWorker::new(self.router.clone(), Base { schema }, vec![])?;
+        let mut reader = ReaderWorker::new(self.router.clone(), vec![base.id], vec![])?;
+        relations.insert(
+            name.into(),
+            Relation {
+                base: Some(base.id),
+                reader: reader.id,
+                view: reader.view(),
+            },
+        );
+
+        thread::spawn(move || base.start());
+        thread::spawn(move || reader.start());
+        Ok(())
+    }
+
+    fn insert(
+        &self,
+        table: &str,
+        columns: &[String],
+        rows: &[Vec<Literal>],
+    ) -> Result<QueryResult, SqlError> {
+        let (base, _, _) = self.table(table)?;
+        let schema = self.schema(table)?;
+        let indices = match columns.is_empty() {
+            true => (0..schema.len()).collect(),
+            false => resolve(&schema, columns)?,
+        };
+
+        let updates = rows
+            .iter()
+            .map(|literals| {
+                if literals.len() != indices.len() {
+                    return Err(SqlError::Syntax(format!(
+                        "expected {} values but found {}",
+                        indices.len(),
+                        literals.len()
+                    )));
+                }
+
+                let mut row = vec![DataType::None; schema.len()];
+                for (i, literal) in indices.iter().zip(literals) {
+                    row[*i] = value(&schema, *i, literal)?;
+                }
+                let row = Row::from(row);
+                schema.check_row(&row)?;
+                Ok(RowUpdate::Add(row))
+            })
+            .collect::<Result<Vec<RowUpdate>, SqlError>>()?;
+
+        let count = updates.len();
+        let mut written = self.written.lock().unwrap(); // Fine with panicking on thread poisoning
+        *written = self.router.write(base, updates)?;
+        Ok(QueryResult::Inserted(count))
+    }
+
+    fn update(
+        &self,
+        table: &str,
+        assignments: &[(String, Literal)],
+        conditions: &[Condition],
+    ) -> Result<QueryResult, SqlError> {
+        let (base, reader, view) = self.table(table)?;
+        let schema = self.schema(table)?;
+        let assignments = assignments
+            .iter()
+            .map(|(column, literal)| {
+                let i = column_index(&schema, column)?;
+                Ok((i, value(&schema, i, literal)?))
+            })
+            .collect::<Result<Vec<(usize, DataType)>, SqlError>>()?;
+        let conditions = predicates(&schema, conditions)?;
+
+        let mut written = self.written.lock().unwrap(); // Fine with panicking on thread poisoning
+        self.wait(table, reader, *written)?;
+        let mut updates = vec![];
+        for row in view.rows().into_iter().filter(|r| matches(r, &conditions)) {
+            let mut new = row.clone();
+            for (i, value) in &assignments {
+                new[*i] = value.clone();
+            }
+            schema.check_row(&new)?;
+            updates.push(RowUpdate::Remove(row));
+            updates.push(RowUpdate::Add(new));
+        }
+
+        let count = updates.len() / 2;
+        *written = self.router.write(base, updates)?;
+        Ok(QueryResult::Updated(count))
+    }
+
+    fn delete(&self, table: &str, conditions: &[Condition]) -> Result<QueryResult, SqlError> {
+        let (base, reader, view) = self.table(table)?;
+        let conditions = predicates(&self.schema(table)?, conditions)?;
+
+        let mut written = self.written.lock().unwrap(); // Fine with panicking on thread poisoning
+        self.wait(table, reader, *written)?;
+        let updates: Vec<RowUpdate> = view
+            .rows()
+            .into_iter()
+            .filter(|r| matches(r, &conditions))
+            .map(RowUpdate::Remove)
+            .collect();
+
+        let count = updates.len();
+        *written = self.router.write(base, updates)?;
+        Ok(QueryResult::Deleted(count))
+    }
+
+    fn select(
+        &self,
+        source: &str,
+        columns: &[String],
+        conditions: &[Condition],
+    ) -> Result<QueryResult, SqlError> {
+        let (reader, view) = {
+            let relations = self.relations.read().unwrap(); // Fine with panicking on thread poisoning
+            let relation = relations
+                .get(source)
+                .ok_or_else(|| SqlError::UnknownTable(source.into()))?;
+            (relation.reader, Arc::clone(&relation.view))
+        };
+        let schema = self.schema(source)?;
+        let indices = projection(&schema, columns)?;
+        let conditions = predicates(&schema, conditions)?;
+
+        let written = *self.written.lock().unwrap(); // Fine with panicking on thread poisoning
+        self.wait(source, reader, written)?;
+        let rows = view
+            .rows()
+            .into_iter()
+            .filter(|r| matches(r, &conditions))
+            .map(|r| {
+                indices
+                    .iter()
+                    .map(|i| r[*i].clone())
+                    .collect::<Vec<_>>()
+                    .into()
+            })
+            .collect();
+
+        Ok(QueryResult::Rows {
+            columns: indices.iter().map(|i| schema.columns[*i].clone()).collect(),
+            rows,
+        })
+    }
+
+    fn table(&self, name: &str) -> Result<(usize, usize, Arc<View>), SqlError> {
+        let relations = self.relations.read().unwrap(); // Fine with panicking on thread poisoning
+        match relations.get(name) {
+            None => Err(SqlError::UnknownTable(name.into())),
+            Some(Relation { base: None, .. }) => Err(SqlError::ReadOnly(name.into())),
+            Some(Relation {
+                base: Some(base),
+                reader,
+                view,
+            }) => Ok((*base, *reader, Arc::clone(view))),
+        }
+    }
+
+    fn schema(&self, name: &str) -> Result<Schema, SqlError> {
+        let relations = self.relations.read().unwrap(); // Fine with panicking on thread poisoning
+        relations
+            .get(name)
+            .and_then(|r| self.router.schema(r.reader))
+            .ok_or_else(|| SqlError::UnknownTable(name.into()))
+    }
+
+    fn wait(&self, name: &str, reader: usize, timestamp: Timestamp) -> Result<(), SqlError> {
+        match self.router.wait_for(reader, timestamp, self.timeout) {
+            true => Ok(()),
+            false => Err(SqlError::Timeout(name.into())),
+        }
+    }
+}
+
+fn column_index(schema: &Schema, column: &str) -> Result<usize, SqlError> {
+    schema
+        .index_of(column)
+        .ok_or_else(|| SqlError::UnknownColumn(column.into()))
+}
+
+fn resolve(schema: &Schema, columns: &[String]) -> Result<Vec<usize>, SqlError> {
+    columns.iter().map(|c| column_index(schema, c)).collect()
+}
+
+/// projection returns the indices of the selected columns, with no columns meaning all of them
+fn projection(schema: &Schema, columns: &[String]) -> Result<Vec<usize>, SqlError> {
+    match columns.is_empty() {
+        true => Ok((0..schema.len()).collect()),
+        false => resolve(schema, columns),
+    }
+}
+
+/// value converts the literal into a value for the column
+fn value(schema: &Schema, column: usize, literal: &Literal) -> Result<DataType, SqlError> {
+    let column = &schema.columns[column];
+    literal
+        .value(column.column_type)
+        .map_err(|error| SqlError::InvalidValue {
+            column: column.name.clone(),
+            error,
+        })
+}
+
+/// Predicate is a condition with its column and value resolved
+type Predicate = (usize, Comparison, DataType);
+
+/// predicates resolves the columns and values of the conditions
+fn predicates(schema: &Schema, conditions: &[Condition]) -> Result<Vec<Predicate>, SqlError> {
+    conditions
+        .iter()
+        .map(|c| {
+            let i = column_index(schema, &c.column)?;
+            Ok((i, c.comparison, value(schema, i, &c.value)?))
+        })
+        .collect()
+}
+
+/// matches checks the row passes every predicate. Comparisons with null fail like they do in SQL.
+fn matches(row: &Row, predicates: &[Predicate]) -> bool {
+    predicates.iter().all(|(i, comparison, value)| {
+        comparison.compare_collated(&row[*i], value, Collation::Binary)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::filter::{ColumnConstraint, Constraint};
+    use crate::operations::Filter;
+
+    #[test]
+    fn runs_statements() {
+        let router = Arc::new(MessageRouter::new());
+        let frontend = Frontend::new(router.clone());
+        frontend
+            .run("CREATE TABLE items (id INT NOT NULL, name TEXT, price INT)")
+            .unwrap();
+        assert_eq!(
+            frontend.run("CREATE TABLE items (id INT)"),
+            Err(SqlError::TableExists("items".into()))
+        );
+
+        let results = frontend
+            .run(
+                "INSERT INTO items VALUES (1, 'apple', 5), (2, 'pear', 12);
+                INSERT INTO items (name, id) VALUES ('plum', 3);
+                UPDATE items SET price = 7 WHERE name = 'plum';
+                DELETE FROM items WHERE id = 2",
+            )
+            .unwrap();
+        assert_eq!(
+            results,
+            vec![
+                QueryResult::Inserted(2),
+                QueryResult::Inserted(1),
+                QueryResult::Updated(1),
+                QueryResult::Deleted(1),
+            ]
+        );
+
+        let mut rows = match frontend.run("SELECT name, price FROM items WHERE id > 0") {
+            Ok(mut results) => match results.remove(0) {
+                QueryResult::Rows { columns, rows } => {
+                    assert_eq!(
+                        columns[1],
+                        ColumnSchema::new("price", ColumnType::Integer, true)
+                    );
+                    rows
+                }
+                r => panic!("expected rows but got {:?}", r),
+            },
+            Err(e) => panic!("select failed: {}", e),
+        };
+        rows.sort();
+        assert_eq!(
+            rows,
+            vec![
+                vec!["apple".into(), 5.into()].into(),
+                vec!["plum".into(), 7.into()].into(),
+            ]
+        );
+
+        assert!(matches!(
+            frontend.run("INSERT INTO items VALUES (NULL, 'x', 1)"),
+            Err(SqlError::Schema(SchemaError::NullValue(0)))
+        ));
+        assert!(matches!(
+            frontend.run("INSERT INTO items (id) VALUES ('one')"),
+            Err(SqlError::InvalidValue { .. })
+        ));
+        assert_eq!(
+            frontend.run("SELECT missing FROM items"),
+            Err(SqlError::UnknownColumn("missing".into()))
+        );
+    }
+
+    #[test]
+    fn selects_from_views() {
+        let router = Arc::new(MessageRouter::new());
+        let frontend = Frontend::new(router.clone());
+        frontend
+            .run("CREATE TABLE items (id INT, price INT)")
+            .unwrap();
+        let base = frontend.relations.read().unwrap()["items"].base.unwrap();
+
+        let mut cheap = OpWorker::new(
+            router.clone(),
+            Filter {
+                constraints: vec![ColumnConstraint {
+                    column: "price".into(),
+                    constraint: Constraint::Comparison(Comparison::LessThan, 10.into()),
+                }],
+            },
+            vec![base],
+        )
+        .unwrap();
+        let mut reader = ReaderWorker::new(router.clone(), vec![cheap.id], vec![]).unwrap();
+        frontend.add_view("cheap", &reader);
+        thread::spawn(move || cheap.start());
+        thread::spawn(move || reader.start());
+
+        frontend
+            .run("INSERT INTO items VALUES (1, 5), (2, 50)")
+            .unwrap();
+        assert_eq!(
+            frontend.run("SELECT id FROM cheap").unwrap(),
+            vec![QueryResult::Rows {
+                columns: vec![ColumnSchema::new("id", ColumnType::Integer, true)],
+                rows: vec![vec![1.into()].into()],
+            }]
+        );
+        assert_eq!(
+            frontend.run("DELETE FROM cheap"),
+            Err(SqlError::ReadOnly("cheap".into()))
+        );
+    }
+}
@@ -0,0 +1,717 @@
+//! Serves the frontend over the Postgres wire protocol so standard Postgres clients and drivers can connect.
+//!
+//! Both the simple query protocol, used by psql, and the extended query protocol, used by drivers for prepared
+//! statements, are supported. Values are only sent and received in text format. Connections aren't authenticated
+//! and SSL requests are refused so clients fall back to plain connections.
+
+use super::sql::{self, Statement};
+use super::{Frontend, QueryResult, SqlError};
+use crate::operations::data::DataType;
+use crate::operations::schema::{ColumnSchema, ColumnType, SchemaError};
+use crate::operations::types::{format_date, format_timestamp};
+use crate::server::protocol::MAX_FRAME;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+const PROTOCOL_VERSION: i32 = 196_608; // 3.0
+const SSL_REQUEST: i32 = 80_877_103;
+const GSS_REQUEST: i32 = 80_877_104;
+const CANCEL_REQUEST: i32 = 80_877_102;
+
+/// serve accepts Postgres connections until the listener fails, handling each connection on its own thread
+pub fn serve(frontend: Arc<Frontend>, listener: TcpListener) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let frontend = Arc::clone(&frontend);
+        thread::spawn(move || {
+            if let Err(e) = Connection::new(&frontend, stream).and_then(|mut c| c.run()) {
+                eprintln!("postgres connection failed: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// type_oid returns the Postgres type used to describe columns of the type
+fn type_oid(column_type: ColumnType) -> i32 {
+    match column_type {
+        ColumnType::Any | ColumnType::Text => 25,
+        ColumnType::Integer => 23,
+        ColumnType::Boolean => 16,
+        ColumnType::Float => 700,
+        ColumnType::BigInt => 20,
+        ColumnType::Double => 701,
+        ColumnType::Decimal => 1700,
+        ColumnType::Timestamp => 1114,
+        ColumnType::Date => 1082,
+        ColumnType::Interval => 1186,
+        ColumnType::Bytes => 17,
+    }
+}
+
+fn type_size(column_type: ColumnType) -> i16 {
+    match column_type {
+        ColumnType::Boolean => 1,
+        ColumnType::Integer | ColumnType::Float | ColumnType::Date => 4,
+        ColumnType::BigInt | ColumnType::Double | ColumnType::Timestamp => 8,
+        ColumnType::Interval => 16,
+        _ => -1,
+    }
+}
+
+/// text formats a value the way Postgres does in text format, with None for nulls
+fn text(value: &DataType) -> Option<String> {
+    Some(match value {
+        DataType::None => return None,
+        DataType::Integer(n) => n.to_string(),
+        DataType::Text(s) => s.clone(),
+        DataType::Boolean(b) => if *b { "t" } else { "f" }.into(),
+        DataType::Float(f) => f.to_string(),
+        DataType::BigInt(n) => n.to_string(),
+        DataType::Double(f) => f.to_string(),
+        DataType::Decimal(d) => d.to_string(),
+        DataType::Timestamp(t) => format_timestamp(*t),
+        DataType::Date(d) => format_date(*d),
+        DataType::Interval(i) => i.to_string(),
+        DataType::Bytes(b) => b
+            .iter()
+            .fold("\\x".into(), |s, b| s + &format!("{:02x}", b)),
+    })
+}
+
+/// sql_state returns the Postgres error code for the error
+fn sql_state(error: &SqlError) -> &'static str {
+    match error {
+        SqlError::Syntax(_) => "42601",
+        SqlError::Unsupported(_) => "0A000",
+        SqlError::UnknownTable(_) => "42P01",
+        SqlError::UnknownColumn(_) => "42703",
+        SqlError::TableExists(_) => "42P07",
+        SqlError::ReadOnly(_) => "42809",
+        SqlError::InvalidValue { .. } => "22P02",
+        SqlError::Schema(SchemaError::NullValue(_)) => "23502",
+        SqlError::Schema(_) => "42804",
+        SqlError::Write(_) => "XX000",
+        SqlError::Timeout(_) => "57014",
+    }
+}
+
+fn command_tag(result: &QueryResult) -> String {
+    match result {
+        QueryResult::Created => "CREATE TABLE".into(),
+        QueryResult::Inserted(n) => format!("INSERT 0 {}", n),
+        QueryResult::Updated(n) => format!("UPDATE {}", n),
+        QueryResult::Deleted(n) => format!("DELETE {}", n),
+        QueryResult::Set => "SET".into(),
+        QueryResult::Rows { rows, .. } => format!("SELECT {}", rows.len()),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// read_body reads a message's length and then its body. The length includes itself, so anything under 4 is invalid, and
+/// lengths over MAX_FRAME are refused before anything is allocated for them.
+fn read_body<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = i32::from_be_bytes(len)
+        .checked_sub(4)
+        .and_then(|len| usize::try_from(len).ok())
+        .ok_or_else(|| invalid("invalid message length"))?;
+    if len > MAX_FRAME {
+        return Err(invalid("message too large"));
+    }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+    Ok(body)
+}
+
+/// Message builds a single backend message
+struct Message {
+    tag: u8,
+    body: Vec<u8>,
+}
+
+impl Message {
+    fn new(tag: u8) -> Self {
+        Self { tag, body: vec![] }
+    }
+
+    fn i16(mut self, n: i16) -> Self {
+        self.body.extend_from_slice(&n.to_be_bytes());
+        self
+    }
+
+    fn u16(mut self, n: u16) -> Self {
+        self.body.extend_from_slice(&n.to_be_bytes());
+        self
+    }
+
+    fn i32(mut self, n: i32) -> Self {
+        self.body.extend_from_slice(&n.to_be_bytes());
+        self
+    }
+
+    fn bytes(mut self, b: &[u8]) -> Self {
+        self.body.extend_from_slice(b);
+        self
+    }
+
+    fn str(self, s: &str) -> Self {
+        self.bytes(s.as_bytes()).bytes(&[0])
+    }
+
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&[self.tag])?;
+        w.write_all(&(self.body.len() as i32 + 4).to_be_bytes())?;
+        w.write_all(&self.body)
+    }
+}
+
+/// Body reads the fields of a frontend message
+struct Body<'a>(&'a [u8]);
+
+impl<'a> Body<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(invalid("message ended early"));
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn i16(&mut self) -> io::Result<i16> {
+        let b = self.take(2)?;
+        Ok(i16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        let b = self.take(4)?;
+        Ok(i32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn str(&mut self) -> io::Result<String> {
+        let end = self
+            .0
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or_else(|| invalid("string missing terminator"))?;
+        let s = String::from_utf8(self.take(end)?.to_vec()).map_err(|_| invalid("invalid utf8"))?;
+        self.take(1)?;
+        Ok(s)
+    }
+
+    /// text_formats reads a list of format codes, failing if any are binary
+    fn text_formats(&mut self, what: &str) -> io::Result<Result<(), SqlError>> {
+        let count = self.i16()?;
+        for _ in 0..count {
+            if self.i16()? != 0 {
+                return Ok(Err(SqlError::Unsupported(format!("binary {}", what))));
+            }
+        }
+        Ok(Ok(()))
+    }
+}
+
+struct Connection<'a> {
+    frontend: &'a Frontend,
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    // Prepared statements, and portals which are statements with their parameters bound. Empty queries have no
+    // statement.
+    statements: HashMap<String, Option<Statement>>,
+    portals: HashMap<String, Option<Statement>>,
+    // After an error in the extended protocol messages are skipped until the next Sync
+    failed: bool,
+}
+
+impl<'a> Connection<'a> {
+    fn new(frontend: &'a Frontend, stream: TcpStream) -> io::Result<Self> {
+        Ok(Self {
+            frontend,
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            statements: HashMap::new(),
+            portals: HashMap::new(),
+            failed: false,
+        })
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        if !self.startup()? {
+            return Ok(());
+        }
+
+        loop {
+            let mut tag = [0];
+            match self.reader.read_exact(&mut tag) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            }
+            let body = read_body(&mut self.reader)?;
+            let mut body = Body(&body);
+
+            if self.failed && !matches!(tag[0], b'S' | b'X') {
+                continue;
+            }
+            match tag[0] {
+                b'Q' => self.query(&body.str()?)?,
+                b'P' => self.parse(&mut body)?,
+                b'B' => self.bind(&mut body)?,
+                b'D' => self.describe(&mut body)?,
+                b'E' => self.execute(&mut body)?,
+                b'C' => {
+                    let kind = body.u8()?;
+                    let name = body.str()?;
+                    match kind {
+                        b'S' => drop(self.statements.remove(&name)),
+                        _ => drop(self.portals.remove(&name)),
+                    }
+                    Message::new(b'3').write(&mut self.writer)?;
+                }
+                b'S' => {
+                    self.failed = false;
+                    self.ready()?;
+                }
+                b'H' => self.writer.flush()?,
+                b'X' => return Ok(()),
+                other => {
+                    let message = format!("unsupported message type {}", other as char);
+                    self.error(&SqlError::Unsupported(message))?;
+                    // The client is told it's ready again, so it mustn't be left waiting for a Sync
+                    self.failed = false;
+                    self.ready()?;
+                }
+            }
+        }
+    }
+
+    /// startup handles the startup message, refusing any encryption requests first. Returns false if the client
+    /// doesn't want a session.
+    fn startup(&mut self) -> io::Result<bool> {
+        loop {
+            let body = read_body(&mut self.reader)?;
+            let mut body = Body(&body);
+            match body.i32()? {
+                SSL_REQUEST | GSS_REQUEST => {
+                    self.writer.write_all(b"N")?;
+                    self.writer.flush()?;
+                }
+                CANCEL_REQUEST => return Ok(false),
+                PROTOCOL_VERSION => break,
+                version => {
+                    let message =
+                        format!("protocol version {}.{}", version >> 16, version & 0xffff);
+                    self.error(&SqlError::Unsupported(message))?;
+                    self.writer.flush()?;
+                    return Ok(false);
+                }
+            }
+        }
+
+        Message::new(b'R').i32(0).write(&mut self.writer)?;
+        for (name, value) in [
+            ("server_version", "14.0"),
+            ("server_encoding", "UTF8"),
+            ("client_encoding", "UTF8"),
+            ("DateStyle", "ISO, MDY"),
+            ("integer_datetimes", "on"),
+            ("standard_conforming_strings", "on"),
+        ] {
+            Message::new(b'S')
+                .str(name)
+                .str(value)
+                .write(&mut self.writer)?;
+        }
+        self.ready()?;
+        Ok(true)
+    }
+
+    fn ready(&mut self) -> io::Result<()> {
+        Message::new(b'Z').bytes(b"I").write(&mut self.writer)?;
+        self.writer.flush()
+    }
+
+    fn error(&mut self, error: &SqlError) -> io::Result<()> {
+        self.failed = true;
+        Message::new(b'E')
+            .bytes(b"S")
+            .str("ERROR")
+            .bytes(b"V")
+            .str("ERROR")
+            .bytes(b"C")
+            .str(sql_state(error))
+            .bytes(b"M")
+            .str(&error.to_string())
+            .bytes(&[0])
+            .write(&mut self.writer)
+    }
+
+    /// query runs every statement in a simple query, stopping at the first error
+    fn query(&mut self, query: &str) -> io::Result<()> {
+        let statements = match sql::parse(query) {
+            Ok(statements) => statements,
+            Err(e) => {
+                self.error(&e)?;
+                self.failed = false;
+                return self.ready();
+            }
+        };
+        if statements.is_empty() {
+            Message::new(b'I').write(&mut self.writer)?;
+        }
+
+        for statement in &statements {
+            let result = self.frontend.execute(statement);
+            if let Ok(QueryResult::Rows { columns, .. }) = &result {
+                self.row_description(columns)?;
+            }
+            if !self.result(result)? {
+                break;
+            }
+        }
+        self.failed = false;
+        self.ready()
+    }
+
+    /// result sends the rows and completion of a statement, or the error it failed with. Returns false on errors.
+    fn result(&mut self, result: Result<QueryResult, SqlError>) -> io::Result<bool> {
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => {
+                self.error(&e)?;
+                return Ok(false);
+            }
+        };
+
+        if let QueryResult::Rows { rows, .. } = &result {
+            for row in rows {
+                let mut message = Message::new(b'D').i16(row.data.len() as i16);
+                for value in &row.data {
+                    message = match text(value) {
+                        None => message.i32(-1),
+                        Some(s) => message.i32(s.len() as i32).bytes(s.as_bytes()),
+                    };
+                }
+                message.write(&mut self.writer)?;
+            }
+        }
+        Message::new(b'C')
+            .str(&command_tag(&result))
+            .write(&mut self.writer)?;
+        Ok(true)
+    }
+
+    fn row_description(&mut self, columns: &[ColumnSchema]) -> io::Result<()> {
+        let mut message = Message::new(b'T').i16(columns.len() as i16);
+        for column in columns {
+            message = message
+                .str(&column.name)
+                .i32(0)
+                .i16(0)
+                .i32(type_oid(column.column_type))
+                .i16(type_size(column.column_type))
+                .i32(-1)
+                .i16(0);
+        }
+        message.write(&mut self.writer)
+    }
+
+    fn parse(&mut self, body: &mut Body) -> io::Result<()> {
+        let name = body.str()?;
+        let query = body.str()?;
+        let statement = sql::parse(&query).and_then(|mut statements| match statements.len() {
+            0 => Ok(None),
+            1 => Ok(statements.pop()),
+            _ => Err(SqlError::Unsupported(
+                "multiple statements in a prepared statement".into(),
+            )),
+        });
+
+        match statement {
+            Ok(statement) => {
+                self.statements.insert(name, statement);
+                Message::new(b'1').write(&mut self.writer)
+            }
+            Err(e) => self.error(&e),
+        }
+    }
+
+    fn bind(&mut self, body: &mut Body) -> io::Result<()> {
+        let portal = body.str()?;
+        let name = body.str()?;
+        if let Err(e) = body.text_formats("parameters")? {
+            return self.error(&e);
+        }
+        let mut parameters = vec![];
+        for _ in 0..body.i16()? {
+            parameters.push(match body.i32()? {
+                -1 => None,
+                len => {
+                    let len =
+                        usize::try_from(len).map_err(|_| invalid("invalid parameter length"))?;
+                    let value = body.take(len)?.to_vec();
+                    Some(String::from_utf8(value).map_err(|_| invalid("invalid utf8"))?)
+                }
+            });
+        }
+        if let Err(e) = body.text_formats("results")? {
+            return self.error(&e);
+        }
+
+        let statement = match self.statements.get(&name) {
+            None => Err(SqlError::Syntax(format!(
+                "no prepared statement named {}",
+                name
+            ))),
+            Some(None) => Ok(None),
+            Some(Some(statement)) => statement.bind(&parameters).map(Some),
+        };
+        match statement {
+            Ok(statement) => {
+                self.portals.insert(portal, statement);
+                Message::new(b'2').write(&mut self.writer)
+            }
+            Err(e) => self.error(&e),
+        }
+    }
+
+    fn describe(&mut self, body: &mut Body) -> io::Result<()> {
+        let kind = body.u8()?;
+        let name = body.str()?;
+        let statement = match kind {
+            b'S' => self.statements.get(&name).cloned(),
+            _ => self.portals.get(&name).cloned(),
+        };
+        let statement = match statement {
+            Some(statement) => statement,
+            None => {
+                return self.error(&SqlError::Syntax(format!(
+                    "nothing named {} to describe",
+                    name
+                )))
+            }
+        };
+
+        if kind == b'S' {
+            let types = statement
+                .as_ref()
+                .map(|s| self.frontend.parameter_types(s))
+                .unwrap_or_default();
+            // Parameters are capped at $65535 when they're parsed, so the count always fits
+            let count = u16::try_from(types.len()).unwrap_or(u16::MAX);
+            let mut message = Message::new(b't').u16(count);
+            for t in types {
+                message = message.i32(type_oid(t));
+            }
+            message.write(&mut self.writer)?;
+        }
+
+        let columns = match statement.as_ref().map(|s| self.frontend.describe(s)) {
+            Some(Ok(columns)) => columns,
+            Some(Err(e)) => return self.error(&e),
+            None => None,
+        };
+        match columns {
+            Some(columns) => self.row_description(&columns),
+            None => Message::new(b'n').write(&mut self.writer),
+        }
+    }
+
+    fn execute(&mut self, body: &mut Body) -> io::Result<()> {
+        let name = body.str()?;
+        body.i32()?; // The row limit is ignored and all rows are always sent
+        let statement = match self.portals.get(&name) {
+            Some(statement) => statement.clone(),
+            None => return self.error(&SqlError::Syntax(format!("no portal named {}", name))),
+        };
+
+        match statement {
+            None => Message::new(b'I').write(&mut self.writer),
+            Some(statement) => {
+                let result = self.frontend.execute(&statement);
+                self.result(result).map(drop)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processing::MessageRouter;
+
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn connect(addr: std::net::SocketAddr) -> Self {
+            let mut client = Client {
+                stream: TcpStream::connect(addr).unwrap(),
+            };
+            let body = Message::new(0)
+                .i32(PROTOCOL_VERSION)
+                .str("user")
+                .str("test")
+                .bytes(&[0])
+                .body;
+            client
+                .stream
+                .write_all(&(body.len() as i32 + 4).to_be_bytes())
+                .unwrap();
+            client.stream.write_all(&body).unwrap();
+            client.until_ready();
+            client
+        }
+
+        fn send(&mut self, message: Message) {
+            message.write(&mut self.stream).unwrap();
+        }
+
+        /// until_ready reads messages up to ReadyForQuery, returning the tag and body of each
+        fn until_ready(&mut self) -> Vec<(u8, Vec<u8>)> {
+            let mut messages = vec![];
+            loop {
+                let mut header = [0; 5];
+                self.stream.read_exact(&mut header).unwrap();
+                let len = i32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+                let mut body = vec![0; len as usize - 4];
+                self.stream.read_exact(&mut body).unwrap();
+                if header[0] == b'Z' {
+                    return messages;
+                }
+                messages.push((header[0], body));
+            }
+        }
+
+        fn query(&mut self, sql: &str) -> Vec<(u8, Vec<u8>)> {
+            self.send(Message::new(b'Q').str(sql));
+            self.until_ready()
+        }
+    }
+
+    fn tags(messages: &[(u8, Vec<u8>)]) -> Vec<String> {
+        messages
+            .iter()
+            .filter(|(tag, _)| *tag == b'C')
+            .map(|(_, body)| Body(body).str().unwrap())
+            .collect()
+    }
+
+    fn rows(messages: &[(u8, Vec<u8>)]) -> Vec<Vec<Option<String>>> {
+        let mut rows: Vec<Vec<Option<String>>> = messages
+            .iter()
+            .filter(|(tag, _)| *tag == b'D')
+            .map(|(_, body)| {
+                let mut body = Body(body);
+                (0..body.i16().unwrap())
+                    .map(|_| match body.i32().unwrap() {
+                        -1 => None,
+                        len => Some(
+                            String::from_utf8(body.take(len as usize).unwrap().to_vec()).unwrap(),
+                        ),
+                    })
+                    .collect()
+            })
+            .collect();
+        rows.sort();
+        rows
+    }
+
+    #[test]
+    fn rejects_bad_message_lengths() {
+        let frame = |len: i32| {
+            let mut frame = len.to_be_bytes().to_vec();
+            frame.extend_from_slice(b"body");
+            frame
+        };
+        assert_eq!(read_body(&mut frame(8).as_slice()).unwrap(), b"body");
+        assert_eq!(read_body(&mut frame(4).as_slice()).unwrap(), b"");
+        for len in [3, -1, i32::MIN, MAX_FRAME as i32 + 5] {
+            let error = read_body(&mut frame(len).as_slice()).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn speaks_postgres() {
+        let frontend = Arc::new(Frontend::new(Arc::new(MessageRouter::new())));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve(frontend, listener));
+
+        let mut client = Client::connect(addr);
+        let messages = client.query(
+            "CREATE TABLE items (id INT NOT NULL, name TEXT, ok BOOLEAN);
+            INSERT INTO items VALUES (1, 'apple', true), (2, NULL, false);
+            UPDATE items SET name = 'pear' WHERE id = 2;
+            SELECT * FROM items",
+        );
+        assert_eq!(
+            tags(&messages),
+            vec!["CREATE TABLE", "INSERT 0 2", "UPDATE 1", "SELECT 2"]
+        );
+        let description = &messages.iter().find(|(tag, _)| *tag == b'T').unwrap().1;
+        assert_eq!(Body(description).i16().unwrap(), 3);
+        let some = |s: &str| Some(s.to_string());
+        assert_eq!(
+            rows(&messages),
+            vec![
+                vec![some("1"), some("apple"), some("t")],
+                vec![some("2"), some("pear"), some("f")],
+            ]
+        );
+
+        let messages = client.query("SELECT * FROM missing; SELECT * FROM items");
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].0, b'E');
+
+        // Prepared statements through the extended protocol
+        client.send(
+            Message::new(b'P')
+                .str("")
+                .str("DELETE FROM items WHERE id = $1")
+                .i16(0),
+        );
+        client.send(
+            Message::new(b'B')
+                .str("")
+                .str("")
+                .i16(0)
+                .i16(1)
+                .i32(1)
+                .bytes(b"1")
+                .i16(0),
+        );
+        client.send(Message::new(b'D').bytes(b"P").str(""));
+        client.send(Message::new(b'E').str("").i32(0));
+        client.send(Message::new(b'S'));
+        let messages = client.until_ready();
+        let tags_seen: Vec<u8> = messages.iter().map(|(tag, _)| *tag).collect();
+        assert_eq!(tags_seen, vec![b'1', b'2', b'n', b'C']);
+        assert_eq!(tags(&messages), vec!["DELETE 1"]);
+
+        let messages = client.query("SELECT name FROM items WHERE ok = false");
+        assert_eq!(rows(&messages), vec![vec![some("pear")]]);
+
+        // The session carries on after a message type it doesn't know
+        client.send(Message::new(b'F'));
+        let messages = client.until_ready();
+        assert_eq!(messages[0].0, b'E');
+        let messages = client.query("SELECT id FROM items");
+        assert_eq!(rows(&messages), vec![vec![some("2")]]);
+    }
+}
@@ -0,0 +1,692 @@
+//! A small SQL dialect covering what the frontends support.
+//!
+//! ```text
+//! CREATE TABLE name (column type [NOT NULL | NULL | PRIMARY KEY], ...)
+//! INSERT INTO name [(column, ...)] VALUES (value, ...), ...
+//! UPDATE name SET column = value, ... [WHERE conditions]
+//! DELETE FROM name [WHERE conditions]
+//! SELECT * | column, ... FROM name [WHERE conditions]
+//! SET anything
+//! ```
+//!
+//! Conditions are comparisons between a column and a value joined by AND. Values are literals or $n parameters
+//! which are bound before the statement is run.
+
+use super::SqlError;
+use crate::operations::data::{Comparison, DataType};
+use crate::operations::schema::{ColumnSchema, ColumnType};
+use crate::operations::types::ParseError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    CreateTable {
+        name: String,
+        columns: Vec<ColumnSchema>,
+    },
+    /// An empty column list means values are given for every column in order
+    Insert {
+        table: String,
+        columns: Vec<String>,
+        rows: Vec<Vec<Literal>>,
+    },
+    Update {
+        table: String,
+        assignments: Vec<(String, Literal)>,
+        conditions: Vec<Condition>,
+    },
+    Delete {
+        table: String,
+        conditions: Vec<Condition>,
+    },
+    /// An empty column list selects every column
+    Select {
+        source: String,
+        columns: Vec<String>,
+        conditions: Vec<Condition>,
+    },
+    /// Session settings are accepted so clients can connect but they don't change anything
+    Set,
+}
+
+/// Literal is a value written in a statement. Its type isn't known until it's matched up with a column.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Null,
+    Boolean(bool),
+    Number(String),
+    Text(String),
+    /// A $n parameter, counting from 1
+    Parameter(usize),
+}
+
+impl Literal {
+    /// value converts the literal into a value for a column of the type
+    pub fn value(&self, column_type: ColumnType) -> Result<DataType, ParseError> {
+        match (self, column_type) {
+            (Literal::Null, _) => Ok(DataType::None),
+            (Literal::Boolean(b), ColumnType::Any) => Ok(DataType::Boolean(*b)),
+            (Literal::Boolean(b), t) => t.parse(&b.to_string()),
+            (Literal::Number(n), ColumnType::Any) => ColumnType::Integer
+                .parse(n)
+                .or_else(|_| ColumnType::BigInt.parse(n))
+                .or_else(|_| ColumnType::Decimal.parse(n)),
+            (Literal::Number(n), t) | (Literal::Text(n), t) => t.parse(n),
+            (Literal::Parameter(n), _) => Err(ParseError(format!("parameter ${} isn't bound", n))),
+        }
+    }
+
+    fn bind(&mut self, parameters: &[Option<String>]) -> Result<(), SqlError> {
+        if let Literal::Parameter(n) = self {
+            *self = match parameters.get(*n - 1) {
+                Some(Some(value)) => Literal::Text(value.clone()),
+                Some(None) => Literal::Null,
+                None => return Err(SqlError::Syntax(format!("no value given for ${}", n))),
+            };
+        }
+        Ok(())
+    }
+}
+
+/// Condition compares a column with a value
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    pub column: String,
+    pub comparison: Comparison,
+    pub value: Literal,
+}
+
+impl Statement {
+    /// literals returns every value in the statement along with the column it's used with
+    pub fn literals(&self) -> Vec<(Option<&str>, &Literal)> {
+        match self {
+            Statement::Insert { columns, rows, .. } => rows
+                .iter()
+                .flat_map(|row| {
+                    row.iter()
+                        .enumerate()
+                        .map(move |(i, l)| (columns.get(i).map(|c| c.as_str()), l))
+                })
+                .collect(),
+            Statement::Update {
+                assignments,
+                conditions: c,
+                ..
+            } => assignments
+                .iter()
+                .map(|(column, l)| (Some(column.as_str()), l))
+                .chain(condition_literals(c))
+                .collect(),
+            Statement::Delete { conditions: c, .. } | Statement::Select { conditions: c, .. } => {
+                condition_literals(c).collect()
+            }
+            Statement::CreateTable { .. } | Statement::Set => vec![],
+        }
+    }
+
+    /// parameters returns the number of $n parameters the statement takes
+    pub fn parameters(&self) -> usize {
+        self.literals()
+            .into_iter()
+            .filter_map(|(_, l)| match l {
+                Literal::Parameter(n) => Some(*n),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// bind replaces every parameter with its value. Values are given as text, with None for nulls.
+    pub fn bind(&self, parameters: &[Option<String>]) -> Result<Statement, SqlError> {
+        let mut statement = self.clone();
+        let literals: Vec<&mut Literal> = match &mut statement {
+            Statement::Insert { rows, .. } => rows.iter_mut().flatten().collect(),
+            Statement::Update {
+                assignments,
+                conditions,
+                ..
+            } => assignments
+                .iter_mut()
+                .map(|(_, l)| l)
+                .chain(conditions.iter_mut().map(|c| &mut c.value))
+                .collect(),
+            Statement::Delete { conditions, .. } | Statement::Select { conditions, .. } => {
+                conditions.iter_mut().map(|c| &mut c.value).collect()
+            }
+            Statement::CreateTable { .. } | Statement::Set => vec![],
+        };
+
+        for literal in literals {
+            literal.bind(parameters)?;
+        }
+        Ok(statement)
+    }
+}
+
+fn condition_literals(conditions: &[Condition]) -> impl Iterator<Item = (Option<&str>, &Literal)> {
+    conditions
+        .iter()
+        .map(|c| (Some(c.column.as_str()), &c.value))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// Unquoted words are lowercased as SQL identifiers and keywords aren't case sensitive
+    Word(String),
+    /// A "quoted" identifier keeps its case
+    Quoted(String),
+    Number(String),
+    Text(String),
+    Parameter(usize),
+    Symbol(&'static str),
+}
+
+/// MAX_PARAMETER is the highest $n a statement can use, the most parameters Postgres allows
+const MAX_PARAMETER: usize = 65535;
+
+const SYMBOLS: [&str; 13] = [
+    "<>", "!=", "<=", ">=", "(", ")", ",", ";", "*", "=", "<", ">", "-",
+];
+
+fn tokenize(sql: &str) -> Result<Vec<Token>, SqlError> {
+    let mut tokens = vec![];
+    let mut rest = sql;
+    while let Some(c) = rest.chars().next() {
+        if c.is_whitespace() {
+            rest = &rest[c.len_utf8()..];
+        } else if rest.starts_with("--") {
+            rest = rest.find('\n').map_or("", |i| &rest[i..]);
+        } else if c.is_alphabetic() || c == '_' {
+            let (word, r) = split_while(rest, |c| c.is_alphanumeric() || c == '_');
+            tokens.push(Token::Word(word.to_lowercase()));
+            rest = r;
+        } else if c.is_ascii_digit()
+            || (c == '.' && rest[1..].starts_with(|c: char| c.is_ascii_digit()))
+        {
+            let (number, r) = split_while(rest, |c| c.is_ascii_digit() || c == '.');
+            tokens.push(Token::Number(number.into()));
+            rest = r;
+        } else if c == '\'' || c == '"' {
+            let (quoted, r) = quoted(&rest[1..], c)?;
+            tokens.push(match c {
+                '\'' => Token::Text(quoted),
+                _ => Token::Quoted(quoted),
+            });
+            rest = r;
+        } else if c == '$' {
+            let (n, r) = split_while(&rest[1..], |c| c.is_ascii_digit());
+            match n.parse() {
+                Ok(n) if n > 0 && n <= MAX_PARAMETER => tokens.push(Token::Parameter(n)),
+                Ok(n) if n > 0 => {
+                    return Err(SqlError::Syntax(format!(
+                        "parameter ${} is above the limit of ${}",
+                        n, MAX_PARAMETER
+                    )))
+                }
+                _ => {
+                    return Err(SqlError::Syntax(
+                        "expected a parameter number after $".into(),
+                    ))
+                }
+            }
+            rest = r;
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|s| rest.starts_with(*s))
+                .ok_or_else(|| SqlError::Syntax(format!("unexpected character {}", c)))?;
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        }
+    }
+    Ok(tokens)
+}
+
+fn split_while<F: Fn(char) -> bool>(s: &str, f: F) -> (&str, &str) {
+    s.split_at(s.find(|c| !f(c)).unwrap_or(s.len()))
+}
+
+/// quoted reads up to the closing quote, returning the text and what follows it. Quotes inside are escaped by
+/// doubling them.
+fn quoted(s: &str, quote: char) -> Result<(String, &str), SqlError> {
+    let mut text = String::new();
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c != quote {
+            text.push(c);
+        } else if s[i + 1..].starts_with(quote) {
+            text.push(quote);
+            chars.next();
+        } else {
+            return Ok((text, &s[i + 1..]));
+        }
+    }
+    Err(SqlError::Syntax("unterminated quote".into()))
+}
+
+/// parse reads all of the statements in the SQL, which are separated by semicolons
+pub fn parse(sql: &str) -> Result<Vec<Statement>, SqlError> {
+    let mut parser = Parser {
+        tokens: tokenize(sql)?,
+        position: 0,
+    };
+
+    let mut statements = vec![];
+    loop {
+        while parser.eat_symbol(";") {}
+        if parser.peek().is_none() {
+            return Ok(statements);
+        }
+        statements.push(parser.statement()?);
+        if parser.peek().is_some() && !parser.eat_symbol(";") {
+            return Err(parser.unexpected("end of statement"));
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn unexpected(&self, expected: &str) -> SqlError {
+        match self.peek() {
+            Some(token) => SqlError::Syntax(format!("expected {} but found {:?}", expected, token)),
+            None => SqlError::Syntax(format!("expected {} but the statement ended", expected)),
+        }
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Word(w)) if w == keyword => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn keyword(&mut self, keyword: &str) -> Result<(), SqlError> {
+        match self.eat_keyword(keyword) {
+            true => Ok(()),
+            false => Err(self.unexpected(&keyword.to_uppercase())),
+        }
+    }
+
+    fn eat_symbol(&mut self, symbol: &str) -> bool {
+        match self.peek() {
+            Some(Token::Symbol(s)) if *s == symbol => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn symbol(&mut self, symbol: &str) -> Result<(), SqlError> {
+        match self.eat_symbol(symbol) {
+            true => Ok(()),
+            false => Err(self.unexpected(symbol)),
+        }
+    }
+
+    fn identifier(&mut self) -> Result<String, SqlError> {
+        match self.peek() {
+            Some(Token::Word(w)) | Some(Token::Quoted(w)) => {
+                let w = w.clone();
+                self.position += 1;
+                Ok(w)
+            }
+            _ => Err(self.unexpected("a name")),
+        }
+    }
+
+    /// list parses one or more items separated by commas
+    fn list<T, F>(&mut self, mut item: F) -> Result<Vec<T>, SqlError>
+    where
+        F: FnMut(&mut Self) -> Result<T, SqlError>,
+    {
+        let mut items = vec![item(self)?];
+        while self.eat_symbol(",") {
+            items.push(item(self)?);
+        }
+        Ok(items)
+    }
+
+    fn statement(&mut self) -> Result<Statement, SqlError> {
+        match self.next() {
+            Some(Token::Word(w)) => match w.as_str() {
+                "create" => self.create_table(),
+                "insert" => self.insert(),
+                "update" => self.update(),
+                "delete" => self.delete(),
+                "select" => self.select(),
+                "set" => {
+                    while !matches!(self.peek(), None | Some(Token::Symbol(";"))) {
+                        self.position += 1;
+                    }
+                    Ok(Statement::Set)
+                }
+                _ => Err(SqlError::Unsupported(format!(
+                    "{} statements",
+                    w.to_uppercase()
+                ))),
+            },
+            _ => {
+                self.position -= 1;
+                Err(self.unexpected("a statement"))
+            }
+        }
+    }
+
+    fn create_table(&mut self) -> Result<Statement, SqlError> {
+        self.keyword("table")?;
+        let name = self.identifier()?;
+        self.symbol("(")?;
+        let columns = self.list(|p| p.column())?;
+        self.symbol(")")?;
+        Ok(Statement::CreateTable { name, columns })
+    }
+
+    fn column(&mut self) -> Result<ColumnSchema, SqlError> {
+        let name = self.identifier()?;
+        let column_type = self.column_type()?;
+        let mut nullable = true;
+        loop {
+            if self.eat_keyword("not") {
+                self.keyword("null")?;
+                nullable = false;
+            } else if self.eat_keyword("primary") {
+                self.keyword("key")?;
+                nullable = false;
+            } else if !self.eat_keyword("null") {
+                return Ok(ColumnSchema::new(&name, column_type, nullable));
+            }
+        }
+    }
+
+    fn column_type(&mut self) -> Result<ColumnType, SqlError> {
+        let name = match self.next() {
+            Some(Token::Word(w)) => w,
+            _ => {
+                self.position -= 1;
+                return Err(self.unexpected("a type"));
+            }
+        };
+
+        let column_type = match name.as_str() {
+            "int" | "integer" | "int4" | "smallint" | "int2" => ColumnType::Integer,
+            "bigint" | "int8" => ColumnType::BigInt,
+            "text" | "varchar" | "char" | "string" => ColumnType::Text,
+            "character" => {
+                self.eat_keyword("varying");
+                ColumnType::Text
+            }
+            "boolean" | "bool" => ColumnType::Boolean,
+            "real" | "float4" => ColumnType::Float,
+            "double" => {
+                self.keyword("precision")?;
+                ColumnType::Double
+            }
+            "float" | "float8" => ColumnType::Double,
+            "numeric" | "decimal" => ColumnType::Decimal,
+            "timestamp" => {
+                if self.eat_keyword("without") {
+                    self.keyword("time")?;
+                    self.keyword("zone")?;
+                }
+                ColumnType::Timestamp
+            }
+            "date" => ColumnType::Date,
+            "interval" => ColumnType::Interval,
+            "bytea" | "blob" => ColumnType::Bytes,
+            _ => return Err(SqlError::Unsupported(format!("type {}", name))),
+        };
+
+        // Lengths and precisions like varchar(20) or numeric(10, 2) aren't enforced
+        if self.eat_symbol("(") {
+            self.list(|p| match p.next() {
+                Some(Token::Number(_)) => Ok(()),
+                _ => {
+                    p.position -= 1;
+                    Err(p.unexpected("a number"))
+                }
+            })?;
+            self.symbol(")")?;
+        }
+        Ok(column_type)
+    }
+
+    fn insert(&mut self) -> Result<Statement, SqlError> {
+        self.keyword("into")?;
+        let table = self.identifier()?;
+        let mut columns = vec![];
+        if self.eat_symbol("(") {
+            columns = self.list(|p| p.identifier())?;
+            self.symbol(")")?;
+        }
+
+        self.keyword("values")?;
+        let rows = self.list(|p| {
+            p.symbol("(")?;
+            let row = p.list(|p| p.literal())?;
+            p.symbol(")")?;
+            Ok(row)
+        })?;
+        Ok(Statement::Insert {
+            table,
+            columns,
+            rows,
+        })
+    }
+
+    fn update(&mut self) -> Result<Statement, SqlError> {
+        let table = self.identifier()?;
+        self.keyword("set")?;
+        let assignments = self.list(|p| {
+            let column = p.identifier()?;
+            p.symbol("=")?;
+            Ok((column, p.literal()?))
+        })?;
+        Ok(Statement::Update {
+            table,
+            assignments,
+            conditions: self.conditions()?,
+        })
+    }
+
+    fn delete(&mut self) -> Result<Statement, SqlError> {
+        self.keyword("from")?;
+        let table = self.identifier()?;
+        Ok(Statement::Delete {
+            table,
+            conditions: self.conditions()?,
+        })
+    }
+
+    fn select(&mut self) -> Result<Statement, SqlError> {
+        let columns = match self.eat_symbol("*") {
+            true => vec![],
+            false => self.list(|p| p.identifier())?,
+        };
+        self.keyword("from")?;
+        let source = self.identifier()?;
+        Ok(Statement::Select {
+            source,
+            columns,
+            conditions: self.conditions()?,
+        })
+    }
+
+    fn conditions(&mut self) -> Result<Vec<Condition>, SqlError> {
+        if !self.eat_keyword("where") {
+            return Ok(vec![]);
+        }
+
+        let mut conditions = vec![];
+        loop {
+            let column = self.identifier()?;
+            let comparison = match self.next() {
+                Some(Token::Symbol("=")) => Comparison::Equal,
+                Some(Token::Symbol("<>")) | Some(Token::Symbol("!=")) => Comparison::NotEqual,
+                Some(Token::Symbol("<")) => Comparison::LessThan,
+                Some(Token::Symbol(">")) => Comparison::GreaterThan,
+                Some(Token::Symbol("<=")) => Comparison::LessEqualThan,
+                Some(Token::Symbol(">=")) => Comparison::GreaterEqualThan,
+                _ => {
+                    self.position -= 1;
+                    return Err(self.unexpected("a comparison"));
+                }
+            };
+            conditions.push(Condition {
+                column,
+                comparison,
+                value: self.literal()?,
+            });
+
+            if !self.eat_keyword("and") {
+                return Ok(conditions);
+            }
+        }
+    }
+
+    fn literal(&mut self) -> Result<Literal, SqlError> {
+        let literal = match self.next() {
+            Some(Token::Number(n)) => Literal::Number(n),
+            Some(Token::Symbol("-")) => match self.next() {
+                Some(Token::Number(n)) => Literal::Number(format!("-{}", n)),
+                _ => {
+                    self.position -= 1;
+                    return Err(self.unexpected("a number"));
+                }
+            },
+            Some(Token::Text(s)) => Literal::Text(s),
+            Some(Token::Parameter(n)) => Literal::Parameter(n),
+            Some(Token::Word(w)) => match w.as_str() {
+                "null" => Literal::Null,
+                "true" => Literal::Boolean(true),
+                "false" => Literal::Boolean(false),
+                // Typed literals like DATE '2021-01-01' take their type from the column they're used with
+                "date" | "timestamp" | "interval" => match self.next() {
+                    Some(Token::Text(s)) => Literal::Text(s),
+                    _ => {
+                        self.position -= 1;
+                        return Err(self.unexpected("quoted text"));
+                    }
+                },
+                _ => {
+                    self.position -= 1;
+                    return Err(self.unexpected("a value"));
+                }
+            },
+            _ => {
+                self.position -= 1;
+                return Err(self.unexpected("a value"));
+            }
+        };
+        Ok(literal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_statements() {
+        let statements = parse(
+            "CREATE TABLE Items (id INT PRIMARY KEY, \"Name\" VARCHAR(20), price NUMERIC(10, 2) NOT NULL);
+            INSERT INTO items VALUES (1, 'it''s', -2.50), (2, NULL, $1);
+            UPDATE items SET price = 3 WHERE id >= 2 AND \"Name\" <> 'x'; -- comment
+            DELETE FROM items;
+            SELECT id, price FROM items WHERE id = $2",
+        )
+        .unwrap();
+
+        assert_eq!(
+            statements[0],
+            Statement::CreateTable {
+                name: "items".into(),
+                columns: vec![
+                    ColumnSchema::new("id", ColumnType::Integer, false),
+                    ColumnSchema::new("Name", ColumnType::Text, true),
+                    ColumnSchema::new("price", ColumnType::Decimal, false),
+                ],
+            }
+        );
+        assert_eq!(
+            statements[1],
+            Statement::Insert {
+                table: "items".into(),
+                columns: vec![],
+                rows: vec![
+                    vec![
+                        Literal::Number("1".into()),
+                        Literal::Text("it's".into()),
+                        Literal::Number("-2.50".into())
+                    ],
+                    vec![
+                        Literal::Number("2".into()),
+                        Literal::Null,
+                        Literal::Parameter(1)
+                    ],
+                ],
+            }
+        );
+        assert_eq!(
+            statements[2],
+            Statement::Update {
+                table: "items".into(),
+                assignments: vec![("price".into(), Literal::Number("3".into()))],
+                conditions: vec![
+                    Condition {
+                        column: "id".into(),
+                        comparison: Comparison::GreaterEqualThan,
+                        value: Literal::Number("2".into()),
+                    },
+                    Condition {
+                        column: "Name".into(),
+                        comparison: Comparison::NotEqual,
+                        value: Literal::Text("x".into()),
+                    },
+                ],
+            }
+        );
+        assert_eq!(
+            statements[3],
+            Statement::Delete {
+                table: "items".into(),
+                conditions: vec![],
+            }
+        );
+        assert_eq!(statements[4].parameters(), 2);
+
+        let bound = statements[4].bind(&[None, Some("7".into())]).unwrap();
+        match bound {
+            Statement::Select { conditions, .. } => {
+                assert_eq!(conditions[0].value, Literal::Text("7".into()))
+            }
+            s => panic!("expected a select but got {:?}", s),
+        }
+        assert!(statements[4].bind(&[]).is_err());
+        assert_eq!(
+            parse("SELECT * FROM items WHERE id = $65535").unwrap()[0].parameters(),
+            MAX_PARAMETER
+        );
+        assert!(parse("SELECT * FROM items WHERE id = $1000000000000").is_err());
+
+        assert!(parse("SELECT FROM items").is_err());
+        assert!(parse("INSERT INTO items VALUES (1").is_err());
+        assert!(parse("DROP TABLE items").is_err());
+    }
+}
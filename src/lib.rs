@@ -1,2 +1,5 @@
+pub mod connectors;
+pub mod frontend;
 pub mod operations;
 pub mod processing;
+pub mod server;
@@ -0,0 +1,26 @@
+use super::data::{Diff, Updates};
+use super::schema::{Schema, SchemaError};
+use super::{Description, Operation, ProcessError};
+
+/// Base is the root of a table in the dataflow graph. It declares the schema of the table and forwards all writes to
+/// it on to its children.
+pub struct Base {
+    pub schema: Schema,
+}
+
+impl Operation for Base {
+    fn process(&mut self, updates: Updates) -> Result<Vec<Diff>, ProcessError> {
+        Ok(updates.into_updates())
+    }
+
+    fn schema(&mut self, parents: &[&Schema]) -> Result<Schema, SchemaError> {
+        match parents.is_empty() {
+            true => Ok(self.schema.clone()),
+            false => Err(SchemaError::UnexpectedParents),
+        }
+    }
+
+    fn describe(&self, _: &[&Schema]) -> Description {
+        Description::new("base")
+    }
+}
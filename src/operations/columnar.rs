@@ -0,0 +1,324 @@
+use super::data::{Column, Comparison, DataType, Diff, Row};
+use super::ProcessError;
+use ordered_float::OrderedFloat;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+/// ColumnValues holds every value of a single column in a batch. Columns where all non null values share a type are
+/// stored in a typed vector so operations can work on them without matching on DataType for every value. Anything
+/// else falls back to storing the DataTypes directly.
+///
+/// Text is stored as shared strs so copying a text column (say for a Map or Filter) never copies the strings.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColumnValues {
+    Integer(Vec<Option<i32>>),
+    Float(Vec<Option<OrderedFloat<f32>>>),
+    Boolean(Vec<Option<bool>>),
+    Text(Vec<Option<Arc<str>>>),
+    Mixed(Vec<DataType>),
+}
+
+impl ColumnValues {
+    /// from_values builds the most specific column representation that can hold all of the values
+    pub fn from_values(values: Vec<DataType>) -> Self {
+        let kind = values.iter().find(|v| **v != DataType::None);
+        let typed = match kind {
+            Some(DataType::Integer(_)) => values
+                .iter()
+                .map(|v| match v {
+                    DataType::Integer(n) => Some(Some(*n)),
+                    DataType::None => Some(None),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()
+                .map(ColumnValues::Integer),
+            Some(DataType::Float(_)) => values
+                .iter()
+                .map(|v| match v {
+                    DataType::Float(n) => Some(Some(*n)),
+                    DataType::None => Some(None),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()
+                .map(ColumnValues::Float),
+            Some(DataType::Boolean(_)) => values
+                .iter()
+                .map(|v| match v {
+                    DataType::Boolean(b) => Some(Some(*b)),
+                    DataType::None => Some(None),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()
+                .map(ColumnValues::Boolean),
+            Some(DataType::Text(_)) => values
+                .iter()
+                .map(|v| match v {
+                    DataType::Text(s) => Some(Some(Arc::from(s.as_str()))),
+                    DataType::None => Some(None),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()
+                .map(ColumnValues::Text),
+            _ => None,
+        };
+
+        typed.unwrap_or(ColumnValues::Mixed(values))
+    }
+
+    /// repeat builds a column holding the same value n times
+    pub fn repeat(value: &DataType, n: usize) -> Self {
+        match value {
+            DataType::Integer(v) => ColumnValues::Integer(vec![Some(*v); n]),
+            DataType::Float(v) => ColumnValues::Float(vec![Some(*v); n]),
+            DataType::Boolean(v) => ColumnValues::Boolean(vec![Some(*v); n]),
+            DataType::Text(v) => ColumnValues::Text(vec![Some(Arc::from(v.as_str())); n]),
+            v => ColumnValues::Mixed(vec![v.clone(); n]),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            ColumnValues::Integer(v) => v.len(),
+            ColumnValues::Float(v) => v.len(),
+            ColumnValues::Boolean(v) => v.len(),
+            ColumnValues::Text(v) => v.len(),
+            ColumnValues::Mixed(v) => v.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// get returns the value at index i as a DataType
+    pub fn get(&self, i: usize) -> DataType {
+        fn or_none<T>(v: &Option<T>, f: impl FnOnce(&T) -> DataType) -> DataType {
+            v.as_ref().map_or(DataType::None, f)
+        }
+
+        match self {
+            ColumnValues::Integer(v) => or_none(&v[i], |n| DataType::Integer(*n)),
+            ColumnValues::Float(v) => or_none(&v[i], |n| DataType::Float(*n)),
+            ColumnValues::Boolean(v) => or_none(&v[i], |b| DataType::Boolean(*b)),
+            ColumnValues::Text(v) => or_none(&v[i], |s| DataType::Text(s.to_string())),
+            ColumnValues::Mixed(v) => v[i].clone(),
+        }
+    }
+
+    /// gather builds a new column from the values at the given indices
+    pub fn gather(&self, indices: &[usize]) -> Self {
+        fn pick<T: Clone>(v: &[T], indices: &[usize]) -> Vec<T> {
+            indices.iter().map(|i| v[*i].clone()).collect()
+        }
+
+        match self {
+            ColumnValues::Integer(v) => ColumnValues::Integer(pick(v, indices)),
+            ColumnValues::Float(v) => ColumnValues::Float(pick(v, indices)),
+            ColumnValues::Boolean(v) => ColumnValues::Boolean(pick(v, indices)),
+            ColumnValues::Text(v) => ColumnValues::Text(pick(v, indices)),
+            ColumnValues::Mixed(v) => ColumnValues::Mixed(pick(v, indices)),
+        }
+    }
+
+    /// compare runs the comparison against every value in the column returning whether each value passed.
+    /// Gives the same results as running Comparison::compare on each value.
+    pub fn compare(&self, op: &Comparison, value: &DataType) -> Vec<bool> {
+        fn typed<T: Ord>(
+            v: &[Option<T>],
+            op: &Comparison,
+            other: &T,
+            value: &DataType,
+        ) -> Vec<bool> {
+            // Nulls are only compared with the slow path as it's rare for them to be most of a column
+            let null = op.compare(&DataType::None, value);
+            v.iter()
+                .map(|x| match x {
+                    Some(x) => op.compare_values(x, other),
+                    None => null,
+                })
+                .collect()
+        }
+
+        match (self, value) {
+            (ColumnValues::Integer(v), DataType::Integer(n)) => typed(v, op, n, value),
+            (ColumnValues::Float(v), DataType::Float(n)) => typed(v, op, n, value),
+            (ColumnValues::Boolean(v), DataType::Boolean(b)) => typed(v, op, b, value),
+            (ColumnValues::Text(v), DataType::Text(s)) => {
+                let s: Arc<str> = Arc::from(s.as_str());
+                typed(v, op, &s, value)
+            }
+            _ => (0..self.len())
+                .map(|i| op.compare(&self.get(i), value))
+                .collect(),
+        }
+    }
+}
+
+/// ColumnarUpdates holds a batch of updates column by column rather than row by row. The diff of each row is its
+/// multiplicity, positive for added copies of the row and negative for removed ones.
+///
+/// Columns are shared so operations that only move columns around (like a Map) never copy their values.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnarUpdates {
+    pub columns: Vec<Arc<ColumnValues>>,
+    pub diffs: Vec<i64>,
+}
+
+impl ColumnarUpdates {
+    /// column returns the values of the column
+    pub fn column(&self, column: &Column) -> Result<&Arc<ColumnValues>, ProcessError> {
+        let index = column.index()?;
+        self.columns.get(index).ok_or(ProcessError::MissingColumn {
+            column: index,
+            width: self.columns.len(),
+        })
+    }
+
+    /// len returns the number of rows in the batch
+    pub fn len(&self) -> usize {
+        self.diffs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.diffs.is_empty()
+    }
+
+    /// row returns the values of a single row of the batch
+    pub fn row(&self, i: usize) -> Row {
+        self.columns
+            .iter()
+            .map(|c| c.get(i))
+            .collect::<Vec<DataType>>()
+            .into()
+    }
+
+    /// select keeps only the rows at the given indices
+    pub fn select(&self, indices: &[usize]) -> Self {
+        Self {
+            columns: self
+                .columns
+                .iter()
+                .map(|c| Arc::new(c.gather(indices)))
+                .collect(),
+            diffs: indices.iter().map(|i| self.diffs[*i]).collect(),
+        }
+    }
+
+    /// into_diffs converts the batch back into rows and their diffs
+    pub fn into_diffs(self) -> Vec<Diff> {
+        self.diffs
+            .iter()
+            .enumerate()
+            .map(|(i, d)| (self.row(i), *d))
+            .collect()
+    }
+}
+
+/// Rows must all have the same number of columns. A row of a different width fails the conversion with the first
+/// column missing from the narrower row, the same error the row at a time path reports.
+impl TryFrom<&[Diff]> for ColumnarUpdates {
+    type Error = ProcessError;
+
+    fn try_from(updates: &[Diff]) -> Result<Self, ProcessError> {
+        let width = updates.first().map_or(0, |(row, _)| row.data.len());
+        if let Some((row, _)) = updates.iter().find(|(row, _)| row.data.len() != width) {
+            let narrowest = row.data.len().min(width);
+            return Err(ProcessError::MissingColumn {
+                column: narrowest,
+                width: narrowest,
+            });
+        }
+
+        let columns = (0..width)
+            .map(|c| {
+                let values = updates.iter().map(|(row, _)| row[c].clone()).collect();
+                Arc::new(ColumnValues::from_values(values))
+            })
+            .collect();
+        let diffs = updates.iter().map(|(_, diff)| *diff).collect();
+
+        Ok(Self { columns, diffs })
+    }
+}
+
+/// A ColumnarOperation can process a whole batch of updates a column at a time
+pub trait ColumnarOperation {
+    /// process_columns handles a batch of columnar updates, returning the batch to forward on. Like
+    /// Operation::process the operation is left as it was if an error is returned.
+    fn process_columns(
+        &mut self,
+        updates: ColumnarUpdates,
+    ) -> Result<ColumnarUpdates, ProcessError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_typed_columns() {
+        let ints = ColumnValues::from_values(vec![1.into(), DataType::None, 3.into()]);
+        assert_eq!(ints, ColumnValues::Integer(vec![Some(1), None, Some(3)]));
+
+        let text = ColumnValues::from_values(vec!["a".into(), "b".into()]);
+        assert_eq!(
+            text,
+            ColumnValues::Text(vec![Some("a".into()), Some("b".into())])
+        );
+
+        let mixed = ColumnValues::from_values(vec![1.into(), "b".into()]);
+        assert_eq!(mixed, ColumnValues::Mixed(vec![1.into(), "b".into()]));
+
+        let nulls = ColumnValues::from_values(vec![DataType::None, DataType::None]);
+        assert_eq!(
+            nulls,
+            ColumnValues::Mixed(vec![DataType::None, DataType::None])
+        );
+    }
+
+    #[test]
+    fn compares_like_rows() {
+        let values: Vec<DataType> = vec![1.into(), DataType::None, 3.into(), 5.into()];
+        let column = ColumnValues::from_values(values.clone());
+
+        for op in [
+            Comparison::Equal,
+            Comparison::NotEqual,
+            Comparison::GreaterThan,
+            Comparison::LessThan,
+            Comparison::GreaterEqualThan,
+            Comparison::LessEqualThan,
+        ] {
+            for other in [3.into(), DataType::None, "3".into()] {
+                let expected: Vec<bool> = values.iter().map(|v| op.compare(v, &other)).collect();
+                assert_eq!(column.compare(&op, &other), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips_rows() {
+        let updates: Vec<Diff> = vec![
+            (vec![0.into(), "hello".into(), DataType::None].into(), 1),
+            (vec![1.into(), "there".into(), 2.5.into()].into(), -3),
+        ];
+
+        let columnar = ColumnarUpdates::try_from(updates.as_slice()).unwrap();
+        assert_eq!(columnar.len(), 2);
+        assert_eq!(columnar.diffs, vec![1, -3]);
+        assert_eq!(columnar.into_diffs(), updates);
+
+        // Short rows fail rather than being padded with nulls
+        let short = [
+            (vec![0.into(), "a".into()].into(), 1),
+            (vec![1.into()].into(), 1),
+        ];
+        assert_eq!(
+            ColumnarUpdates::try_from(&short[..]),
+            Err(ProcessError::MissingColumn {
+                column: 1,
+                width: 1
+            })
+        );
+    }
+}
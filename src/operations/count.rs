@@ -1,6 +1,11 @@
-use super::data::{DataType, RowUpdate, Source, Updates};
+use super::columnar::{ColumnValues, ColumnarOperation, ColumnarUpdates};
+use super::data::{Column, DataType, Diff, Source, Updates};
+use super::schema::{ColumnSchema, ColumnType, Schema, SchemaError};
 use super::state::State;
-use super::Operation;
+use super::{Description, Operation, ProcessError};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
 
 /// Count is used to get the non-distinct count of rows with non null values passing through it.
 /// It can be optionally be grouped by any number of columns.
@@ -9,62 +14,171 @@ use super::Operation;
 /// to Count(1) before it gets to this node.
 pub struct Count<S: State> {
     pub source: Source,
-    pub group: Vec<usize>,
+    pub group: Vec<Column>,
     pub state: S,
 }
 
 impl<S: State> Count<S> {
-    fn get_count(&self, group: &Vec<DataType>) -> Option<i32> {
-        let data = self.state.get(group);
-
-        match data.len() {
-            0 => None,
-            1 => match data[0] {
-                DataType::Integer(c) => Some(c),
-                _ => unreachable!("Count will only ever store ints"),
-            },
-            _ => unreachable!("Count state will only ever hold one value"),
+    fn get_count(&self, group: &Vec<DataType>) -> Result<Option<i32>, ProcessError> {
+        match self.state.get(group).as_slice() {
+            [] => Ok(None),
+            [DataType::Integer(c)] => Ok(Some(*c)),
+            data => Err(ProcessError::InvalidState(format!(
+                "count for group {:?} is {:?}",
+                group, data
+            ))),
         }
     }
 
-    fn set_count(&mut self, group: Vec<DataType>, value: i32) {
-        self.state.set(group, vec![DataType::Integer(value)])
+    /// update_count returns the new count for the group, preferring counts staged earlier in the batch over the
+    /// state. Counts are only written to the state once the whole batch is handled, so a failure leaves it as it was.
+    fn update_count(
+        &self,
+        staged: &mut HashMap<Vec<DataType>, i32>,
+        group: Vec<DataType>,
+        change: i32,
+    ) -> Result<i32, ProcessError> {
+        let cur = match staged.get(&group) {
+            Some(c) => *c,
+            None => self.get_count(&group)?.unwrap_or(0),
+        };
+        let count = cur.checked_add(change).ok_or_else(|| {
+            ProcessError::Overflow(format!("count for group {:?} is too large", group))
+        })?;
+        staged.insert(group, count);
+        Ok(count)
+    }
+
+    fn set_counts(&mut self, staged: HashMap<Vec<DataType>, i32>) {
+        for (group, value) in staged {
+            self.state.set(group, vec![DataType::Integer(value)])
+        }
     }
 }
 
-impl<S: State> Operation for Count<S> {
-    // TODO: Group updates and don't send out update if addition/removal cancel out
-    fn process(&mut self, mut updates: Updates) -> Vec<RowUpdate> {
-        for mut update in updates.updates.iter_mut() {
-            let mut group = vec![];
-            for column in &self.group {
-                group.push(update[*column].clone())
-            }
+/// change converts a row's multiplicity into the change it makes to its group's count
+fn change(multiplicity: i64) -> Result<i32, ProcessError> {
+    i32::try_from(multiplicity).map_err(|_| {
+        ProcessError::Overflow(format!(
+            "{} copies of a row are too many to count",
+            multiplicity
+        ))
+    })
+}
 
-            let cur = self.get_count(&group).unwrap_or(0);
+impl<S: State> Operation for Count<S> {
+    fn process(&mut self, updates: Updates) -> Result<Vec<Diff>, ProcessError> {
+        let mut staged = HashMap::new();
+        let mut counts = Vec::with_capacity(updates.updates.len());
+        for (row, multiplicity) in updates.updates.iter() {
+            let group = self
+                .group
+                .iter()
+                .map(|column| column.get(row).map(DataType::normalized))
+                .collect::<Result<Vec<DataType>, ProcessError>>()?;
+            let value = self.source.get(row)?;
 
-            let value = match &self.source {
-                Source::Column(c) => update[*c].clone(),
-                Source::Literal(d) => d.clone(),
+            let source_change = if value == DataType::None {
+                0
+            } else {
+                change(*multiplicity)?
             };
+            counts.push(self.update_count(&mut staged, group, source_change)?);
+        }
 
-            let (change, r) = match &mut update {
-                RowUpdate::Add(r) => (1, r),
-                RowUpdate::Remove(r) => (-1, r),
-            };
-            let source_change = if value == DataType::None { 0 } else { change };
+        self.set_counts(staged);
+        Ok(updates
+            .updates
+            .iter()
+            .zip(counts)
+            .map(|((row, multiplicity), count)| {
+                let mut data = Vec::with_capacity(row.data.len() + 1);
+                data.extend(row.data.iter().cloned());
+                data.push(DataType::Integer(count));
+                (data.into(), *multiplicity)
+            })
+            .collect())
+    }
 
-            self.set_count(group, cur + source_change);
-            r.data.push(DataType::Integer(cur + source_change));
+    fn schema(&mut self, parents: &[&Schema]) -> Result<Schema, SchemaError> {
+        let input = Schema::input(parents)?;
+        if let Source::Column(c) = &mut self.source {
+            c.resolve(input)?;
+        }
+        for column in &mut self.group {
+            column.resolve(input)?;
         }
-        updates.updates
+
+        let mut schema = input.clone();
+        schema
+            .columns
+            .push(ColumnSchema::new("count", ColumnType::Integer, false));
+        Ok(schema)
+    }
+
+    fn describe(&self, parents: &[&Schema]) -> Description {
+        let input = Schema::input(parents).cloned().unwrap_or_default();
+        let group: Vec<String> = self.group.iter().map(|c| c.describe(&input)).collect();
+        Description::new("count")
+            .param("source", self.source.describe(&input))
+            .param("group", group.join(", "))
+    }
+
+    fn state_size(&self) -> usize {
+        self.state.size()
+    }
+
+    fn columnar(&mut self) -> Option<&mut dyn ColumnarOperation> {
+        Some(self)
+    }
+}
+
+impl<S: State> ColumnarOperation for Count<S> {
+    fn process_columns(
+        &mut self,
+        mut updates: ColumnarUpdates,
+    ) -> Result<ColumnarUpdates, ProcessError> {
+        let values = match &self.source {
+            Source::Column(c) => Arc::clone(updates.column(c)?),
+            Source::Literal(d) => Arc::new(ColumnValues::repeat(d, updates.len())),
+        };
+        let groups = self
+            .group
+            .iter()
+            .map(|c| updates.column(c).map(Arc::clone))
+            .collect::<Result<Vec<Arc<ColumnValues>>, ProcessError>>()?;
+
+        let mut staged = HashMap::new();
+        let mut counts = Vec::with_capacity(updates.len());
+        for (i, diff) in updates.diffs.iter().enumerate() {
+            let group: Vec<DataType> = groups.iter().map(|c| c.get(i).normalized()).collect();
+            let source_change = if values.get(i) == DataType::None {
+                0
+            } else {
+                change(*diff)?
+            };
+            counts.push(Some(self.update_count(
+                &mut staged,
+                group,
+                source_change,
+            )?));
+        }
+
+        self.set_counts(staged);
+        updates
+            .columns
+            .push(Arc::new(ColumnValues::Integer(counts)));
+        Ok(updates)
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::operations::data::{Row, RowUpdate};
     use crate::operations::state::MemStore;
+    use crate::processing::oracle::Rng;
+    use std::convert::TryInto;
 
     #[test]
     fn counts_literals() {
@@ -79,11 +193,11 @@ mod test {
             RowUpdate::Remove(vec![0.into(), "hello".into(), DataType::None].into()),
         ];
 
-        let processed = node.process(updates.into());
+        let processed = node.process(updates.into()).unwrap();
         assert_eq!(processed.len(), 3);
-        assert_eq!(processed[0][3], 1.into());
-        assert_eq!(processed[1][3], 2.into());
-        assert_eq!(processed[2][3], 1.into());
+        assert_eq!(processed[0].0[3], 1.into());
+        assert_eq!(processed[1].0[3], 2.into());
+        assert_eq!(processed[2].0[3], 1.into());
 
         // Checking it works with non-1 literal
         let mut node = Count {
@@ -98,11 +212,35 @@ mod test {
             RowUpdate::Remove(vec![0.into(), "hello".into(), DataType::None].into()),
         ];
 
-        let processed = node.process(updates.into());
+        let processed = node.process(updates.into()).unwrap();
         assert_eq!(processed.len(), 3);
-        assert_eq!(processed[0][3], 1.into());
-        assert_eq!(processed[1][3], 2.into());
-        assert_eq!(processed[2][3], 1.into());
+        assert_eq!(processed[0].0[3], 1.into());
+        assert_eq!(processed[1].0[3], 2.into());
+        assert_eq!(processed[2].0[3], 1.into());
+    }
+
+    #[test]
+    fn groups_numbers_by_value() {
+        let mut node = Count {
+            source: Source::Literal(DataType::Integer(1)),
+            group: vec![0.into()],
+            state: MemStore::new(),
+        };
+        let updates = vec![
+            RowUpdate::Add(vec![DataType::Integer(1)].into()),
+            RowUpdate::Add(vec![DataType::BigInt(1)].into()),
+            RowUpdate::Remove(vec![DataType::Integer(1)].into()),
+        ];
+
+        let processed = node.process(updates.into()).unwrap();
+        let counts: Vec<DataType> = processed.iter().map(|(row, _)| row[1].clone()).collect();
+        assert_eq!(counts, vec![1.into(), 2.into(), 1.into()]);
+        // Rows are passed on with the values they were written with
+        assert_eq!(processed[1].0[0], DataType::BigInt(1));
+        assert_eq!(
+            node.state.get(&vec![DataType::BigInt(1)]),
+            vec![DataType::Integer(1)]
+        );
     }
 
     #[test]
@@ -118,17 +256,17 @@ mod test {
             RowUpdate::Remove(vec![0.into(), "hello".into(), DataType::None].into()),
         ];
 
-        let processed = node.process(updates.into());
+        let processed = node.process(updates.into()).unwrap();
         assert_eq!(processed.len(), 3);
-        assert_eq!(processed[0][3], 0.into());
-        assert_eq!(processed[1][3], 0.into());
-        assert_eq!(processed[2][3], 0.into());
+        assert_eq!(processed[0].0[3], 0.into());
+        assert_eq!(processed[1].0[3], 0.into());
+        assert_eq!(processed[2].0[3], 0.into());
     }
 
     #[test]
     fn counts_columns() {
         let mut node = Count {
-            source: Source::Column(0),
+            source: Source::Column(0.into()),
             group: vec![],
             state: MemStore::new(),
         };
@@ -138,14 +276,14 @@ mod test {
             RowUpdate::Remove(vec![0.into(), "hello".into(), DataType::None].into()),
         ];
 
-        let processed = node.process(updates.into());
+        let processed = node.process(updates.into()).unwrap();
         assert_eq!(processed.len(), 3);
-        assert_eq!(processed[0][3], 1.into());
-        assert_eq!(processed[1][3], 2.into());
-        assert_eq!(processed[2][3], 1.into());
+        assert_eq!(processed[0].0[3], 1.into());
+        assert_eq!(processed[1].0[3], 2.into());
+        assert_eq!(processed[2].0[3], 1.into());
 
         let mut node = Count {
-            source: Source::Column(2),
+            source: Source::Column(2.into()),
             group: vec![],
             state: MemStore::new(),
         };
@@ -156,18 +294,48 @@ mod test {
             RowUpdate::Remove(vec![0.into(), "hello".into(), DataType::None].into()),
         ];
 
-        let processed = node.process(updates.into());
+        let processed = node.process(updates.into()).unwrap();
         assert_eq!(processed.len(), 3);
-        assert_eq!(processed[0][3], 0.into());
-        assert_eq!(processed[1][3], 0.into());
-        assert_eq!(processed[2][3], 0.into());
+        assert_eq!(processed[0].0[3], 0.into());
+        assert_eq!(processed[1].0[3], 0.into());
+        assert_eq!(processed[2].0[3], 0.into());
+    }
+
+    #[test]
+    fn counts_columnar_like_rows() {
+        let updates = vec![
+            RowUpdate::Add(vec![0.into(), "hello".into(), DataType::None].into()),
+            RowUpdate::Add(vec![1.into(), "hello".into(), DataType::None].into()),
+            RowUpdate::Add(vec![0.into(), "there".into(), 1.into()].into()),
+            RowUpdate::Remove(vec![0.into(), "hello".into(), DataType::None].into()),
+        ];
+
+        let mut node = Count {
+            source: Source::Column(2.into()),
+            group: vec![0.into()],
+            state: MemStore::new(),
+        };
+        let processed = node.process(updates.clone().into()).unwrap();
+
+        let mut node = Count {
+            source: Source::Column(2.into()),
+            group: vec![0.into()],
+            state: MemStore::new(),
+        };
+        let diffs: Vec<Diff> = updates.into_iter().map(Diff::from).collect();
+        let columnar = node
+            .process_columns(ColumnarUpdates::try_from(diffs.as_slice()).unwrap())
+            .unwrap()
+            .into_diffs();
+
+        assert_eq!(columnar, processed);
     }
 
     #[test]
     fn groups_by_correctly() {
         let mut node = Count {
-            source: Source::Column(0),
-            group: vec![0],
+            source: Source::Column(0.into()),
+            group: vec![0.into()],
             state: MemStore::new(),
         };
         let updates = vec![
@@ -176,15 +344,15 @@ mod test {
             RowUpdate::Remove(vec![0.into(), "hello".into(), DataType::None].into()),
         ];
 
-        let processed = node.process(updates.into());
+        let processed = node.process(updates.into()).unwrap();
         assert_eq!(processed.len(), 3);
-        assert_eq!(processed[0][3], 1.into());
-        assert_eq!(processed[1][3], 1.into());
-        assert_eq!(processed[2][3], 0.into());
+        assert_eq!(processed[0].0[3], 1.into());
+        assert_eq!(processed[1].0[3], 1.into());
+        assert_eq!(processed[2].0[3], 0.into());
 
         let mut node = Count {
-            source: Source::Column(2),
-            group: vec![0],
+            source: Source::Column(2.into()),
+            group: vec![0.into()],
             state: MemStore::new(),
         };
 
@@ -194,10 +362,107 @@ mod test {
             RowUpdate::Remove(vec![0.into(), "hello".into(), DataType::None].into()),
         ];
 
-        let processed = node.process(updates.into());
+        let processed = node.process(updates.into()).unwrap();
         assert_eq!(processed.len(), 3);
-        assert_eq!(processed[0][3], 0.into());
-        assert_eq!(processed[1][3], 0.into());
-        assert_eq!(processed[2][3], 0.into());
+        assert_eq!(processed[0].0[3], 0.into());
+        assert_eq!(processed[1].0[3], 0.into());
+        assert_eq!(processed[2].0[3], 0.into());
+    }
+
+    #[test]
+    fn leaves_state_alone_on_errors() {
+        let mut node = Count {
+            source: Source::Literal(DataType::Integer(1)),
+            group: vec![1.into()],
+            state: MemStore::new(),
+        };
+
+        let updates = vec![
+            RowUpdate::Add(vec![0.into(), "hello".into()].into()),
+            RowUpdate::Add(vec![1.into()].into()),
+        ];
+        assert_eq!(
+            node.process(updates.into()),
+            Err(ProcessError::MissingColumn {
+                column: 1,
+                width: 1
+            })
+        );
+        assert_eq!(node.state_size(), 0);
+
+        node.state.set(vec!["hello".into()], vec!["oops".into()]);
+        let updates = vec![RowUpdate::Add(vec![0.into(), "hello".into()].into())];
+        assert!(matches!(
+            node.process(updates.into()),
+            Err(ProcessError::InvalidState(_))
+        ));
+    }
+
+    #[test]
+    fn counts_match_recompute() {
+        // Every count should be the count of its group's non null values once the row's update has been applied
+        for seed in 0..20 {
+            let mut rng = Rng::new(seed);
+            let mut rows: Vec<Row> = vec![];
+            let mut updates = vec![];
+            let mut expected = vec![];
+            for _ in 0..100 {
+                let update = match rows.is_empty() || rng.below(3) > 0 {
+                    true => {
+                        let row: Row = vec![
+                            rng.value(ColumnType::Integer, false),
+                            rng.value(ColumnType::Text, true),
+                        ]
+                        .into();
+                        rows.push(row.clone());
+                        RowUpdate::Add(row)
+                    }
+                    false => RowUpdate::Remove(rows.swap_remove(rng.below(rows.len()))),
+                };
+                let group = &update.row()[0];
+                let count = rows
+                    .iter()
+                    .filter(|r| r[0] == *group && r[1] != DataType::None)
+                    .count();
+                expected.push(count as i32);
+                updates.push(Diff::from(update));
+            }
+
+            let count = || Count {
+                source: Source::Column(1.into()),
+                group: vec![0.into()],
+                state: MemStore::new(),
+            };
+            let (mut node, mut columnar) = (count(), count());
+            let (mut processed, mut processed_columns) = (vec![], vec![]);
+            let mut remaining = updates.as_slice();
+            while !remaining.is_empty() {
+                let (batch, rest) = remaining.split_at(1 + rng.below(remaining.len().min(10)));
+                let rows = Updates {
+                    updates: Arc::new(batch.to_vec()),
+                    source: 0,
+                    destination: 0,
+                    timestamp: 0,
+                };
+                processed.extend(node.process(rows).unwrap());
+                processed_columns.extend(
+                    columnar
+                        .process_columns(batch.try_into().unwrap())
+                        .unwrap()
+                        .into_diffs(),
+                );
+                remaining = rest;
+            }
+
+            let counts: Vec<i32> = processed
+                .iter()
+                .map(|(row, _)| match row[2] {
+                    DataType::Integer(c) => c,
+                    ref d => panic!("count {:?} isn't an integer", d),
+                })
+                .collect();
+            assert_eq!(counts, expected, "seed {}", seed);
+            assert_eq!(processed_columns, processed, "seed {}", seed);
+        }
     }
 }
@@ -1,17 +1,37 @@
+use super::json::Json;
+use super::schema::{Schema, SchemaError};
+use super::types::{system_time_micros, Decimal, Interval, DECIMAL_SCALE, MICROS_PER_DAY};
+use super::ProcessError;
 use ordered_float::OrderedFloat;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
 use std::ops::{Index, IndexMut};
 use std::slice::SliceIndex;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 /// DataType exists to make code generic over the supported data types
 ///
 /// Uses ordered floats to make them hashable but as a result don't support NaN's to IEEE standard.
+///
+/// Timestamps are stored as microseconds since 1970-01-01 00:00:00 UTC and dates as days since 1970-01-01.
 #[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DataType {
     None,
     Integer(i32),
     Text(String),
     Boolean(bool),
     Float(OrderedFloat<f32>),
+    BigInt(i64),
+    Double(OrderedFloat<f64>),
+    Decimal(Decimal),
+    Timestamp(i64),
+    Date(i32),
+    Interval(Interval),
+    Bytes(Vec<u8>),
 }
 
 impl From<i32> for DataType {
@@ -44,7 +64,157 @@ impl From<bool> for DataType {
     }
 }
 
+impl From<i64> for DataType {
+    fn from(n: i64) -> Self {
+        Self::BigInt(n)
+    }
+}
+
+impl From<f64> for DataType {
+    fn from(n: f64) -> Self {
+        Self::Double(OrderedFloat::from(n))
+    }
+}
+
+impl From<Decimal> for DataType {
+    fn from(n: Decimal) -> Self {
+        Self::Decimal(n)
+    }
+}
+
+impl From<SystemTime> for DataType {
+    fn from(n: SystemTime) -> Self {
+        Self::Timestamp(system_time_micros(n))
+    }
+}
+
+impl From<Interval> for DataType {
+    fn from(n: Interval) -> Self {
+        Self::Interval(n)
+    }
+}
+
+impl From<Duration> for DataType {
+    fn from(n: Duration) -> Self {
+        Self::Interval(n.into())
+    }
+}
+
+impl From<Vec<u8>> for DataType {
+    fn from(n: Vec<u8>) -> Self {
+        Self::Bytes(n)
+    }
+}
+
+impl From<&[u8]> for DataType {
+    fn from(n: &[u8]) -> Self {
+        Self::Bytes(n.to_vec())
+    }
+}
+
+/// Collation decides how text is ordered when compared
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Collation {
+    /// Text is ordered by its bytes
+    #[default]
+    Binary,
+    /// Text is ordered ignoring the case of letters
+    CaseInsensitive,
+}
+
+impl DataType {
+    /// compare_to orders two values after coercing them to a common type. All numeric types can be compared with
+    /// each other by value, as can dates and timestamps.
+    ///
+    /// Returns None when the order is unknown. This is the case for any comparison with a null, matching SQL, or
+    /// when the types can't be compared.
+    pub fn compare_to(&self, other: &DataType, collation: Collation) -> Option<Ordering> {
+        use DataType::*;
+
+        match (self, other) {
+            (None, _) | (_, None) => Option::None,
+            (Text(a), Text(b)) => Some(match collation {
+                Collation::Binary => a.cmp(b),
+                Collation::CaseInsensitive => a.to_lowercase().cmp(&b.to_lowercase()),
+            }),
+            // Dates are compared as their midnight, which can fall outside the timestamps that fit in an i64
+            (Date(a), Timestamp(b)) => {
+                Some((i128::from(*a) * i128::from(MICROS_PER_DAY)).cmp(&i128::from(*b)))
+            }
+            (Timestamp(a), Date(b)) => {
+                Some(i128::from(*a).cmp(&(i128::from(*b) * i128::from(MICROS_PER_DAY))))
+            }
+            _ => match (self.as_number(), other.as_number()) {
+                (Some(a), Some(b)) => Some(a.cmp(&b)),
+                _ if std::mem::discriminant(self) == std::mem::discriminant(other) => {
+                    Some(self.cmp(other))
+                }
+                _ => Option::None,
+            },
+        }
+    }
+
+    /// normalized returns the value in the form used to group and key by it, so values that compare as equal also
+    /// hash the same. Numbers with a whole value become big ints and other exact numbers decimals. Floats do the same
+    /// when a decimal holds their value exactly and otherwise become doubles.
+    pub fn normalized(&self) -> DataType {
+        let exact = match self.as_number() {
+            Some(Number::Exact(n)) => n,
+            Some(Number::Approximate(f)) => {
+                let units = f * 10f64.powi(DECIMAL_SCALE as i32);
+                let decimal = Decimal::from_units(units as i128);
+                match units.fract() == 0.0 && decimal.to_f64() == f {
+                    true => decimal,
+                    false => return DataType::Double(OrderedFloat(f)),
+                }
+            }
+            Option::None => return self.clone(),
+        };
+        let scale = 10i128.pow(DECIMAL_SCALE);
+        match exact.units() % scale {
+            0 => i64::try_from(exact.units() / scale)
+                .map_or(DataType::Decimal(exact), DataType::BigInt),
+            _ => DataType::Decimal(exact),
+        }
+    }
+
+    fn as_number(&self) -> Option<Number> {
+        match self {
+            DataType::Integer(n) => Some(Number::Exact(Decimal::from(*n as i64))),
+            DataType::BigInt(n) => Some(Number::Exact(Decimal::from(*n))),
+            DataType::Decimal(n) => Some(Number::Exact(*n)),
+            DataType::Float(n) => Some(Number::Approximate(n.into_inner() as f64)),
+            DataType::Double(n) => Some(Number::Approximate(n.into_inner())),
+            _ => Option::None,
+        }
+    }
+}
+
+/// Number is used to compare numbers of different types. Exact numbers are only compared as floats when compared
+/// with a float.
+enum Number {
+    Exact(Decimal),
+    Approximate(f64),
+}
+
+impl Number {
+    fn cmp(&self, other: &Number) -> Ordering {
+        match (self, other) {
+            (Number::Exact(a), Number::Exact(b)) => a.cmp(b),
+            (a, b) => OrderedFloat(a.to_f64()).cmp(&OrderedFloat(b.to_f64())),
+        }
+    }
+
+    fn to_f64(&self) -> f64 {
+        match self {
+            Number::Exact(n) => n.to_f64(),
+            Number::Approximate(n) => *n,
+        }
+    }
+}
+
 /// Comparison is used to hold and perform comparisons of two DataTypes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Comparison {
     Equal,
     NotEqual,
@@ -55,25 +225,72 @@ pub enum Comparison {
 }
 
 impl Comparison {
-    /// compares two DataType values based on the op
+    /// compares two DataType values based on the op. Values of different types are coerced to a common type first.
+    ///
+    /// Comparisons with an unknown result, like any comparison with a null, are false.
     pub fn compare(&self, d1: &DataType, d2: &DataType) -> bool {
+        self.compare_collated(d1, d2, Collation::Binary)
+    }
+
+    /// compares two DataType values based on the op using the collation for text
+    pub fn compare_collated(&self, d1: &DataType, d2: &DataType, collation: Collation) -> bool {
+        self.evaluate(d1, d2, collation).unwrap_or(false)
+    }
+
+    /// evaluates the comparison with SQL semantics, returning None if the result is unknown
+    pub fn evaluate(&self, d1: &DataType, d2: &DataType, collation: Collation) -> Option<bool> {
+        d1.compare_to(d2, collation).map(|o| self.holds(o))
+    }
+
+    /// compares any two ordered values of the same type based on the op
+    pub fn compare_values<T: Ord + ?Sized>(&self, d1: &T, d2: &T) -> bool {
+        self.holds(d1.cmp(d2))
+    }
+
+    fn holds(&self, ordering: Ordering) -> bool {
         match self {
-            Comparison::Equal => d1 == d2,
-            Comparison::NotEqual => d1 != d2,
-            Comparison::GreaterThan => d1 > d2,
-            Comparison::LessThan => d1 < d2,
-            Comparison::GreaterEqualThan => d1 >= d2,
-            Comparison::LessEqualThan => d1 <= d2,
+            Comparison::Equal => ordering == Ordering::Equal,
+            Comparison::NotEqual => ordering != Ordering::Equal,
+            Comparison::GreaterThan => ordering == Ordering::Greater,
+            Comparison::LessThan => ordering == Ordering::Less,
+            Comparison::GreaterEqualThan => ordering != Ordering::Less,
+            Comparison::LessEqualThan => ordering != Ordering::Greater,
         }
     }
 }
 
+impl fmt::Display for Comparison {
+    /// Writes the comparison as its SQL operator
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Comparison::Equal => "=",
+            Comparison::NotEqual => "<>",
+            Comparison::GreaterThan => ">",
+            Comparison::LessThan => "<",
+            Comparison::GreaterEqualThan => ">=",
+            Comparison::LessEqualThan => "<=",
+        })
+    }
+}
+
 /// A single row of data
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Row {
     pub data: Vec<DataType>,
 }
 
+impl Row {
+    /// normalized returns the row with every value normalized, for grouping rows that compare as equal
+    pub fn normalized(&self) -> Row {
+        self.data
+            .iter()
+            .map(DataType::normalized)
+            .collect::<Vec<DataType>>()
+            .into()
+    }
+}
+
 impl From<Vec<DataType>> for Row {
     fn from(data: Vec<DataType>) -> Self {
         Self { data }
@@ -101,12 +318,67 @@ where
 }
 
 /// Used to send how rows have changed
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum RowUpdate {
     Add(Row),
     Remove(Row),
 }
 
+impl RowUpdate {
+    /// row returns the row being added or removed
+    pub fn row(&self) -> &Row {
+        match self {
+            RowUpdate::Add(r) => r,
+            RowUpdate::Remove(r) => r,
+        }
+    }
+
+    /// multiplicity returns how many copies of the row this update adds. Removals are negative.
+    pub fn multiplicity(&self) -> i64 {
+        match self {
+            RowUpdate::Add(_) => 1,
+            RowUpdate::Remove(_) => -1,
+        }
+    }
+}
+
+/// Diff is a row along with how many copies of it have been added. Removed rows have a negative multiplicity.
+pub type Diff = (Row, i64);
+
+impl From<RowUpdate> for Diff {
+    fn from(update: RowUpdate) -> Self {
+        let multiplicity = update.multiplicity();
+        match update {
+            RowUpdate::Add(r) => (r, multiplicity),
+            RowUpdate::Remove(r) => (r, multiplicity),
+        }
+    }
+}
+
+/// consolidate sums the multiplicities of identical rows, dropping any whose additions and removals cancel out.
+/// Rows are kept in the order they first appear. Writes are consolidated as they enter the graph so a row written n
+/// times travels through it as a single diff.
+pub fn consolidate<I: IntoIterator<Item = Diff>>(diffs: I) -> Vec<Diff> {
+    let mut positions: HashMap<Row, usize> = HashMap::new();
+    let mut consolidated: Vec<Diff> = vec![];
+
+    // Rows that only differ in how their numbers are held are the same row, kept as it was first written
+    for (row, multiplicity) in diffs {
+        let normalized = row.normalized();
+        match positions.get(&normalized) {
+            Some(i) => consolidated[*i].1 = consolidated[*i].1.saturating_add(multiplicity),
+            None => {
+                positions.insert(normalized, consolidated.len());
+                consolidated.push((row, multiplicity));
+            }
+        }
+    }
+
+    consolidated.retain(|(_, multiplicity)| *multiplicity != 0);
+    consolidated
+}
+
 impl<Idx> Index<Idx> for RowUpdate
 where
     Idx: SliceIndex<[DataType]>,
@@ -133,41 +405,146 @@ where
     }
 }
 
-/// Updates store RowUpdates in a vec in case we need to send both an add and remove (say in case of a base row
-/// being updated).
+/// Batch is an immutable, reference counted set of diffs. Cloning a batch only bumps the reference count so the same
+/// diffs can be fanned out to any number of children without copying rows.
+pub type Batch = Arc<Vec<Diff>>;
+
+/// Timestamp is the logical time of a write. Every write to the graph gets the next timestamp so all updates caused
+/// by it can be tracked as they flow through the graph.
+pub type Timestamp = u64;
+
+/// Updates store diffs in a vec in case we need to send both an add and remove (say in case of a base row being
+/// updated).
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Updates {
-    pub updates: Vec<RowUpdate>,
+    pub updates: Batch,
     pub source: usize,
     pub destination: usize,
+    pub timestamp: Timestamp,
+}
+
+impl Updates {
+    /// into_updates takes ownership of the diffs. They are only copied if another node still holds a reference to
+    /// the same batch.
+    pub fn into_updates(self) -> Vec<Diff> {
+        Arc::try_unwrap(self.updates).unwrap_or_else(|shared| (*shared).clone())
+    }
+}
+
+/// Column refers to a column of a worker's input rows either by position or by name. Names are resolved to
+/// positions against the input schema when the worker is added to the graph, so adding columns upstream doesn't
+/// change which column a name refers to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Column {
+    Index(usize),
+    Name(String),
 }
 
-pub type Column = usize;
+impl Column {
+    /// index returns the position of the column in the row
+    ///
+    /// Names are an error until they've been resolved. Operations resolve their columns when they're added to the
+    /// graph.
+    pub fn index(&self) -> Result<usize, ProcessError> {
+        match self {
+            Column::Index(i) => Ok(*i),
+            Column::Name(n) => Err(ProcessError::UnresolvedColumn(n.clone())),
+        }
+    }
+
+    /// get returns the column's value in the row
+    pub fn get<'a>(&self, row: &'a Row) -> Result<&'a DataType, ProcessError> {
+        let index = self.index()?;
+        row.data.get(index).ok_or(ProcessError::MissingColumn {
+            column: index,
+            width: row.data.len(),
+        })
+    }
+
+    /// resolve looks up the column in the schema, replacing a name with its position
+    pub fn resolve(&mut self, schema: &Schema) -> Result<usize, SchemaError> {
+        let index = match self {
+            Column::Index(i) => *i,
+            Column::Name(n) => schema
+                .index_of(n)
+                .ok_or_else(|| SchemaError::UnknownColumn(n.clone()))?,
+        };
+        schema.column(index)?;
 
+        *self = Column::Index(index);
+        Ok(index)
+    }
+
+    /// describe names the column for people, using the schema to name columns referred to by position
+    pub fn describe(&self, schema: &Schema) -> String {
+        match self {
+            Column::Index(i) => schema
+                .column(*i)
+                .map_or_else(|_| format!("#{}", i), |c| c.name.clone()),
+            Column::Name(n) => n.clone(),
+        }
+    }
+}
+
+impl From<usize> for Column {
+    fn from(i: usize) -> Self {
+        Self::Index(i)
+    }
+}
+
+impl From<&str> for Column {
+    fn from(n: &str) -> Self {
+        Self::Name(n.into())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Source {
     Column(Column),
     Literal(DataType),
 }
 
+impl Source {
+    /// get returns the source's value for the row
+    pub fn get(&self, row: &Row) -> Result<DataType, ProcessError> {
+        match self {
+            Source::Column(c) => c.get(row).cloned(),
+            Source::Literal(d) => Ok(d.clone()),
+        }
+    }
+
+    /// describe writes the source for people, naming columns from the schema and writing literals as JSON
+    pub fn describe(&self, schema: &Schema) -> String {
+        match self {
+            Source::Column(c) => c.describe(schema),
+            Source::Literal(d) => Json::from(d).to_string(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::operations::schema::{ColumnSchema, ColumnType};
 
     impl From<Vec<RowUpdate>> for Updates {
         fn from(u: Vec<RowUpdate>) -> Self {
             Self {
-                updates: u,
+                updates: Arc::new(u.into_iter().map(Diff::from).collect()),
                 source: 0,
-                destination: 0
+                destination: 0,
+                timestamp: 0,
             }
         }
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn equality_works() {
         assert_eq!(
             Comparison::Equal.compare(&DataType::None, &DataType::None),
-            true
+            false
         );
         assert_eq!(
             Comparison::NotEqual.compare(&DataType::None, &DataType::None),
@@ -222,4 +599,186 @@ mod tests {
             true
         );
     }
+
+    #[test]
+    fn coerces_types() {
+        assert!(!Comparison::LessThan.compare(&1.into(), &0.5f32.into()));
+        assert!(Comparison::GreaterThan.compare(&1.into(), &0.5f32.into()));
+        assert!(Comparison::Equal.compare(&1.into(), &DataType::BigInt(1)));
+        assert!(Comparison::Equal.compare(&DataType::BigInt(2), &2.0f64.into()));
+        assert!(Comparison::LessThan
+            .compare(&DataType::Decimal(Decimal::new(199, 2).unwrap()), &2.into()));
+        assert!(Comparison::Equal.compare(&DataType::Date(1), &DataType::Timestamp(MICROS_PER_DAY)));
+        assert!(Comparison::GreaterThan
+            .compare(&DataType::Date(i32::MAX), &DataType::Timestamp(i64::MAX)));
+
+        // Types that can't be compared are unknown rather than ordered by type
+        assert_eq!(
+            Comparison::LessThan.evaluate(&1.into(), &"a".into(), Collation::Binary),
+            None
+        );
+        assert!(!Comparison::NotEqual.compare(&1.into(), &"a".into()));
+    }
+
+    #[test]
+    fn nulls_are_unknown() {
+        for op in [
+            Comparison::Equal,
+            Comparison::NotEqual,
+            Comparison::GreaterThan,
+            Comparison::LessThan,
+            Comparison::GreaterEqualThan,
+            Comparison::LessEqualThan,
+        ] {
+            assert_eq!(
+                op.evaluate(&DataType::None, &1.into(), Collation::Binary),
+                None
+            );
+            assert_eq!(
+                op.evaluate(&1.into(), &DataType::None, Collation::Binary),
+                None
+            );
+            assert!(!op.compare(&DataType::None, &DataType::None));
+        }
+    }
+
+    #[test]
+    fn collates_text() {
+        assert!(!Comparison::Equal.compare(&"Hello".into(), &"hello".into()));
+        assert!(Comparison::Equal.compare_collated(
+            &"Hello".into(),
+            &"hello".into(),
+            Collation::CaseInsensitive
+        ));
+        assert!(Comparison::LessThan.compare(&"B".into(), &"a".into()));
+        assert!(Comparison::GreaterThan.compare_collated(
+            &"B".into(),
+            &"a".into(),
+            Collation::CaseInsensitive
+        ));
+    }
+
+    #[test]
+    fn converts_wide_types() {
+        assert_eq!(
+            DataType::from(5_000_000_000i64),
+            DataType::BigInt(5_000_000_000)
+        );
+        assert_eq!(DataType::from(0.1f64), DataType::Double(OrderedFloat(0.1)));
+        assert_eq!(
+            DataType::from(&b"abc"[..]),
+            DataType::Bytes(vec![97, 98, 99])
+        );
+        assert_eq!(
+            DataType::from(Duration::from_millis(1500)),
+            DataType::Interval(Interval {
+                months: 0,
+                days: 0,
+                micros: 1_500_000
+            })
+        );
+        assert_eq!(
+            DataType::from(std::time::UNIX_EPOCH + Duration::from_secs(1)),
+            DataType::Timestamp(1_000_000)
+        );
+
+        let a: DataType = "1.50".parse::<Decimal>().unwrap().into();
+        let b: DataType = Decimal::new(15, 1).unwrap().into();
+        assert_eq!(a, b);
+        assert!(Comparison::LessThan.compare(&DataType::BigInt(1), &DataType::BigInt(2)));
+        assert!(Comparison::GreaterThan.compare(&DataType::Date(1), &DataType::Date(0)));
+    }
+
+    #[test]
+    fn resolves_columns() {
+        let schema = Schema::new(vec![
+            ColumnSchema::new("id", ColumnType::Integer, false),
+            ColumnSchema::new("name", ColumnType::Text, true),
+        ]);
+
+        let mut column = Column::from("name");
+        assert_eq!(column.resolve(&schema), Ok(1));
+        assert_eq!(column, Column::Index(1));
+
+        assert_eq!(
+            Column::from("missing").resolve(&schema),
+            Err(SchemaError::UnknownColumn("missing".into()))
+        );
+        assert_eq!(
+            Column::from(2).resolve(&schema),
+            Err(SchemaError::MissingColumn {
+                column: 2,
+                width: 2
+            })
+        );
+    }
+
+    #[test]
+    fn consolidates_diffs() {
+        let a: Row = vec![1.into(), "a".into()].into();
+        let b: Row = vec![2.into(), "b".into()].into();
+        let updates = vec![
+            RowUpdate::Add(a.clone()),
+            RowUpdate::Add(b.clone()),
+            RowUpdate::Add(b.clone()),
+            RowUpdate::Remove(a.clone()),
+            RowUpdate::Add(b.clone()),
+        ];
+
+        let consolidated = consolidate(updates.into_iter().map(Diff::from));
+        assert_eq!(consolidated, vec![(b.clone(), 3)]);
+
+        let consolidated = consolidate(vec![(a.clone(), -2), (b, 0)]);
+        assert_eq!(consolidated, vec![(a, -2)]);
+
+        // The same number held as different types cancels out
+        let int: Row = vec![1.into()].into();
+        let big: Row = vec![DataType::BigInt(1)].into();
+        assert!(consolidate(vec![(int.clone(), 1), (big.clone(), -1)]).is_empty());
+        assert_eq!(
+            consolidate(vec![(int.clone(), 1), (big, 1)]),
+            vec![(int, 2)]
+        );
+    }
+
+    #[test]
+    fn normalizes_values_that_compare_equal() {
+        let one = DataType::BigInt(1);
+        for value in [
+            DataType::Integer(1),
+            DataType::Decimal(Decimal::from(1)),
+            DataType::Double(1.0.into()),
+            DataType::Float(1.0.into()),
+        ] {
+            assert_eq!(value.normalized(), one);
+        }
+
+        let half = DataType::Decimal(Decimal::new(5, 1).unwrap());
+        assert_eq!(DataType::Double(0.5.into()).normalized(), half);
+        assert_eq!(half.normalized(), half);
+        // A float that no decimal holds exactly stays a float
+        let third = DataType::Double((1.0 / 3.0).into());
+        assert_eq!(third.normalized(), third);
+        assert_eq!(DataType::Text("1".into()).normalized(), "1".into());
+    }
+
+    #[test]
+    fn into_updates_copies_only_when_shared() {
+        let updates: Updates = vec![RowUpdate::Add(vec![1.into()].into())].into();
+        let shared = updates.updates.clone();
+
+        let owned = updates.into_updates();
+        assert_eq!(owned.len(), 1);
+        assert_eq!(shared.len(), 1);
+
+        let updates = Updates {
+            updates: shared,
+            source: 0,
+            destination: 0,
+            timestamp: 0,
+        };
+        let ptr = updates.updates.as_ptr();
+        let owned = updates.into_updates();
+        assert_eq!(owned.as_ptr(), ptr);
+    }
 }
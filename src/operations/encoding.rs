@@ -0,0 +1,469 @@
+//! A compact binary encoding for rows and updates so they can be written to logs, sent over the network and stored
+//! on disk.
+//!
+//! Encoded values start with a single version byte followed by the value. Integers are written as LEB128 varints,
+//! with signed integers zigzag encoded first so small negative numbers stay small. Floats are written as little
+//! endian bytes and text and bytes as a varint length followed by their contents.
+//!
+//! Each DataType starts with a tag byte. Tags are never reused so encoded data stays readable as types are added:
+//!
+//! | Tag | Type      | Value                                   |
+//! |-----|-----------|-----------------------------------------|
+//! | 0   | None      |                                         |
+//! | 1   | Integer   | zigzag varint                           |
+//! | 2   | Text      | varint length, utf8 bytes               |
+//! | 3   | Boolean   | 0 or 1                                  |
+//! | 4   | Float     | 4 bytes                                 |
+//! | 5   | BigInt    | zigzag varint                           |
+//! | 6   | Double    | 8 bytes                                 |
+//! | 7   | Decimal   | zigzag varint units of 10^-6            |
+//! | 8   | Timestamp | zigzag varint                           |
+//! | 9   | Date      | zigzag varint                           |
+//! | 10  | Interval  | zigzag varint months, days and micros   |
+//! | 11  | Bytes     | varint length, bytes                    |
+//!
+//! Rows are a varint column count followed by their values. RowUpdates are a 0 (Add) or 1 (Remove) byte followed by
+//! the row and diffs are the row followed by its zigzag varint multiplicity. Updates are the varint source,
+//! destination and timestamp followed by a varint count of diffs.
+
+use super::data::{DataType, Diff, Row, RowUpdate, Updates};
+use super::types::{Decimal, Interval};
+use ordered_float::OrderedFloat;
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::Arc;
+
+/// VERSION is written at the start of every encoded value
+pub const VERSION: u8 = 1;
+
+/// Encode writes a value in the binary format
+pub trait Encode {
+    fn encode(&self, buf: &mut Vec<u8>);
+}
+
+/// Decode reads a value in the binary format, advancing the input past it
+pub trait Decode: Sized {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError>;
+}
+
+/// to_bytes encodes the value with the version header
+pub fn to_bytes<T: Encode + ?Sized>(value: &T) -> Vec<u8> {
+    let mut buf = vec![VERSION];
+    value.encode(&mut buf);
+    buf
+}
+
+/// from_bytes decodes a value written by to_bytes. All of the bytes must belong to the value.
+pub fn from_bytes<T: Decode>(mut input: &[u8]) -> Result<T, DecodeError> {
+    match read_byte(&mut input)? {
+        VERSION => {}
+        v => return Err(DecodeError::UnsupportedVersion(v)),
+    }
+
+    let value = T::decode(&mut input)?;
+    match input.is_empty() {
+        true => Ok(value),
+        false => Err(DecodeError::TrailingBytes(input.len())),
+    }
+}
+
+/// DecodeError is returned when bytes can't be decoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The input ended part way through a value
+    UnexpectedEnd,
+    /// The data was written by a version of the encoding this one can't read
+    UnsupportedVersion(u8),
+    /// A tag byte didn't match any known type
+    UnknownTag(u8),
+    /// A varint didn't fit in the integer it was read into
+    Overflow,
+    /// Text wasn't valid utf8
+    InvalidText,
+    /// Bytes were left over after decoding the value
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedEnd => f.write_str("unexpected end of input"),
+            DecodeError::UnsupportedVersion(v) => write!(f, "unsupported encoding version {}", v),
+            DecodeError::UnknownTag(t) => write!(f, "unknown tag {}", t),
+            DecodeError::Overflow => f.write_str("integer overflow"),
+            DecodeError::InvalidText => f.write_str("text isn't valid utf8"),
+            DecodeError::TrailingBytes(n) => write!(f, "{} bytes left after decoding", n),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+fn write_varint(buf: &mut Vec<u8>, mut n: u128) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn write_signed(buf: &mut Vec<u8>, n: i128) {
+    write_varint(buf, ((n << 1) ^ (n >> 127)) as u128)
+}
+
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(buf, bytes.len() as u128);
+    buf.extend_from_slice(bytes);
+}
+
+fn read_byte(input: &mut &[u8]) -> Result<u8, DecodeError> {
+    let (first, rest) = input.split_first().ok_or(DecodeError::UnexpectedEnd)?;
+    *input = rest;
+    Ok(*first)
+}
+
+fn read_array<const N: usize>(input: &mut &[u8]) -> Result<[u8; N], DecodeError> {
+    if input.len() < N {
+        return Err(DecodeError::UnexpectedEnd);
+    }
+    let (bytes, rest) = input.split_at(N);
+    *input = rest;
+
+    let mut array = [0; N];
+    array.copy_from_slice(bytes);
+    Ok(array)
+}
+
+fn read_varint(input: &mut &[u8]) -> Result<u128, DecodeError> {
+    let mut n: u128 = 0;
+    for shift in (0..128).step_by(7) {
+        let byte = read_byte(input)?;
+        let bits = (byte & 0x7f) as u128;
+        // The last byte only has room for the top two bits, anything above them would be lost
+        if bits >> (128 - shift).min(7) != 0 {
+            return Err(DecodeError::Overflow);
+        }
+        n |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(DecodeError::Overflow)
+}
+
+fn read_signed(input: &mut &[u8]) -> Result<i128, DecodeError> {
+    let n = read_varint(input)?;
+    Ok((n >> 1) as i128 ^ -((n & 1) as i128))
+}
+
+fn read_bytes<'a>(input: &mut &'a [u8]) -> Result<&'a [u8], DecodeError> {
+    let len = read_len(input)?;
+    if input.len() < len {
+        return Err(DecodeError::UnexpectedEnd);
+    }
+    let (bytes, rest) = input.split_at(len);
+    *input = rest;
+    Ok(bytes)
+}
+
+fn read_len(input: &mut &[u8]) -> Result<usize, DecodeError> {
+    let len = read_varint(input)?;
+    usize::try_from(len).map_err(|_| DecodeError::Overflow)
+}
+
+fn read_int<T: TryFrom<i128>>(input: &mut &[u8]) -> Result<T, DecodeError> {
+    T::try_from(read_signed(input)?).map_err(|_| DecodeError::Overflow)
+}
+
+impl Encode for DataType {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            DataType::None => buf.push(0),
+            DataType::Integer(n) => {
+                buf.push(1);
+                write_signed(buf, *n as i128);
+            }
+            DataType::Text(s) => {
+                buf.push(2);
+                write_bytes(buf, s.as_bytes());
+            }
+            DataType::Boolean(b) => buf.extend_from_slice(&[3, *b as u8]),
+            DataType::Float(n) => {
+                buf.push(4);
+                buf.extend_from_slice(&n.into_inner().to_le_bytes());
+            }
+            DataType::BigInt(n) => {
+                buf.push(5);
+                write_signed(buf, *n as i128);
+            }
+            DataType::Double(n) => {
+                buf.push(6);
+                buf.extend_from_slice(&n.into_inner().to_le_bytes());
+            }
+            DataType::Decimal(d) => {
+                buf.push(7);
+                write_signed(buf, d.units());
+            }
+            DataType::Timestamp(n) => {
+                buf.push(8);
+                write_signed(buf, *n as i128);
+            }
+            DataType::Date(n) => {
+                buf.push(9);
+                write_signed(buf, *n as i128);
+            }
+            DataType::Interval(i) => {
+                buf.push(10);
+                write_signed(buf, i.months as i128);
+                write_signed(buf, i.days as i128);
+                write_signed(buf, i.micros as i128);
+            }
+            DataType::Bytes(b) => {
+                buf.push(11);
+                write_bytes(buf, b);
+            }
+        }
+    }
+}
+
+impl Decode for DataType {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(match read_byte(input)? {
+            0 => DataType::None,
+            1 => DataType::Integer(read_int(input)?),
+            2 => DataType::Text(String::decode(input)?),
+            3 => DataType::Boolean(read_byte(input)? != 0),
+            4 => DataType::Float(OrderedFloat(f32::from_le_bytes(read_array(input)?))),
+            5 => DataType::BigInt(read_int(input)?),
+            6 => DataType::Double(OrderedFloat(f64::from_le_bytes(read_array(input)?))),
+            7 => DataType::Decimal(Decimal::from_units(read_signed(input)?)),
+            8 => DataType::Timestamp(read_int(input)?),
+            9 => DataType::Date(read_int(input)?),
+            10 => DataType::Interval(Interval {
+                months: read_int(input)?,
+                days: read_int(input)?,
+                micros: read_int(input)?,
+            }),
+            11 => DataType::Bytes(read_bytes(input)?.to_vec()),
+            tag => return Err(DecodeError::UnknownTag(tag)),
+        })
+    }
+}
+
+impl Encode for Row {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.data.as_slice().encode(buf)
+    }
+}
+
+impl Decode for Row {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(Vec::<DataType>::decode(input)?.into())
+    }
+}
+
+impl Encode for RowUpdate {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            RowUpdate::Add(r) => {
+                buf.push(0);
+                r.encode(buf);
+            }
+            RowUpdate::Remove(r) => {
+                buf.push(1);
+                r.encode(buf);
+            }
+        }
+    }
+}
+
+impl Decode for RowUpdate {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match read_byte(input)? {
+            0 => Ok(RowUpdate::Add(Row::decode(input)?)),
+            1 => Ok(RowUpdate::Remove(Row::decode(input)?)),
+            tag => Err(DecodeError::UnknownTag(tag)),
+        }
+    }
+}
+
+impl Encode for Diff {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.0.encode(buf);
+        write_signed(buf, self.1 as i128);
+    }
+}
+
+impl Decode for Diff {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok((Row::decode(input)?, read_int(input)?))
+    }
+}
+
+impl<T: Encode> Encode for [T] {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        write_varint(buf, self.len() as u128);
+        for value in self {
+            value.encode(buf);
+        }
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let len = read_len(input)?;
+        // Don't trust the length for allocating as every value takes at least a byte
+        let mut values = Vec::with_capacity(len.min(input.len()));
+        for _ in 0..len {
+            values.push(T::decode(input)?);
+        }
+        Ok(values)
+    }
+}
+
+impl Encode for str {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        write_bytes(buf, self.as_bytes())
+    }
+}
+
+impl Decode for String {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let bytes = read_bytes(input)?;
+        let text = std::str::from_utf8(bytes).map_err(|_| DecodeError::InvalidText)?;
+        Ok(text.into())
+    }
+}
+
+impl Encode for u64 {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        write_varint(buf, *self as u128)
+    }
+}
+
+impl Decode for u64 {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        u64::try_from(read_varint(input)?).map_err(|_| DecodeError::Overflow)
+    }
+}
+
+impl Encode for Updates {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        write_varint(buf, self.source as u128);
+        write_varint(buf, self.destination as u128);
+        self.timestamp.encode(buf);
+        self.updates.as_slice().encode(buf);
+    }
+}
+
+impl Decode for Updates {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let source = read_len(input)?;
+        let destination = read_len(input)?;
+        let timestamp = u64::decode(input)?;
+        let updates = Vec::<Diff>::decode(input)?;
+
+        Ok(Updates {
+            updates: Arc::new(updates),
+            source,
+            destination,
+            timestamp,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values() -> Vec<DataType> {
+        vec![
+            DataType::None,
+            i32::MIN.into(),
+            (-1).into(),
+            "héllo".into(),
+            true.into(),
+            1.5f32.into(),
+            i64::MAX.into(),
+            (-0.25f64).into(),
+            DataType::Decimal(Decimal::new(-12345, 2).unwrap()),
+            DataType::Timestamp(-1),
+            DataType::Date(18_000),
+            DataType::Interval(Interval {
+                months: 1,
+                days: -2,
+                micros: 3,
+            }),
+            vec![0u8, 255].into(),
+        ]
+    }
+
+    #[test]
+    fn round_trips_values() {
+        for value in values() {
+            let bytes = to_bytes(&value);
+            assert_eq!(bytes[0], VERSION);
+            assert_eq!(from_bytes::<DataType>(&bytes), Ok(value));
+        }
+
+        // Small numbers should stay small
+        assert_eq!(to_bytes(&DataType::Integer(-1)), vec![VERSION, 1, 1]);
+    }
+
+    #[test]
+    fn round_trips_updates() {
+        let updates = Updates {
+            updates: Arc::new(vec![
+                (values().into(), 1),
+                (vec![1.into(), "a".into()].into(), -3),
+            ]),
+            source: 3,
+            destination: 7,
+            timestamp: 300,
+        };
+
+        let decoded: Updates = from_bytes(&to_bytes(&updates)).unwrap();
+        assert_eq!(decoded.source, 3);
+        assert_eq!(decoded.destination, 7);
+        assert_eq!(decoded.timestamp, 300);
+        assert_eq!(decoded.updates.len(), 2);
+        assert_eq!(decoded.updates, updates.updates);
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let bytes = to_bytes(&DataType::from("hello"));
+        assert_eq!(
+            from_bytes::<DataType>(&bytes[..4]),
+            Err(DecodeError::UnexpectedEnd)
+        );
+        assert_eq!(
+            from_bytes::<DataType>(&[2, 0]),
+            Err(DecodeError::UnsupportedVersion(2))
+        );
+        assert_eq!(
+            from_bytes::<DataType>(&[VERSION, 99]),
+            Err(DecodeError::UnknownTag(99))
+        );
+        assert_eq!(
+            from_bytes::<DataType>(&[VERSION, 0, 0]),
+            Err(DecodeError::TrailingBytes(1))
+        );
+        assert_eq!(
+            from_bytes::<DataType>(&[VERSION, 1, 0xff, 0xff, 0xff, 0xff, 0x7f]),
+            Err(DecodeError::Overflow)
+        );
+
+        // The largest varint fills the top two bits of its last byte and nothing more
+        let mut max = vec![VERSION, 7];
+        write_varint(&mut max, u128::MAX);
+        assert_eq!(max.len(), 21);
+        assert_eq!(max[20], 0x03);
+        assert_eq!(
+            from_bytes::<DataType>(&max),
+            Ok(DataType::Decimal(Decimal::from_units(i128::MIN)))
+        );
+        max[20] = 0x07;
+        assert_eq!(from_bytes::<DataType>(&max), Err(DecodeError::Overflow));
+    }
+}
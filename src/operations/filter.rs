@@ -1,6 +1,9 @@
-use super::data::{Column, Comparison, DataType, Updates};
-use super::Operation;
-use crate::operations::data::RowUpdate;
+use super::columnar::{ColumnarOperation, ColumnarUpdates};
+use super::data::{Collation, Column, Comparison, DataType, Updates};
+use super::json::Json;
+use super::schema::{Schema, SchemaError};
+use super::{Description, Operation, ProcessError};
+use crate::operations::data::Diff;
 
 /// Filter will remove all rows that don't meet all of the constraint
 pub struct Filter {
@@ -12,40 +15,138 @@ pub struct ColumnConstraint {
     pub constraint: Constraint,
 }
 
+/// Constraints follow SQL semantics so any comparison with a null fails
 pub enum Constraint {
     Comparison(Comparison, DataType),
+    /// Compares text using the collation rather than by bytes
+    Collated(Comparison, DataType, Collation),
     In(Vec<DataType>),
 }
 
-impl Operation for Filter {
-    fn process(&mut self, mut updates: Updates) -> Vec<RowUpdate> {
-        updates.updates.retain(|update| {
-            self.constraints
+impl Constraint {
+    /// matches checks if a single value passes the constraint
+    fn matches(&self, value: &DataType) -> bool {
+        match self {
+            Constraint::Comparison(op, other) => op.compare(value, other),
+            Constraint::Collated(op, other, collation) => {
+                op.compare_collated(value, other, *collation)
+            }
+            Constraint::In(values) => values
                 .iter()
-                .all(|constraint| !match &constraint.constraint {
+                .any(|other| Comparison::Equal.compare(value, other)),
+        }
+    }
+}
+
+impl Operation for Filter {
+    fn process(&mut self, updates: Updates) -> Result<Vec<Diff>, ProcessError> {
+        let mut keep = Vec::with_capacity(updates.updates.len());
+        for (row, _) in updates.updates.iter() {
+            let mut passed = true;
+            for constraint in &self.constraints {
+                passed &= constraint.constraint.matches(constraint.column.get(row)?);
+            }
+            keep.push(passed);
+        }
+
+        // Only the kept rows are copied out of the shared batch
+        Ok(updates
+            .updates
+            .iter()
+            .zip(keep)
+            .filter(|(_, keep)| *keep)
+            .map(|(update, _)| update.clone())
+            .collect())
+    }
+
+    fn schema(&mut self, parents: &[&Schema]) -> Result<Schema, SchemaError> {
+        let input = Schema::input(parents)?;
+        for constraint in &mut self.constraints {
+            let column = constraint.column.resolve(input)?;
+            match &constraint.constraint {
+                Constraint::Comparison(_, value) | Constraint::Collated(_, value, _) => {
+                    input.check_comparable(column, value)?
+                }
+                Constraint::In(values) => {
+                    for value in values {
+                        input.check_comparable(column, value)?
+                    }
+                }
+            }
+        }
+
+        Ok(input.clone())
+    }
+
+    fn describe(&self, parents: &[&Schema]) -> Description {
+        let input = Schema::input(parents).cloned().unwrap_or_default();
+        let constraints: Vec<String> = self
+            .constraints
+            .iter()
+            .map(|c| {
+                let column = c.column.describe(&input);
+                match &c.constraint {
                     Constraint::Comparison(op, value) => {
-                        !op.compare(&update[constraint.column], &value)
+                        format!("{} {} {}", column, op, Json::from(value))
                     }
-                    Constraint::In(values) => values.contains(&update[constraint.column]),
-                })
-        });
+                    Constraint::Collated(op, value, collation) => {
+                        format!("{} {} {} ({:?})", column, op, Json::from(value), collation)
+                    }
+                    Constraint::In(values) => {
+                        let values: Vec<String> =
+                            values.iter().map(|v| Json::from(v).to_string()).collect();
+                        format!("{} IN ({})", column, values.join(", "))
+                    }
+                }
+            })
+            .collect();
+        Description::new("filter").param("constraints", constraints.join(" AND "))
+    }
 
-        return updates.updates;
+    fn columnar(&mut self) -> Option<&mut dyn ColumnarOperation> {
+        Some(self)
+    }
+}
+
+impl ColumnarOperation for Filter {
+    fn process_columns(
+        &mut self,
+        updates: ColumnarUpdates,
+    ) -> Result<ColumnarUpdates, ProcessError> {
+        let mut keep = vec![true; updates.len()];
+        for constraint in &self.constraints {
+            let column = updates.column(&constraint.column)?;
+            let passed = match &constraint.constraint {
+                Constraint::Comparison(op, value) => column.compare(op, value),
+                c => (0..column.len())
+                    .map(|i| c.matches(&column.get(i)))
+                    .collect(),
+            };
+            keep.iter_mut().zip(passed).for_each(|(k, p)| *k &= p);
+        }
+
+        if keep.iter().all(|k| *k) {
+            return Ok(updates);
+        }
+
+        let indices: Vec<usize> = (0..keep.len()).filter(|i| keep[*i]).collect();
+        Ok(updates.select(&indices))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::operations::data::RowUpdate;
-    use crate::processing::Message::Update;
+    use crate::operations::data::{Diff, RowUpdate};
+    use crate::operations::schema::{ColumnSchema, ColumnType};
+    use std::convert::TryFrom;
 
     #[test]
     fn filters_nothing() {
         let mut filter = Filter {
             constraints: vec![],
         };
-        assert_eq!(filter.process(vec![].into()).len(), 0);
+        assert_eq!(filter.process(vec![].into()).unwrap().len(), 0);
     }
 
     #[test]
@@ -53,27 +154,61 @@ mod tests {
         let row_updates = vec![
             RowUpdate::Add(vec![27.into(), "true".into(), 31.into()].into()),
             RowUpdate::Remove(vec![27.into(), "false".into(), 31.into()].into()),
-            RowUpdate::Add(vec![27.into(), "not true or false".into(), 31.into()].into()), // Should be only passing row
+            RowUpdate::Add(vec![27.into(), "not true or false".into(), 31.into()].into()),
             RowUpdate::Remove(vec![32.into(), "not true or false".into(), 31.into()].into()),
-            RowUpdate::Add(vec![32.into(), "true".into(), 31.into()].into()),
+            RowUpdate::Add(vec![32.into(), "true".into(), 31.into()].into()), // Should be only passing row
         ];
 
         let constraints = vec![
             ColumnConstraint {
-                column: 0,
+                column: 0.into(),
                 constraint: Constraint::Comparison(Comparison::GreaterThan, DataType::Integer(30)),
             },
             ColumnConstraint {
-                column: 1,
-                constraint: Constraint::In(vec!["true".into(), "false".into()].into()),
+                column: 1.into(),
+                constraint: Constraint::In(vec!["true".into(), "false".into()]),
             },
         ];
         let mut filter = Filter { constraints };
 
-        let filtered = filter.process(row_updates.into());
+        let diffs: Vec<Diff> = row_updates.iter().cloned().map(Diff::from).collect();
+        let columns = ColumnarUpdates::try_from(diffs.as_slice()).unwrap();
+        let columnar = filter.process_columns(columns).unwrap();
+        let filtered = filter.process(row_updates.into()).unwrap();
         assert_eq!(filtered.len(), 1);
-        assert_eq!(filtered[0][0], 32.into());
-        assert_eq!(filtered[0][1], "not true or false".into());
-        assert_eq!(filtered[0][2], 31.into());
+        assert_eq!(filtered[0].0[0], 32.into());
+        assert_eq!(filtered[0].0[1], "true".into());
+        assert_eq!(filtered[0].0[2], 31.into());
+
+        assert_eq!(columnar.len(), 1);
+        assert_eq!(columnar.diffs, vec![1]);
+        assert_eq!(columnar.row(0)[..], filtered[0].0[..]);
+    }
+
+    #[test]
+    fn rejects_mismatched_types() {
+        let mut filter = Filter {
+            constraints: vec![ColumnConstraint {
+                column: "id".into(),
+                constraint: Constraint::In(vec![1.into(), "1".into()]),
+            }],
+        };
+        let schema = Schema::new(vec![ColumnSchema::new("id", ColumnType::Integer, true)]);
+        assert_eq!(
+            filter.schema(&[&schema]),
+            Err(SchemaError::TypeMismatch {
+                column: 0,
+                expected: ColumnType::Integer,
+                found: ColumnType::Text,
+            })
+        );
+
+        // Types are only checked against the schema, so rows written around it just don't match
+        filter.constraints[0].constraint = Constraint::In(vec![1.into()]);
+        let row_updates = vec![
+            RowUpdate::Add(vec![1.into()].into()),
+            RowUpdate::Add(vec!["1".into()].into()),
+        ];
+        assert_eq!(filter.process(row_updates.into()).unwrap().len(), 1);
     }
 }
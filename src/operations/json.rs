@@ -0,0 +1,403 @@
+//! A small JSON reader and writer for exchanging rows with other systems.
+//!
+//! Numbers keep the text they were written with so large integers and decimals aren't rounded through floats.
+
+use super::data::DataType;
+use super::schema::ColumnType;
+use super::types::{format_date, format_timestamp, ParseError, MAX_DAYS};
+use std::fmt;
+
+/// MAX_DEPTH is the deepest arrays and objects can be nested, so parsing untrusted input can't overflow the stack
+const MAX_DEPTH: usize = 128;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    /// Objects keep their keys in the order they were written
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// parse reads a single JSON value. Only whitespace may follow it.
+    pub fn parse(s: &str) -> Result<Json, ParseError> {
+        let mut parser = Parser {
+            input: s,
+            position: 0,
+            depth: 0,
+        };
+        let value = parser.value()?;
+        parser.whitespace();
+        match parser.position == s.len() {
+            true => Ok(value),
+            false => Err(parser.error("end of input")),
+        }
+    }
+
+    /// get returns the value of the key if this is an object containing it
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        *self == Json::Null
+    }
+
+    /// to_value converts the JSON into a value for a column of the type. Text is parsed as the column's type, and
+    /// numbers are read as microseconds for timestamps and days for dates.
+    pub fn to_value(&self, column_type: ColumnType) -> Result<DataType, ParseError> {
+        let err = || ParseError(format!("can't convert {} to {:?}", self, column_type));
+        match (self, column_type) {
+            (Json::Null, _) => Ok(DataType::None),
+            (Json::Bool(b), ColumnType::Any) | (Json::Bool(b), ColumnType::Boolean) => {
+                Ok(DataType::Boolean(*b))
+            }
+            (Json::Number(n), ColumnType::Any) => ColumnType::Integer
+                .parse(n)
+                .or_else(|_| ColumnType::BigInt.parse(n))
+                .or_else(|_| ColumnType::Double.parse(n)),
+            (Json::Number(n), ColumnType::Timestamp) => {
+                Ok(DataType::Timestamp(n.parse().map_err(|_| err())?))
+            }
+            (Json::Number(n), ColumnType::Date) => match n.parse() {
+                Ok(days) if (-MAX_DAYS..=MAX_DAYS).contains(&days) => Ok(DataType::Date(days)),
+                _ => Err(err()),
+            },
+            (Json::Number(n), t) if t.is_numeric() => t.parse(n),
+            (Json::String(s), t) => t.parse(s),
+            _ => Err(err()),
+        }
+    }
+}
+
+impl From<&DataType> for Json {
+    /// Converts values to the JSON that to_value reads back. Types JSON doesn't have are written as text.
+    fn from(value: &DataType) -> Self {
+        match value {
+            DataType::None => Json::Null,
+            DataType::Integer(n) => Json::Number(n.to_string()),
+            DataType::Text(s) => Json::String(s.clone()),
+            DataType::Boolean(b) => Json::Bool(*b),
+            DataType::BigInt(n) => Json::Number(n.to_string()),
+            DataType::Float(f) if f.is_finite() => Json::Number(f.to_string()),
+            DataType::Double(f) if f.is_finite() => Json::Number(f.to_string()),
+            DataType::Float(f) => Json::String(f.to_string()),
+            DataType::Double(f) => Json::String(f.to_string()),
+            DataType::Decimal(d) => Json::String(d.to_string()),
+            DataType::Timestamp(t) => Json::String(format_timestamp(*t)),
+            DataType::Date(d) => Json::String(format_date(*d)),
+            DataType::Interval(i) => Json::String(i.to_string()),
+            DataType::Bytes(b) => Json::String(
+                b.iter()
+                    .fold("\\x".into(), |s, b| s + &format!("{:02x}", b)),
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Json {
+    /// Writes the JSON without any whitespace
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => f.write_str("null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => f.write_str(n),
+            Json::String(s) => write_string(f, s),
+            Json::Array(values) => {
+                f.write_str("[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    value.fmt(f)?;
+                }
+                f.write_str("]")
+            }
+            Json::Object(fields) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_string(f, key)?;
+                    f.write_str(":")?;
+                    value.fmt(f)?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, expected: &str) -> ParseError {
+        ParseError(format!(
+            "invalid json: expected {} at position {}",
+            expected, self.position
+        ))
+    }
+
+    fn rest(&self) -> &str {
+        &self.input[self.position..]
+    }
+
+    fn whitespace(&mut self) {
+        let rest = self.rest();
+        self.position += rest.len() - rest.trim_start().len();
+    }
+
+    fn eat(&mut self, s: &str) -> bool {
+        self.whitespace();
+        match self.rest().starts_with(s) {
+            true => {
+                self.position += s.len();
+                true
+            }
+            false => false,
+        }
+    }
+
+    fn expect(&mut self, s: &str) -> Result<(), ParseError> {
+        match self.eat(s) {
+            true => Ok(()),
+            false => Err(self.error(s)),
+        }
+    }
+
+    /// nested parses an array or object, failing if it's nested too deeply
+    fn nested(
+        &mut self,
+        parse: fn(&mut Self) -> Result<Json, ParseError>,
+    ) -> Result<Json, ParseError> {
+        if self.depth == MAX_DEPTH {
+            return Err(ParseError(format!(
+                "invalid json: nested more than {} deep at position {}",
+                MAX_DEPTH, self.position
+            )));
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn value(&mut self) -> Result<Json, ParseError> {
+        self.whitespace();
+        let c = self
+            .rest()
+            .chars()
+            .next()
+            .ok_or_else(|| self.error("a value"))?;
+        match c {
+            'n' if self.eat("null") => Ok(Json::Null),
+            't' if self.eat("true") => Ok(Json::Bool(true)),
+            'f' if self.eat("false") => Ok(Json::Bool(false)),
+            '"' => Ok(Json::String(self.string()?)),
+            '[' => self.nested(Self::array),
+            '{' => self.nested(Self::object),
+            '-' | '0'..='9' => {
+                let len = self
+                    .rest()
+                    .find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c)))
+                    .unwrap_or(self.rest().len());
+                let number = self.rest()[..len].to_string();
+                if number.parse::<f64>().is_err() {
+                    return Err(self.error("a number"));
+                }
+                self.position += len;
+                Ok(Json::Number(number))
+            }
+            _ => Err(self.error("a value")),
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, ParseError> {
+        self.position += 1;
+        let mut values = vec![];
+        if !self.eat("]") {
+            loop {
+                values.push(self.value()?);
+                if self.eat("]") {
+                    break;
+                }
+                self.expect(",")?;
+            }
+        }
+        Ok(Json::Array(values))
+    }
+
+    fn object(&mut self) -> Result<Json, ParseError> {
+        self.position += 1;
+        let mut fields = vec![];
+        if !self.eat("}") {
+            loop {
+                self.whitespace();
+                let key = self.string()?;
+                self.expect(":")?;
+                fields.push((key, self.value()?));
+                if self.eat("}") {
+                    break;
+                }
+                self.expect(",")?;
+            }
+        }
+        Ok(Json::Object(fields))
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        if !self.rest().starts_with('"') {
+            return Err(self.error("a string"));
+        }
+        self.position += 1;
+
+        let mut s = String::new();
+        let mut chars = self.rest().char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.position += i + 1;
+                    return Ok(s);
+                }
+                '\\' => {
+                    let escaped = match chars.next().map(|(_, c)| c) {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('/') => '/',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => {
+                            let mut code = hex(&mut chars)?;
+                            // Characters outside the basic plane are written as a surrogate pair
+                            if (0xd800..0xdc00).contains(&code) {
+                                if chars.next().map(|(_, c)| c) != Some('\\')
+                                    || chars.next().map(|(_, c)| c) != Some('u')
+                                {
+                                    return Err(self.error("a low surrogate"));
+                                }
+                                let low = hex(&mut chars)?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return Err(self.error("a low surrogate"));
+                                }
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            char::from_u32(code).ok_or_else(|| self.error("a valid character"))?
+                        }
+                        _ => return Err(self.error("an escape")),
+                    };
+                    s.push(escaped);
+                }
+                c => s.push(c),
+            }
+        }
+        Err(self.error("the end of the string"))
+    }
+}
+
+fn hex(chars: &mut std::str::CharIndices) -> Result<u32, ParseError> {
+    let digits: String = chars.take(4).map(|(_, c)| c).collect();
+    u32::from_str_radix(&digits, 16)
+        .ok()
+        .filter(|_| digits.len() == 4)
+        .ok_or_else(|| ParseError(format!("invalid json: bad unicode escape {}", digits)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_json() {
+        let s = r#"{"a": [1, -2.5e3, true, null], "b\n": "x\"é😀", "c": {}}"#;
+        let json = Json::parse(s).unwrap();
+        assert_eq!(json.get("b\n").and_then(Json::as_str), Some("x\"é😀"));
+        assert_eq!(
+            json.get("a"),
+            Some(&Json::Array(vec![
+                Json::Number("1".into()),
+                Json::Number("-2.5e3".into()),
+                Json::Bool(true),
+                Json::Null,
+            ]))
+        );
+        assert_eq!(Json::parse(&json.to_string()), Ok(json));
+
+        assert!(Json::parse("{\"a\": 1,}").is_err());
+        assert!(Json::parse("[1] 2").is_err());
+        assert!(Json::parse("\"open").is_err());
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(Json::parse(&nested(MAX_DEPTH)).is_ok());
+        assert!(Json::parse(&nested(MAX_DEPTH + 1)).is_err());
+        assert!(Json::parse(&"{\"a\":".repeat(100_000)).is_err());
+    }
+
+    #[test]
+    fn converts_values() {
+        let number = Json::Number("86400000000".into());
+        assert_eq!(
+            number.to_value(ColumnType::Any),
+            Ok(DataType::BigInt(86_400_000_000))
+        );
+        assert_eq!(
+            number.to_value(ColumnType::Timestamp),
+            Ok(DataType::Timestamp(86_400_000_000))
+        );
+        assert!(number.to_value(ColumnType::Integer).is_err());
+        assert!(Json::Bool(true).to_value(ColumnType::Integer).is_err());
+        assert!(Json::Number("2147483647".into())
+            .to_value(ColumnType::Date)
+            .is_err());
+
+        for value in [
+            DataType::Integer(5),
+            DataType::Text("a".into()),
+            DataType::Date(3),
+            DataType::Bytes(vec![1, 2]),
+            DataType::None,
+        ] {
+            let column_type = ColumnType::of(&value);
+            assert_eq!(Json::from(&value).to_value(column_type), Ok(value));
+        }
+    }
+}
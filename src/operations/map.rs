@@ -1,5 +1,8 @@
-use super::data::{DataType, RowUpdate, Source, Updates};
-use super::Operation;
+use super::columnar::{ColumnValues, ColumnarOperation, ColumnarUpdates};
+use super::data::{DataType, Diff, Source, Updates};
+use super::schema::{ColumnSchema, ColumnType, Schema, SchemaError};
+use super::{Description, Operation, ProcessError};
+use std::sync::Arc;
 
 /// Map will alter all incoming rows to match the sources. This may reorder columns, add new
 /// copies of columns, or add new columns of literals
@@ -8,26 +11,70 @@ pub struct Map {
 }
 
 impl Operation for Map {
-    fn process(&mut self, updates: Updates) -> Vec<RowUpdate> {
+    fn process(&mut self, updates: Updates) -> Result<Vec<Diff>, ProcessError> {
         updates
             .updates
             .iter()
-            .map(|update| {
+            .map(|(row, multiplicity)| {
                 let mapped_row = self
                     .sources
                     .iter()
-                    .map(|source| match source {
-                        Source::Column(c) => update[*c].clone(),
-                        Source::Literal(d) => d.clone(),
-                    })
-                    .collect::<Vec<DataType>>()
+                    .map(|source| source.get(row))
+                    .collect::<Result<Vec<DataType>, ProcessError>>()?
                     .into();
 
-                match update {
-                    RowUpdate::Add(_) => RowUpdate::Add(mapped_row),
-                    RowUpdate::Remove(_) => RowUpdate::Remove(mapped_row),
-                }
+                Ok((mapped_row, *multiplicity))
             })
             .collect()
     }
+
+    fn schema(&mut self, parents: &[&Schema]) -> Result<Schema, SchemaError> {
+        let input = Schema::input(parents)?;
+        let columns = self
+            .sources
+            .iter_mut()
+            .map(|source| match source {
+                Source::Column(c) => input.column(c.resolve(input)?).cloned(),
+                Source::Literal(d) => Ok(ColumnSchema::new(
+                    "literal",
+                    ColumnType::of(d),
+                    *d == DataType::None,
+                )),
+            })
+            .collect::<Result<Vec<ColumnSchema>, SchemaError>>()?;
+
+        Ok(Schema::new(columns))
+    }
+
+    fn describe(&self, parents: &[&Schema]) -> Description {
+        let input = Schema::input(parents).cloned().unwrap_or_default();
+        let sources: Vec<String> = self.sources.iter().map(|s| s.describe(&input)).collect();
+        Description::new("map").param("sources", sources.join(", "))
+    }
+
+    fn columnar(&mut self) -> Option<&mut dyn ColumnarOperation> {
+        Some(self)
+    }
+}
+
+impl ColumnarOperation for Map {
+    fn process_columns(
+        &mut self,
+        updates: ColumnarUpdates,
+    ) -> Result<ColumnarUpdates, ProcessError> {
+        let len = updates.len();
+        let columns = self
+            .sources
+            .iter()
+            .map(|source| match source {
+                Source::Column(c) => updates.column(c).map(Arc::clone),
+                Source::Literal(d) => Ok(Arc::new(ColumnValues::repeat(d, len))),
+            })
+            .collect::<Result<Vec<Arc<ColumnValues>>, ProcessError>>()?;
+
+        Ok(ColumnarUpdates {
+            columns,
+            diffs: updates.diffs,
+        })
+    }
 }
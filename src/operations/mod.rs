@@ -1,18 +1,123 @@
+pub use self::base::Base;
+use self::columnar::ColumnarOperation;
 pub use self::count::Count;
 use self::data::Updates;
 pub use self::filter::Filter;
 pub use self::map::Map;
 pub use self::state::State;
-use crate::operations::data::RowUpdate;
+use crate::operations::data::Diff;
+use crate::operations::schema::{ColumnType, Schema, SchemaError};
+use std::error::Error;
+use std::fmt;
 
+mod base;
+pub mod columnar;
 mod count;
 pub mod data;
+pub mod encoding;
 pub mod filter;
+pub mod json;
 mod map;
+pub mod schema;
 pub mod state;
+pub mod types;
 
-/// An Operation can process any RowUpdates it gets
+/// An Operation can process any diffs it gets
 pub trait Operation {
     /// Process handles any updates that may then be forwarded on to the next node in the graph
-    fn process(&mut self, updates: Updates) -> Vec<RowUpdate>;
+    ///
+    /// If any update can't be handled an error is returned and the operation must be left as it was before the call,
+    /// so the updates can be retried one at a time to find the ones that fail.
+    fn process(&mut self, updates: Updates) -> Result<Vec<Diff>, ProcessError>;
+
+    /// Schema checks the operation can handle rows from its parents and returns the schema of the rows it outputs.
+    /// Any columns referenced by name are resolved against the parents' schema.
+    fn schema(&mut self, parents: &[&Schema]) -> Result<Schema, SchemaError>;
+
+    /// Describe names the operation and its parameters so graphs can be shown to people. Columns are named using the
+    /// parents' schemas.
+    fn describe(&self, parents: &[&Schema]) -> Description;
+
+    /// state_size returns the number of entries the operation holds in its state
+    fn state_size(&self) -> usize {
+        0
+    }
+
+    /// columnar returns the operation as a ColumnarOperation if it can also process batches a column at a time
+    fn columnar(&mut self) -> Option<&mut dyn ColumnarOperation> {
+        None
+    }
+}
+
+/// ProcessError is returned when an operation can't handle an update
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ProcessError {
+    /// A column was used by name without being resolved against the input schema
+    UnresolvedColumn(String),
+    /// A row didn't have a column the operation reads
+    MissingColumn { column: usize, width: usize },
+    /// A value couldn't be compared with the values the operation expected
+    TypeMismatch {
+        column: usize,
+        expected: ColumnType,
+        found: ColumnType,
+    },
+    /// A result was too large to hold
+    Overflow(String),
+    /// The operation's state held something it never writes
+    InvalidState(String),
+    /// A sink couldn't deliver the update, even after retrying
+    Delivery(String),
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessError::UnresolvedColumn(name) => {
+                write!(f, "column {} used before being resolved", name)
+            }
+            ProcessError::MissingColumn { column, width } => write!(
+                f,
+                "column {} doesn't exist in row with {} columns",
+                column, width
+            ),
+            ProcessError::TypeMismatch {
+                column,
+                expected,
+                found,
+            } => write!(
+                f,
+                "column {} expected {:?} but found {:?}",
+                column, expected, found
+            ),
+            ProcessError::Overflow(message) => write!(f, "overflow: {}", message),
+            ProcessError::InvalidState(message) => write!(f, "invalid state: {}", message),
+            ProcessError::Delivery(message) => write!(f, "delivery failed: {}", message),
+        }
+    }
+}
+
+impl Error for ProcessError {}
+
+/// Description names a worker and its parameters, like a filter's constraints
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Description {
+    pub name: String,
+    pub params: Vec<(String, String)>,
+}
+
+impl Description {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.into(),
+            params: vec![],
+        }
+    }
+
+    /// param adds a parameter to the description
+    pub fn param(mut self, name: &str, value: String) -> Self {
+        self.params.push((name.into(), value));
+        self
+    }
 }
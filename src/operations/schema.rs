@@ -0,0 +1,308 @@
+use super::data::{DataType, Row};
+use super::types::{parse_date, parse_timestamp, ParseError};
+use std::fmt;
+
+/// ColumnType is the type of values a column holds. Any is used when the type can't be known ahead of time, like for
+/// a column of null literals.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ColumnType {
+    Any,
+    Integer,
+    Text,
+    Boolean,
+    Float,
+    BigInt,
+    Double,
+    Decimal,
+    Timestamp,
+    Date,
+    Interval,
+    Bytes,
+}
+
+impl ColumnType {
+    /// of returns the type of a value. Nulls could belong to any column so are Any.
+    pub fn of(value: &DataType) -> Self {
+        match value {
+            DataType::None => ColumnType::Any,
+            DataType::Integer(_) => ColumnType::Integer,
+            DataType::Text(_) => ColumnType::Text,
+            DataType::Boolean(_) => ColumnType::Boolean,
+            DataType::Float(_) => ColumnType::Float,
+            DataType::BigInt(_) => ColumnType::BigInt,
+            DataType::Double(_) => ColumnType::Double,
+            DataType::Decimal(_) => ColumnType::Decimal,
+            DataType::Timestamp(_) => ColumnType::Timestamp,
+            DataType::Date(_) => ColumnType::Date,
+            DataType::Interval(_) => ColumnType::Interval,
+            DataType::Bytes(_) => ColumnType::Bytes,
+        }
+    }
+
+    /// accepts checks if values of the other type can be used where this type is expected
+    pub fn accepts(&self, other: ColumnType) -> bool {
+        *self == ColumnType::Any || other == ColumnType::Any || *self == other
+    }
+
+    /// comparable checks if values of the two types can be compared. Numbers can be compared with numbers of any
+    /// type, and dates can be compared with timestamps.
+    pub fn comparable(&self, other: ColumnType) -> bool {
+        use ColumnType::*;
+
+        self.accepts(other)
+            || (self.is_numeric() && other.is_numeric())
+            || matches!((self, other), (Date, Timestamp) | (Timestamp, Date))
+    }
+
+    pub fn is_numeric(&self) -> bool {
+        use ColumnType::*;
+
+        matches!(self, Integer | Float | BigInt | Double | Decimal)
+    }
+
+    /// parse reads a value of this type from text. Any columns keep the text as it is. Bytes can be written as hex
+    /// starting with \\x, otherwise the bytes of the text are used.
+    pub fn parse(&self, s: &str) -> Result<DataType, ParseError> {
+        let err = |name: &str| ParseError(format!("invalid {}: {}", name, s));
+        let trimmed = s.trim();
+        Ok(match self {
+            ColumnType::Any | ColumnType::Text => DataType::Text(s.into()),
+            ColumnType::Integer => DataType::Integer(trimmed.parse().map_err(|_| err("integer"))?),
+            ColumnType::BigInt => DataType::BigInt(trimmed.parse().map_err(|_| err("bigint"))?),
+            ColumnType::Float => DataType::Float(trimmed.parse().map_err(|_| err("float"))?),
+            ColumnType::Double => DataType::Double(trimmed.parse().map_err(|_| err("double"))?),
+            ColumnType::Decimal => DataType::Decimal(trimmed.parse()?),
+            ColumnType::Boolean => match trimmed.to_lowercase().as_str() {
+                "t" | "true" | "y" | "yes" | "on" | "1" => DataType::Boolean(true),
+                "f" | "false" | "n" | "no" | "off" | "0" => DataType::Boolean(false),
+                _ => return Err(err("boolean")),
+            },
+            ColumnType::Timestamp => DataType::Timestamp(parse_timestamp(trimmed)?),
+            ColumnType::Date => DataType::Date(parse_date(trimmed)?),
+            ColumnType::Interval => DataType::Interval(trimmed.parse()?),
+            ColumnType::Bytes => match s.strip_prefix("\\x") {
+                Some(hex) if hex.len() % 2 == 0 => DataType::Bytes(
+                    (0..hex.len())
+                        .step_by(2)
+                        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+                        .collect::<Option<Vec<u8>>>()
+                        .ok_or_else(|| err("bytes"))?,
+                ),
+                Some(_) => return Err(err("bytes")),
+                None => DataType::Bytes(s.as_bytes().to_vec()),
+            },
+        })
+    }
+}
+
+/// ColumnSchema describes a single column
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnSchema {
+    pub name: String,
+    pub column_type: ColumnType,
+    pub nullable: bool,
+}
+
+impl ColumnSchema {
+    pub fn new(name: &str, column_type: ColumnType, nullable: bool) -> Self {
+        Self {
+            name: name.into(),
+            column_type,
+            nullable,
+        }
+    }
+}
+
+/// Schema describes the rows a worker outputs
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schema {
+    pub columns: Vec<ColumnSchema>,
+}
+
+impl Schema {
+    pub fn new(columns: Vec<ColumnSchema>) -> Self {
+        Self { columns }
+    }
+
+    pub fn len(&self) -> usize {
+        self.columns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_empty()
+    }
+
+    /// column returns the column at the index or an error if it doesn't exist
+    pub fn column(&self, column: usize) -> Result<&ColumnSchema, SchemaError> {
+        self.columns.get(column).ok_or(SchemaError::MissingColumn {
+            column,
+            width: self.columns.len(),
+        })
+    }
+
+    /// index_of returns the position of the first column with the name
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|c| c.name == name)
+    }
+
+    /// check_value checks that the value could be stored in the column
+    pub fn check_value(&self, column: usize, value: &DataType) -> Result<(), SchemaError> {
+        let expected = self.column(column)?.column_type;
+        let found = ColumnType::of(value);
+        match expected.accepts(found) {
+            true => Ok(()),
+            false => Err(SchemaError::TypeMismatch {
+                column,
+                expected,
+                found,
+            }),
+        }
+    }
+
+    /// check_row checks that the row has a value of the right type for every column
+    pub fn check_row(&self, row: &Row) -> Result<(), SchemaError> {
+        if row.data.len() != self.columns.len() {
+            return Err(SchemaError::RowWidth {
+                expected: self.columns.len(),
+                found: row.data.len(),
+            });
+        }
+
+        for (i, value) in row.data.iter().enumerate() {
+            if *value == DataType::None && !self.columns[i].nullable {
+                return Err(SchemaError::NullValue(i));
+            }
+            self.check_value(i, value)?;
+        }
+        Ok(())
+    }
+
+    /// check_comparable checks that the value can be compared with values in the column
+    pub fn check_comparable(&self, column: usize, value: &DataType) -> Result<(), SchemaError> {
+        let expected = self.column(column)?.column_type;
+        let found = ColumnType::of(value);
+        match expected.comparable(found) {
+            true => Ok(()),
+            false => Err(SchemaError::TypeMismatch {
+                column,
+                expected,
+                found,
+            }),
+        }
+    }
+
+    /// input returns the schema of the rows coming into a worker. Workers with more than one parent get rows from
+    /// all of them so all parents need to have matching column types.
+    pub fn input<'a>(parents: &[&'a Schema]) -> Result<&'a Schema, SchemaError> {
+        let (first, rest) = parents.split_first().ok_or(SchemaError::NoParents)?;
+        for parent in rest {
+            let matches = parent.len() == first.len()
+                && parent
+                    .columns
+                    .iter()
+                    .zip(&first.columns)
+                    .all(|(a, b)| a.column_type.accepts(b.column_type));
+            if !matches {
+                return Err(SchemaError::IncompatibleParents);
+            }
+        }
+
+        Ok(first)
+    }
+
+    /// passthrough is the schema of a worker that forwards its input without changing it
+    pub fn passthrough(parents: &[&Schema]) -> Result<Schema, SchemaError> {
+        Schema::input(parents).cloned()
+    }
+}
+
+/// SchemaError is returned when a worker can't be wired up because its operation doesn't fit its input, or when a
+/// row doesn't fit a schema
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaError {
+    /// A column past the end of the input was used
+    MissingColumn { column: usize, width: usize },
+    /// No input column has the name
+    UnknownColumn(String),
+    /// A column or value didn't have the expected type
+    TypeMismatch {
+        column: usize,
+        expected: ColumnType,
+        found: ColumnType,
+    },
+    /// A row didn't have a value for every column
+    RowWidth { expected: usize, found: usize },
+    /// A null was given for a column that isn't nullable
+    NullValue(usize),
+    /// A parent worker doesn't exist
+    MissingParent(usize),
+    /// The operation needs input but the worker has no parents
+    NoParents,
+    /// The operation doesn't take any input but the worker was given parents
+    UnexpectedParents,
+    /// The parents of the worker output different kinds of rows
+    IncompatibleParents,
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaError::MissingColumn { column, width } => write!(
+                f,
+                "column {} doesn't exist in input with {} columns",
+                column, width
+            ),
+            SchemaError::UnknownColumn(name) => write!(f, "no column named {}", name),
+            SchemaError::TypeMismatch {
+                column,
+                expected,
+                found,
+            } => write!(
+                f,
+                "column {} expected {:?} but found {:?}",
+                column, expected, found
+            ),
+            SchemaError::RowWidth { expected, found } => {
+                write!(f, "expected {} columns but found {}", expected, found)
+            }
+            SchemaError::NullValue(column) => write!(f, "column {} can't be null", column),
+            SchemaError::MissingParent(id) => write!(f, "parent worker {} doesn't exist", id),
+            SchemaError::NoParents => f.write_str("operation needs at least one parent"),
+            SchemaError::UnexpectedParents => f.write_str("operation can't have parents"),
+            SchemaError::IncompatibleParents => f.write_str("parents have different schemas"),
+        }
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::types::{Decimal, Interval};
+
+    #[test]
+    fn parses_values() {
+        assert_eq!(ColumnType::Integer.parse(" 42 "), Ok(42.into()));
+        assert_eq!(ColumnType::Text.parse(" 42 "), Ok(" 42 ".into()));
+        assert_eq!(ColumnType::Boolean.parse("t"), Ok(true.into()));
+        assert_eq!(
+            ColumnType::Decimal.parse("1.50"),
+            Ok(Decimal::new(150, 2).unwrap().into())
+        );
+        assert_eq!(ColumnType::Date.parse("1970-01-02"), Ok(DataType::Date(1)));
+        assert_eq!(
+            ColumnType::Interval.parse("2 days"),
+            Ok(Interval {
+                months: 0,
+                days: 2,
+                micros: 0
+            }
+            .into())
+        );
+        assert_eq!(ColumnType::Bytes.parse("\\x0aff"), Ok(vec![10, 255].into()));
+        assert!(ColumnType::Integer.parse("4.5").is_err());
+        assert!(ColumnType::Bytes.parse("\\xf").is_err());
+    }
+}
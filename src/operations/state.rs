@@ -1,16 +1,21 @@
 use super::data::DataType;
 use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
 
 /// State handles stateful interactions for operations
 pub trait State {
     fn get(&self, key: &Key) -> Vec<DataType>;
 
     fn set(&mut self, key: Key, values: Vec<DataType>);
+
+    /// size returns the number of keys held
+    fn size(&self) -> usize;
 }
 
 pub type Key = Vec<DataType>;
 
 /// MemStore implements state with an in mem hashmap.
+#[derive(Default)]
 pub struct MemStore {
     data: HashMap<Key, Vec<DataType>>,
 }
@@ -21,6 +26,17 @@ impl MemStore {
             data: HashMap::new(),
         }
     }
+
+    /// entries returns every key and its values, sorted by key
+    pub fn entries(&self) -> Vec<(Key, Vec<DataType>)> {
+        let mut entries: Vec<(Key, Vec<DataType>)> = self
+            .data
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        entries.sort();
+        entries
+    }
 }
 
 impl State for MemStore {
@@ -31,4 +47,40 @@ impl State for MemStore {
     fn set(&mut self, key: Key, values: Vec<DataType>) {
         self.data.insert(key, values);
     }
+
+    fn size(&self) -> usize {
+        self.data.len()
+    }
+}
+
+/// SharedStore is a MemStore that can be read from other threads while an operation running on its own thread uses it.
+/// Clones share the same data.
+#[derive(Default, Clone)]
+pub struct SharedStore {
+    store: Arc<RwLock<MemStore>>,
+}
+
+impl SharedStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// entries returns every key and its values, sorted by key
+    pub fn entries(&self) -> Vec<(Key, Vec<DataType>)> {
+        self.store.read().unwrap().entries() // Fine with panicking on thread poisoning
+    }
+}
+
+impl State for SharedStore {
+    fn get(&self, key: &Key) -> Vec<DataType> {
+        self.store.read().unwrap().get(key) // Fine with panicking on thread poisoning
+    }
+
+    fn set(&mut self, key: Key, values: Vec<DataType>) {
+        self.store.write().unwrap().set(key, values) // Fine with panicking on thread poisoning
+    }
+
+    fn size(&self) -> usize {
+        self.store.read().unwrap().size() // Fine with panicking on thread poisoning
+    }
 }
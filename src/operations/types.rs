@@ -0,0 +1,460 @@
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// DECIMAL_SCALE is the number of digits every decimal keeps after the point
+pub const DECIMAL_SCALE: u32 = 6;
+
+/// Decimal is a fixed point number stored as a whole number of units of 10^-DECIMAL_SCALE. So 12.34 is stored as
+/// 12340000.
+///
+/// Every decimal has the same scale so 1.5 and 1.50 are the same value, and comparing and hashing them is exact.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Decimal {
+    units: i128,
+}
+
+impl Decimal {
+    /// new creates the decimal value * 10^-scale, rounding half away from zero if it has more digits after the point
+    /// than DECIMAL_SCALE. Returns None if it's too large to hold.
+    pub fn new(value: i128, scale: u32) -> Option<Self> {
+        let units = match scale.cmp(&DECIMAL_SCALE) {
+            Ordering::Equal => value,
+            Ordering::Less => value.checked_mul(10i128.checked_pow(DECIMAL_SCALE - scale)?)?,
+            Ordering::Greater => match 10i128.checked_pow(scale - DECIMAL_SCALE) {
+                // Anything this finely scaled rounds to zero
+                None => 0,
+                Some(divisor) => {
+                    let (quotient, remainder) = (value / divisor, value % divisor);
+                    match remainder.unsigned_abs()
+                        >= divisor.unsigned_abs() - remainder.unsigned_abs()
+                    {
+                        true => quotient + value.signum(),
+                        false => quotient,
+                    }
+                }
+            },
+        };
+        Some(Self { units })
+    }
+
+    /// from_units creates a decimal from a whole number of units of 10^-DECIMAL_SCALE
+    pub fn from_units(units: i128) -> Self {
+        Self { units }
+    }
+
+    /// units returns the decimal as a whole number of units of 10^-DECIMAL_SCALE
+    pub fn units(&self) -> i128 {
+        self.units
+    }
+
+    /// to_f64 returns the closest float to the decimal
+    pub fn to_f64(&self) -> f64 {
+        self.units as f64 / 10f64.powi(DECIMAL_SCALE as i32)
+    }
+}
+
+/// Every 64 bit integer fits in a decimal exactly
+impl From<i64> for Decimal {
+    fn from(n: i64) -> Self {
+        Self {
+            units: n as i128 * 10i128.pow(DECIMAL_SCALE),
+        }
+    }
+}
+
+impl fmt::Display for Decimal {
+    /// Decimals are shown without trailing zeros after the point
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let digits = self.units.unsigned_abs().to_string();
+        let sign = if self.units < 0 { "-" } else { "" };
+        let scale = DECIMAL_SCALE as usize;
+        let digits = format!("{:0>width$}", digits, width = scale + 1);
+        let (whole, fraction) = digits.split_at(digits.len() - scale);
+        match fraction.trim_end_matches('0') {
+            "" => write!(f, "{}{}", sign, whole),
+            fraction => write!(f, "{}{}.{}", sign, whole, fraction),
+        }
+    }
+}
+
+impl FromStr for Decimal {
+    type Err = ParseError;
+
+    /// Parses decimals written like 12, -12.340 or .5, rounding any digits after the point past DECIMAL_SCALE
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseError(format!("invalid decimal: {}", s));
+        let (whole, fraction) = match s.find('.') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => (s, ""),
+        };
+        let (negative, whole) = match whole.strip_prefix('-') {
+            Some(w) => (true, w),
+            None => (false, whole.strip_prefix('+').unwrap_or(whole)),
+        };
+
+        let digits = format!("{}{}", whole, fraction);
+        if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+            return Err(err());
+        }
+
+        let value: i128 = digits.parse().map_err(|_| err())?;
+        let value = if negative { -value } else { value };
+        Self::new(value, fraction.len() as u32).ok_or_else(err)
+    }
+}
+
+/// Interval is an amount of time. Months and days are kept separate from the rest as their length isn't fixed.
+#[derive(Debug, Clone, Copy, Default, Ord, PartialOrd, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Interval {
+    pub months: i32,
+    pub days: i32,
+    pub micros: i64,
+}
+
+impl From<Duration> for Interval {
+    fn from(d: Duration) -> Self {
+        Self {
+            months: 0,
+            days: 0,
+            micros: d.as_micros() as i64,
+        }
+    }
+}
+
+impl fmt::Display for Interval {
+    /// Formats intervals the way Postgres does, like 1 year 2 mons 3 days 04:05:06.5
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = vec![];
+        let (years, months) = (self.months / 12, self.months % 12);
+        for (amount, unit) in [(years, "year"), (months, "mon"), (self.days, "day")] {
+            match amount {
+                0 => {}
+                1 => parts.push(format!("1 {}", unit)),
+                n => parts.push(format!("{} {}s", n, unit)),
+            }
+        }
+
+        if self.micros != 0 || parts.is_empty() {
+            let sign = if self.micros < 0 { "-" } else { "" };
+            let micros = self.micros.unsigned_abs();
+            let seconds = micros / 1_000_000;
+            let mut time = format!(
+                "{}{:02}:{:02}:{:02}",
+                sign,
+                seconds / 3600,
+                seconds / 60 % 60,
+                seconds % 60
+            );
+            match micros % 1_000_000 {
+                0 => {}
+                fraction => time += format!(".{:06}", fraction).trim_end_matches('0'),
+            }
+            parts.push(time);
+        }
+
+        f.write_str(&parts.join(" "))
+    }
+}
+
+impl FromStr for Interval {
+    type Err = ParseError;
+
+    /// Parses intervals written as amounts of units, like 1 year 2 months 3 days 4 hours, with the time optionally
+    /// written as HH:MM:SS[.ffffff]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseError(format!("invalid interval: {}", s));
+        let mut interval = Interval::default();
+        let mut words = s.split_whitespace().peekable();
+        if words.peek().is_none() {
+            return Err(err());
+        }
+
+        while let Some(word) = words.next() {
+            if word.contains(':') {
+                let (negative, time) = match word.strip_prefix('-') {
+                    Some(t) => (true, t),
+                    None => (false, word),
+                };
+                let micros = parse_time(time).ok_or_else(err)?;
+                let micros = if negative { -micros } else { micros };
+                interval.micros = interval.micros.checked_add(micros).ok_or_else(err)?;
+                continue;
+            }
+
+            let amount: i64 = word.parse().map_err(|_| err())?;
+            let unit = words.next().ok_or_else(err)?.to_lowercase();
+            // total adds the amount in the unit to a field, failing rather than overflowing
+            let total = |field: i64, unit: i64| {
+                amount
+                    .checked_mul(unit)
+                    .and_then(|n| n.checked_add(field))
+                    .ok_or_else(err)
+            };
+            let overflow = |n: i64| i32::try_from(n).map_err(|_| err());
+            match unit.trim_end_matches('s') {
+                "year" => interval.months = overflow(total(interval.months.into(), 12)?)?,
+                "mon" | "month" => interval.months = overflow(total(interval.months.into(), 1)?)?,
+                "week" => interval.days = overflow(total(interval.days.into(), 7)?)?,
+                "day" => interval.days = overflow(total(interval.days.into(), 1)?)?,
+                "hour" => interval.micros = total(interval.micros, 3_600_000_000)?,
+                "min" | "minute" => interval.micros = total(interval.micros, 60_000_000)?,
+                "sec" | "second" => interval.micros = total(interval.micros, 1_000_000)?,
+                _ => return Err(err()),
+            }
+        }
+
+        Ok(interval)
+    }
+}
+
+/// parse_time parses HH:MM[:SS[.ffffff]] into microseconds, allowing any number of hours that fits
+fn parse_time(s: &str) -> Option<i64> {
+    let (time, fraction) = match s.find('.') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => (s, ""),
+    };
+    let parts = time
+        .split(':')
+        .map(|p| p.parse::<i64>().ok())
+        .collect::<Option<Vec<i64>>>()?;
+    let (hours, minutes, seconds) = match parts.as_slice() {
+        [h, m, s] => (*h, *m, *s),
+        [h, m] => (*h, *m, 0),
+        _ => return None,
+    };
+    if minutes > 59 || seconds > 59 || fraction.len() > 6 {
+        return None;
+    }
+    let micros = match fraction {
+        "" => 0,
+        f => format!("{:0<6}", f).parse::<i64>().ok()?,
+    };
+
+    hours
+        .checked_mul(60)?
+        .checked_add(minutes)?
+        .checked_mul(60)?
+        .checked_add(seconds)?
+        .checked_mul(1_000_000)?
+        .checked_add(micros)
+}
+
+/// ParseError is returned when a value can't be parsed from text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError(pub String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub const MICROS_PER_DAY: i64 = 86_400_000_000;
+
+/// MAX_DAYS is the furthest a date can be from 1970-01-01 in either direction, so its midnight fits in a timestamp
+pub const MAX_DAYS: i32 = (i64::MAX / MICROS_PER_DAY) as i32;
+
+/// days_from_civil returns the number of days since 1970-01-01 for a date in the proleptic Gregorian calendar
+pub fn days_from_civil(year: i32, month: u32, day: u32) -> i64 {
+    let year = i64::from(year) - if month <= 2 { 1 } else { 0 };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let month = i64::from(month);
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// civil_from_days returns the (year, month, day) for a number of days since 1970-01-01
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = if days >= 0 { days } else { days - 146_096 } / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// parse_date parses a YYYY-MM-DD date into days since 1970-01-01
+pub fn parse_date(s: &str) -> Result<i32, ParseError> {
+    let err = || ParseError(format!("invalid date: {}", s));
+    let mut parts = s.splitn(3, '-');
+    let mut next = || parts.next().ok_or_else(err);
+    let (year, month, day) = (next()?, next()?, next()?);
+
+    let year: i32 = year.parse().map_err(|_| err())?;
+    let month: u32 = month.parse().map_err(|_| err())?;
+    let day: u32 = day.parse().map_err(|_| err())?;
+    if !(1..=12).contains(&month) || day == 0 || day > 31 {
+        return Err(err());
+    }
+
+    let days = days_from_civil(year, month, day);
+    // Catch days past the end of the month like 2021-02-30
+    if civil_from_days(days) != (year.into(), month, day) {
+        return Err(err());
+    }
+    i32::try_from(days)
+        .ok()
+        .filter(|d| (-MAX_DAYS..=MAX_DAYS).contains(d))
+        .ok_or_else(|| ParseError(format!("date out of range: {}", s)))
+}
+
+/// format_date formats days since 1970-01-01 as YYYY-MM-DD
+pub fn format_date(days: i32) -> String {
+    format_days(days.into())
+}
+
+fn format_days(days: i64) -> String {
+    let (year, month, day) = civil_from_days(days);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// parse_timestamp parses a YYYY-MM-DD HH:MM:SS[.ffffff] timestamp into microseconds since 1970-01-01. A T may be
+/// used between the date and time and the time may be left off entirely.
+pub fn parse_timestamp(s: &str) -> Result<i64, ParseError> {
+    let err = || ParseError(format!("invalid timestamp: {}", s));
+    let s = s.trim_end_matches('Z');
+    let (date, time) = match s.find([' ', 'T']) {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => (s, "00:00:00"),
+    };
+    let days = parse_date(date).map_err(|_| err())?;
+    let micros = parse_time(time)
+        .filter(|m| (0..MICROS_PER_DAY).contains(m))
+        .ok_or_else(err)?;
+
+    // The last day that fits ends before midnight
+    (days as i64 * MICROS_PER_DAY)
+        .checked_add(micros)
+        .ok_or_else(err)
+}
+
+/// format_timestamp formats microseconds since 1970-01-01 as YYYY-MM-DD HH:MM:SS[.ffffff]
+pub fn format_timestamp(micros: i64) -> String {
+    let days = micros.div_euclid(MICROS_PER_DAY);
+    let time = micros.rem_euclid(MICROS_PER_DAY);
+    let seconds = time / 1_000_000;
+    let fraction = time % 1_000_000;
+
+    let formatted = format!(
+        "{} {:02}:{:02}:{:02}",
+        format_days(days),
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    );
+    match fraction {
+        0 => formatted,
+        f => format!("{}.{:06}", formatted, f),
+    }
+}
+
+/// system_time_micros returns the microseconds since 1970-01-01 for a SystemTime
+pub fn system_time_micros(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_micros() as i64,
+        Err(e) => -(e.duration().as_micros() as i64),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decimals_compare_by_value() {
+        let a: Decimal = "1.50".parse().unwrap();
+        let b: Decimal = "1.5".parse().unwrap();
+        assert_eq!(a, b);
+        let decimal = |value, scale| Decimal::new(value, scale).unwrap();
+        assert!(decimal(-1, 0) < decimal(5, 1));
+        assert!(decimal(2, 0) > decimal(199, 2));
+        assert_eq!(Decimal::new(2, 0), Some(Decimal::from(2)));
+
+        assert_eq!(a.to_string(), "1.5");
+        assert_eq!(Decimal::from(-3).to_string(), "-3");
+        assert_eq!(Decimal::new(-5, 3).unwrap().to_string(), "-0.005");
+        assert_eq!(Decimal::new(1_234_565, 9), Decimal::new(1235, 6));
+        assert_eq!(Decimal::new(-1_234_565, 9), Decimal::new(-1235, 6));
+        assert_eq!(Decimal::new(1, 60), Some(Decimal::default()));
+        assert_eq!(Decimal::new(i128::MAX, 0), None);
+        assert!("1.2.3".parse::<Decimal>().is_err());
+
+        // Values too large to compare as floats still compare exactly
+        let big = Decimal::from_units(i128::MAX - 1);
+        assert!(big < Decimal::from_units(i128::MAX));
+        assert_ne!(big, Decimal::from_units(i128::MAX));
+    }
+
+    #[test]
+    fn dates_round_trip() {
+        assert_eq!(parse_date("1970-01-01"), Ok(0));
+        assert_eq!(parse_date("2000-03-01"), Ok(11017));
+        assert_eq!(parse_date("1969-12-31"), Ok(-1));
+        assert!(parse_date("2021-02-29").is_err());
+
+        for days in [-200_000, -1, 0, 59, 11017, 19_000, MAX_DAYS] {
+            assert_eq!(parse_date(&format_date(days)), Ok(days));
+        }
+
+        // Dates too far out to be timestamps are refused, but any stored date can still be formatted
+        assert!(parse_date(&format_date(MAX_DAYS + 1)).is_err());
+        assert!(parse_date("2000000000-01-01").is_err());
+        assert_eq!(format_date(i32::MAX), "5881580-07-11");
+        assert_eq!(format_date(i32::MIN), "-5877641-06-23");
+    }
+
+    #[test]
+    fn timestamps_round_trip() {
+        let t = parse_timestamp("2021-06-01 12:34:56.5").unwrap();
+        assert_eq!(format_timestamp(t), "2021-06-01 12:34:56.500000");
+        assert_eq!(parse_timestamp("2021-06-01T12:34:56.500000Z"), Ok(t));
+        assert_eq!(parse_timestamp("1970-01-01"), Ok(0));
+        assert_eq!(format_timestamp(-1), "1969-12-31 23:59:59.999999");
+        assert!(parse_timestamp(&format!("{} 23:59:59", format_date(MAX_DAYS))).is_err());
+        assert_eq!(parse_timestamp(&format_timestamp(i64::MAX)), Ok(i64::MAX));
+        assert_eq!(format_timestamp(i64::MIN), "-290308-12-21 19:59:05.224192");
+    }
+
+    #[test]
+    fn intervals_round_trip() {
+        let interval: Interval = "1 year 2 months 3 days 4 hours 30 mins".parse().unwrap();
+        assert_eq!(
+            interval,
+            Interval {
+                months: 14,
+                days: 3,
+                micros: 16_200_000_000
+            }
+        );
+        assert_eq!(interval.to_string(), "1 year 2 mons 3 days 04:30:00");
+        assert_eq!(interval.to_string().parse(), Ok(interval));
+
+        let interval: Interval = "-00:00:01.5".parse().unwrap();
+        assert_eq!(interval.micros, -1_500_000);
+        assert_eq!(interval.to_string(), "-00:00:01.5");
+        assert_eq!(Interval::default().to_string(), "00:00:00");
+        assert!("3 fortnights".parse::<Interval>().is_err());
+        for overflow in [
+            "10000000000 hours",
+            "2000000000 months 2000000000 months",
+            "5000000000000:00",
+            "9223372036854775807 secs",
+        ] {
+            assert!(overflow.parse::<Interval>().is_err(), "{}", overflow);
+        }
+    }
+}
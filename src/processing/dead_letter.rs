@@ -0,0 +1,231 @@
+//! Dead letters are updates workers couldn't process.
+//!
+//! When an operation fails on a batch its worker retries the updates one at a time and sends each one that still fails
+//! to the graph's dead letter queue, recording the worker and the error. Everything else carries on through the graph.
+//! Once the cause is fixed, usually by adding a corrected worker, the dead letters can be taken from the queue and
+//! replayed into the graph with `MessageRouter::replay_dead_letters`.
+
+use crate::operations::data::{Diff, Timestamp};
+use crate::operations::ProcessError;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// CAPACITY is the default number of dead letters kept before the oldest are dropped
+const CAPACITY: usize = 10_000;
+
+/// DeadLetter is an update a worker couldn't process, along with why
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadLetter {
+    /// Identifies the letter in its queue
+    pub id: u64,
+    pub node: usize,
+    pub timestamp: Timestamp,
+    pub update: Diff,
+    pub error: ProcessError,
+}
+
+#[derive(Debug)]
+struct Letters {
+    letters: VecDeque<DeadLetter>,
+    next_id: u64,
+    capacity: usize,
+    dropped: u64,
+}
+
+/// DeadLetterQueue keeps the most recent dead letters of a graph
+#[derive(Debug)]
+pub struct DeadLetterQueue {
+    letters: Mutex<Letters>,
+}
+
+impl Default for DeadLetterQueue {
+    fn default() -> Self {
+        Self::new(CAPACITY)
+    }
+}
+
+impl DeadLetterQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            letters: Mutex::new(Letters {
+                letters: VecDeque::new(),
+                next_id: 1,
+                capacity,
+                dropped: 0,
+            }),
+        }
+    }
+
+    /// set_capacity sets how many letters are kept, dropping the oldest if there are already more
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut letters = self.letters.lock().unwrap(); // Fine with panicking on thread poisoning
+        letters.capacity = capacity;
+        letters.truncate();
+    }
+
+    /// push adds a letter to the queue and returns its id
+    pub fn push(
+        &self,
+        node: usize,
+        timestamp: Timestamp,
+        update: Diff,
+        error: ProcessError,
+    ) -> u64 {
+        let mut letters = self.letters.lock().unwrap(); // Fine with panicking on thread poisoning
+        let id = letters.next_id;
+        letters.next_id += 1;
+        letters.letters.push_back(DeadLetter {
+            id,
+            node,
+            timestamp,
+            update,
+            error,
+        });
+        letters.truncate();
+        id
+    }
+
+    /// list returns every letter in the queue, oldest first
+    pub fn list(&self) -> Vec<DeadLetter> {
+        let letters = self.letters.lock().unwrap(); // Fine with panicking on thread poisoning
+        letters.letters.iter().cloned().collect()
+    }
+
+    /// for_node returns the letters for updates the worker failed to process, oldest first
+    pub fn for_node(&self, node: usize) -> Vec<DeadLetter> {
+        let letters = self.letters.lock().unwrap(); // Fine with panicking on thread poisoning
+        letters
+            .letters
+            .iter()
+            .filter(|l| l.node == node)
+            .cloned()
+            .collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<DeadLetter> {
+        let letters = self.letters.lock().unwrap(); // Fine with panicking on thread poisoning
+        letters.letters.iter().find(|l| l.id == id).cloned()
+    }
+
+    /// take removes and returns the letters matching the predicate, oldest first
+    pub fn take<F: Fn(&DeadLetter) -> bool>(&self, predicate: F) -> Vec<DeadLetter> {
+        let mut letters = self.letters.lock().unwrap(); // Fine with panicking on thread poisoning
+        let (taken, kept) = letters.letters.drain(..).partition(|l| predicate(l));
+        letters.letters = kept;
+        taken.into_iter().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.letters.lock().unwrap().letters.len() // Fine with panicking on thread poisoning
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// dropped returns the number of letters dropped to keep the queue within its capacity
+    pub fn dropped(&self) -> u64 {
+        self.letters.lock().unwrap().dropped // Fine with panicking on thread poisoning
+    }
+}
+
+impl Letters {
+    fn truncate(&mut self) {
+        while self.letters.len() > self.capacity {
+            self.letters.pop_front();
+            self.dropped += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::data::{RowUpdate, Source};
+    use crate::operations::schema::{ColumnSchema, ColumnType, Schema};
+    use crate::operations::state::MemStore;
+    use crate::operations::{Base, Count, Map, Operation, State};
+    use crate::processing::{MessageRouter, OpWorker, ReaderWorker};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn replays_dead_letters_to_fixed_workers() {
+        let router = Arc::new(MessageRouter::new());
+        let schema = Schema::new(vec![
+            ColumnSchema::new("id", ColumnType::Integer, false),
+            ColumnSchema::new("name", ColumnType::Text, true),
+        ]);
+        let mut base = OpWorker::new(router.clone(), Base { schema }, vec![]).unwrap();
+        let broken = Map {
+            sources: vec![Source::Column(1.into())],
+        };
+        let mut broken = OpWorker::new(router.clone(), broken, vec![base.id]).unwrap();
+        let base_id = base.id;
+        let broken_id = broken.id;
+        thread::spawn(move || base.start());
+        thread::spawn(move || broken.start());
+
+        // Rows written straight to the router aren't checked against the schema
+        let short = RowUpdate::Add(vec![2.into()].into());
+        let t = router
+            .write(
+                base_id,
+                vec![
+                    RowUpdate::Add(vec![1.into(), "a".into()].into()),
+                    short.clone(),
+                ],
+            )
+            .unwrap();
+        assert!(router.wait_for(broken_id, t, Duration::from_secs(5)));
+        let letters = router.dead_letters().for_node(broken_id);
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].update, Diff::from(short));
+        assert_eq!(
+            router.dead_letters().get(letters[0].id),
+            Some(letters[0].clone())
+        );
+
+        // A fixed worker only maps the column every row has
+        let fixed = Map {
+            sources: vec![Source::Column(0.into())],
+        };
+        let mut fixed = OpWorker::new(router.clone(), fixed, vec![base_id]).unwrap();
+        let mut reader = ReaderWorker::new(router.clone(), vec![fixed.id], vec![]).unwrap();
+        let (reader_id, view) = (reader.id, reader.view());
+        let fixed_id = fixed.id;
+        thread::spawn(move || fixed.start());
+        thread::spawn(move || reader.start());
+
+        let t = router.replay_dead_letters(broken_id, fixed_id).unwrap();
+        assert!(router.wait_for(reader_id, t, Duration::from_secs(5)));
+        assert_eq!(view.rows(), vec![vec![2.into()].into()]);
+        assert!(router.dead_letters().is_empty());
+        assert_eq!(router.replay_dead_letters(broken_id, fixed_id), None);
+    }
+
+    #[test]
+    fn drops_the_oldest_letters() {
+        let queue = DeadLetterQueue::new(2);
+        let mut count = Count {
+            source: Source::Literal(1.into()),
+            group: vec![],
+            state: MemStore::new(),
+        };
+        count.state.set(vec![], vec![i32::MAX.into()]);
+        let update = RowUpdate::Add(vec![1.into()].into());
+        let error = count.process(vec![update.clone()].into()).unwrap_err();
+        let update = Diff::from(update);
+        assert!(matches!(error, ProcessError::Overflow(_)));
+
+        for timestamp in 1..=3 {
+            queue.push(0, timestamp, update.clone(), error.clone());
+        }
+        let timestamps: Vec<Timestamp> = queue.list().iter().map(|l| l.timestamp).collect();
+        assert_eq!(timestamps, vec![2, 3]);
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(queue.take(|l| l.timestamp == 2).len(), 1);
+        assert_eq!(queue.len(), 1);
+    }
+}
@@ -0,0 +1,658 @@
+//! Builds graphs from JSON definitions so they can be changed without recompiling.
+//!
+//! A definition lists the nodes of the graph in order, each naming its parents. Parents must be defined before their
+//! children:
+//!
+//! ```json
+//! {"nodes": [
+//!     {"name": "items", "type": "base", "columns": [
+//!         {"name": "id", "type": "integer", "nullable": false},
+//!         {"name": "category", "type": "text"}
+//!     ]},
+//!     {"name": "cheap", "type": "filter", "parents": ["items"], "constraints": [
+//!         {"column": "price", "op": "<", "value": 10},
+//!         {"column": "category", "in": ["a", "b"]},
+//!         {"column": "category", "op": "=", "value": "A", "collation": "case_insensitive"}
+//!     ]},
+//!     {"name": "categories", "type": "map", "parents": ["cheap"], "sources": ["category", {"literal": 1}]},
+//!     {"name": "counts", "type": "count", "parents": ["categories"], "group": ["category"]},
+//!     {"name": "by_category", "type": "view", "parents": ["counts"], "key": ["category"]},
+//!     {"name": "print", "type": "debug", "parents": ["counts"]}
+//! ]}
+//! ```
+//!
+//! Columns are referred to by name or by position. Map and count sources are a column or `{"literal": value}`, and
+//! counts count every row when no source is given. Columns are nullable unless they say otherwise. Types are any,
+//! integer, text, boolean, float, bigint, double, decimal, timestamp, date, interval or bytes.
+
+use super::worker::{DebugWorker, Worker};
+use super::{Message, MessageRouter, OpWorker, ReaderWorker, View};
+use crate::operations::data::{Collation, Column, Comparison, DataType, Source};
+use crate::operations::filter::{ColumnConstraint, Constraint};
+use crate::operations::json::Json;
+use crate::operations::schema::{ColumnSchema, ColumnType, Schema, SchemaError};
+use crate::operations::state::{Key, SharedStore};
+use crate::operations::types::ParseError;
+use crate::operations::{Base, Count, Filter, Map};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// DefinitionError is returned when a graph can't be built from a definition
+#[derive(Debug)]
+pub enum DefinitionError {
+    Json(ParseError),
+    /// The node's definition is missing something or has a value of the wrong kind
+    Invalid {
+        node: String,
+        message: String,
+    },
+    UnknownNode(String),
+    DuplicateNode(String),
+    Schema {
+        node: String,
+        error: SchemaError,
+    },
+}
+
+impl fmt::Display for DefinitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DefinitionError::Json(e) => e.fmt(f),
+            DefinitionError::Invalid { node, message } => write!(f, "node {}: {}", node, message),
+            DefinitionError::UnknownNode(name) => write!(f, "no node named {}", name),
+            DefinitionError::DuplicateNode(name) => write!(f, "node {} is defined twice", name),
+            DefinitionError::Schema { node, error } => write!(f, "node {}: {}", node, error),
+        }
+    }
+}
+
+impl std::error::Error for DefinitionError {}
+
+/// NodeKind is what a node of the definition does
+pub enum NodeKind {
+    Base(Schema),
+    Filter(Vec<ColumnConstraint>),
+    Map(Vec<Source>),
+    Count {
+        source: Source,
+        group: Vec<Column>,
+    },
+    /// Materializes rows so they can be read by the key columns
+    View {
+        key: Vec<Column>,
+    },
+    /// Prints the updates it receives
+    Debug,
+}
+
+pub struct Node {
+    pub name: String,
+    pub parents: Vec<String>,
+    pub kind: NodeKind,
+}
+
+/// Definition describes a graph that can be built on a router
+pub struct Definition {
+    pub nodes: Vec<Node>,
+}
+
+impl Definition {
+    /// parse reads a definition from JSON
+    pub fn parse(s: &str) -> Result<Self, DefinitionError> {
+        let json = Json::parse(s).map_err(DefinitionError::Json)?;
+        let nodes = match json.get("nodes") {
+            Some(Json::Array(nodes)) => nodes,
+            _ => {
+                return Err(DefinitionError::Invalid {
+                    node: "".into(),
+                    message: "expected a list of nodes".into(),
+                })
+            }
+        };
+
+        nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| {
+                let name = node
+                    .get("name")
+                    .and_then(Json::as_str)
+                    .map_or_else(|| format!("#{}", i + 1), String::from);
+                parse_node(&name, node).map_err(|message| DefinitionError::Invalid {
+                    node: name.clone(),
+                    message,
+                })
+            })
+            .collect::<Result<Vec<Node>, DefinitionError>>()
+            .map(|nodes| Self { nodes })
+    }
+
+    /// build adds a worker for every node to the router. The workers don't run until the graph is started.
+    pub fn build(self, router: Arc<MessageRouter>) -> Result<Graph, DefinitionError> {
+        let mut graph = Graph {
+            router,
+            nodes: vec![],
+            views: HashMap::new(),
+            states: HashMap::new(),
+            helpers: vec![],
+            workers: vec![],
+            threads: vec![],
+            started: false,
+        };
+        for node in self.nodes {
+            graph.add(node)?;
+        }
+        Ok(graph)
+    }
+}
+
+impl Node {
+    /// parse reads a single node definition from JSON
+    pub fn parse(s: &str) -> Result<Self, DefinitionError> {
+        let json = Json::parse(s).map_err(DefinitionError::Json)?;
+        let name =
+            json.get("name")
+                .and_then(Json::as_str)
+                .ok_or_else(|| DefinitionError::Invalid {
+                    node: "".into(),
+                    message: "nodes need a name".into(),
+                })?;
+        parse_node(name, &json).map_err(|message| DefinitionError::Invalid {
+            node: name.into(),
+            message,
+        })
+    }
+}
+
+impl NodeKind {
+    /// name returns the type of node as written in definitions
+    pub fn name(&self) -> &'static str {
+        match self {
+            NodeKind::Base(_) => "base",
+            NodeKind::Filter(_) => "filter",
+            NodeKind::Map(_) => "map",
+            NodeKind::Count { .. } => "count",
+            NodeKind::View { .. } => "view",
+            NodeKind::Debug => "debug",
+        }
+    }
+}
+
+struct GraphNode {
+    name: String,
+    id: usize,
+    parents: Vec<String>,
+    kind: &'static str,
+    // Tables built elsewhere aren't stopped by the graph
+    external: bool,
+}
+
+/// Graph is a graph built from a definition
+pub struct Graph {
+    pub router: Arc<MessageRouter>,
+    nodes: Vec<GraphNode>,
+    views: HashMap<String, Arc<View>>,
+    // The state of each count node, shared with its worker
+    states: HashMap<String, SharedStore>,
+    // Workers run with the graph that aren't nodes, like readers used by SQL
+    helpers: Vec<usize>,
+    workers: Vec<Box<dyn Worker + Send>>,
+    threads: Vec<JoinHandle<()>>,
+    started: bool,
+}
+
+impl Graph {
+    /// add adds a worker for the node to the router. Its parents must already be in the graph. Once the graph has
+    /// been started the worker starts running straight away.
+    pub fn add(&mut self, node: Node) -> Result<usize, DefinitionError> {
+        if self.id(&node.name).is_some() {
+            return Err(DefinitionError::DuplicateNode(node.name));
+        }
+        let parents = node
+            .parents
+            .iter()
+            .map(|p| {
+                self.id(p)
+                    .ok_or_else(|| DefinitionError::UnknownNode(p.clone()))
+            })
+            .collect::<Result<Vec<usize>, DefinitionError>>()?;
+
+        let router = self.router.clone();
+        let kind = node.kind.name();
+        let name = node.name.clone();
+        let schema_error = |error| DefinitionError::Schema {
+            node: name.clone(),
+            error,
+        };
+        let worker: Box<dyn Worker + Send> = match node.kind {
+            NodeKind::Base(schema) => {
+                Box::new(OpWorker::new(router, Base { schema }, parents).map_err(schema_error)?)
+            }
+            NodeKind::Filter(constraints) => Box::new(
+                OpWorker::new(router, Filter { constraints }, parents).map_err(schema_error)?,
+            ),
+            NodeKind::Map(sources) => {
+                Box::new(OpWorker::new(router, Map { sources }, parents).map_err(schema_error)?)
+            }
+            NodeKind::Count { source, group } => {
+                let state = SharedStore::new();
+                let count = Count {
+                    source,
+                    group,
+                    state: state.clone(),
+                };
+                let w = OpWorker::new(router, count, parents).map_err(schema_error)?;
+                self.states.insert(node.name.clone(), state);
+                Box::new(w)
+            }
+            NodeKind::View { key } => {
+                let w = ReaderWorker::new(router, parents, key).map_err(schema_error)?;
+                self.views.insert(node.name.clone(), w.view());
+                Box::new(w)
+            }
+            NodeKind::Debug => Box::new(DebugWorker::new(router, parents).map_err(schema_error)?),
+        };
+
+        let id = worker.id();
+        self.nodes.push(GraphNode {
+            name: node.name,
+            id,
+            parents: node.parents,
+            kind,
+            external: false,
+        });
+        match self.started {
+            true => self.threads.push(self.spawn(worker)),
+            false => self.workers.push(worker),
+        }
+        Ok(id)
+    }
+
+    /// run adds a worker that isn't a node, like a reader used to query a table, so it's run and stopped along with the
+    /// graph
+    pub fn run(&mut self, worker: Box<dyn Worker + Send>) {
+        self.helpers.push(worker.id());
+        match self.started {
+            true => self.threads.push(self.spawn(worker)),
+            false => self.workers.push(worker),
+        }
+    }
+
+    /// add_table adds a base table that was built elsewhere, like one created through SQL, so nodes can use it as a
+    /// parent. The graph doesn't run or stop its worker.
+    pub fn add_table(&mut self, name: &str, id: usize) -> Result<(), DefinitionError> {
+        if self.id(name).is_some() {
+            return Err(DefinitionError::DuplicateNode(name.into()));
+        }
+        self.nodes.push(GraphNode {
+            name: name.into(),
+            id,
+            parents: vec![],
+            kind: "base",
+            external: true,
+        });
+        Ok(())
+    }
+
+    /// kind returns the type of the named node
+    pub fn kind(&self, name: &str) -> Option<&'static str> {
+        self.nodes.iter().find(|n| n.name == name).map(|n| n.kind)
+    }
+
+    /// parents returns the names of the named node's parents
+    pub fn parents(&self, name: &str) -> Option<&[String]> {
+        self.nodes
+            .iter()
+            .find(|n| n.name == name)
+            .map(|n| n.parents.as_slice())
+    }
+
+    /// id returns the worker id of the named node
+    pub fn id(&self, name: &str) -> Option<usize> {
+        self.nodes.iter().find(|n| n.name == name).map(|n| n.id)
+    }
+
+    /// name returns the name of the node with the worker id
+    pub fn name(&self, id: usize) -> Option<&str> {
+        self.nodes
+            .iter()
+            .find(|n| n.id == id)
+            .map(|n| n.name.as_str())
+    }
+
+    /// names returns the names of the nodes in the order they were defined
+    pub fn names(&self) -> Vec<&str> {
+        self.nodes.iter().map(|n| n.name.as_str()).collect()
+    }
+
+    /// tables returns the names of the base tables
+    pub fn tables(&self) -> Vec<&str> {
+        self.nodes
+            .iter()
+            .filter(|n| n.kind == "base")
+            .map(|n| n.name.as_str())
+            .collect()
+    }
+
+    /// view returns the rows materialized by the named view node
+    pub fn view(&self, name: &str) -> Option<Arc<View>> {
+        self.views.get(name).cloned()
+    }
+
+    /// state returns the entries held in the named node's state, sorted by key. Only count nodes keep state, mapping
+    /// each group to its count.
+    pub fn state(&self, name: &str) -> Option<Vec<(Key, Vec<DataType>)>> {
+        self.states.get(name).map(SharedStore::entries)
+    }
+
+    /// leaves returns the ids of the nodes without children. Once they've reached a timestamp the whole graph has.
+    pub fn leaves(&self) -> Vec<usize> {
+        self.nodes
+            .iter()
+            .filter(|n| !self.nodes.iter().any(|c| c.parents.contains(&n.name)))
+            .map(|n| n.id)
+            .collect()
+    }
+
+    /// start runs every worker on its own thread
+    pub fn start(&mut self) {
+        self.started = true;
+        for worker in std::mem::take(&mut self.workers) {
+            let thread = self.spawn(worker);
+            self.threads.push(thread);
+        }
+    }
+
+    /// take_workers removes the workers that haven't been started so they can be run some other way, like by an
+    /// Executor
+    pub fn take_workers(&mut self) -> Vec<Box<dyn Worker + Send>> {
+        std::mem::take(&mut self.workers)
+    }
+
+    fn spawn(&self, mut worker: Box<dyn Worker + Send>) -> JoinHandle<()> {
+        let router = Arc::clone(&self.router);
+        thread::spawn(move || {
+            for epoch in router.epochs(worker.id()) {
+                worker.handle(epoch);
+            }
+            worker.stop();
+        })
+    }
+
+    /// stop tells every worker to stop and waits for their threads to finish
+    pub fn stop(self) {
+        let nodes = self.nodes.iter().filter(|n| !n.external).map(|n| n.id);
+        for id in nodes.chain(self.helpers.iter().copied()) {
+            self.router.send_message(id, Message::Stop);
+        }
+        for thread in self.threads {
+            thread.join().unwrap(); // Workers only panic on bugs, so pass them along
+        }
+    }
+}
+
+fn parse_node(name: &str, node: &Json) -> Result<Node, String> {
+    let parents = match node.get("parents") {
+        None => vec![],
+        Some(Json::Array(parents)) => parents
+            .iter()
+            .map(|p| p.as_str().map(String::from).ok_or("parents must be names"))
+            .collect::<Result<Vec<String>, &str>>()?,
+        Some(_) => return Err("parents must be a list".into()),
+    };
+
+    let list = |field: &str| match node.get(field) {
+        Some(Json::Array(values)) => Ok(values.as_slice()),
+        None => Ok(&[][..]),
+        Some(_) => Err(format!("{} must be a list", field)),
+    };
+    let kind = match node.get("type").and_then(Json::as_str) {
+        Some("base") => NodeKind::Base(Schema::new(
+            list("columns")?
+                .iter()
+                .map(parse_column_schema)
+                .collect::<Result<_, _>>()?,
+        )),
+        Some("filter") => NodeKind::Filter(
+            list("constraints")?
+                .iter()
+                .map(parse_constraint)
+                .collect::<Result<_, _>>()?,
+        ),
+        Some("map") => NodeKind::Map(
+            list("sources")?
+                .iter()
+                .map(parse_source)
+                .collect::<Result<_, _>>()?,
+        ),
+        Some("count") => NodeKind::Count {
+            source: node
+                .get("source")
+                .map_or(Ok(Source::Literal(1.into())), parse_source)?,
+            group: list("group")?
+                .iter()
+                .map(parse_column)
+                .collect::<Result<_, _>>()?,
+        },
+        Some("view") => NodeKind::View {
+            key: list("key")?
+                .iter()
+                .map(parse_column)
+                .collect::<Result<_, _>>()?,
+        },
+        Some("debug") => NodeKind::Debug,
+        Some(t) => return Err(format!("unknown node type {}", t)),
+        None => return Err("missing type".into()),
+    };
+
+    Ok(Node {
+        name: name.into(),
+        parents,
+        kind,
+    })
+}
+
+fn parse_column_schema(column: &Json) -> Result<ColumnSchema, String> {
+    let name = column
+        .get("name")
+        .and_then(Json::as_str)
+        .ok_or("columns need a name")?;
+    let column_type = match column.get("type").and_then(Json::as_str) {
+        Some("any") => ColumnType::Any,
+        Some("integer") => ColumnType::Integer,
+        Some("text") => ColumnType::Text,
+        Some("boolean") => ColumnType::Boolean,
+        Some("float") => ColumnType::Float,
+        Some("bigint") => ColumnType::BigInt,
+        Some("double") => ColumnType::Double,
+        Some("decimal") => ColumnType::Decimal,
+        Some("timestamp") => ColumnType::Timestamp,
+        Some("date") => ColumnType::Date,
+        Some("interval") => ColumnType::Interval,
+        Some("bytes") => ColumnType::Bytes,
+        Some(t) => return Err(format!("column {} has unknown type {}", name, t)),
+        None => return Err(format!("column {} needs a type", name)),
+    };
+    let nullable = column.get("nullable") != Some(&Json::Bool(false));
+    Ok(ColumnSchema::new(name, column_type, nullable))
+}
+
+fn parse_column(column: &Json) -> Result<Column, String> {
+    match column {
+        Json::String(name) => Ok(Column::Name(name.clone())),
+        Json::Number(n) => n
+            .parse()
+            .map(Column::Index)
+            .map_err(|_| format!("invalid column position {}", n)),
+        c => Err(format!("expected a column but found {}", c)),
+    }
+}
+
+fn parse_value(value: &Json) -> Result<DataType, String> {
+    value.to_value(ColumnType::Any).map_err(|e| e.to_string())
+}
+
+fn parse_source(source: &Json) -> Result<Source, String> {
+    match source.get("literal") {
+        Some(value) => Ok(Source::Literal(parse_value(value)?)),
+        None => Ok(Source::Column(parse_column(
+            source.get("column").unwrap_or(source),
+        )?)),
+    }
+}
+
+fn parse_constraint(constraint: &Json) -> Result<ColumnConstraint, String> {
+    let column = parse_column(
+        constraint
+            .get("column")
+            .ok_or("constraints need a column")?,
+    )?;
+    if let Some(values) = constraint.get("in") {
+        return match values {
+            Json::Array(values) => Ok(ColumnConstraint {
+                column,
+                constraint: Constraint::In(
+                    values.iter().map(parse_value).collect::<Result<_, _>>()?,
+                ),
+            }),
+            _ => Err("in must be a list of values".into()),
+        };
+    }
+
+    let comparison = match constraint.get("op").and_then(Json::as_str) {
+        Some("=") => Comparison::Equal,
+        Some("<>") | Some("!=") => Comparison::NotEqual,
+        Some(">") => Comparison::GreaterThan,
+        Some("<") => Comparison::LessThan,
+        Some(">=") => Comparison::GreaterEqualThan,
+        Some("<=") => Comparison::LessEqualThan,
+        Some(op) => return Err(format!("unknown comparison {}", op)),
+        None => return Err("constraints need an op or in".into()),
+    };
+    let value = parse_value(constraint.get("value").ok_or("comparisons need a value")?)?;
+    let constraint = match constraint.get("collation").and_then(Json::as_str) {
+        None | Some("binary") => Constraint::Comparison(comparison, value),
+        Some("case_insensitive") => {
+            Constraint::Collated(comparison, value, Collation::CaseInsensitive)
+        }
+        Some(c) => return Err(format!("unknown collation {}", c)),
+    };
+    Ok(ColumnConstraint { column, constraint })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::data::RowUpdate;
+    use std::time::Duration;
+
+    #[test]
+    fn builds_graphs() {
+        let definition = r#"{"nodes": [
+            {"name": "items", "type": "base", "columns": [
+                {"name": "id", "type": "integer", "nullable": false},
+                {"name": "category", "type": "text"},
+                {"name": "price", "type": "integer"}
+            ]},
+            {"name": "cheap", "type": "filter", "parents": ["items"], "constraints": [
+                {"column": "price", "op": "<", "value": 10},
+                {"column": "category", "op": "<>", "value": "B", "collation": "case_insensitive"}
+            ]},
+            {"name": "categories", "type": "map", "parents": ["cheap"], "sources": ["category", {"literal": 1}]},
+            {"name": "counts", "type": "count", "parents": ["categories"], "group": [0]},
+            {"name": "by_category", "type": "view", "parents": ["counts"], "key": ["category"]}
+        ]}"#;
+        let router = Arc::new(MessageRouter::new());
+        let mut graph = Definition::parse(definition)
+            .unwrap()
+            .build(router.clone())
+            .unwrap();
+        assert_eq!(graph.tables(), vec!["items"]);
+        assert_eq!(graph.leaves(), vec![graph.id("by_category").unwrap()]);
+        graph.start();
+
+        let row = |id: i32, category: &str, price: i32| -> RowUpdate {
+            RowUpdate::Add(vec![id.into(), category.into(), price.into()].into())
+        };
+        let items = graph.id("items").unwrap();
+        let t = router
+            .write(
+                items,
+                vec![
+                    row(1, "a", 5),
+                    row(2, "a", 7),
+                    row(3, "b", 5),
+                    row(4, "a", 50),
+                ],
+            )
+            .unwrap();
+        assert!(router.wait_for(graph.leaves()[0], t, Duration::from_secs(5)));
+        let view = graph.view("by_category").unwrap();
+        // Count passes on each row with its group's count so far, so both cheap "a" rows reach the view
+        let mut counts = view.lookup(&["a".into()]);
+        counts.sort();
+        assert_eq!(
+            counts,
+            vec![
+                vec!["a".into(), 1.into(), 1.into()].into(),
+                vec!["a".into(), 1.into(), 2.into()].into(),
+            ]
+        );
+        assert_eq!(view.lookup(&["b".into()]), vec![]);
+        assert_eq!(
+            graph.state("counts"),
+            Some(vec![(vec!["a".into()], vec![2.into()])])
+        );
+        assert_eq!(graph.state("cheap"), None);
+
+        // Nodes added to a running graph start straight away
+        let node =
+            Node::parse(r#"{"name": "print", "type": "debug", "parents": ["counts"]}"#).unwrap();
+        let print = graph.add(node).unwrap();
+        assert_eq!(graph.kind("print"), Some("debug"));
+        let t = router.write(items, vec![row(5, "c", 1)]).unwrap();
+        assert!(router.wait_for(print, t, Duration::from_secs(5)));
+        assert!(graph.add_table("items", items).is_err());
+        let bad = r#"{"name": "bad", "type": "count", "parents": ["items"], "group": ["missing"]}"#;
+        assert!(graph.add(Node::parse(bad).unwrap()).is_err());
+        assert_eq!(graph.state("bad"), None);
+
+        // Workers run with the graph are stopped with it too
+        let reader = ReaderWorker::new(router.clone(), vec![items], vec![]).unwrap();
+        let (reader_id, rows) = (reader.id, reader.view());
+        graph.run(Box::new(reader));
+        let t = router.write(items, vec![row(6, "d", 1)]).unwrap();
+        assert!(router.wait_for(reader_id, t, Duration::from_secs(5)));
+        graph.stop();
+        assert_eq!(
+            rows.rows(),
+            vec![vec![6.into(), "d".into(), 1.into()].into()]
+        );
+
+        let err = |definition: &str| {
+            Definition::parse(definition)
+                .and_then(|d| d.build(Arc::new(MessageRouter::new())))
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            err(r#"{"nodes": [{"name": "a", "type": "debug", "parents": ["b"]}]}"#),
+            "no node named b"
+        );
+        assert_eq!(
+            err(
+                r#"{"nodes": [{"name": "a", "type": "base", "columns": [{"name": "x", "type": "uuid"}]}]}"#
+            ),
+            "node a: column x has unknown type uuid"
+        );
+        assert_eq!(
+            err(
+                r#"{"nodes": [{"name": "a", "type": "base"}, {"name": "b", "type": "view", "parents": ["a"], "key": ["x"]}]}"#
+            ),
+            "node b: no column named x"
+        );
+    }
+}
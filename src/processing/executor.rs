@@ -0,0 +1,164 @@
+//! A deterministic way to run a graph on a single thread.
+//!
+//! Workers normally run on their own threads, so tests have to wait for frontiers to move and the order updates
+//! arrive in from different parents can change between runs. An Executor instead runs its workers one at a time in
+//! topological order, handling every epoch that's ready until none are left. The same writes always give the same
+//! updates in the same order, and once `run` returns every worker has caught up with every write.
+
+use crate::processing::definition::Graph;
+use crate::processing::router::{receive, Pending};
+use crate::processing::{MessageRouter, Worker};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Executor runs workers synchronously on the calling thread
+pub struct Executor {
+    router: Arc<MessageRouter>,
+    workers: HashMap<usize, Box<dyn Worker>>,
+    pending: HashMap<usize, Pending>,
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Executor {
+    /// new creates an executor with its own router. The router's channels are unbounded so any number of writes can
+    /// be made between runs.
+    pub fn new() -> Self {
+        Self {
+            router: Arc::new(MessageRouter::unbounded()),
+            workers: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// router returns the router workers run by the executor must be built with
+    pub fn router(&self) -> Arc<MessageRouter> {
+        Arc::clone(&self.router)
+    }
+
+    /// add has the executor run the worker, returning its id
+    pub fn add<W: Worker + 'static>(&mut self, worker: W) -> usize {
+        let id = worker.id();
+        self.workers.insert(id, Box::new(worker));
+        id
+    }
+
+    /// add_graph has the executor run the workers of a graph that haven't been started. The graph must be built with
+    /// the executor's router. Nodes added to the graph later need adding again.
+    pub fn add_graph(&mut self, graph: &mut Graph) {
+        assert!(
+            Arc::ptr_eq(&graph.router, &self.router),
+            "graphs run by an executor must be built with its router"
+        );
+        for worker in graph.take_workers() {
+            self.workers.insert(worker.id(), worker);
+        }
+    }
+
+    /// run handles every message sent to the workers until none are left, returning the number of epochs handled.
+    /// Workers handle their epochs in topological order so each one only runs once its parents have caught up.
+    pub fn run(&mut self) -> usize {
+        let mut handled = 0;
+        loop {
+            let before = handled;
+            for id in self.router.topological_order() {
+                let worker = match self.workers.get_mut(&id) {
+                    Some(worker) => worker,
+                    None => continue, // Run elsewhere, or not at all
+                };
+
+                let pending = self.pending.entry(id).or_default();
+                while let Some(message) = self.router.try_message(id) {
+                    // Stopping means nothing here, the executor only runs when asked to
+                    receive(pending, message);
+                }
+                while let Some(epoch) = self.router.ready_epoch(id, pending) {
+                    worker.handle(epoch);
+                    handled += 1;
+                }
+            }
+
+            if handled == before {
+                return handled;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::data::{Comparison, RowUpdate, Source};
+    use crate::operations::filter::{ColumnConstraint, Constraint};
+    use crate::operations::schema::{ColumnSchema, ColumnType, Schema};
+    use crate::operations::state::MemStore;
+    use crate::operations::{Base, Count, Filter};
+    use crate::processing::definition::Definition;
+    use crate::processing::{OpWorker, ReaderWorker};
+
+    #[test]
+    fn runs_graphs_to_completion() {
+        let mut executor = Executor::new();
+        let router = executor.router();
+        let schema = Schema::new(vec![
+            ColumnSchema::new("id", ColumnType::Integer, false),
+            ColumnSchema::new("flag", ColumnType::Boolean, false),
+        ]);
+        let base = OpWorker::new(router.clone(), Base { schema }, vec![]).unwrap();
+        let filter = Filter {
+            constraints: vec![ColumnConstraint {
+                column: "id".into(),
+                constraint: Constraint::Comparison(Comparison::GreaterThan, 30.into()),
+            }],
+        };
+        let filter = OpWorker::new(router.clone(), filter, vec![base.id]).unwrap();
+        let count = Count {
+            source: Source::Literal(1.into()),
+            group: vec!["flag".into()],
+            state: MemStore::new(),
+        };
+        let count = OpWorker::new(router.clone(), count, vec![filter.id]).unwrap();
+        let reader = ReaderWorker::new(router.clone(), vec![count.id], vec![]).unwrap();
+        let view = reader.view();
+        let base_id = executor.add(base);
+        executor.add(filter);
+        executor.add(count);
+        let reader_id = executor.add(reader);
+
+        // More writes than a bounded channel holds, with nothing running in between
+        let mut last = 0;
+        for id in 0..20 {
+            let row = vec![(id * 10).into(), (id % 2 == 0).into()];
+            last = router
+                .write(base_id, vec![RowUpdate::Add(row.into())])
+                .unwrap();
+        }
+        assert_eq!(executor.run(), 80);
+        assert_eq!(router.frontier(reader_id), last);
+        let rows = view.rows();
+        assert_eq!(rows.len(), 16);
+        assert!(rows.contains(&vec![190.into(), false.into(), 8.into()].into()));
+        assert_eq!(executor.run(), 0);
+
+        let mut graph = Definition::parse(include_str!("../../graphs/example.json"))
+            .unwrap()
+            .build(router.clone())
+            .unwrap();
+        executor.add_graph(&mut graph);
+        let t = router
+            .write(
+                graph.id("rows").unwrap(),
+                vec![RowUpdate::Add(vec![300.into(), true.into()].into())],
+            )
+            .unwrap();
+        executor.run();
+        assert_eq!(router.frontier(reader_id), t);
+        for leaf in graph.leaves() {
+            assert_eq!(router.frontier(leaf), t);
+        }
+    }
+}
@@ -0,0 +1,350 @@
+//! Counters and histograms recorded for each worker in the graph.
+//!
+//! Workers record the rows they receive and send, how long each epoch takes to process and how much state they hold.
+//! The router records how long senders are blocked waiting for room in a worker's queue, and reads queue depths live
+//! when metrics are pulled. A worker that is a bottleneck shows up with a deep queue, long processing times and
+//! senders blocked on it.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Write};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// BATCH_SIZES are the upper bounds of the buckets for the number of rows in an epoch
+const BATCH_SIZES: &[f64] = &[1.0, 10.0, 100.0, 1000.0, 10000.0, 100000.0];
+
+/// SECONDS are the upper bounds of the buckets for durations
+const SECONDS: &[f64] = &[0.0001, 0.001, 0.01, 0.1, 1.0, 10.0];
+
+/// Histogram counts observed values in buckets with fixed upper bounds
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        if let Some(i) = self.bounds.iter().position(|b| value <= *b) {
+            self.counts[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// buckets returns each upper bound with the number of values at or below it
+    pub fn buckets(&self) -> Vec<(f64, u64)> {
+        let mut total = 0;
+        self.bounds
+            .iter()
+            .zip(&self.counts)
+            .map(|(bound, count)| {
+                total += count;
+                (*bound, total)
+            })
+            .collect()
+    }
+}
+
+/// NodeMetrics is everything recorded for a single worker
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeMetrics {
+    /// The type of worker, like filter or count
+    pub kind: String,
+    pub rows_in: u64,
+    pub rows_out: u64,
+    /// Rows the node failed to process
+    pub rows_failed: u64,
+    /// Rows received in each epoch
+    pub batch_size: Histogram,
+    /// Seconds spent processing each epoch
+    pub latency: Histogram,
+    /// Seconds senders spent waiting for room in the worker's queue
+    pub blocked: Histogram,
+    /// Entries held in the worker's state
+    pub state_size: usize,
+    /// Messages waiting in the worker's queue
+    pub queue_depth: usize,
+}
+
+impl Default for NodeMetrics {
+    fn default() -> Self {
+        Self {
+            kind: String::new(),
+            rows_in: 0,
+            rows_out: 0,
+            rows_failed: 0,
+            batch_size: Histogram::new(BATCH_SIZES),
+            latency: Histogram::new(SECONDS),
+            blocked: Histogram::new(SECONDS),
+            state_size: 0,
+            queue_depth: 0,
+        }
+    }
+}
+
+/// Metrics holds what's been recorded for every worker
+#[derive(Debug, Default)]
+pub struct Metrics {
+    nodes: Mutex<HashMap<usize, NodeMetrics>>,
+}
+
+impl Metrics {
+    /// record_epoch records the rows a worker received and sent for an epoch and how long it took
+    pub fn record_epoch(&self, id: usize, rows_in: usize, rows_out: usize, latency: Duration) {
+        let mut nodes = self.nodes.lock().unwrap(); // Fine with panicking on thread poisoning
+        let node = nodes.entry(id).or_default();
+        node.rows_in += rows_in as u64;
+        node.rows_out += rows_out as u64;
+        node.batch_size.observe(rows_in as f64);
+        node.latency.observe(latency.as_secs_f64());
+    }
+
+    /// record_failure records a row the worker failed to process
+    pub fn record_failure(&self, id: usize) {
+        let mut nodes = self.nodes.lock().unwrap(); // Fine with panicking on thread poisoning
+        nodes.entry(id).or_default().rows_failed += 1;
+    }
+
+    /// record_blocked records a sender waiting for room in the worker's queue
+    pub fn record_blocked(&self, id: usize, blocked: Duration) {
+        let mut nodes = self.nodes.lock().unwrap(); // Fine with panicking on thread poisoning
+        nodes
+            .entry(id)
+            .or_default()
+            .blocked
+            .observe(blocked.as_secs_f64());
+    }
+
+    pub fn set_state_size(&self, id: usize, size: usize) {
+        let mut nodes = self.nodes.lock().unwrap(); // Fine with panicking on thread poisoning
+        nodes.entry(id).or_default().state_size = size;
+    }
+
+    /// get returns what's been recorded for the worker
+    pub fn get(&self, id: usize) -> NodeMetrics {
+        let nodes = self.nodes.lock().unwrap(); // Fine with panicking on thread poisoning
+        nodes.get(&id).cloned().unwrap_or_default()
+    }
+}
+
+/// prometheus writes the metrics in the Prometheus text format, labelling each series with the node id and type
+pub fn prometheus(nodes: &BTreeMap<usize, NodeMetrics>) -> String {
+    let mut out = String::new();
+    family(
+        &mut out,
+        nodes,
+        "rows_in_total",
+        "Rows received by the node",
+        "counter",
+        |n| n.rows_in,
+    );
+    family(
+        &mut out,
+        nodes,
+        "rows_out_total",
+        "Rows sent by the node",
+        "counter",
+        |n| n.rows_out,
+    );
+    family(
+        &mut out,
+        nodes,
+        "rows_failed_total",
+        "Rows the node failed to process",
+        "counter",
+        |n| n.rows_failed,
+    );
+    family(
+        &mut out,
+        nodes,
+        "queue_depth",
+        "Messages waiting in the node's queue",
+        "gauge",
+        |n| n.queue_depth as u64,
+    );
+    family(
+        &mut out,
+        nodes,
+        "state_size",
+        "Entries held in the node's state",
+        "gauge",
+        |n| n.state_size as u64,
+    );
+    histogram(
+        &mut out,
+        nodes,
+        "batch_size",
+        "Rows received by the node in each epoch",
+        |n| &n.batch_size,
+    );
+    histogram(
+        &mut out,
+        nodes,
+        "latency_seconds",
+        "Time spent processing each epoch",
+        |n| &n.latency,
+    );
+    histogram(
+        &mut out,
+        nodes,
+        "blocked_seconds",
+        "Time senders spent waiting for room in the node's queue",
+        |n| &n.blocked,
+    );
+    out
+}
+
+/// family writes a counter or gauge with a series for every node
+fn family<F>(
+    out: &mut String,
+    nodes: &BTreeMap<usize, NodeMetrics>,
+    name: &str,
+    help: &str,
+    kind: &str,
+    value: F,
+) where
+    F: Fn(&NodeMetrics) -> u64,
+{
+    header(out, name, help, kind);
+    for (id, node) in nodes {
+        series(out, name, *id, node, None, value(node));
+    }
+}
+
+/// histogram writes the buckets, sum and count of a histogram for every node
+fn histogram<F>(
+    out: &mut String,
+    nodes: &BTreeMap<usize, NodeMetrics>,
+    name: &str,
+    help: &str,
+    value: F,
+) where
+    F: Fn(&NodeMetrics) -> &Histogram,
+{
+    header(out, name, help, "histogram");
+    let bucket = format!("{}_bucket", name);
+    for (id, node) in nodes {
+        let histogram = value(node);
+        for (bound, count) in histogram.buckets() {
+            series(out, &bucket, *id, node, Some(&bound.to_string()), count);
+        }
+        series(out, &bucket, *id, node, Some("+Inf"), histogram.count);
+        series(
+            out,
+            &format!("{}_sum", name),
+            *id,
+            node,
+            None,
+            histogram.sum,
+        );
+        series(
+            out,
+            &format!("{}_count", name),
+            *id,
+            node,
+            None,
+            histogram.count,
+        );
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    // Writing to a string can't fail
+    let _ = writeln!(out, "# HELP dataflow_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE dataflow_{} {}", name, kind);
+}
+
+fn series<T: fmt::Display>(
+    out: &mut String,
+    name: &str,
+    id: usize,
+    node: &NodeMetrics,
+    le: Option<&str>,
+    value: T,
+) {
+    let le = le.map_or_else(String::new, |le| format!(",le=\"{}\"", le));
+    let _ = writeln!(
+        out,
+        "dataflow_{}{{node=\"{}\",type=\"{}\"{}}} {}",
+        name, id, node.kind, le, value
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::data::{RowUpdate, Source};
+    use crate::operations::schema::{ColumnSchema, ColumnType, Schema};
+    use crate::operations::state::MemStore;
+    use crate::operations::{Base, Count};
+    use crate::processing::{MessageRouter, OpWorker};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn records_worker_metrics() {
+        let router = Arc::new(MessageRouter::new());
+        let schema = Schema::new(vec![ColumnSchema::new("group", ColumnType::Text, false)]);
+        let mut base = OpWorker::new(router.clone(), Base { schema }, vec![]).unwrap();
+        let count = Count {
+            source: Source::Literal(1.into()),
+            group: vec!["group".into()],
+            state: MemStore::new(),
+        };
+        let mut count = OpWorker::new(router.clone(), count, vec![base.id]).unwrap();
+        let count_id = count.id;
+        router
+            .write(
+                base.id,
+                vec![
+                    RowUpdate::Add(vec!["a".into()].into()),
+                    RowUpdate::Add(vec!["b".into()].into()),
+                    RowUpdate::Add(vec!["a".into()].into()),
+                ],
+            )
+            .unwrap();
+        let t = router.write(base.id, vec![]).unwrap();
+        thread::spawn(move || base.start());
+        thread::spawn(move || count.start());
+        assert!(router.wait_for(count_id, t, Duration::from_secs(5)));
+
+        let metrics = router.metrics();
+        let counted = &metrics[&count_id];
+        assert_eq!(counted.kind, "count");
+        // The two copies of "a" arrive as a single diff
+        assert_eq!(counted.rows_in, 2);
+        assert_eq!(counted.state_size, 2);
+        assert_eq!(counted.batch_size.count, 2);
+        assert_eq!(counted.batch_size.buckets()[0], (1.0, 1));
+        assert_eq!(counted.latency.count, 2);
+
+        let text = router.prometheus();
+        assert!(
+            text.contains("# TYPE dataflow_rows_in_total counter"),
+            "{}",
+            text
+        );
+        let series = format!(
+            "dataflow_rows_in_total{{node=\"{}\",type=\"count\"}} 2",
+            count_id
+        );
+        assert!(text.contains(&series), "{}", text);
+        let bucket = format!(
+            "dataflow_batch_size_bucket{{node=\"{}\",type=\"count\",le=\"+Inf\"}} 2",
+            count_id
+        );
+        assert!(text.contains(&bucket), "{}", text);
+    }
+}
@@ -1,12 +1,28 @@
-use crate::operations::data::Updates;
+use crate::operations::data::{Timestamp, Updates};
 
-pub use self::router::MessageRouter;
-pub use self::worker::OpWorker;
+pub use self::dead_letter::{DeadLetter, DeadLetterQueue};
+pub use self::executor::Executor;
+pub use self::reader::{Change, ReaderWorker, Subscription, View, SUBSCRIBER_CAPACITY};
+pub use self::router::{MessageRouter, WriteError};
+pub use self::worker::{OpWorker, Worker};
 
+pub mod dead_letter;
+pub mod definition;
+pub mod executor;
+pub mod metrics;
+#[cfg(test)]
+pub(crate) mod oracle;
+pub mod reader;
 pub mod router;
+pub mod trace;
 pub mod worker;
 
 pub enum Message {
     Update(Updates),
+    /// Progress tells a worker that the source has nothing to send it for the timestamp
+    Progress {
+        source: usize,
+        timestamp: Timestamp,
+    },
     Stop,
 }
@@ -0,0 +1,515 @@
+//! A reference evaluator for checking views against.
+//!
+//! The Oracle keeps the rows of every table of a definition and recomputes the rows of any other node from scratch,
+//! using the simplest reading of each node rather than the operations that maintain them incrementally. `check`
+//! builds the definition on an Executor, makes a random sequence of inserts and deletes and compares every view with
+//! the oracle after each write. The same seed always makes the same writes, so a failing seed can be replayed.
+//!
+//! Counts add the running count of a row's group to the row as it passes through, which depends on the order rows
+//! arrive in rather than just on the rows in the tables. The oracle recomputes the count of every group from the
+//! rows of the count's parent instead, and `check` compares those with the counts the node holds. The count column
+//! is filled in with the group's current count, but views whose rows still carry it, or that are filtered on it,
+//! aren't compared. Nodes that drop the count column are recomputed like any other.
+
+use crate::operations::data::{Column, Comparison, DataType, Row, RowUpdate, Source};
+use crate::operations::filter::{ColumnConstraint, Constraint};
+use crate::operations::schema::{ColumnType, Schema};
+use crate::processing::definition::{Definition, DefinitionError, Graph, NodeKind};
+use crate::processing::{Executor, WriteError};
+use ordered_float::OrderedFloat;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+
+/// Rng is a small xorshift generator so random tests can be replayed from their seed
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // A zero state would only ever generate zeros
+        Self {
+            state: seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1,
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /// below returns a number from 0 up to but not including n
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n.max(1) as u64) as usize
+    }
+
+    /// value returns a value for a column of the type. Values are picked from a handful of each type so rows often
+    /// share them. Types other than integer, text, boolean and float are always null.
+    pub fn value(&mut self, column_type: ColumnType, nullable: bool) -> DataType {
+        if nullable && self.below(8) == 0 {
+            return DataType::None;
+        }
+        match column_type {
+            ColumnType::Integer => DataType::Integer(self.below(8) as i32),
+            ColumnType::Text => ["a", "b", "c", "A", "B"][self.below(5)].into(),
+            ColumnType::Boolean => DataType::Boolean(self.below(2) == 0),
+            ColumnType::Float => DataType::Float(OrderedFloat(self.below(4) as f32 + 0.5)),
+            _ => DataType::None,
+        }
+    }
+}
+
+/// OracleError is returned when the oracle can't evaluate a definition or disagrees with a view
+#[derive(Debug)]
+pub enum OracleError {
+    Definition(DefinitionError),
+    Write(WriteError),
+    /// There are no tables to write to
+    NoTables,
+    /// A view's rows, or a count's counts, differed from the oracle's after a write
+    Mismatch {
+        node: String,
+        seed: u64,
+        write: usize,
+        expected: Vec<Row>,
+        found: Vec<Row>,
+    },
+}
+
+impl fmt::Display for OracleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OracleError::Definition(e) => e.fmt(f),
+            OracleError::Write(e) => e.fmt(f),
+            OracleError::NoTables => f.write_str("the definition has no tables to write to"),
+            OracleError::Mismatch {
+                node,
+                seed,
+                write,
+                expected,
+                found,
+            } => write!(
+                f,
+                "node {} differs after write {} with seed {}: expected {:?} but found {:?}",
+                node, write, seed, expected, found
+            ),
+        }
+    }
+}
+
+impl std::error::Error for OracleError {}
+
+impl From<DefinitionError> for OracleError {
+    fn from(e: DefinitionError) -> Self {
+        OracleError::Definition(e)
+    }
+}
+
+impl From<WriteError> for OracleError {
+    fn from(e: WriteError) -> Self {
+        OracleError::Write(e)
+    }
+}
+
+/// Oracle recomputes the rows of the nodes of a definition from the rows of its tables
+pub struct Oracle {
+    definition: Definition,
+    tables: HashMap<String, Vec<Row>>,
+    // The columns of each node that hold, or were computed from, a count's running count. None if the node's rows
+    // themselves depend on one.
+    ordered: HashMap<String, Option<HashSet<usize>>>,
+}
+
+impl Oracle {
+    /// new creates an oracle for a definition with empty tables. Columns are looked up in the schemas of the graph
+    /// built from the same definition.
+    pub fn new(definition: &str, graph: &Graph) -> Result<Self, OracleError> {
+        let mut definition = Definition::parse(definition)?;
+        let mut ordered: HashMap<String, Option<HashSet<usize>>> = HashMap::new();
+        for node in &mut definition.nodes {
+            // Parents are always defined before their children
+            let parents: Option<HashSet<usize>> = node
+                .parents
+                .iter()
+                .map(|p| ordered.get(p).cloned().flatten())
+                .try_fold(HashSet::new(), |mut all, columns| {
+                    all.extend(columns?);
+                    Some(all)
+                });
+            let input = match node.parents.first() {
+                Some(parent) => graph.id(parent).and_then(|id| graph.router.schema(id)),
+                None => {
+                    ordered.insert(node.name.clone(), Some(HashSet::new()));
+                    continue;
+                }
+            }
+            .ok_or_else(|| DefinitionError::UnknownNode(node.parents[0].clone()))?;
+            let name = node.name.clone();
+            let invalid = |error| DefinitionError::Schema {
+                node: name.clone(),
+                error,
+            };
+
+            let columns = match &mut node.kind {
+                NodeKind::Filter(constraints) => {
+                    for c in constraints.iter_mut() {
+                        c.column.resolve(&input).map_err(invalid)?;
+                    }
+                    parents.filter(|p| {
+                        constraints
+                            .iter()
+                            .all(|c| !matches!(c.column, Column::Index(i) if p.contains(&i)))
+                    })
+                }
+                NodeKind::Map(sources) => {
+                    for source in sources.iter_mut() {
+                        if let Source::Column(c) = source {
+                            c.resolve(&input).map_err(invalid)?;
+                        }
+                    }
+                    parents.map(|p| {
+                        (0..sources.len())
+                            .filter(|i| matches!(&sources[*i], Source::Column(Column::Index(c)) if p.contains(c)))
+                            .collect()
+                    })
+                }
+                NodeKind::Count { source, group } => {
+                    if let Source::Column(c) = source {
+                        c.resolve(&input).map_err(invalid)?;
+                    }
+                    for c in group {
+                        c.resolve(&input).map_err(invalid)?;
+                    }
+                    parents.map(|mut p| {
+                        p.insert(input.columns.len());
+                        p
+                    })
+                }
+                NodeKind::Base(_) | NodeKind::View { .. } | NodeKind::Debug => parents,
+            };
+            ordered.insert(node.name.clone(), columns);
+        }
+
+        Ok(Self {
+            definition,
+            tables: HashMap::new(),
+            ordered,
+        })
+    }
+
+    /// apply adds and removes rows from the table
+    pub fn apply(&mut self, table: &str, updates: &[RowUpdate]) {
+        let rows = self.tables.entry(table.into()).or_default();
+        for update in updates {
+            match update {
+                RowUpdate::Add(row) => rows.push(row.clone()),
+                RowUpdate::Remove(row) => {
+                    if let Some(i) = rows.iter().position(|r| r == row) {
+                        rows.swap_remove(i);
+                    }
+                }
+            }
+        }
+    }
+
+    /// table returns the rows in the table
+    pub fn table(&self, name: &str) -> &[Row] {
+        self.tables.get(name).map_or(&[], Vec::as_slice)
+    }
+
+    /// comparable returns whether the named node's rows are the same whatever order rows arrive in, so they can be
+    /// compared with the oracle's
+    pub fn comparable(&self, name: &str) -> bool {
+        matches!(self.ordered.get(name), Some(Some(columns)) if columns.is_empty())
+    }
+
+    /// counts recomputes the count of every group of the named count node from its parent's rows, as rows of the
+    /// group's values followed by the count. Groups with a count of 0 are left out.
+    pub fn counts(&self, name: &str) -> Vec<Row> {
+        let mut counts: Vec<Row> = self
+            .group_counts(name)
+            .into_iter()
+            .filter(|(_, count)| *count != 0)
+            .map(|(mut group, count)| {
+                group.push(i32::try_from(count).map_or(DataType::BigInt(count), DataType::Integer));
+                group.into()
+            })
+            .collect();
+        counts.sort();
+        counts
+    }
+
+    fn group_counts(&self, name: &str) -> HashMap<Vec<DataType>, i64> {
+        let mut counts = HashMap::new();
+        let node = self.definition.nodes.iter().find(|n| n.name == name);
+        if let Some(NodeKind::Count { source, group }) = node.map(|n| &n.kind) {
+            for row in node
+                .iter()
+                .flat_map(|n| &n.parents)
+                .flat_map(|p| self.rows(p))
+            {
+                let key = group.iter().map(|c| value(&row, c).normalized()).collect();
+                let count = counts.entry(key).or_default();
+                if source_value(source, &row) != &DataType::None {
+                    *count += 1;
+                }
+            }
+        }
+        counts
+    }
+
+    /// rows recomputes the rows of the named node, sorted so they can be compared. The count column of a count's
+    /// rows holds the group's current count.
+    pub fn rows(&self, name: &str) -> Vec<Row> {
+        let node = match self.definition.nodes.iter().find(|n| n.name == name) {
+            Some(node) => node,
+            None => return vec![],
+        };
+        let input: Vec<Row> = node.parents.iter().flat_map(|p| self.rows(p)).collect();
+
+        let mut rows: Vec<Row> = match &node.kind {
+            NodeKind::Base(_) => self.table(name).to_vec(),
+            NodeKind::Filter(constraints) => input
+                .into_iter()
+                .filter(|row| passes(constraints, row))
+                .collect(),
+            NodeKind::Map(sources) => input.iter().map(|row| map(sources, row)).collect(),
+            NodeKind::Count { group, .. } => {
+                let counts = self.group_counts(name);
+                input
+                    .into_iter()
+                    .map(|row| {
+                        let key: Vec<DataType> =
+                            group.iter().map(|c| value(&row, c).normalized()).collect();
+                        let count = counts.get(&key).copied().unwrap_or_default();
+                        let mut data = row.data.clone();
+                        data.push(
+                            i32::try_from(count).map_or(DataType::BigInt(count), DataType::Integer),
+                        );
+                        data.into()
+                    })
+                    .collect()
+            }
+            NodeKind::View { .. } | NodeKind::Debug => input,
+        };
+        rows.sort();
+        rows
+    }
+}
+
+fn passes(constraints: &[ColumnConstraint], row: &Row) -> bool {
+    constraints
+        .iter()
+        .all(|c| satisfies(&c.constraint, value(row, &c.column)))
+}
+
+fn map(sources: &[Source], row: &Row) -> Row {
+    let data: Vec<DataType> = sources
+        .iter()
+        .map(|s| source_value(s, row).clone())
+        .collect();
+    data.into()
+}
+
+fn source_value<'a>(source: &'a Source, row: &'a Row) -> &'a DataType {
+    match source {
+        Source::Column(c) => value(row, c),
+        Source::Literal(d) => d,
+    }
+}
+
+fn value<'a>(row: &'a Row, column: &Column) -> &'a DataType {
+    match column {
+        Column::Index(i) => &row[*i],
+        Column::Name(n) => unreachable!("column {} wasn't resolved", n),
+    }
+}
+
+/// satisfies is the plain reading of a constraint, where nulls never satisfy anything
+fn satisfies(constraint: &Constraint, value: &DataType) -> bool {
+    match constraint {
+        Constraint::Comparison(op, other) => op.compare(value, other),
+        Constraint::Collated(op, other, collation) => op.compare_collated(value, other, *collation),
+        Constraint::In(values) => values.iter().any(|v| Comparison::Equal.compare(value, v)),
+    }
+}
+
+/// check runs random writes through the definition, comparing every comparable view and every count with the oracle
+/// after each one.
+/// Each write is a transaction of up to four inserts and deletes across the tables. Only rows already in a table are
+/// deleted.
+pub fn check(definition: &str, seed: u64, writes: usize) -> Result<(), OracleError> {
+    let mut executor = Executor::new();
+    let mut graph = Definition::parse(definition)?.build(executor.router())?;
+    executor.add_graph(&mut graph);
+    let mut oracle = Oracle::new(definition, &graph)?;
+
+    let tables: Vec<(String, Schema)> = graph
+        .tables()
+        .into_iter()
+        .filter_map(|t| Some((t.to_string(), graph.router.schema(graph.id(t)?)?)))
+        .collect();
+    if tables.is_empty() {
+        return Err(OracleError::NoTables);
+    }
+    let nodes = |kind: &str| -> Vec<String> {
+        graph
+            .names()
+            .into_iter()
+            .filter(|n| graph.kind(n) == Some(kind))
+            .map(String::from)
+            .collect()
+    };
+    let views: Vec<String> = nodes("view")
+        .into_iter()
+        .filter(|v| oracle.comparable(v))
+        .collect();
+    let counts = nodes("count");
+
+    let mut rng = Rng::new(seed);
+    for write in 1..=writes {
+        let mut transaction = vec![];
+        for _ in 0..=rng.below(4) {
+            let (table, schema) = &tables[rng.below(tables.len())];
+            let rows = oracle.table(table);
+            let update = match rows.is_empty() || rng.below(3) > 0 {
+                true => RowUpdate::Add(
+                    schema
+                        .columns
+                        .iter()
+                        .map(|c| rng.value(c.column_type, c.nullable))
+                        .collect::<Vec<DataType>>()
+                        .into(),
+                ),
+                false => RowUpdate::Remove(rows[rng.below(rows.len())].clone()),
+            };
+            oracle.apply(table, std::slice::from_ref(&update));
+            transaction.push((graph.id(table).unwrap_or_default(), update));
+        }
+
+        executor.router().write_transaction(
+            transaction
+                .into_iter()
+                .map(|(id, update)| (id, vec![update]))
+                .collect(),
+        )?;
+        executor.run();
+
+        let views = views.iter().map(|view| {
+            let mut found = graph.view(view).map(|v| v.rows()).unwrap_or_default();
+            found.sort();
+            (view, oracle.rows(view), found)
+        });
+        let counts = counts.iter().map(|count| {
+            let found: Vec<Row> = graph
+                .state(count)
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|(_, values)| values != &[DataType::Integer(0)])
+                .map(|(mut group, values)| {
+                    group.extend(values);
+                    group.into()
+                })
+                .collect();
+            (count, oracle.counts(count), found)
+        });
+        for (node, expected, found) in views.chain(counts) {
+            if found != expected {
+                return Err(OracleError::Mismatch {
+                    node: node.clone(),
+                    seed,
+                    write,
+                    expected,
+                    found,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ITEMS: &str = r#"{"nodes": [
+        {"name": "items", "type": "base", "columns": [
+            {"name": "id", "type": "integer", "nullable": false},
+            {"name": "category", "type": "text"},
+            {"name": "price", "type": "integer"},
+            {"name": "on_sale", "type": "boolean", "nullable": false}
+        ]},
+        {"name": "stock", "type": "base", "columns": [
+            {"name": "id", "type": "integer", "nullable": false},
+            {"name": "category", "type": "text"},
+            {"name": "price", "type": "integer"},
+            {"name": "on_sale", "type": "boolean", "nullable": false}
+        ]},
+        {"name": "cheap", "type": "filter", "parents": ["items", "stock"], "constraints": [
+            {"column": "price", "op": "<", "value": 4},
+            {"column": "category", "in": ["a", "b"]}
+        ]},
+        {"name": "cheap_view", "type": "view", "parents": ["cheap"], "key": ["category"]},
+        {"name": "big_a", "type": "filter", "parents": ["items"], "constraints": [
+            {"column": "category", "op": "=", "value": "A", "collation": "case_insensitive"},
+            {"column": "price", "op": ">=", "value": 2}
+        ]},
+        {"name": "sales", "type": "map", "parents": ["big_a"], "sources": ["on_sale", "id", {"literal": 1}]},
+        {"name": "sales_view", "type": "view", "parents": ["sales"]},
+        {"name": "everything", "type": "view", "parents": ["stock"]}
+    ]}"#;
+
+    #[test]
+    fn views_match_recomputed_rows() {
+        for seed in 0..20 {
+            if let Err(e) = check(ITEMS, seed, 40) {
+                panic!("{}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn counts_match_recomputed_groups() {
+        let counts = r#"{"nodes": [
+            {"name": "items", "type": "base", "columns": [
+                {"name": "id", "type": "integer", "nullable": false},
+                {"name": "category", "type": "text"},
+                {"name": "price", "type": "integer"}
+            ]},
+            {"name": "priced", "type": "count", "parents": ["items"], "source": "price", "group": ["category"]},
+            {"name": "priced_view", "type": "view", "parents": ["priced"]},
+            {"name": "categories", "type": "map", "parents": ["priced"], "sources": ["category", {"literal": 3}]},
+            {"name": "categories_view", "type": "view", "parents": ["categories"]},
+            {"name": "firsts", "type": "filter", "parents": ["priced"], "constraints": [
+                {"column": 3, "op": "=", "value": 1}
+            ]},
+            {"name": "firsts_view", "type": "view", "parents": ["firsts"]},
+            {"name": "all", "type": "count", "parents": ["items"]},
+            {"name": "all_view", "type": "view", "parents": ["all"]}
+        ]}"#;
+        let executor = Executor::new();
+        let graph = Definition::parse(counts)
+            .unwrap()
+            .build(executor.router())
+            .unwrap();
+        let oracle = Oracle::new(counts, &graph).unwrap();
+        assert!(oracle.comparable("categories_view"));
+        assert!(!oracle.comparable("priced_view"));
+        assert!(!oracle.comparable("firsts_view"));
+
+        for seed in 0..20 {
+            if let Err(e) = check(counts, seed, 40) {
+                panic!("{}", e);
+            }
+        }
+
+        assert!(matches!(
+            check(r#"{"nodes": []}"#, 0, 1),
+            Err(OracleError::NoTables)
+        ));
+    }
+}
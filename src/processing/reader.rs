@@ -0,0 +1,360 @@
+use crate::operations::data::{Batch, Column, DataType, Diff, Row, Timestamp};
+use crate::operations::schema::{Schema, SchemaError};
+use crate::operations::{Description, ProcessError};
+use crate::processing::router::{Epoch, MessageRouter};
+use crate::processing::Worker;
+use crossbeam::channel::{bounded, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+type Key = Vec<DataType>;
+
+/// SUBSCRIBER_CAPACITY is the most changes buffered for a subscriber before it's disconnected for falling behind
+pub const SUBSCRIBER_CAPACITY: usize = 1024;
+
+#[derive(Debug, Default)]
+struct Rows {
+    // Keys and rows are normalized, so values that compare as equal share them. Each row is shown as it was first
+    // written.
+    groups: HashMap<Key, HashMap<Row, (Row, i64)>>,
+    // The timestamp of the last updates applied
+    timestamp: Timestamp,
+}
+
+/// View holds the materialized rows of a reader so they can be read from other threads. Rows are indexed by the
+/// reader's key columns.
+#[derive(Debug, Default)]
+pub struct View {
+    key: Vec<usize>,
+    rows: RwLock<Rows>,
+    // Each subscriber's channel along with the timestamp of its snapshot
+    subscribers: Mutex<Vec<(Timestamp, Sender<Change>)>>,
+}
+
+/// Change is the set of updates made to a view at a timestamp
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Change {
+    pub timestamp: Timestamp,
+    pub updates: Batch,
+}
+
+impl View {
+    pub fn new(key: Vec<usize>) -> Self {
+        Self {
+            key,
+            rows: RwLock::new(Rows::default()),
+            subscribers: Mutex::new(vec![]),
+        }
+    }
+
+    /// apply adds and removes the rows from the view and sends the updates to any subscribers. Returns the updates
+    /// whose rows are missing a key column, which are left out.
+    ///
+    /// A row removed before it's added keeps a negative count until the addition cancels it out. Rows are only shown
+    /// while their count is positive.
+    pub fn apply(&self, timestamp: Timestamp, updates: Vec<Diff>) -> Vec<Diff> {
+        let (updates, missing): (Vec<Diff>, Vec<Diff>) = updates
+            .into_iter()
+            .partition(|(row, _)| self.key.iter().all(|c| *c < row.data.len()));
+        let mut rows = self.rows.write().unwrap(); // Fine with panicking on thread poisoning
+        for (row, multiplicity) in &updates {
+            let key = self.key.iter().map(|c| row[*c].normalized()).collect();
+            let group = rows.groups.entry(key).or_default();
+            let normalized = row.normalized();
+            let (_, count) = group
+                .entry(normalized.clone())
+                .or_insert_with(|| (row.clone(), 0));
+            *count += multiplicity;
+
+            if *count == 0 {
+                group.remove(&normalized);
+            }
+        }
+        rows.groups.retain(|_, group| !group.is_empty());
+        rows.timestamp = rows.timestamp.max(timestamp);
+        drop(rows);
+        if updates.is_empty() {
+            return missing;
+        }
+
+        // Subscribers that joined after the rows were unlocked already have these updates in their snapshot, so they're
+        // skipped. Full subscribers have fallen behind and are dropped, disconnecting them once they've read the rest.
+        let change = Change {
+            timestamp,
+            updates: Arc::new(updates),
+        };
+        let mut subscribers = self.subscribers.lock().unwrap(); // Fine with panicking on thread poisoning
+        subscribers
+            .retain(|(snapshot, s)| *snapshot >= timestamp || s.try_send(change.clone()).is_ok());
+        missing
+    }
+
+    /// lookup returns all rows with the given values for the key columns. If the key is empty all rows are returned.
+    pub fn lookup(&self, key: &[DataType]) -> Vec<Row> {
+        let rows = self.rows.read().unwrap(); // Fine with panicking on thread poisoning
+        if key.is_empty() {
+            return rows.groups.values().flat_map(expand_group).collect();
+        }
+
+        let key: Key = key.iter().map(DataType::normalized).collect();
+        rows.groups.get(&key).map(expand_group).unwrap_or_default()
+    }
+
+    /// subscribe returns a snapshot of the rows in the view along with every change made to it after the snapshot.
+    /// Up to SUBSCRIBER_CAPACITY changes are buffered until they're received. Subscribers that fall further behind are
+    /// disconnected and have to subscribe again for a new snapshot. Dropping the subscription unsubscribes.
+    pub fn subscribe(&self) -> Subscription {
+        let rows = self.rows.read().unwrap(); // Fine with panicking on thread poisoning
+        let (sender, receiver) = bounded(SUBSCRIBER_CAPACITY);
+        // Fine with panicking on thread poisoning
+        self.subscribers
+            .lock()
+            .unwrap()
+            .push((rows.timestamp, sender));
+        Subscription {
+            timestamp: rows.timestamp,
+            snapshot: rows.groups.values().flat_map(expand_group).collect(),
+            changes: receiver,
+        }
+    }
+
+    /// rows returns every row in the view
+    pub fn rows(&self) -> Vec<Row> {
+        self.lookup(&[])
+    }
+}
+
+/// Subscription receives the changes made to a view after its snapshot was taken
+#[derive(Debug)]
+pub struct Subscription {
+    /// The timestamp of the last change included in the snapshot
+    pub timestamp: Timestamp,
+    pub snapshot: Vec<Row>,
+    changes: Receiver<Change>,
+}
+
+impl Subscription {
+    /// recv waits for the next change. Returns None once the view has been dropped or the subscriber fell behind.
+    pub fn recv(&self) -> Option<Change> {
+        self.changes.recv().ok()
+    }
+
+    /// recv_timeout waits up to the timeout for the next change
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Change, RecvTimeoutError> {
+        self.changes.recv_timeout(timeout)
+    }
+
+    /// try_recv returns the next change if there is one
+    pub fn try_recv(&self) -> Result<Change, TryRecvError> {
+        self.changes.try_recv()
+    }
+
+    /// iter returns the changes as they happen, ending once the view has been dropped or the subscriber fell behind
+    pub fn iter(&self) -> impl Iterator<Item = Change> + '_ {
+        self.changes.iter()
+    }
+}
+
+fn expand_group(group: &HashMap<Row, (Row, i64)>) -> Vec<Row> {
+    group
+        .values()
+        .flat_map(|(row, count)| std::iter::repeat(row).take(usize::try_from(*count).unwrap_or(0)))
+        .cloned()
+        .collect()
+}
+
+/// ReaderWorkers materialize the rows they receive into a view that can be read while the graph is running
+pub struct ReaderWorker {
+    pub id: usize,
+    router: Arc<MessageRouter>,
+    view: Arc<View>,
+}
+
+impl ReaderWorker {
+    /// new adds a reader to the graph. Rows in the view can be looked up by the values of the key columns.
+    pub fn new(
+        router: Arc<MessageRouter>,
+        parents: Vec<usize>,
+        mut key: Vec<Column>,
+    ) -> Result<Self, SchemaError> {
+        let mut indices = vec![];
+        let mut description = Description::default();
+        let id = router.add_worker(parents, |p| {
+            let input = Schema::input(p)?;
+            for column in &mut key {
+                indices.push(column.resolve(input)?);
+            }
+            let names: Vec<String> = key.iter().map(|c| c.describe(input)).collect();
+            description = match names.is_empty() {
+                true => Description::new("reader"),
+                false => Description::new("reader").param("key", names.join(", ")),
+            };
+            Ok(input.clone())
+        })?;
+        router.set_description(id, description);
+
+        Ok(Self {
+            id,
+            router,
+            view: Arc::new(View::new(indices)),
+        })
+    }
+
+    /// view returns the view the reader keeps up to date
+    pub fn view(&self) -> Arc<View> {
+        Arc::clone(&self.view)
+    }
+
+    /// starts running the worker. This will loop until the message router stops providing messages
+    pub fn start(&mut self) {
+        let router = Arc::clone(&self.router);
+        for epoch in router.epochs(self.id) {
+            self.handle(epoch);
+        }
+    }
+}
+
+impl Worker for ReaderWorker {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn handle(&mut self, epoch: Epoch) {
+        let started = Instant::now();
+        let updates: Vec<Diff> = epoch
+            .updates
+            .iter()
+            .flat_map(|u| u.updates.iter().cloned())
+            .collect();
+        let rows = updates.len();
+        for update in self.view.apply(epoch.timestamp, updates) {
+            let width = update.0.data.len();
+            let column = self.view.key.iter().copied().find(|c| *c >= width);
+            let error = ProcessError::MissingColumn {
+                column: column.unwrap_or(width),
+                width,
+            };
+            self.router
+                .dead_letter(self.id, epoch.timestamp, update, error);
+        }
+        self.router
+            .record_hop(self.id, epoch.timestamp, &epoch.sources, rows, started);
+        for source in &epoch.sources {
+            self.router.complete(self.id, *source, epoch.timestamp);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::data::RowUpdate;
+    use crate::operations::schema::{ColumnSchema, ColumnType};
+    use crate::operations::Base;
+    use crate::processing::{Executor, OpWorker};
+
+    #[test]
+    fn materializes_rows() {
+        let view = View::new(vec![1]);
+        let a: Row = vec![1.into(), "a".into()].into();
+        let b: Row = vec![2.into(), "b".into()].into();
+
+        view.apply(1, vec![(a.clone(), 1), (b.clone(), 2)]);
+        assert_eq!(view.rows().len(), 3);
+        assert_eq!(view.lookup(&["a".into()]), vec![a.clone()]);
+        assert_eq!(view.lookup(&["b".into()]), vec![b.clone(), b.clone()]);
+
+        let subscription = view.subscribe();
+        assert_eq!(subscription.timestamp, 1);
+        assert_eq!(subscription.snapshot.len(), 3);
+
+        view.apply(2, vec![(a.clone(), -1), (b.clone(), -1)]);
+        assert_eq!(view.lookup(&["a".into()]), vec![]);
+        assert_eq!(view.rows(), vec![b.clone()]);
+        assert_eq!(
+            subscription.try_recv(),
+            Ok(Change {
+                timestamp: 2,
+                updates: Arc::new(vec![(a.clone(), -1), (b.clone(), -1)]),
+            })
+        );
+        assert_eq!(subscription.try_recv(), Err(TryRecvError::Empty));
+
+        // Dropped subscriptions stop being sent changes
+        drop(subscription);
+        view.apply(3, vec![(b.clone(), -1)]);
+        assert!(view.subscribers.lock().unwrap().is_empty());
+
+        // Removals that arrive first are held until the addition cancels them
+        view.apply(4, vec![(b.clone(), -1)]);
+        assert_eq!(view.rows(), vec![]);
+        view.apply(5, vec![(b.clone(), 1)]);
+        assert_eq!(view.rows(), vec![]);
+        view.apply(6, vec![(b.clone(), 1)]);
+        assert_eq!(view.rows(), vec![b]);
+
+        // Changes already in a subscriber's snapshot aren't sent to it again
+        let lagging = view.subscribe();
+        assert_eq!(lagging.timestamp, 6);
+        view.apply(6, vec![(a.clone(), 1), (a.clone(), -1)]);
+        assert_eq!(lagging.try_recv(), Err(TryRecvError::Empty));
+
+        // Subscribers that fall behind are disconnected once they've received the changes already buffered
+        for timestamp in 7..=7 + SUBSCRIBER_CAPACITY as Timestamp {
+            view.apply(timestamp, vec![(a.clone(), 1), (a.clone(), -1)]);
+        }
+        assert!(view.subscribers.lock().unwrap().is_empty());
+        assert_eq!(lagging.iter().count(), SUBSCRIBER_CAPACITY);
+
+        // Rows missing a key column are handed back rather than panicking with the rows locked
+        let short: Row = vec![3.into()].into();
+        assert_eq!(
+            view.apply(
+                8 + SUBSCRIBER_CAPACITY as Timestamp,
+                vec![(short.clone(), 1), (a.clone(), 1)]
+            ),
+            vec![(short, 1)]
+        );
+        assert_eq!(view.lookup(&["a".into()]), vec![a.clone()]);
+
+        // Numbers held as different types are the same key and the same row
+        let view = View::new(vec![0]);
+        let int: Row = vec![DataType::Integer(1)].into();
+        view.apply(1, vec![(int.clone(), 2)]);
+        view.apply(2, vec![(vec![DataType::BigInt(1)].into(), -1)]);
+        assert_eq!(view.lookup(&[DataType::BigInt(1)]), vec![int]);
+    }
+
+    #[test]
+    fn dead_letters_rows_missing_key_columns() {
+        let mut executor = Executor::new();
+        let router = executor.router();
+        let schema = Schema::new(vec![ColumnSchema::new("id", ColumnType::Integer, false)]);
+        let base = OpWorker::new(router.clone(), Base { schema }, vec![]).unwrap();
+        let reader = ReaderWorker::new(router.clone(), vec![base.id], vec!["id".into()]).unwrap();
+        let (reader_id, view) = (reader.id, reader.view());
+        let base_id = executor.add(base);
+        executor.add(reader);
+
+        // Writes straight to the router skip the schema checks made by the server and frontend
+        let updates = vec![
+            RowUpdate::Add(vec![].into()),
+            RowUpdate::Add(vec![1.into()].into()),
+        ];
+        router.write(base_id, updates).unwrap();
+        executor.run();
+        assert_eq!(view.rows(), vec![vec![1.into()].into()]);
+        let letters = router.dead_letters().for_node(reader_id);
+        assert_eq!(letters.len(), 1);
+        assert_eq!(
+            letters[0].error,
+            ProcessError::MissingColumn {
+                column: 0,
+                width: 0
+            }
+        );
+    }
+}
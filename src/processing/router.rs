@@ -1,48 +1,358 @@
-use crate::operations::data::{RowUpdate, Updates};
-use crate::processing::Message;
-use crossbeam::channel::bounded;
-use crossbeam::channel::{Receiver, Sender};
+use crate::operations::data::{consolidate, Batch, Diff, RowUpdate, Timestamp, Updates};
+use crate::operations::json::Json;
+use crate::operations::schema::{Schema, SchemaError};
+use crate::operations::Description;
+use crate::operations::ProcessError;
+use crate::processing::metrics::{self, Metrics, NodeMetrics};
+use crate::processing::trace::{Trace, Tracer};
+use crate::processing::{DeadLetterQueue, Message};
+use crossbeam::channel::{bounded, unbounded};
+use crossbeam::channel::{Receiver, Sender, TrySendError};
+use petgraph::algo::toposort;
+use petgraph::dot::{Config, Dot};
 use petgraph::stable_graph::{NodeIndex, StableGraph};
 use petgraph::Direction;
-use std::collections::HashMap;
-use std::sync::RwLock;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+type Channel = (Sender<Message>, Receiver<Message>);
+
+/// WriteError is returned when updates are written to a worker that can't take them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteError {
+    UnknownWorker(usize),
+    /// Only root workers can be written to. The others get their updates from their parents.
+    NotRoot(usize),
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteError::UnknownWorker(id) => write!(f, "no worker with id {}", id),
+            WriteError::NotRoot(id) => {
+                write!(f, "worker {} has parents and can't be written to", id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for WriteError {}
 
 /// MessageRouter handles sending and receiving messages
+///
+/// Every write into the graph is given a timestamp. All root workers get a message for every timestamp and workers
+/// always forward a message for every timestamp they process. Workers with updates send them, while the others only
+/// send a Progress message. This means once a worker has processed timestamp T from all of its parents it has seen
+/// every update caused by writes up to T. That timestamp is the worker's frontier.
+#[derive(Default)]
 pub struct MessageRouter {
-    graph: RwLock<StableGraph<(), ()>>,
-    channels: RwLock<HashMap<usize, (Sender<Message>, Receiver<Message>)>>,
+    graph: RwLock<StableGraph<Schema, ()>>,
+    channels: RwLock<HashMap<usize, Channel>>,
+    clock: Mutex<Timestamp>,
+    progress: Mutex<HashMap<usize, HashMap<usize, Timestamp>>>,
+    progressed: Condvar,
+    descriptions: RwLock<HashMap<usize, Description>>,
+    metrics: Metrics,
+    tracer: Tracer,
+    dead_letters: DeadLetterQueue,
+    replays: Mutex<HashMap<(usize, Timestamp), Vec<Diff>>>,
+    unbounded: bool,
 }
 
 impl MessageRouter {
     pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// unbounded creates a router whose channels never fill up, so sending never blocks. This is needed when every
+    /// worker runs on the same thread, like with an Executor, as a full channel would never be drained.
+    pub fn unbounded() -> Self {
         Self {
-            graph: RwLock::new(StableGraph::new()),
-            channels: RwLock::new(HashMap::new()),
+            unbounded: true,
+            ..Self::default()
         }
     }
 
     /// Add_node handles adding a new worker to the dataflow graph
-    pub fn add_worker(&self, parents: Vec<usize>) -> usize {
+    ///
+    /// The worker declares its schema from the schemas of its parents. If the worker can't handle its parents' rows
+    /// the error is returned and the worker isn't added.
+    ///
+    /// Workers can be added while the graph is running. They only see writes made after they were added, so they
+    /// start with a frontier of the latest write.
+    pub fn add_worker<F>(&self, parents: Vec<usize>, declare: F) -> Result<usize, SchemaError>
+    where
+        F: FnOnce(&[&Schema]) -> Result<Schema, SchemaError>,
+    {
+        // Holding the clock stops writes from being given timestamps while the worker is added
+        let clock = self.clock.lock().unwrap(); // Fine with panicking on thread poisoning
         let mut graph = self.graph.write().unwrap(); // Fine with writes panicking if the lock is poisoned
-        let idx = graph.add_node(());
-        for parent in parents {
-            graph.add_edge(NodeIndex::new(parent), idx, ());
+        let inputs = parents
+            .iter()
+            .map(|p| {
+                graph
+                    .node_weight(NodeIndex::new(*p))
+                    .ok_or(SchemaError::MissingParent(*p))
+            })
+            .collect::<Result<Vec<&Schema>, SchemaError>>()?;
+        let schema = declare(&inputs)?;
+
+        let idx = graph.add_node(schema);
+        for parent in &parents {
+            graph.add_edge(NodeIndex::new(*parent), idx, ());
         }
+        drop(graph);
 
         let index = idx.index();
 
-        let chan = bounded::<Message>(10);
+        let chan = match self.unbounded {
+            true => unbounded::<Message>(),
+            false => bounded::<Message>(10),
+        };
         self.channels.write().unwrap().insert(index, chan);
 
-        index
+        if *clock > 0 {
+            let mut progress = self.progress.lock().unwrap();
+            let seen = progress.entry(index).or_default();
+            let sources = match parents.is_empty() {
+                true => vec![index], // Roots record progress against themselves
+                false => parents,
+            };
+            for source in sources {
+                seen.insert(source, *clock);
+            }
+        }
+
+        Ok(index)
+    }
+
+    /// schema returns the schema of the rows the worker outputs
+    pub fn schema(&self, id: usize) -> Option<Schema> {
+        let graph = self.graph.read().unwrap(); // Fine with panicking on thread poisoning
+        graph.node_weight(NodeIndex::new(id)).cloned()
+    }
+
+    /// set_description records what the worker does so it can be shown in exports of the graph
+    pub fn set_description(&self, id: usize, description: Description) {
+        let mut descriptions = self.descriptions.write().unwrap(); // Fine with panicking on thread poisoning
+        descriptions.insert(id, description);
+    }
+
+    /// description returns what the worker does, if it said
+    pub fn description(&self, id: usize) -> Option<Description> {
+        let descriptions = self.descriptions.read().unwrap(); // Fine with panicking on thread poisoning
+        descriptions.get(&id).cloned()
+    }
+
+    /// queue_depth returns the number of messages waiting for the worker
+    pub fn queue_depth(&self, id: usize) -> usize {
+        let channels = self.channels.read().unwrap(); // Fine with panicking on thread poisoning
+        channels.get(&id).map_or(0, |(s, _)| s.len())
+    }
+
+    /// record_epoch records the rows the worker received and sent for an epoch and how long it took to process
+    pub fn record_epoch(&self, id: usize, rows_in: usize, rows_out: usize, latency: Duration) {
+        self.metrics.record_epoch(id, rows_in, rows_out, latency);
+    }
+
+    /// record_state_size records how many entries the worker holds in its state
+    pub fn record_state_size(&self, id: usize, size: usize) {
+        self.metrics.set_state_size(id, size);
+    }
+
+    /// metrics returns what's been recorded for every worker, along with the current depth of its queue
+    pub fn metrics(&self) -> BTreeMap<usize, NodeMetrics> {
+        let ids: Vec<usize> = {
+            let graph = self.graph.read().unwrap(); // Fine with panicking on thread poisoning
+            graph.node_indices().map(|idx| idx.index()).collect()
+        };
+        ids.into_iter()
+            .map(|id| {
+                let mut node = self.metrics.get(id);
+                node.kind = self.description(id).unwrap_or_default().name;
+                node.queue_depth = self.queue_depth(id);
+                (id, node)
+            })
+            .collect()
+    }
+
+    /// prometheus returns the metrics of every worker in the Prometheus text format
+    pub fn prometheus(&self) -> String {
+        metrics::prometheus(&self.metrics())
+    }
+
+    /// dead_letter reports an update the worker couldn't process. The failure is counted in the worker's metrics and
+    /// the update is kept in the dead letter queue so it can be looked at and replayed later.
+    pub fn dead_letter(&self, id: usize, timestamp: Timestamp, update: Diff, error: ProcessError) {
+        self.metrics.record_failure(id);
+        self.dead_letters.push(id, timestamp, update, error);
+    }
+
+    /// dead_letters returns the queue of updates workers failed to process
+    pub fn dead_letters(&self) -> &DeadLetterQueue {
+        &self.dead_letters
+    }
+
+    /// replay sends the updates to the worker under a new timestamp, as if they came from its parents. Returns None
+    /// if there's no such worker.
+    pub fn replay(&self, id: usize, updates: Vec<Diff>) -> Option<Timestamp> {
+        if !self.channels.read().unwrap().contains_key(&id) {
+            return None;
+        }
+        Some(self.commit(HashMap::new(), Some((id, updates))))
+    }
+
+    /// replay_dead_letters takes the dead letters of one worker out of the queue and replays their updates into
+    /// another, like a worker added to fix the first. Letters that fail again go back in the queue. Returns None if
+    /// there were no letters to replay or no worker to replay them into.
+    pub fn replay_dead_letters(&self, from: usize, to: usize) -> Option<Timestamp> {
+        if !self.channels.read().unwrap().contains_key(&to) {
+            return None;
+        }
+        let letters = self.dead_letters.take(|l| l.node == from);
+        if letters.is_empty() {
+            return None;
+        }
+        self.replay(to, letters.into_iter().map(|l| l.update).collect())
+    }
+
+    /// set_tracing turns tracing of writes through the graph on or off. Turning it off drops all traces.
+    pub fn set_tracing(&self, enabled: bool) {
+        self.tracer.set_enabled(enabled);
+    }
+
+    /// record_hop adds the worker's handling of the timestamp to the write's trace, if it's being traced. started is
+    /// when the worker began processing the timestamp.
+    pub fn record_hop(
+        &self,
+        id: usize,
+        timestamp: Timestamp,
+        sources: &[usize],
+        rows: usize,
+        started: Instant,
+    ) {
+        self.tracer.record(id, timestamp, sources, rows, started);
+    }
+
+    /// trace returns the hops recorded for the write so far
+    pub fn trace(&self, timestamp: Timestamp) -> Option<Trace> {
+        self.tracer.get(timestamp)
+    }
+
+    /// latest_trace returns the trace of the most recent traced write
+    pub fn latest_trace(&self) -> Option<Trace> {
+        self.tracer.latest()
+    }
+
+    /// latest returns the timestamp given to the most recent write
+    pub fn latest(&self) -> Timestamp {
+        *self.clock.lock().unwrap() // Fine with panicking on thread poisoning
+    }
+
+    /// lag returns how stale the worker is: how long ago the oldest write it hasn't processed was made. Returns None
+    /// if that write isn't being traced.
+    pub fn lag(&self, id: usize) -> Option<Duration> {
+        let behind = self.frontier(id) + 1;
+        if behind > *self.clock.lock().unwrap() {
+            return Some(Duration::from_secs(0));
+        }
+        self.tracer.written(behind).map(|w| w.elapsed())
+    }
+
+    /// to_dot renders the graph in the Graphviz DOT format. Each node is labelled with its worker's type, parameters
+    /// and the number of messages waiting for it.
+    pub fn to_dot(&self) -> String {
+        let graph = self.graph.read().unwrap(); // Fine with panicking on thread poisoning
+        let labelled = graph.map(
+            |idx, _| {
+                let id = idx.index();
+                let description = self.description(id).unwrap_or_default();
+                let mut label = format!("{}: {}\n", id, description.name);
+                for (name, value) in &description.params {
+                    label.push_str(&format!("{}: {}\n", name, value));
+                }
+                label.push_str(&format!("queue: {}", self.queue_depth(id)));
+                label
+            },
+            |_, _| "",
+        );
+        format!("{}", Dot::with_config(&labelled, &[Config::EdgeNoLabel]))
+    }
+
+    /// to_json exports the graph as JSON, with a node for each worker and an edge from each parent to its children.
+    /// Nodes hold the worker's type, parameters, columns, queue depth and frontier.
+    pub fn to_json(&self) -> Json {
+        // The graph isn't held while building the nodes since finding frontiers reads it again
+        let graph = self.graph.read().unwrap(); // Fine with panicking on thread poisoning
+        let schemas: Vec<(usize, Schema)> = graph
+            .node_indices()
+            .map(|idx| (idx.index(), graph[idx].clone()))
+            .collect();
+        let edges: Vec<(usize, usize)> = graph
+            .edge_indices()
+            .filter_map(|e| graph.edge_endpoints(e))
+            .map(|(from, to)| (from.index(), to.index()))
+            .collect();
+        drop(graph);
+
+        let number = |n: usize| Json::Number(n.to_string());
+        let nodes = schemas
+            .into_iter()
+            .map(|(id, schema)| {
+                let description = self.description(id).unwrap_or_default();
+                let params = description
+                    .params
+                    .into_iter()
+                    .map(|(name, value)| (name, Json::String(value)))
+                    .collect();
+                let columns = schema
+                    .columns
+                    .into_iter()
+                    .map(|c| {
+                        let column_type = format!("{:?}", c.column_type).to_lowercase();
+                        Json::Object(vec![
+                            ("name".into(), Json::String(c.name)),
+                            ("type".into(), Json::String(column_type)),
+                            ("nullable".into(), Json::Bool(c.nullable)),
+                        ])
+                    })
+                    .collect();
+                Json::Object(vec![
+                    ("id".into(), number(id)),
+                    ("type".into(), Json::String(description.name)),
+                    ("params".into(), Json::Object(params)),
+                    ("columns".into(), Json::Array(columns)),
+                    ("queue".into(), number(self.queue_depth(id))),
+                    (
+                        "frontier".into(),
+                        Json::Number(self.frontier(id).to_string()),
+                    ),
+                ])
+            })
+            .collect();
+        let edges = edges
+            .into_iter()
+            .map(|(from, to)| {
+                Json::Object(vec![
+                    ("from".into(), number(from)),
+                    ("to".into(), number(to)),
+                ])
+            })
+            .collect();
+        Json::Object(vec![
+            ("nodes".into(), Json::Array(nodes)),
+            ("edges".into(), Json::Array(edges)),
+        ])
     }
 
     /// next_message waits for the next message for the given worker id
     pub fn next_message(&self, id: usize) -> Message {
-        let map = self.channels.read().unwrap(); // Fine with panicking on thread poisoning
-        let r = match map.get(&id) {
+        // The receiver is cloned so the lock isn't held while waiting, which would block adding workers
+        let r = match self.channels.read().unwrap().get(&id) {
             None => return Message::Stop, // Worker doesn't exist in channels. It must have been removed so we should stop
-            Some((_, r)) => r,
+            Some((_, r)) => r.clone(),
         };
 
         match r.recv() {
@@ -51,43 +361,278 @@ impl MessageRouter {
         }
     }
 
-    /// send_updates sends the RowUpdates to all children of the worker
-    pub fn send_updates(&self, id: usize, updates: Vec<RowUpdate>) {
-        if updates.is_empty() {
-            return;
-        }
+    /// try_message returns the next message for the given worker id if there is one, without waiting
+    pub fn try_message(&self, id: usize) -> Option<Message> {
+        let channels = self.channels.read().unwrap(); // Fine with panicking on thread poisoning
+        channels.get(&id)?.1.try_recv().ok()
+    }
 
-        let graph = self.graph.read().unwrap(); // Fine with panicking on thread poisoning
-        let children = graph.neighbors_directed(NodeIndex::new(id), Direction::Outgoing);
+    /// send_updates sends the diffs to all children of the worker
+    pub fn send_updates(&self, id: usize, timestamp: Timestamp, updates: Vec<Diff>) {
+        self.send_batch(id, timestamp, Arc::new(updates))
+    }
+
+    /// send_batch sends an already shared batch to all children of the worker. Every child gets a reference to the
+    /// same batch so fanning out is constant time per child.
+    ///
+    /// Children are sent a Progress message instead of an empty batch so they still know they've seen everything up
+    /// to the timestamp.
+    pub fn send_batch(&self, id: usize, timestamp: Timestamp, updates: Batch) {
+        let children: Vec<usize> = {
+            let graph = self.graph.read().unwrap(); // Fine with panicking on thread poisoning
+            graph
+                .neighbors_directed(NodeIndex::new(id), Direction::Outgoing)
+                .map(|child| child.index())
+                .collect()
+        };
 
         for child in children {
-            self.send_message(
-                child.index(),
-                Message::Update(Updates {
-                    updates: updates.clone(),
+            let message = match updates.is_empty() {
+                true => Message::Progress {
+                    source: id,
+                    timestamp,
+                },
+                false => Message::Update(Updates {
+                    updates: Arc::clone(&updates),
                     source: id,
-                    destination: child.index(),
+                    destination: child,
+                    timestamp,
                 }),
-            );
+            };
+            self.send_message(child, message);
+        }
+    }
+
+    /// write sends the updates into the graph at the given root worker and returns the timestamp given to them.
+    /// All other root workers are sent a Progress message for the timestamp so their frontiers move forward too.
+    pub fn write(&self, id: usize, updates: Vec<RowUpdate>) -> Result<Timestamp, WriteError> {
+        self.write_transaction(vec![(id, updates)])
+    }
+
+    /// write_transaction sends updates to any number of root workers under a single timestamp. Workers process all
+    /// updates for a timestamp together, so nothing downstream ever sees only part of the transaction.
+    ///
+    /// Each root's updates are consolidated into diffs here, once, so rows written several times travel through the
+    /// graph as a single diff and updates that cancel out are never sent.
+    ///
+    /// Nothing is written if any of the workers isn't a root.
+    pub fn write_transaction(
+        &self,
+        writes: Vec<(usize, Vec<RowUpdate>)>,
+    ) -> Result<Timestamp, WriteError> {
+        let mut batches: HashMap<usize, Vec<RowUpdate>> = HashMap::new();
+        for (id, updates) in writes {
+            self.check_root(id)?;
+            batches.entry(id).or_default().extend(updates);
+        }
+        let batches = batches
+            .into_iter()
+            .map(|(id, updates)| (id, consolidate(updates.into_iter().map(Diff::from))))
+            .collect();
+        Ok(self.commit(batches, None))
+    }
+
+    /// check_root checks the worker exists and has no parents
+    fn check_root(&self, id: usize) -> Result<(), WriteError> {
+        let graph = self.graph.read().unwrap(); // Fine with panicking on thread poisoning
+        let idx = NodeIndex::new(id);
+        if !graph.contains_node(idx) {
+            return Err(WriteError::UnknownWorker(id));
+        }
+        match graph.neighbors_directed(idx, Direction::Incoming).next() {
+            Some(_) => Err(WriteError::NotRoot(id)),
+            None => Ok(()),
+        }
+    }
+
+    /// commit gives the batches and any replay the next timestamp and sends a message for it to every root worker.
+    /// Roots with updates are sent them and the others are sent a Progress message. Replayed updates are held until
+    /// the worker reaches the timestamp.
+    fn commit(
+        &self,
+        mut batches: HashMap<usize, Vec<Diff>>,
+        replay: Option<(usize, Vec<Diff>)>,
+    ) -> Timestamp {
+        // Only the timestamp is given out under the clock. Sends can block, so they happen after it's released and
+        // roots put timestamps that arrive out of order back in order themselves.
+        let (timestamp, roots) = {
+            let mut clock = self.clock.lock().unwrap(); // Fine with panicking on thread poisoning
+            *clock += 1;
+            self.tracer.start(*clock);
+            if let Some((id, updates)) = replay {
+                let mut replays = self.replays.lock().unwrap();
+                replays.insert((id, *clock), updates);
+            }
+
+            let graph = self.graph.read().unwrap();
+            let roots: Vec<usize> = graph
+                .externals(Direction::Incoming)
+                .map(|idx| idx.index())
+                .collect();
+            (*clock, roots)
+        };
+
+        for root in roots {
+            let message = match batches.remove(&root) {
+                Some(batch) if !batch.is_empty() => Message::Update(Updates {
+                    updates: Arc::new(batch),
+                    source: root,
+                    destination: root,
+                    timestamp,
+                }),
+                _ => Message::Progress {
+                    source: root,
+                    timestamp,
+                },
+            };
+            self.send_message(root, message);
+        }
+
+        timestamp
+    }
+
+    /// take_replay returns the updates replayed into the worker at the timestamp
+    fn take_replay(&self, id: usize, timestamp: Timestamp) -> Option<Vec<Diff>> {
+        let mut replays = self.replays.lock().unwrap(); // Fine with panicking on thread poisoning
+        replays.remove(&(id, timestamp))
+    }
+
+    /// complete records that the worker has finished processing the timestamp from the given source
+    pub fn complete(&self, id: usize, source: usize, timestamp: Timestamp) {
+        let mut progress = self.progress.lock().unwrap(); // Fine with panicking on thread poisoning
+        let seen = progress.entry(id).or_default().entry(source).or_default();
+        *seen = timestamp.max(*seen);
+        self.progressed.notify_all();
+    }
+
+    /// frontier returns the latest timestamp the worker has fully processed. Every update caused by writes at or
+    /// before this timestamp has been handled by the worker.
+    pub fn frontier(&self, id: usize) -> Timestamp {
+        let progress = self.progress.lock().unwrap(); // Fine with panicking on thread poisoning
+        self.frontier_of(&progress, id)
+    }
+
+    fn frontier_of(
+        &self,
+        progress: &HashMap<usize, HashMap<usize, Timestamp>>,
+        id: usize,
+    ) -> Timestamp {
+        let seen = match progress.get(&id) {
+            None => return 0,
+            Some(seen) => seen,
+        };
+
+        let graph = self.graph.read().unwrap();
+        let mut parents = graph
+            .neighbors_directed(NodeIndex::new(id), Direction::Incoming)
+            .peekable();
+
+        match parents.peek() {
+            None => seen.values().copied().max().unwrap_or(0), // Roots only hear from writes
+            Some(_) => parents
+                .map(|p| seen.get(&p.index()).copied().unwrap_or(0))
+                .min()
+                .unwrap_or(0),
+        }
+    }
+
+    /// wait_for blocks until the worker's frontier has reached the timestamp. Returns false if the timeout passed
+    /// first.
+    pub fn wait_for(&self, id: usize, timestamp: Timestamp, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut progress = self.progress.lock().unwrap(); // Fine with panicking on thread poisoning
+
+        while self.frontier_of(&progress, id) < timestamp {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            progress = self
+                .progressed
+                .wait_timeout(progress, deadline - now)
+                .unwrap()
+                .0;
         }
+
+        true
     }
 
     pub fn send_message(&self, destination: usize, message: Message) {
-        let map = self.channels.read().unwrap(); // Fine with panicking on thread poisoning
-        let s = match map.get(&destination) {
+        // The sender is cloned so the lock isn't held while blocked on a full channel. A waiting add_worker would
+        // otherwise stop the destination from reading its next message too.
+        let s = match self.channels.read().unwrap().get(&destination) {
             None => return, // Node doesn't exist in map. We'll skip sending this message
-            Some((s, _)) => s,
+            Some((s, _)) => s.clone(),
         };
 
-        let _ = s.send(message); // We don't care about if the channel has been disconnected so can ignore the error
+        // We don't care about if the channel has been disconnected so can ignore the error
+        if let Err(TrySendError::Full(message)) = s.try_send(message) {
+            let started = Instant::now();
+            let _ = s.send(message);
+            self.metrics.record_blocked(destination, started.elapsed());
+        }
     }
 
-    pub fn iter(&self, id: usize) -> MessageRouterIter {
+    pub fn iter(&self, id: usize) -> MessageRouterIter<'_> {
         MessageRouterIter {
             router: self,
             worker_id: id,
         }
     }
+
+    /// epochs iterates over the updates for the worker grouped by timestamp. An epoch is only returned once every
+    /// parent has sent its updates for the timestamp.
+    pub fn epochs(&self, id: usize) -> EpochIter<'_> {
+        EpochIter {
+            router: self,
+            worker_id: id,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// topological_order returns the ids of every worker with parents always before their children
+    pub fn topological_order(&self) -> Vec<usize> {
+        // Workers are added after their parents so the graph never has cycles
+        let graph = self.graph.read().unwrap(); // Fine with panicking on thread poisoning
+        toposort(&*graph, None)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|idx| idx.index())
+            .collect()
+    }
+
+    /// ready_epoch removes the earliest epoch from the pending messages if every parent has sent its messages for it
+    pub(crate) fn ready_epoch(&self, id: usize, pending: &mut Pending) -> Option<Epoch> {
+        let (timestamp, epoch) = pending.iter().next()?;
+        let ready = match self.parent_count(id) {
+            // Roots get a message for every write, but writes can be sent to them out of order
+            0 => *timestamp == self.frontier(id) + 1,
+            parents => epoch.sources.len() >= parents,
+        };
+        if !ready {
+            return None;
+        }
+
+        let timestamp = *timestamp;
+        let mut epoch = pending.remove(&timestamp)?;
+        // Replayed updates arrive as if the worker sent them to itself
+        if let Some(replayed) = self.take_replay(id, timestamp) {
+            epoch.updates.push(Updates {
+                updates: Arc::new(replayed),
+                source: id,
+                destination: id,
+                timestamp,
+            });
+        }
+        Some(epoch)
+    }
+
+    fn parent_count(&self, id: usize) -> usize {
+        let graph = self.graph.read().unwrap(); // Fine with panicking on thread poisoning
+        graph
+            .neighbors_directed(NodeIndex::new(id), Direction::Incoming)
+            .count()
+    }
 }
 
 pub struct MessageRouterIter<'a> {
@@ -106,3 +651,333 @@ impl Iterator for MessageRouterIter<'_> {
         }
     }
 }
+
+/// Epoch holds all updates sent to a worker for a single timestamp
+pub struct Epoch {
+    pub timestamp: Timestamp,
+    /// Every source that sent a message for the timestamp, whether or not it had updates
+    pub sources: Vec<usize>,
+    pub updates: Vec<Updates>,
+}
+
+/// Pending holds the messages a worker has been sent for timestamps it hasn't handled yet
+pub(crate) type Pending = BTreeMap<Timestamp, Epoch>;
+
+/// receive adds an update or progress message to the pending epochs. Returns false if the message was a Stop.
+pub(crate) fn receive(pending: &mut Pending, message: Message) -> bool {
+    let (source, timestamp, updates) = match message {
+        Message::Update(u) => (u.source, u.timestamp, Some(u)),
+        Message::Progress { source, timestamp } => (source, timestamp, None),
+        Message::Stop => return false,
+    };
+    let epoch = pending.entry(timestamp).or_insert_with(|| Epoch {
+        timestamp,
+        sources: vec![],
+        updates: vec![],
+    });
+    epoch.sources.push(source);
+    epoch.updates.extend(updates);
+    true
+}
+
+pub struct EpochIter<'a> {
+    router: &'a MessageRouter,
+    worker_id: usize,
+    pending: Pending,
+}
+
+impl Iterator for EpochIter<'_> {
+    type Item = Epoch;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(epoch) = self.router.ready_epoch(self.worker_id, &mut self.pending) {
+                return Some(epoch);
+            }
+
+            if !receive(&mut self.pending, self.router.next_message(self.worker_id)) {
+                return None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::data::Comparison;
+    use crate::operations::data::Source;
+    use crate::operations::filter::{ColumnConstraint, Constraint, Filter};
+    use crate::operations::schema::{ColumnSchema, ColumnType};
+    use crate::operations::{Base, Map, Operation};
+    use crate::processing::OpWorker;
+    use std::thread;
+
+    fn table(router: &MessageRouter) -> usize {
+        router
+            .add_worker(vec![], |_| {
+                Ok(Schema::new(vec![ColumnSchema::new(
+                    "id",
+                    ColumnType::Integer,
+                    false,
+                )]))
+            })
+            .unwrap()
+    }
+
+    fn process(router: &MessageRouter, id: usize) {
+        match router.next_message(id) {
+            Message::Update(u) => {
+                router.send_batch(id, u.timestamp, u.updates);
+                router.complete(id, u.source, u.timestamp);
+            }
+            Message::Progress { source, timestamp } => {
+                router.send_batch(id, timestamp, Arc::new(vec![]));
+                router.complete(id, source, timestamp);
+            }
+            Message::Stop => {}
+        }
+    }
+
+    #[test]
+    fn tracks_frontiers() {
+        let router = MessageRouter::new();
+        let left = table(&router);
+        let right = table(&router);
+        let joined = router
+            .add_worker(vec![left, right], Schema::passthrough)
+            .unwrap();
+
+        let t1 = router
+            .write(left, vec![RowUpdate::Add(vec![1.into()].into())])
+            .unwrap();
+        let t2 = router
+            .write(right, vec![RowUpdate::Add(vec![2.into()].into())])
+            .unwrap();
+        assert!(t1 < t2);
+
+        process(&router, left);
+        process(&router, right);
+        assert_eq!(router.frontier(left), t1);
+        assert_eq!(router.frontier(right), t1);
+
+        process(&router, joined);
+        process(&router, joined);
+        assert_eq!(router.frontier(joined), t1);
+        assert!(!router.wait_for(joined, t2, Duration::from_millis(1)));
+
+        process(&router, left);
+        process(&router, right);
+        process(&router, joined);
+        process(&router, joined);
+        assert_eq!(router.frontier(joined), t2);
+        assert!(router.wait_for(joined, t2, Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn adds_workers_while_a_channel_is_full() {
+        let router = Arc::new(MessageRouter::new());
+        let id = table(&router);
+        while let Ok(()) = router.channels.read().unwrap()[&id]
+            .0
+            .try_send(Message::Stop)
+        {}
+
+        // The sender blocks on the full channel, then adding a worker waits for the channels lock
+        let sender = Arc::clone(&router);
+        thread::spawn(move || sender.send_message(id, Message::Stop));
+        thread::sleep(Duration::from_millis(20));
+        let adder = Arc::clone(&router);
+        thread::spawn(move || table(&adder));
+        thread::sleep(Duration::from_millis(20));
+
+        let (done, finished) = crossbeam::channel::bounded(1);
+        let reader = Arc::clone(&router);
+        thread::spawn(move || {
+            while reader.try_message(id).is_some() {}
+            let _ = done.send(reader.next_message(id));
+        });
+        assert!(finished.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+
+    #[test]
+    fn sends_progress_to_roots_without_updates() {
+        let router = MessageRouter::new();
+        let left = table(&router);
+        let right = table(&router);
+        let t1 = router
+            .write(left, vec![RowUpdate::Add(vec![1.into()].into())])
+            .unwrap();
+        let t2 = router
+            .write(left, vec![RowUpdate::Add(vec![2.into()].into())])
+            .unwrap();
+        assert!(matches!(router.try_message(left), Some(Message::Update(u)) if u.timestamp == t1));
+
+        // Writes can reach a root out of order, but it still handles them in order
+        let first = router.try_message(right).unwrap();
+        let second = router.try_message(right).unwrap();
+        assert!(matches!(second, Message::Progress { timestamp, .. } if timestamp == t2));
+        router.send_message(right, second);
+        router.send_message(right, first);
+        let mut epochs = router.epochs(right);
+        for t in [t1, t2] {
+            let epoch = epochs.next().unwrap();
+            assert_eq!(epoch.timestamp, t);
+            assert_eq!(epoch.sources, vec![right]);
+            assert!(epoch.updates.is_empty());
+            router.complete(right, right, t);
+        }
+    }
+
+    #[test]
+    fn rejects_writes_to_non_roots() {
+        let router = MessageRouter::new();
+        let left = table(&router);
+        let child = router.add_worker(vec![left], Schema::passthrough).unwrap();
+        let update = || vec![RowUpdate::Add(vec![1.into()].into())];
+
+        assert_eq!(
+            router.write(child, update()),
+            Err(WriteError::NotRoot(child))
+        );
+        assert_eq!(
+            router.write_transaction(vec![(left, update()), (42, update())]),
+            Err(WriteError::UnknownWorker(42))
+        );
+        // Nothing from the failed transaction was written
+        assert!(router.try_message(left).is_none());
+        assert!(router.try_message(child).is_none());
+
+        let t = router.write(left, update()).unwrap();
+        assert!(matches!(router.try_message(left), Some(Message::Update(u)) if u.timestamp == t));
+    }
+
+    #[test]
+    fn groups_transactions_into_epochs() {
+        let router = MessageRouter::new();
+        let left = table(&router);
+        let right = table(&router);
+        let joined = router
+            .add_worker(vec![left, right], Schema::passthrough)
+            .unwrap();
+
+        let t = router
+            .write_transaction(vec![
+                (left, vec![RowUpdate::Add(vec![1.into()].into())]),
+                (right, vec![RowUpdate::Add(vec![2.into()].into())]),
+                (left, vec![RowUpdate::Add(vec![3.into()].into())]),
+            ])
+            .unwrap();
+
+        let epoch = router.epochs(left).next().unwrap();
+        assert_eq!(epoch.timestamp, t);
+        assert_eq!(epoch.updates[0].updates.len(), 2);
+        router.send_batch(left, t, Arc::clone(&epoch.updates[0].updates));
+
+        let mut epochs = router.epochs(joined);
+        process(&router, right);
+        let epoch = epochs.next().unwrap();
+        assert_eq!(epoch.timestamp, t);
+        assert_eq!(epoch.updates.len(), 2);
+        let rows: usize = epoch.updates.iter().map(|u| u.updates.len()).sum();
+        assert_eq!(rows, 3);
+    }
+
+    #[test]
+    fn validates_schemas() {
+        let router = MessageRouter::new();
+        let mut base = Base {
+            schema: Schema::new(vec![
+                ColumnSchema::new("id", ColumnType::Integer, false),
+                ColumnSchema::new("name", ColumnType::Text, true),
+            ]),
+        };
+        let base = router.add_worker(vec![], |p| base.schema(p)).unwrap();
+
+        let mut map = Map {
+            sources: vec![Source::Column("name".into()), Source::Literal(1.into())],
+        };
+        let mapped = router.add_worker(vec![base], |p| map.schema(p)).unwrap();
+        assert_eq!(map.sources[0], Source::Column(1.into()));
+        let schema = router.schema(mapped).unwrap();
+        assert_eq!(schema.columns[0].name, "name");
+        assert_eq!(schema.columns[1].column_type, ColumnType::Integer);
+
+        let mut map = Map {
+            sources: vec![Source::Column(7.into())],
+        };
+        assert_eq!(
+            router.add_worker(vec![base], |p| map.schema(p)),
+            Err(SchemaError::MissingColumn {
+                column: 7,
+                width: 2
+            })
+        );
+        let mut map = Map {
+            sources: vec![Source::Column("missing".into())],
+        };
+        assert_eq!(
+            router.add_worker(vec![base], |p| map.schema(p)),
+            Err(SchemaError::UnknownColumn("missing".into()))
+        );
+        assert_eq!(
+            router.add_worker(vec![42], |p| map.schema(p)),
+            Err(SchemaError::MissingParent(42))
+        );
+        assert_eq!(
+            router.add_worker(vec![], |p| map.schema(p)),
+            Err(SchemaError::NoParents)
+        );
+    }
+
+    #[test]
+    fn exports_graphs() {
+        let router = Arc::new(MessageRouter::new());
+        let base = Base {
+            schema: Schema::new(vec![
+                ColumnSchema::new("value", ColumnType::Integer, false),
+                ColumnSchema::new("name", ColumnType::Text, true),
+            ]),
+        };
+        let base = OpWorker::new(router.clone(), base, vec![]).unwrap();
+        let filter = Filter {
+            constraints: vec![ColumnConstraint {
+                column: "value".into(),
+                constraint: Constraint::Comparison(Comparison::GreaterThan, 30.into()),
+            }],
+        };
+        let filter = OpWorker::new(router.clone(), filter, vec![base.id]).unwrap();
+        let map = Map {
+            sources: vec![Source::Column(1.into()), Source::Literal("x".into())],
+        };
+        let map = OpWorker::new(router.clone(), map, vec![filter.id]).unwrap();
+        router.write(base.id, vec![]).unwrap();
+
+        let dot = router.to_dot();
+        assert!(dot.contains("filter\\lconstraints: value > 30"), "{}", dot);
+        assert!(dot.contains("sources: name, \\\"x\\\""), "{}", dot);
+        assert!(
+            dot.contains(&format!("{} -> {}", filter.id, map.id)),
+            "{}",
+            dot
+        );
+
+        let json = router.to_json();
+        let nodes = match json.get("nodes") {
+            Some(Json::Array(nodes)) => nodes,
+            _ => panic!("no nodes in {}", json),
+        };
+        assert_eq!(nodes.len(), 3);
+        let base = &nodes[base.id];
+        assert_eq!(base.get("type").and_then(Json::as_str), Some("base"));
+        assert_eq!(base.get("queue"), Some(&Json::Number("1".into())));
+        let filter = &nodes[filter.id];
+        let constraints = filter.get("params").and_then(|p| p.get("constraints"));
+        assert_eq!(constraints.and_then(Json::as_str), Some("value > 30"));
+        let edges = match json.get("edges") {
+            Some(Json::Array(edges)) => edges.len(),
+            _ => 0,
+        };
+        assert_eq!(edges, 2);
+    }
+}
@@ -0,0 +1,228 @@
+//! Traces follow writes through the graph, recording when each worker handled them.
+//!
+//! Tracing is off by default. Once enabled, every write starts a trace and each worker adds a hop to it after
+//! processing the write's timestamp. A hop records how long the timestamp waited to reach the worker after its
+//! parents finished with it, and how long the worker spent on it. Only the most recent traces are kept.
+
+use crate::operations::data::Timestamp;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// CAPACITY is the number of traces kept before the oldest are dropped
+const CAPACITY: usize = 1024;
+
+/// Hop is a single worker's handling of a traced write
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hop {
+    pub node: usize,
+    /// The workers the updates came from. Roots list themselves.
+    pub sources: Vec<usize>,
+    /// Rows the worker received
+    pub rows: usize,
+    /// Time between the last parent finishing and this worker starting
+    pub waited: Duration,
+    /// Time spent processing
+    pub processed: Duration,
+    /// Time between the write and this worker finishing
+    pub finished: Duration,
+}
+
+impl fmt::Display for Hop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sources: Vec<String> = self.sources.iter().map(|s| s.to_string()).collect();
+        write!(
+            f,
+            "node {} from {}: {} rows, waited {:?}, processed {:?}, finished {:?} after the write",
+            self.node,
+            sources.join(", "),
+            self.rows,
+            self.waited,
+            self.processed,
+            self.finished
+        )
+    }
+}
+
+/// Trace is every hop recorded for a write so far
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trace {
+    pub timestamp: Timestamp,
+    pub hops: Vec<Hop>,
+}
+
+impl Trace {
+    /// hop returns the worker's hop, if it has handled the write
+    pub fn hop(&self, node: usize) -> Option<&Hop> {
+        self.hops.iter().find(|h| h.node == node)
+    }
+}
+
+#[derive(Debug)]
+struct Pending {
+    written: Instant,
+    hops: Vec<Hop>,
+}
+
+/// Tracer records traces for the router
+#[derive(Debug, Default)]
+pub struct Tracer {
+    enabled: AtomicBool,
+    traces: Mutex<BTreeMap<Timestamp, Pending>>,
+}
+
+impl Tracer {
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+        if !enabled {
+            self.traces.lock().unwrap().clear(); // Fine with panicking on thread poisoning
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// start begins a trace for the write
+    pub fn start(&self, timestamp: Timestamp) {
+        if !self.enabled() {
+            return;
+        }
+
+        let mut traces = self.traces.lock().unwrap(); // Fine with panicking on thread poisoning
+        traces.insert(
+            timestamp,
+            Pending {
+                written: Instant::now(),
+                hops: vec![],
+            },
+        );
+        while traces.len() > CAPACITY {
+            let oldest = *traces.keys().next().unwrap_or(&timestamp);
+            traces.remove(&oldest);
+        }
+    }
+
+    /// record adds a hop for the worker to the write's trace. started is when the worker began processing the
+    /// timestamp and the hop is taken to finish now.
+    pub fn record(
+        &self,
+        node: usize,
+        timestamp: Timestamp,
+        sources: &[usize],
+        rows: usize,
+        started: Instant,
+    ) {
+        if !self.enabled() {
+            return;
+        }
+
+        let finished = Instant::now();
+        let mut traces = self.traces.lock().unwrap(); // Fine with panicking on thread poisoning
+        let trace = match traces.get_mut(&timestamp) {
+            Some(trace) => trace,
+            None => return, // Written before tracing was enabled or already dropped
+        };
+
+        // Roots wait from the write itself
+        let ready = sources
+            .iter()
+            .filter(|s| **s != node)
+            .filter_map(|s| trace.hops.iter().find(|h| h.node == *s))
+            .map(|h| trace.written + h.finished)
+            .max()
+            .unwrap_or(trace.written);
+        trace.hops.push(Hop {
+            node,
+            sources: sources.to_vec(),
+            rows,
+            waited: started.saturating_duration_since(ready),
+            processed: finished.saturating_duration_since(started),
+            finished: finished.saturating_duration_since(trace.written),
+        });
+    }
+
+    /// get returns the trace of the write
+    pub fn get(&self, timestamp: Timestamp) -> Option<Trace> {
+        let traces = self.traces.lock().unwrap(); // Fine with panicking on thread poisoning
+        traces.get(&timestamp).map(|t| Trace {
+            timestamp,
+            hops: t.hops.clone(),
+        })
+    }
+
+    /// latest returns the most recent trace
+    pub fn latest(&self) -> Option<Trace> {
+        let timestamp = {
+            let traces = self.traces.lock().unwrap(); // Fine with panicking on thread poisoning
+            *traces.keys().next_back()?
+        };
+        self.get(timestamp)
+    }
+
+    /// written returns when the write was made, if it's still being traced
+    pub fn written(&self, timestamp: Timestamp) -> Option<Instant> {
+        let traces = self.traces.lock().unwrap(); // Fine with panicking on thread poisoning
+        traces.get(&timestamp).map(|t| t.written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::data::{Comparison, RowUpdate};
+    use crate::operations::filter::{ColumnConstraint, Constraint};
+    use crate::operations::schema::{ColumnSchema, ColumnType, Schema};
+    use crate::operations::{Base, Filter};
+    use crate::processing::{MessageRouter, OpWorker, ReaderWorker};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn traces_writes_to_readers() {
+        let router = Arc::new(MessageRouter::new());
+        let schema = Schema::new(vec![ColumnSchema::new("id", ColumnType::Integer, false)]);
+        let mut base = OpWorker::new(router.clone(), Base { schema }, vec![]).unwrap();
+        let filter = Filter {
+            constraints: vec![ColumnConstraint {
+                column: "id".into(),
+                constraint: Constraint::Comparison(Comparison::GreaterThan, 1.into()),
+            }],
+        };
+        let mut filter = OpWorker::new(router.clone(), filter, vec![base.id]).unwrap();
+        let mut reader = ReaderWorker::new(router.clone(), vec![filter.id], vec![]).unwrap();
+        let (base_id, filter_id, reader_id) = (base.id, filter.id, reader.id);
+
+        let untraced = router.write(base_id, vec![]).unwrap();
+        router.set_tracing(true);
+        let t = router
+            .write(
+                base_id,
+                vec![
+                    RowUpdate::Add(vec![1.into()].into()),
+                    RowUpdate::Add(vec![2.into()].into()),
+                ],
+            )
+            .unwrap();
+        thread::spawn(move || base.start());
+        thread::spawn(move || filter.start());
+        thread::spawn(move || reader.start());
+        assert!(router.wait_for(reader_id, t, Duration::from_secs(5)));
+
+        assert_eq!(router.trace(untraced), None);
+        let trace = router.trace(t).unwrap();
+        let nodes: Vec<usize> = trace.hops.iter().map(|h| h.node).collect();
+        assert_eq!(nodes, vec![base_id, filter_id, reader_id]);
+        let base = trace.hop(base_id).unwrap();
+        assert_eq!(base.sources, vec![base_id]);
+        assert_eq!(base.rows, 2);
+        let filter = trace.hop(filter_id).unwrap();
+        assert_eq!(filter.sources, vec![base_id]);
+        let reader = trace.hop(reader_id).unwrap();
+        assert_eq!(reader.rows, 1);
+        assert!(base.finished <= filter.finished && filter.finished <= reader.finished);
+        assert_eq!(router.lag(reader_id), Some(Duration::from_secs(0)));
+    }
+}
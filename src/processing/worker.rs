@@ -1,7 +1,27 @@
-use crate::operations::Operation;
-use crate::processing::router::MessageRouter;
-use crate::processing::Message;
+use crate::operations::columnar::ColumnarUpdates;
+use crate::operations::data::{Batch, Diff, Timestamp, Updates};
+use crate::operations::schema::{Schema, SchemaError};
+use crate::operations::{Description, Operation};
+use crate::processing::router::{Epoch, MessageRouter};
+use std::convert::TryFrom;
 use std::sync::Arc;
+use std::time::Instant;
+
+/// COLUMNAR_BATCH is the fewest updates a batch needs before it's worth converting to columns
+const COLUMNAR_BATCH: usize = 64;
+
+/// Worker is the part of a worker that handles its messages. Workers usually run on their own thread with start, but
+/// anything implementing Worker can also be run by an Executor.
+pub trait Worker {
+    fn id(&self) -> usize;
+
+    /// handle processes the updates sent to the worker for a timestamp and forwards the results to its children
+    fn handle(&mut self, epoch: Epoch);
+
+    /// stop is called once a worker running on its own thread has been stopped, so it can finish anything it's
+    /// holding on to. Executors never stop their workers.
+    fn stop(&mut self) {}
+}
 
 /// OpWorkers use operations to handle incoming messages
 pub struct OpWorker<T: Operation> {
@@ -11,26 +31,99 @@ pub struct OpWorker<T: Operation> {
 }
 
 impl<T: Operation> OpWorker<T> {
-    pub fn new(router: Arc<MessageRouter>, op: T, parents: Vec<usize>) -> Self {
-        Self {
-            id: router.add_worker(parents),
-            op,
-            router,
-        }
+    pub fn new(
+        router: Arc<MessageRouter>,
+        mut op: T,
+        parents: Vec<usize>,
+    ) -> Result<Self, SchemaError> {
+        let mut description = Description::default();
+        let id = router.add_worker(parents, |p| {
+            let schema = op.schema(p)?;
+            description = op.describe(p);
+            Ok(schema)
+        })?;
+        router.set_description(id, description);
+        Ok(Self { id, op, router })
     }
 
     /// starts running the worker. This will loop until the message router stops providing messages
     pub fn start(&mut self) {
-        for message in self.router.iter(self.id) {
-            match message {
-                Message::Update(u) => {
-                    let updates = self.op.process(u);
-
-                    self.router.send_updates(self.id, updates)
-                }
-                Message::Stop => break,
+        let router = Arc::clone(&self.router);
+        for epoch in router.epochs(self.id) {
+            self.handle(epoch);
+        }
+    }
+
+    /// process_columns processes the batch a column at a time if the operation can and the batch is large enough.
+    /// Returns None if the batch should be processed as rows instead. Batches with rows of different widths can't be
+    /// converted to columns, so they're always left to rows.
+    fn process_columns(&mut self, batch: &Batch) -> Option<Vec<Diff>> {
+        let op = self.op.columnar()?;
+        if batch.len() < COLUMNAR_BATCH {
+            return None;
+        }
+        let columns = ColumnarUpdates::try_from(batch.as_slice()).ok()?;
+        op.process_columns(columns)
+            .ok()
+            .map(ColumnarUpdates::into_diffs)
+    }
+
+    /// process_rows processes each update on its own so only the ones that fail are left out. They're sent to the
+    /// router's dead letters instead.
+    fn process_rows(&mut self, batch: &Batch, source: usize, timestamp: Timestamp) -> Vec<Diff> {
+        let mut processed = vec![];
+        for update in batch.iter() {
+            let single = Updates {
+                updates: Arc::new(vec![update.clone()]),
+                source,
+                destination: self.id,
+                timestamp,
+            };
+            match self.op.process(single) {
+                Ok(rows) => processed.extend(rows),
+                Err(error) => self
+                    .router
+                    .dead_letter(self.id, timestamp, update.clone(), error),
             }
         }
+        processed
+    }
+}
+
+impl<T: Operation> Worker for OpWorker<T> {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    /// All updates for a timestamp are processed before any are forwarded so children see the whole epoch at once.
+    fn handle(&mut self, epoch: Epoch) {
+        let started = Instant::now();
+        let mut rows = 0;
+        let mut updates = vec![];
+        for u in epoch.updates {
+            rows += u.updates.len();
+            // The batch is kept so it can be retried a row at a time if any row fails
+            let batch = Arc::clone(&u.updates);
+            let (source, timestamp) = (u.source, u.timestamp);
+            if let Some(processed) = self.process_columns(&batch) {
+                updates.extend(processed);
+                continue;
+            }
+            match self.op.process(u) {
+                Ok(processed) => updates.extend(processed),
+                Err(_) => updates.extend(self.process_rows(&batch, source, timestamp)),
+            }
+        }
+        self.router
+            .record_epoch(self.id, rows, updates.len(), started.elapsed());
+        self.router.record_state_size(self.id, self.op.state_size());
+        self.router
+            .record_hop(self.id, epoch.timestamp, &epoch.sources, rows, started);
+
+        self.router.send_updates(self.id, epoch.timestamp, updates);
+        for source in &epoch.sources {
+            self.router.complete(self.id, *source, epoch.timestamp);
+        }
     }
 }
 
@@ -41,26 +134,149 @@ pub struct DebugWorker {
 }
 
 impl DebugWorker {
-    pub fn new(router: Arc<MessageRouter>, parents: Vec<usize>) -> Self {
-        Self {
-            id: router.add_worker(parents),
-            router,
-        }
+    pub fn new(router: Arc<MessageRouter>, parents: Vec<usize>) -> Result<Self, SchemaError> {
+        let id = router.add_worker(parents, Schema::passthrough)?;
+        router.set_description(id, Description::new("debug"));
+        Ok(Self { id, router })
     }
 
     /// starts running the worker. This will loop until the message router stops providing messages
     pub fn start(&mut self) {
-        for message in self.router.iter(self.id) {
-            match message {
-                Message::Update(u) => {
-                    println!("{:?}", u);
-                    self.router.send_updates(self.id, u.updates)
-                }
-                Message::Stop => {
-                    println!("Stopping due to message"); // This should never happen. Stop messages are checked in the iter
-                    break;
-                }
-            }
+        let router = Arc::clone(&self.router);
+        for epoch in router.epochs(self.id) {
+            self.handle(epoch);
         }
     }
 }
+
+impl Worker for DebugWorker {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn handle(&mut self, epoch: Epoch) {
+        let started = Instant::now();
+        for u in epoch.updates.iter().filter(|u| !u.updates.is_empty()) {
+            println!("{:?}", u);
+        }
+
+        // A single batch can be passed along without copying it
+        let batch = match epoch.updates.as_slice() {
+            [u] => Arc::clone(&u.updates),
+            all => Arc::new(all.iter().flat_map(|u| u.updates.iter().cloned()).collect()),
+        };
+        self.router.record_hop(
+            self.id,
+            epoch.timestamp,
+            &epoch.sources,
+            batch.len(),
+            started,
+        );
+        self.router.send_batch(self.id, epoch.timestamp, batch);
+        for source in &epoch.sources {
+            self.router.complete(self.id, *source, epoch.timestamp);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::data::{DataType, RowUpdate, Source};
+    use crate::operations::schema::{ColumnSchema, ColumnType};
+    use crate::operations::state::MemStore;
+    use crate::operations::ProcessError;
+    use crate::operations::{Base, Count, Map};
+    use crate::processing::{Executor, ReaderWorker};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn sends_failed_rows_to_dead_letters() {
+        let router = Arc::new(MessageRouter::new());
+        let schema = Schema::new(vec![
+            ColumnSchema::new("id", ColumnType::Integer, false),
+            ColumnSchema::new("name", ColumnType::Text, true),
+        ]);
+        let mut base = OpWorker::new(router.clone(), Base { schema }, vec![]).unwrap();
+        let map = Map {
+            sources: vec![Source::Column("name".into())],
+        };
+        let mut map = OpWorker::new(router.clone(), map, vec![base.id]).unwrap();
+        let mut reader = ReaderWorker::new(router.clone(), vec![map.id], vec![]).unwrap();
+        let (map_id, reader_id, view) = (map.id, reader.id, reader.view());
+
+        // Writes straight to the router skip the schema checks made by the server and frontend
+        let short = RowUpdate::Add(vec![2.into()].into());
+        let t = router
+            .write(
+                base.id,
+                vec![
+                    RowUpdate::Add(vec![1.into(), "a".into()].into()),
+                    short.clone(),
+                ],
+            )
+            .unwrap();
+        thread::spawn(move || base.start());
+        thread::spawn(move || map.start());
+        thread::spawn(move || reader.start());
+
+        assert!(router.wait_for(reader_id, t, Duration::from_secs(5)));
+        assert_eq!(view.rows(), vec![vec!["a".into()].into()]);
+        let letters = router.dead_letters().list();
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].node, map_id);
+        assert_eq!(letters[0].timestamp, t);
+        assert_eq!(letters[0].update, Diff::from(short));
+        assert_eq!(
+            letters[0].error,
+            ProcessError::MissingColumn {
+                column: 1,
+                width: 1
+            }
+        );
+        assert_eq!(router.metrics()[&map_id].rows_failed, 1);
+    }
+
+    #[test]
+    fn processes_large_batches_as_columns() {
+        let count = || Count {
+            source: Source::Column(1.into()),
+            group: vec![0.into()],
+            state: MemStore::new(),
+        };
+        let rows: Vec<RowUpdate> = (0..COLUMNAR_BATCH as i32 * 2)
+            .map(|i| {
+                let value = if i % 3 == 0 { DataType::None } else { i.into() };
+                RowUpdate::Add(vec![(i % 4).into(), value].into())
+            })
+            .collect();
+        let expected = count().process(rows.clone().into()).unwrap();
+
+        let mut executor = Executor::new();
+        let router = executor.router();
+        let schema = Schema::new(vec![
+            ColumnSchema::new("group", ColumnType::Integer, false),
+            ColumnSchema::new("value", ColumnType::Integer, true),
+        ]);
+        let base = OpWorker::new(router.clone(), Base { schema }, vec![]).unwrap();
+        let mut worker = OpWorker::new(router.clone(), count(), vec![base.id]).unwrap();
+        let batch = Arc::new(rows.into_iter().map(Diff::from).collect::<Vec<Diff>>());
+        assert_eq!(worker.process_columns(&batch), Some(expected));
+
+        // Short rows can't be converted to columns, so they go through rows and are dead lettered
+        let reader = ReaderWorker::new(router.clone(), vec![worker.id], vec![]).unwrap();
+        let view = reader.view();
+        let mut updates: Vec<RowUpdate> = (0..COLUMNAR_BATCH as i32)
+            .map(|i| RowUpdate::Add(vec![0.into(), i.into()].into()))
+            .collect();
+        updates.push(RowUpdate::Add(vec![1.into()].into()));
+        let base_id = executor.add(base);
+        executor.add(worker);
+        executor.add(reader);
+        router.write(base_id, updates).unwrap();
+        executor.run();
+        assert_eq!(view.rows().len(), COLUMNAR_BATCH);
+        assert_eq!(router.dead_letters().len(), 1);
+    }
+}
@@ -0,0 +1,99 @@
+//! A minimal HTTP endpoint serving the graph's metrics in the Prometheus text format.
+//!
+//! Only `GET /metrics` is answered. Every response closes the connection, which is all a Prometheus scraper needs.
+
+use crate::processing::MessageRouter;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// MAX_REQUEST is the most of a request that's read, enough for the request line and any reasonable headers
+const MAX_REQUEST: u64 = 8192;
+
+/// TIMEOUT is how long a client has to send its request and read the response
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// serve accepts scrapes until the listener fails. Scrapes are cheap and rare so they're answered one at a time, and
+/// each client only has a few seconds to send its request so a slow one can't hold up the rest for long.
+pub fn serve(router: Arc<MessageRouter>, listener: TcpListener) -> io::Result<()> {
+    for stream in listener.incoming() {
+        if let Err(e) = respond(&router, stream?) {
+            eprintln!("metrics connection failed: {}", e);
+        }
+    }
+    Ok(())
+}
+
+fn respond(router: &MessageRouter, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?.take(MAX_REQUEST));
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // The headers are read so the client isn't reset by closing with unread data
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    // Request lines cut off by the limit don't end with a newline
+    if !request.ends_with('\n') {
+        request.clear();
+    }
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", router.prometheus()),
+        (Some("GET"), _) => ("404 Not Found", "not found\n".into()),
+        (None, _) => ("400 Bad Request", "bad request\n".into()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".into()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::schema::{ColumnSchema, ColumnType, Schema};
+    use crate::operations::Base;
+    use crate::processing::OpWorker;
+    use std::thread;
+
+    #[test]
+    fn serves_metrics() {
+        let router = Arc::new(MessageRouter::new());
+        let schema = Schema::new(vec![ColumnSchema::new("id", ColumnType::Integer, false)]);
+        OpWorker::new(router.clone(), Base { schema }, vec![]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let served = router.clone();
+        thread::spawn(move || serve(served, listener));
+
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with(&router.prometheus()), "{}", response);
+        assert!(response.contains("dataflow_queue_depth{node=\"0\",type=\"base\"} 0"));
+        assert!(get("/").starts_with("HTTP/1.1 404"));
+
+        // Request lines longer than the limit are refused without reading any more
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET /{}", "x".repeat(MAX_REQUEST as usize - 5)).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+    }
+}
@@ -0,0 +1,382 @@
+use self::protocol::{read_frame, write_frame, Request, Response};
+use crate::operations::data::{DataType, Row, RowUpdate, Timestamp};
+use crate::processing::{Change, MessageRouter, ReaderWorker, View, SUBSCRIBER_CAPACITY};
+use crossbeam::channel::RecvTimeoutError;
+use std::collections::HashMap;
+use std::io;
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+pub mod metrics;
+pub mod protocol;
+
+/// Server exposes writes to base tables and reads from views to other processes over TCP. See the protocol module
+/// for the format of requests and responses.
+pub struct Server {
+    router: Arc<MessageRouter>,
+    tables: HashMap<String, usize>,
+    views: HashMap<String, (usize, Arc<View>)>,
+    read_timeout: Duration,
+}
+
+impl Server {
+    pub fn new(router: Arc<MessageRouter>) -> Self {
+        Self {
+            router,
+            tables: HashMap::new(),
+            views: HashMap::new(),
+            read_timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// add_table lets clients write to the base worker under the name
+    pub fn add_table(&mut self, name: &str, id: usize) {
+        self.tables.insert(name.into(), id);
+    }
+
+    /// add_view lets clients read the reader's view under the name
+    pub fn add_view(&mut self, name: &str, reader: &ReaderWorker) {
+        self.views.insert(name.into(), (reader.id, reader.view()));
+    }
+
+    /// read_timeout sets how long reads will wait for a view to catch up to a timestamp
+    pub fn read_timeout(&mut self, timeout: Duration) {
+        self.read_timeout = timeout;
+    }
+
+    /// handle runs a single request against the graph
+    pub fn handle(&self, request: Request) -> Response {
+        let result = match request {
+            Request::Write { table, updates } => self
+                .check_write(&table, &updates)
+                .and_then(|id| self.router.write(id, updates).map_err(|e| e.to_string())),
+            Request::Transaction { writes } => writes
+                .into_iter()
+                .map(|(table, updates)| Ok((self.check_write(&table, &updates)?, updates)))
+                .collect::<Result<Vec<(usize, Vec<RowUpdate>)>, String>>()
+                .and_then(|writes| {
+                    self.router
+                        .write_transaction(writes)
+                        .map_err(|e| e.to_string())
+                }),
+            Request::Read {
+                view,
+                key,
+                timestamp,
+            } => return self.read(&view, &key, timestamp),
+            Request::Subscribe { .. } => Err("subscriptions need their own connection".into()),
+        };
+
+        match result {
+            Ok(timestamp) => Response::Written { timestamp },
+            Err(message) => Response::Error { message },
+        }
+    }
+
+    fn check_write(&self, table: &str, updates: &[RowUpdate]) -> Result<usize, String> {
+        let id = *self
+            .tables
+            .get(table)
+            .ok_or_else(|| format!("no table named {}", table))?;
+        let schema = self.router.schema(id).unwrap_or_default();
+        for update in updates {
+            schema
+                .check_row(update.row())
+                .map_err(|e| format!("invalid row for {}: {}", table, e))?;
+        }
+        Ok(id)
+    }
+
+    fn read(&self, view: &str, key: &[DataType], timestamp: Timestamp) -> Response {
+        let (id, rows) = match self.views.get(view) {
+            Some(v) => v,
+            None => {
+                return Response::Error {
+                    message: format!("no view named {}", view),
+                }
+            }
+        };
+
+        // Views never reach timestamps that haven't been given out, so waiting for one would only time out
+        if timestamp > self.router.latest() {
+            return Response::Error {
+                message: format!("no write has been made at {} yet", timestamp),
+            };
+        }
+        if !self.router.wait_for(*id, timestamp, self.read_timeout) {
+            return Response::Error {
+                message: format!("timed out waiting for {} to reach {}", view, timestamp),
+            };
+        }
+
+        Response::Rows {
+            frontier: self.router.frontier(*id),
+            rows: rows.lookup(key),
+        }
+    }
+
+    /// serve accepts connections until the listener fails, handling each connection on its own thread
+    pub fn serve(self: Arc<Self>, listener: TcpListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let server = Arc::clone(&self);
+            thread::spawn(move || {
+                if let Err(e) = server.handle_connection(stream) {
+                    eprintln!("connection failed: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn handle_connection(&self, mut stream: TcpStream) -> io::Result<()> {
+        while let Some(request) = read_frame(&mut stream)? {
+            if let Request::Subscribe { view } = request {
+                return self.stream_changes(&view, stream);
+            }
+            write_frame(&mut stream, &self.handle(request))?;
+        }
+        Ok(())
+    }
+
+    /// stream_changes sends a snapshot of the view followed by its changes until the client disconnects
+    fn stream_changes(&self, view: &str, mut stream: TcpStream) -> io::Result<()> {
+        let subscription = match self.views.get(view) {
+            Some((_, view)) => view.subscribe(),
+            None => {
+                let message = format!("no view named {}", view);
+                return write_frame(&mut stream, &Response::Error { message });
+            }
+        };
+
+        write_frame(
+            &mut stream,
+            &Response::Snapshot {
+                timestamp: subscription.timestamp,
+                rows: subscription.snapshot.clone(),
+            },
+        )?;
+        loop {
+            match subscription.recv_timeout(Duration::from_secs(1)) {
+                Ok(change) => write_frame(
+                    &mut stream,
+                    &Response::Changes {
+                        timestamp: change.timestamp,
+                        updates: change.updates.to_vec(),
+                    },
+                )?,
+                // Quiet views only notice the client has gone when checking for it
+                Err(RecvTimeoutError::Timeout) if !closed(&stream)? => {}
+                Err(RecvTimeoutError::Timeout) => return Ok(()),
+                // The server keeps its views, so the subscription only ends when the client falls behind
+                Err(RecvTimeoutError::Disconnected) => {
+                    let message = format!("fell more than {} changes behind", SUBSCRIBER_CAPACITY);
+                    return write_frame(&mut stream, &Response::Error { message });
+                }
+            }
+        }
+    }
+}
+
+/// closed checks whether the client has closed the connection without waiting for it to send anything
+fn closed(stream: &TcpStream) -> io::Result<bool> {
+    stream.set_nonblocking(true)?;
+    let result = match stream.peek(&mut [0]) {
+        Ok(n) => Ok(n == 0),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e),
+    };
+    stream.set_nonblocking(false)?;
+    result
+}
+
+/// Client talks to a Server over TCP
+pub struct Client {
+    stream: TcpStream,
+}
+
+impl Client {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        Ok(Self {
+            stream: TcpStream::connect(addr)?,
+        })
+    }
+
+    /// write sends updates to a table, returning the timestamp they were written at
+    pub fn write(&mut self, table: &str, updates: Vec<RowUpdate>) -> io::Result<Timestamp> {
+        self.written(Request::Write {
+            table: table.into(),
+            updates,
+        })
+    }
+
+    /// transaction writes to any number of tables under a single timestamp
+    pub fn transaction(&mut self, writes: Vec<(String, Vec<RowUpdate>)>) -> io::Result<Timestamp> {
+        self.written(Request::Transaction { writes })
+    }
+
+    /// read looks up rows in a view by key, waiting for the view to catch up to the timestamp first. An empty key
+    /// reads every row and a timestamp of 0 doesn't wait.
+    pub fn read(
+        &mut self,
+        view: &str,
+        key: Vec<DataType>,
+        timestamp: Timestamp,
+    ) -> io::Result<Vec<Row>> {
+        match self.request(Request::Read {
+            view: view.into(),
+            key,
+            timestamp,
+        })? {
+            Response::Rows { rows, .. } => Ok(rows),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// subscribe turns the connection into a stream of the view's changes, starting with a snapshot of its rows
+    pub fn subscribe(mut self, view: &str) -> io::Result<RemoteSubscription> {
+        match self.request(Request::Subscribe { view: view.into() })? {
+            Response::Snapshot { timestamp, rows } => Ok(RemoteSubscription {
+                timestamp,
+                snapshot: rows,
+                stream: self.stream,
+            }),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn written(&mut self, request: Request) -> io::Result<Timestamp> {
+        match self.request(request)? {
+            Response::Written { timestamp } => Ok(timestamp),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn request(&mut self, request: Request) -> io::Result<Response> {
+        write_frame(&mut self.stream, &request)?;
+        read_frame(&mut self.stream)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "server closed connection"))
+    }
+}
+
+/// RemoteSubscription receives the changes made to a view on a server after its snapshot was taken
+pub struct RemoteSubscription {
+    /// The timestamp of the last change included in the snapshot
+    pub timestamp: Timestamp,
+    pub snapshot: Vec<Row>,
+    stream: TcpStream,
+}
+
+impl RemoteSubscription {
+    /// recv waits for the next change. Returns None once the server closes the connection.
+    pub fn recv(&mut self) -> io::Result<Option<Change>> {
+        match read_frame(&mut self.stream)? {
+            None => Ok(None),
+            Some(Response::Changes { timestamp, updates }) => Ok(Some(Change {
+                timestamp,
+                updates: Arc::new(updates),
+            })),
+            Some(response) => Err(unexpected(response)),
+        }
+    }
+
+    /// set_timeout sets how long recv waits for a change before failing. None waits forever.
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+}
+
+fn unexpected(response: Response) -> io::Error {
+    match response {
+        Response::Error { message } => io::Error::other(message),
+        r => io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected response {:?}", r),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::data::Diff;
+    use crate::operations::schema::{ColumnSchema, ColumnType, Schema};
+    use crate::operations::Base;
+    use crate::processing::OpWorker;
+
+    #[test]
+    fn serves_writes_and_reads() {
+        let router = Arc::new(MessageRouter::new());
+        let mut base = OpWorker::new(
+            router.clone(),
+            Base {
+                schema: Schema::new(vec![
+                    ColumnSchema::new("id", ColumnType::Integer, false),
+                    ColumnSchema::new("category", ColumnType::Text, true),
+                ]),
+            },
+            vec![],
+        )
+        .unwrap();
+        let mut reader =
+            ReaderWorker::new(router.clone(), vec![base.id], vec!["category".into()]).unwrap();
+
+        let mut server = Server::new(router.clone());
+        server.add_table("items", base.id);
+        server.add_view("by_category", &reader);
+
+        thread::spawn(move || base.start());
+        thread::spawn(move || reader.start());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || Arc::new(server).serve(listener));
+
+        let mut client = Client::connect(addr).unwrap();
+        let t = client
+            .write(
+                "items",
+                vec![
+                    RowUpdate::Add(vec![1.into(), "a".into()].into()),
+                    RowUpdate::Add(vec![2.into(), "b".into()].into()),
+                ],
+            )
+            .unwrap();
+
+        let rows = client.read("by_category", vec!["a".into()], t).unwrap();
+        assert_eq!(rows, vec![vec![1.into(), "a".into()].into()]);
+        assert_eq!(client.read("by_category", vec![], t).unwrap().len(), 2);
+
+        let err = client
+            .write("items", vec![RowUpdate::Add(vec!["x".into()].into())])
+            .unwrap_err();
+        assert!(err.to_string().contains("expected 2 columns"));
+        assert!(client.read("missing", vec![], 0).is_err());
+        // Reads from the future fail straight away rather than waiting for the timeout
+        let started = std::time::Instant::now();
+        assert!(client.read("by_category", vec![], t + 100).is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let mut subscription = Client::connect(addr)
+            .unwrap()
+            .subscribe("by_category")
+            .unwrap();
+        assert_eq!(subscription.snapshot.len(), 2);
+        assert_eq!(subscription.timestamp, t);
+        subscription
+            .set_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let removed = RowUpdate::Remove(vec![1.into(), "a".into()].into());
+        let t = client.write("items", vec![removed.clone()]).unwrap();
+        assert_eq!(
+            subscription.recv().unwrap(),
+            Some(Change {
+                timestamp: t,
+                updates: Arc::new(vec![Diff::from(removed)]),
+            })
+        );
+        assert!(Client::connect(addr).unwrap().subscribe("missing").is_err());
+    }
+}
@@ -0,0 +1,287 @@
+//! The request/response protocol used by the server.
+//!
+//! Clients open a TCP connection and send requests one at a time, reading the response to each before sending the
+//! next. Every request and response is sent as a frame: a 4 byte big endian length followed by that many bytes of
+//! payload. Payloads use the binary encoding from `operations::encoding`, so they start with the encoding version
+//! byte followed by a tag byte saying what kind of message it is.
+//!
+//! Text is a varint length followed by utf8 bytes and lists are a varint count followed by their items.
+//!
+//! Requests:
+//!
+//! | Tag | Request     | Body                                                                  |
+//! |-----|-------------|-----------------------------------------------------------------------|
+//! | 0   | Write       | table name, list of RowUpdates                                        |
+//! | 1   | Transaction | list of (table name, list of RowUpdates)                              |
+//! | 2   | Read        | view name, key row (empty for all rows), varint timestamp to wait for |
+//! | 3   | Subscribe   | view name                                                             |
+//!
+//! Responses:
+//!
+//! | Tag | Response | Body                                                       |
+//! |-----|----------|------------------------------------------------------------|
+//! | 0   | Written  | varint timestamp given to the write                        |
+//! | 1   | Rows     | varint frontier of the view when read, list of rows        |
+//! | 2   | Error    | error message                                              |
+//! | 3   | Snapshot | varint timestamp of the last change included, list of rows |
+//! | 4   | Changes  | varint timestamp, list of diffs                            |
+//!
+//! Reads with a timestamp of 0 return immediately. Otherwise the server waits until the view has processed every
+//! write up to the timestamp, so passing the timestamp from a write response gives read-your-writes consistency.
+//!
+//! Subscribing turns the connection into a stream of the view's changes. The server responds with a Snapshot of the
+//! view, or an Error if there's no such view, then sends Changes as they're made until the client disconnects. Clients
+//! that fall too far behind are sent an Error and disconnected. No more requests can be sent on the connection.
+
+use crate::operations::data::{DataType, Diff, Row, RowUpdate, Timestamp};
+use crate::operations::encoding::{from_bytes, to_bytes, Decode, DecodeError, Encode};
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+
+/// MAX_FRAME is the largest payload that will be read, to stop a bad length from allocating huge buffers
+pub const MAX_FRAME: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    Write {
+        table: String,
+        updates: Vec<RowUpdate>,
+    },
+    Transaction {
+        writes: Vec<(String, Vec<RowUpdate>)>,
+    },
+    Read {
+        view: String,
+        key: Vec<DataType>,
+        timestamp: Timestamp,
+    },
+    Subscribe {
+        view: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    Written {
+        timestamp: Timestamp,
+    },
+    Rows {
+        frontier: Timestamp,
+        rows: Vec<Row>,
+    },
+    Error {
+        message: String,
+    },
+    Snapshot {
+        timestamp: Timestamp,
+        rows: Vec<Row>,
+    },
+    Changes {
+        timestamp: Timestamp,
+        updates: Vec<Diff>,
+    },
+}
+
+fn read_tag(input: &mut &[u8]) -> Result<u8, DecodeError> {
+    let (tag, rest) = input.split_first().ok_or(DecodeError::UnexpectedEnd)?;
+    *input = rest;
+    Ok(*tag)
+}
+
+impl Encode for Request {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Request::Write { table, updates } => {
+                buf.push(0);
+                table.encode(buf);
+                updates.as_slice().encode(buf);
+            }
+            Request::Transaction { writes } => {
+                buf.push(1);
+                (writes.len() as u64).encode(buf);
+                for (table, updates) in writes {
+                    table.encode(buf);
+                    updates.as_slice().encode(buf);
+                }
+            }
+            Request::Read {
+                view,
+                key,
+                timestamp,
+            } => {
+                buf.push(2);
+                view.encode(buf);
+                key.as_slice().encode(buf);
+                timestamp.encode(buf);
+            }
+            Request::Subscribe { view } => {
+                buf.push(3);
+                view.encode(buf);
+            }
+        }
+    }
+}
+
+impl Decode for Request {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match read_tag(input)? {
+            0 => Ok(Request::Write {
+                table: String::decode(input)?,
+                updates: Vec::decode(input)?,
+            }),
+            1 => {
+                let len =
+                    usize::try_from(u64::decode(input)?).map_err(|_| DecodeError::Overflow)?;
+                let mut writes = Vec::with_capacity(len.min(input.len()));
+                for _ in 0..len {
+                    writes.push((String::decode(input)?, Vec::decode(input)?));
+                }
+                Ok(Request::Transaction { writes })
+            }
+            2 => Ok(Request::Read {
+                view: String::decode(input)?,
+                key: Vec::decode(input)?,
+                timestamp: u64::decode(input)?,
+            }),
+            3 => Ok(Request::Subscribe {
+                view: String::decode(input)?,
+            }),
+            tag => Err(DecodeError::UnknownTag(tag)),
+        }
+    }
+}
+
+impl Encode for Response {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Response::Written { timestamp } => {
+                buf.push(0);
+                timestamp.encode(buf);
+            }
+            Response::Rows { frontier, rows } => {
+                buf.push(1);
+                frontier.encode(buf);
+                rows.as_slice().encode(buf);
+            }
+            Response::Error { message } => {
+                buf.push(2);
+                message.encode(buf);
+            }
+            Response::Snapshot { timestamp, rows } => {
+                buf.push(3);
+                timestamp.encode(buf);
+                rows.as_slice().encode(buf);
+            }
+            Response::Changes { timestamp, updates } => {
+                buf.push(4);
+                timestamp.encode(buf);
+                updates.as_slice().encode(buf);
+            }
+        }
+    }
+}
+
+impl Decode for Response {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match read_tag(input)? {
+            0 => Ok(Response::Written {
+                timestamp: u64::decode(input)?,
+            }),
+            1 => Ok(Response::Rows {
+                frontier: u64::decode(input)?,
+                rows: Vec::decode(input)?,
+            }),
+            2 => Ok(Response::Error {
+                message: String::decode(input)?,
+            }),
+            3 => Ok(Response::Snapshot {
+                timestamp: u64::decode(input)?,
+                rows: Vec::decode(input)?,
+            }),
+            4 => Ok(Response::Changes {
+                timestamp: u64::decode(input)?,
+                updates: Vec::decode(input)?,
+            }),
+            tag => Err(DecodeError::UnknownTag(tag)),
+        }
+    }
+}
+
+/// write_frame writes the message as a single length prefixed frame
+pub fn write_frame<W: Write, T: Encode>(w: &mut W, message: &T) -> io::Result<()> {
+    let payload = to_bytes(message);
+    let len = u32::try_from(payload.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message too large"))?;
+    w.write_all(&len.to_be_bytes())?;
+    w.write_all(&payload)?;
+    w.flush()
+}
+
+/// read_frame reads a single frame and decodes the message in it. Returns None if the connection was closed
+/// before a new frame started.
+pub fn read_frame<R: Read, T: Decode>(r: &mut R) -> io::Result<Option<T>> {
+    let mut len = [0; 4];
+    match r.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes is too large", len),
+        ));
+    }
+
+    let mut payload = vec![0; len];
+    r.read_exact(&mut payload)?;
+    from_bytes(&payload)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_frames() {
+        let requests = vec![
+            Request::Write {
+                table: "items".into(),
+                updates: vec![RowUpdate::Add(vec![1.into(), "a".into()].into())],
+            },
+            Request::Transaction {
+                writes: vec![
+                    ("items".into(), vec![]),
+                    (
+                        "orders".into(),
+                        vec![RowUpdate::Remove(vec![2.into()].into())],
+                    ),
+                ],
+            },
+            Request::Read {
+                view: "by_category".into(),
+                key: vec!["a".into()],
+                timestamp: 12,
+            },
+            Request::Subscribe {
+                view: "by_category".into(),
+            },
+        ];
+
+        let mut buf = vec![];
+        for request in &requests {
+            write_frame(&mut buf, request).unwrap();
+        }
+
+        let mut reader = buf.as_slice();
+        for request in requests {
+            let read: Option<Request> = read_frame(&mut reader).unwrap();
+            assert_eq!(read, Some(request));
+        }
+        assert_eq!(read_frame::<_, Request>(&mut reader).unwrap(), None);
+    }
+}